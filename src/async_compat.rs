@@ -0,0 +1,120 @@
+use std::collections::VecDeque;
+
+use anyhow::{Result, anyhow};
+use tokio::{runtime::Handle, task::JoinHandle};
+
+use crate::{
+    AsyncRestart, Restart,
+    sinks::{AsyncSendAudio, SendAudio},
+    sources::{AsyncRecvAudio, RecvAudio},
+};
+
+/// Makes an async pack usable where a blocking one is expected, like in a
+/// [`Pipeline`](crate::pipeline::Pipeline), by blocking on every call.
+/// Must not be called from inside the runtime
+pub struct BlockOn<T> {
+    pub inner: T,
+    runtime: Handle,
+}
+
+impl<T> BlockOn<T> {
+    pub fn new(inner: T, runtime: Handle) -> Self {
+        Self { inner, runtime }
+    }
+}
+
+impl<T: AsyncRecvAudio> RecvAudio for BlockOn<T> {
+    fn recv_to_deque(&mut self, buf: &mut VecDeque<u8>) -> Result<()> {
+        self.runtime.block_on(self.inner.recv_to_deque(buf))
+    }
+}
+
+impl<T: AsyncSendAudio> SendAudio for BlockOn<T> {
+    fn send_from_deque(&mut self, data: &mut VecDeque<u8>) -> Result<()> {
+        self.runtime.block_on(self.inner.send_from_deque(data))
+    }
+}
+
+impl<T: AsyncRestart> Restart for BlockOn<T> {
+    fn restart(&mut self) -> Result<()> {
+        self.runtime.block_on(self.inner.restart())
+    }
+}
+
+type Call<T> = fn(&mut T, &mut VecDeque<u8>) -> Result<()>;
+type Running<T> = JoinHandle<(T, VecDeque<u8>, Result<()>)>;
+
+/// Makes a blocking pack, like a device, usable from async code by running
+/// every call on tokio's blocking threads. A cancelled call keeps running
+/// there and the next one picks up its result, so nothing gets lost. Wrap the
+/// source and the sink side of something separately
+pub struct Unblock<T> {
+    /// None while a call is running
+    inner: Option<T>,
+    running: Option<Running<T>>,
+}
+
+impl<T: Send + 'static> Unblock<T> {
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner: Some(inner),
+            running: None,
+        }
+    }
+
+    /// Starts `call` on the data from `data` unless a cancelled call is still
+    /// running, then waits for whichever it is and returns its data and result
+    async fn run(
+        &mut self,
+        data: impl FnOnce() -> VecDeque<u8>,
+        call: Call<T>,
+    ) -> Result<(VecDeque<u8>, Result<()>)> {
+        if self.running.is_none() {
+            let mut inner = self
+                .inner
+                .take()
+                .ok_or(anyhow!("The blocking pack was lost to a panic"))?;
+            let mut data = data();
+            self.running = Some(tokio::task::spawn_blocking(move || {
+                let result = call(&mut inner, &mut data);
+                (inner, data, result)
+            }));
+        }
+        let joined = self.running.as_mut().unwrap().await;
+        self.running = None;
+        let (inner, data, result) =
+            joined.map_err(|err| anyhow!("The blocking pack panicked: {err}"))?;
+        self.inner = Some(inner);
+        Ok((data, result))
+    }
+}
+
+impl<T: RecvAudio + Send + 'static> AsyncRecvAudio for Unblock<T> {
+    async fn recv_to_deque(&mut self, buf: &mut VecDeque<u8>) -> Result<()> {
+        let (received, result) = self.run(VecDeque::new, T::recv_to_deque).await?;
+        buf.extend(received);
+        result
+    }
+}
+
+impl<T: SendAudio + Send + 'static> AsyncSendAudio for Unblock<T> {
+    async fn send_from_deque(&mut self, data: &mut VecDeque<u8>) -> Result<()> {
+        let (mut unsent, result) = self
+            .run(|| std::mem::take(data), T::send_from_deque)
+            .await?;
+        // Anything added while a cancelled call was running goes after what it didn't send
+        unsent.append(data);
+        *data = unsent;
+        result
+    }
+}
+
+impl<T: Restart + Send + 'static> AsyncRestart for Unblock<T> {
+    async fn restart(&mut self) -> Result<()> {
+        if self.running.is_some() {
+            // What a cancelled call got doesn't matter anymore
+            let _ = self.run(VecDeque::new, |_, _| Ok(())).await?;
+        }
+        self.run(VecDeque::new, |inner, _| inner.restart()).await?.1
+    }
+}
@@ -0,0 +1,483 @@
+use std::{
+    path::Path,
+    sync::{
+        Arc, Mutex, OnceLock,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::{self, JoinHandle, Thread},
+    time::Instant,
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use log::{error, info, warn};
+use toml::{Table, Value};
+
+use crate::{
+    Args, crypto,
+    duplex::Duplex,
+    idc::ReconnectPolicy,
+    pipeline::{Pipeline, PipelineHandle, PipelineStats},
+    recovery::{passes_on_rebuild, restarts_pipeline},
+    sinks, sources,
+};
+
+/// A source and a sink with their settings, run as one pipeline, or as a
+/// duplex link if `args.duplex` is set
+#[derive(Debug, Clone)]
+pub struct Route {
+    pub name: String,
+    pub args: Args,
+}
+
+impl Route {
+    /// Runs the route on this thread until it's stopped through `handle`. Builds
+    /// it again with the same backoff as idc reconnects whenever a source or a
+    /// sink asks for that. Fails on any other error and when out of attempts
+    pub fn run(&self, handle: &RouteHandle) -> Result<()> {
+        let name = &self.name;
+        let state = &handle.state;
+        state.thread.get_or_init(thread::current);
+        let policy = ReconnectPolicy::from_args(&self.args)?;
+        let mut attempt = 0;
+        loop {
+            let started = Instant::now();
+            let result = if self.args.duplex.is_some() {
+                // Opening binds the link, which can fail like a source would
+                match Duplex::open(&self.args) {
+                    Ok(duplex) => {
+                        *state.pipelines.lock().unwrap() =
+                            vec![duplex.outgoing(), duplex.incoming()];
+                        if state.stopped.load(Ordering::Acquire) {
+                            return Ok(());
+                        }
+                        duplex.run()
+                    }
+                    Err(err) => Err(err),
+                }
+            } else {
+                let pipeline = Pipeline::builder()
+                    .name(name)
+                    .args(self.args.clone())
+                    .build()?;
+                *state.pipelines.lock().unwrap() = vec![pipeline.handle()];
+                if state.stopped.load(Ordering::Acquire) {
+                    return Ok(());
+                }
+                pipeline.run()
+            };
+            let Err(err) = result else {
+                info!("Route {name} stopped");
+                return Ok(());
+            };
+            if state.stopped.load(Ordering::Acquire) {
+                return Ok(());
+            }
+            let rebuilding = attempt > 0;
+            let restarts = restarts_pipeline(&err) || (rebuilding && passes_on_rebuild(&err));
+            if !restarts {
+                return Err(err.context(format!("Route {name} failed")));
+            }
+            // Only a run that lasted is a reason to start over with short delays
+            if started.elapsed() >= policy.max_delay {
+                attempt = 0;
+            }
+            attempt += 1;
+            if policy
+                .max_attempts
+                .is_some_and(|max_attempts| attempt > max_attempts)
+            {
+                error!("Route {name} failed {attempt} times in a row, giving up: {err:#}");
+                return Err(err.context(format!("Route {name} gave up")));
+            }
+            let delay = policy.delay(attempt);
+            warn!("Route {name} failed, restarting in {delay:?}: {err:#}");
+            thread::park_timeout(delay);
+            if state.stopped.load(Ordering::Acquire) {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Named routes from a file like
+///
+/// ```toml
+/// # Settings for every route
+/// [defaults]
+/// key-file = "stream.key"
+///
+/// [routes.mic-to-pc]
+/// source = "mic"
+/// sink = "idc://192.168.1.2:5678"
+/// buffer-limit = 20000
+///
+/// [routes.pc-to-headphones]
+/// source = "idc://0.0.0.0:5679"
+/// sink = "headphones"
+/// ```
+///
+/// Besides `source` and `sink`, the keys are the command line flags without the
+/// dashes. Unlike on the command line, `on-fatal` is `restart-pipeline` unless
+/// set, and `exit` only ends the route
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub routes: Vec<Route>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| anyhow!("Couldn't read {}: {err}", path.display()))?;
+        Self::parse(&text).map_err(|err| anyhow!("{}: {err:#}", path.display()))
+    }
+
+    /// Parses and checks every route, so that one broken route keeps all of them from starting
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut table: Table = text.parse()?;
+        let defaults = match table.remove("defaults") {
+            Some(Value::Table(defaults)) => defaults,
+            Some(_) => bail!("defaults must be a table"),
+            None => Table::new(),
+        };
+        let routes = match table.remove("routes") {
+            Some(Value::Table(routes)) if !routes.is_empty() => routes,
+            Some(Value::Table(_)) | None => bail!("No routes, add some as [routes.<name>]"),
+            Some(_) => bail!("routes must be a table"),
+        };
+        if let Some(key) = table.keys().next() {
+            bail!("Unknown key {key:?}, expected defaults or routes");
+        }
+
+        let routes = routes
+            .into_iter()
+            .map(|(name, route)| {
+                let Value::Table(route) = route else {
+                    bail!("Route {name} must be a table");
+                };
+                let args = route_args(&defaults, &route)
+                    .and_then(|args| check(&args).map(|()| args))
+                    .with_context(|| format!("Route {name}"))?;
+                Ok(Route { name, args })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { routes })
+    }
+
+    /// Starts every route on a thread of its own, named after it
+    pub fn spawn(self) -> Result<RunningRoutes> {
+        let mut running = Vec::new();
+        for route in self.routes {
+            let handle = RouteHandle::default();
+            let route_handle = handle.clone();
+            let name = route.name.clone();
+            let thread = thread::Builder::new()
+                .name(route.name.clone())
+                .spawn(move || route.run(&route_handle))?;
+            running.push(RunningRoute {
+                name,
+                thread,
+                handle,
+            });
+        }
+        info!("Running {} routes", running.len());
+        Ok(RunningRoutes { routes: running })
+    }
+
+    /// Runs every route until all of them are stopped or give up
+    pub fn run(self) -> Result<()> {
+        self.spawn()?.join()
+    }
+}
+
+/// Reads the settings of a route over the defaults into [`Args`]
+fn route_args(defaults: &Table, route: &Table) -> Result<Args> {
+    // Either spelling of a flag overrides the other
+    let kebab = |(key, value): (&String, &Value)| (key.replace('_', "-"), value.clone());
+    let mut settings: Table = defaults.iter().map(kebab).collect();
+    settings.extend(route.iter().map(kebab));
+    if settings.contains_key("config") {
+        bail!("Routes can't have configs of their own");
+    }
+    if settings.contains_key("duplex") {
+        bail!("Routes only go one way, run duplex links on their own");
+    }
+    for name in ["source", "sink"] {
+        if !settings.contains_key(name) {
+            bail!("Missing the {name}");
+        }
+    }
+    // Like on the command line, where it's a comma separated list
+    if let Some(Value::String(allow)) = settings.get("allow") {
+        let allow = allow.split(',').map(|cidr| Value::String(cidr.to_owned()));
+        settings.insert("allow".to_owned(), Value::Array(allow.collect()));
+    }
+    // One route failing for good shouldn't take the others down
+    settings
+        .entry("on-fatal")
+        .or_insert_with(|| Value::String("restart-pipeline".to_owned()));
+
+    let args: Args = settings.try_into()?;
+    // What the command line refuses before it gets this far
+    if args.psk.is_some() && args.key_file.is_some() {
+        bail!("Either psk or key-file, not both");
+    }
+    if args.quic_key.is_some() && args.quic_cert.is_none() {
+        bail!("quic-key needs a quic-cert");
+    }
+    if args.echo_suppression {
+        bail!("echo-suppression only works on duplex links");
+    }
+    Ok(args)
+}
+
+/// Everything that can be wrong with a route without opening its source and sink
+fn check(args: &Args) -> Result<()> {
+    Pipeline::builder().args(args.clone()).build()?;
+    let sources = sources::registry();
+    for url in std::iter::once(&args.source).chain(&args.mix) {
+        sources.check(url, args)?;
+    }
+    let sinks = sinks::registry();
+    sinks.check(&args.sink, args)?;
+    for url in &args.tee {
+        sinks.check(url, args)?;
+    }
+    crypto::key_from_args(args)?;
+    ReconnectPolicy::from_args(args)?;
+    Ok(())
+}
+
+#[derive(Default)]
+struct RouteState {
+    stopped: AtomicBool,
+    /// What runs right now, two pipelines for a duplex link
+    pipelines: Mutex<Vec<PipelineHandle>>,
+    /// Woken up to stop during a backoff
+    thread: OnceLock<Thread>,
+}
+
+/// Stops a route from any thread while [`Route::run`] runs it
+#[derive(Clone, Default)]
+pub struct RouteHandle {
+    state: Arc<RouteState>,
+}
+
+impl RouteHandle {
+    pub fn stop(&self) {
+        let state = &self.state;
+        state.stopped.store(true, Ordering::Release);
+        for pipeline in &*state.pipelines.lock().unwrap() {
+            pipeline.stop();
+        }
+        if let Some(thread) = state.thread.get() {
+            thread.unpark();
+        }
+    }
+
+    /// Stats of the pipeline running right now, the outgoing one of a duplex link
+    pub fn stats(&self) -> Option<PipelineStats> {
+        Some(self.state.pipelines.lock().unwrap().first()?.stats())
+    }
+}
+
+struct RunningRoute {
+    name: String,
+    thread: JoinHandle<Result<()>>,
+    handle: RouteHandle,
+}
+
+/// Routes started by [`Config::spawn`]
+pub struct RunningRoutes {
+    routes: Vec<RunningRoute>,
+}
+
+impl RunningRoutes {
+    /// Stats of the pipeline each route runs right now, they start over when it restarts
+    pub fn stats(&self) -> Vec<(String, PipelineStats)> {
+        self.routes
+            .iter()
+            .filter_map(|route| Some((route.name.clone(), route.handle.stats()?)))
+            .collect()
+    }
+
+    pub fn handle(&self) -> RoutesHandle {
+        RoutesHandle {
+            routes: self
+                .routes
+                .iter()
+                .map(|route| route.handle.clone())
+                .collect(),
+        }
+    }
+
+    pub fn stop(&self) {
+        self.handle().stop();
+    }
+
+    /// Waits until every route is stopped or gave up, fails if any gave up
+    pub fn join(self) -> Result<()> {
+        let mut failed = Vec::new();
+        for route in self.routes {
+            match route.thread.join() {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => failed.push(err.to_string()),
+                Err(_) => failed.push(format!("Route {} panicked", route.name)),
+            }
+        }
+        if !failed.is_empty() {
+            bail!("{}", failed.join("; "));
+        }
+        Ok(())
+    }
+}
+
+/// Stops the routes from any thread, while [`RunningRoutes::join`] waits for them
+#[derive(Clone)]
+pub struct RoutesHandle {
+    routes: Vec<RouteHandle>,
+}
+
+impl RoutesHandle {
+    /// Stops every route, [`RunningRoutes::join`] returns once they're done
+    pub fn stop(&self) {
+        for route in &self.routes {
+            route.stop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{network_utils::Cidr, pipeline::OverflowPolicy, recovery::Recovery};
+
+    fn parse_err(text: &str) -> String {
+        format!("{:#}", Config::parse(text).unwrap_err())
+    }
+
+    #[test]
+    fn routes_take_the_defaults_they_dont_set() {
+        let config = Config::parse(
+            r#"
+            [defaults]
+            buffer-limit = 20000
+            overflow = "drop-new"
+
+            [routes.first]
+            source = "udp://0.0.0.0:5000"
+            sink = "udp://127.0.0.1:5001"
+            buffer_limit = 30000
+            tee = ["udp://127.0.0.1:5002", "udp://127.0.0.1:5003"]
+            allow = "10.0.0.0/24,192.168.1.5"
+            idc-reconnect-factor = 3
+
+            [routes.second]
+            source = "udp://0.0.0.0:5004"
+            sink = "udp://127.0.0.1:5005"
+            on-fatal = "exit"
+            "#,
+        )
+        .unwrap();
+        let [first, second] = &config.routes[..] else {
+            panic!("{:?}", config.routes);
+        };
+        assert_eq!(first.name, "first");
+        assert_eq!(first.args.source, "udp://0.0.0.0:5000");
+        assert_eq!(first.args.buffer_limit, 30000);
+        assert_eq!(first.args.overflow, OverflowPolicy::DropNew);
+        assert_eq!(first.args.tee.len(), 2);
+        assert_eq!(
+            first.args.allow,
+            ["10.0.0.0/24", "192.168.1.5"].map(|cidr| cidr.parse::<Cidr>().unwrap())
+        );
+        assert_eq!(first.args.idc_reconnect_factor, 3.0);
+        assert_eq!(first.args.on_fatal, Recovery::RestartPipeline);
+        // Untouched flags keep the command line defaults
+        assert_eq!(first.args.datagram_size, Args::default().datagram_size);
+
+        assert_eq!(second.args.buffer_limit, 20000);
+        assert_eq!(second.args.on_fatal, Recovery::Exit);
+    }
+
+    #[test]
+    fn config_needs_routes() {
+        assert!(parse_err("").contains("No routes"));
+        assert!(parse_err("[routes]").contains("No routes"));
+        assert!(parse_err("routes = 1").contains("routes must be a table"));
+        assert!(parse_err("stray = 1\n[routes.a]").contains("Unknown key \"stray\""));
+    }
+
+    #[test]
+    fn broken_route_keeps_every_route_from_starting() {
+        for (route, expected) in [
+            (r#"source = "udp://0.0.0.0:5000""#, "Missing the sink"),
+            (
+                r#"source = "udp://0.0.0.0:5000"
+                sink = 5001"#,
+                "invalid type",
+            ),
+            (
+                r#"source = "udp://0.0.0.0:5000"
+                sink = "udp://127.0.0.1:5001"
+                bogus = 1"#,
+                "unknown field `bogus`",
+            ),
+            (
+                r#"source = "udp://0.0.0.0:5000"
+                sink = "udp://127.0.0.1:5001"
+                buffer-limit = -1"#,
+                "buffer-limit",
+            ),
+            (
+                r#"source = "udp://0.0.0.0:5000"
+                sink = "udp://127.0.0.1:5001"
+                overflow = "sometimes""#,
+                "unknown variant `sometimes`",
+            ),
+            (
+                r#"source = "udp://0.0.0.0:5000"
+                sink = "udp://127.0.0.1:5001?bogus=1""#,
+                "Unknown parameter \"bogus\"",
+            ),
+            (
+                r#"source = "udp://0.0.0.0:5000"
+                sink = "udp://127.0.0.1:5001"
+                allow = "10.0.0.0/33""#,
+                "Prefix length",
+            ),
+            (
+                r#"source = "udp://0.0.0.0:5000"
+                sink = "udp://127.0.0.1:5001"
+                psk = "ab"
+                key-file = "stream.key""#,
+                "not both",
+            ),
+            (
+                r#"source = "udp://0.0.0.0:5000"
+                sink = "udp://127.0.0.1:5001"
+                duplex = "idc-connect://127.0.0.1:5002""#,
+                "Routes only go one way",
+            ),
+            (
+                r#"source = "udp://0.0.0.0:5000"
+                sink = "udp://127.0.0.1:5001"
+                config = "other.toml""#,
+                "configs of their own",
+            ),
+            (
+                r#"source = "udp://0.0.0.0:5000"
+                sink = "udp://127.0.0.1:5001"
+                list-schemes = true"#,
+                "unknown field `list-schemes`",
+            ),
+        ] {
+            let text = format!(
+                "[routes.good]\nsource = \"udp://0.0.0.0:6000\"\nsink = \"udp://127.0.0.1:6001\"\n\
+                 [routes.broken]\n{route}"
+            );
+            let err = parse_err(&text);
+            assert!(err.contains("Route broken"), "{err}");
+            assert!(err.contains(expected), "Expected {expected:?} in {err}");
+        }
+    }
+}
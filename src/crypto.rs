@@ -0,0 +1,664 @@
+use std::{
+    io,
+    net::SocketAddr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Result, anyhow, bail};
+use chacha20poly1305::{
+    Key, KeyInit, XChaCha20Poly1305, XNonce,
+    aead::{Aead, OsRng, Payload, rand_core::RngCore},
+};
+use log::{debug, info, warn};
+
+#[cfg(feature = "tokio")]
+use crate::network_utils::AsyncDatagramSocket;
+use crate::{Args, network_utils::DatagramSocket};
+
+/// The session, then a big-endian counter
+const NONCE_LEN: usize = 24;
+/// Random for every session, so that no two senders ever share a nonce, even
+/// if they start in the same millisecond or a clock goes back
+const SALT_LEN: usize = 10;
+/// The epoch is when the sender started, in milliseconds since the Unix epoch,
+/// big-endian after the salt
+const EPOCH_LEN: usize = 6;
+const SESSION_LEN: usize = SALT_LEN + EPOCH_LEN;
+const TAG_LEN: usize = 16;
+/// How much sealing adds to every datagram or chunk
+pub const OVERHEAD: usize = NONCE_LEN + TAG_LEN;
+/// How many packets behind the newest one can still arrive without being taken for replays
+const REPLAY_WINDOW: u64 = 64;
+/// How many packets of a newer session have to come in a row, without one of
+/// the current session in between, before it takes over
+const SWITCH_AFTER: u32 = 4;
+/// How long the current session has to be quiet before an older one can take
+/// over, like that of a sender whose clock went back
+const QUIET_BEFORE_OLDER: Duration = Duration::from_secs(1);
+/// Epoch of the last session started in this process, so no two get the same
+static LAST_EPOCH: AtomicU64 = AtomicU64::new(0);
+
+/// The salt and the epoch every nonce of a session starts with
+pub type Session = [u8; SESSION_LEN];
+
+fn epoch_of(session: &Session) -> u64 {
+    let mut epoch = [0; 8];
+    epoch[8 - EPOCH_LEN..].copy_from_slice(&session[SALT_LEN..]);
+    u64::from_be_bytes(epoch)
+}
+
+/// Which way sealed data goes, authenticated along with it so that it can't
+/// be turned around
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// From a sink to a source. Also both ways of a UDP duplex link, whose ends
+    /// can't tell each other apart, so there the opener refuses the session of
+    /// its own sealer
+    Forward,
+    /// From the end of a duplex link that listens
+    FromListener,
+    /// From the end of a duplex link that connects
+    FromConnector,
+}
+
+impl Direction {
+    fn aad(self) -> &'static [u8] {
+        match self {
+            Self::Forward => b"stupid-audio-stream forward",
+            Self::FromListener => b"stupid-audio-stream from listener",
+            Self::FromConnector => b"stupid-audio-stream from connector",
+        }
+    }
+}
+
+/// Parses a 32 byte key written as 64 hex digits
+pub fn parse_key(hex: &str) -> Result<Key> {
+    let hex = hex.trim();
+    if hex.len() != 64 {
+        bail!("Key must be 64 hex digits, e.g. from `openssl rand -hex 32`");
+    }
+    let mut key = Key::default();
+    for (byte, digits) in key.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(digits)?, 16)?;
+    }
+    Ok(key)
+}
+
+pub fn key_from_args(args: &Args) -> Result<Option<Key>> {
+    if let Some(psk) = &args.psk {
+        Ok(Some(parse_key(psk)?))
+    } else if let Some(path) = &args.key_file {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|err| anyhow!("Couldn't read key file {path:?}: {err}"))?;
+        Ok(Some(parse_key(&contents)?))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Encrypts and authenticates outgoing chunks
+pub struct Sealer {
+    cipher: XChaCha20Poly1305,
+    direction: Direction,
+    session: Session,
+    counter: u64,
+}
+
+impl Sealer {
+    pub fn new(key: &Key) -> Self {
+        Self::with_direction(key, Direction::Forward)
+    }
+
+    /// Starts a session newer than any this process started before
+    pub fn with_direction(key: &Key, direction: Direction) -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64);
+        let epoch = match LAST_EPOCH.fetch_max(now, Ordering::AcqRel) {
+            last if last < now => now,
+            _ => LAST_EPOCH.fetch_add(1, Ordering::AcqRel) + 1,
+        };
+        Self::starting_at(key, direction, epoch)
+    }
+
+    fn starting_at(key: &Key, direction: Direction, epoch: u64) -> Self {
+        let mut session = Session::default();
+        OsRng.fill_bytes(&mut session[..SALT_LEN]);
+        session[SALT_LEN..].copy_from_slice(&epoch.to_be_bytes()[8 - EPOCH_LEN..]);
+        Self {
+            cipher: XChaCha20Poly1305::new(key),
+            direction,
+            session,
+            counter: 0,
+        }
+    }
+
+    pub fn session(&self) -> Session {
+        self.session
+    }
+
+    /// Appends the nonce and the sealed `payload` to `out`
+    pub fn seal(&mut self, payload: &[u8], out: &mut Vec<u8>) {
+        let mut nonce = XNonce::default();
+        nonce[..SESSION_LEN].copy_from_slice(&self.session);
+        nonce[SESSION_LEN..].copy_from_slice(&self.counter.to_be_bytes());
+        self.counter += 1;
+        out.extend_from_slice(&nonce);
+        let payload = Payload {
+            msg: payload,
+            aad: self.direction.aad(),
+        };
+        out.extend(
+            self.cipher
+                .encrypt(&nonce, payload)
+                .expect("XChaCha20-Poly1305 can't fail to encrypt"),
+        );
+    }
+}
+
+/// Checks and decrypts incoming chunks, rejecting forgeries and replays.
+/// Senders that restart start a newer session, which takes over once
+/// [`SWITCH_AFTER`] of its packets came in a row. Replays of older sessions
+/// only do once the current one was quiet for [`QUIET_BEFORE_OLDER`]
+pub struct Opener {
+    cipher: XChaCha20Poly1305,
+    direction: Direction,
+    /// The session of the sealer on the other half of the link, whose packets
+    /// only come back here if someone reflects them
+    refused: Option<Session>,
+    session: Option<Session>,
+    highest: u64,
+    /// Bit `i` is set if counter `highest - i` was already seen
+    seen: u64,
+    /// Another session that hasn't taken over yet, with the highest counter
+    /// and the number of its packets so far
+    candidate: Option<(Session, u64, u32)>,
+    /// The link started over, so a newer session can take over right away
+    fresh_link: bool,
+    /// When the current session was last heard from
+    last_current: Instant,
+    pub rejected: u64,
+}
+
+/// A packet that isn't authentic or was already seen
+#[derive(Debug)]
+pub struct Rejected;
+
+impl Opener {
+    pub fn new(key: &Key) -> Self {
+        Self::with_direction(key, Direction::Forward)
+    }
+
+    /// Only opens what was sealed going `direction`
+    pub fn with_direction(key: &Key, direction: Direction) -> Self {
+        Self {
+            cipher: XChaCha20Poly1305::new(key),
+            direction,
+            refused: None,
+            session: None,
+            highest: 0,
+            seen: 0,
+            candidate: None,
+            fresh_link: false,
+            last_current: Instant::now(),
+            rejected: 0,
+        }
+    }
+
+    /// Rejects everything of `session`, that of the sealer sending the other way
+    pub fn refusing(mut self, session: Session) -> Self {
+        self.refused = Some(session);
+        self
+    }
+
+    /// The connection the packets come over was made anew, nobody is left on
+    /// the old one to lock out
+    pub fn link_changed(&mut self) {
+        self.candidate = None;
+        self.fresh_link = true;
+    }
+
+    /// Returns the payload if `packet` is authentic and not a replay, or
+    /// nothing if it's from another session that hasn't taken over yet
+    pub fn open(&mut self, packet: &[u8]) -> Result<Option<Vec<u8>>, Rejected> {
+        let opened = self.try_open(packet);
+        if opened.is_err() {
+            self.rejected += 1;
+        }
+        opened
+    }
+
+    fn try_open(&mut self, packet: &[u8]) -> Result<Option<Vec<u8>>, Rejected> {
+        if packet.len() < OVERHEAD {
+            return Err(Rejected);
+        }
+        let (nonce, ciphertext) = packet.split_at(NONCE_LEN);
+        let session: Session = nonce[..SESSION_LEN].try_into().unwrap();
+        let counter = u64::from_be_bytes(nonce[SESSION_LEN..].try_into().unwrap());
+        if self.refused == Some(session) {
+            return Err(Rejected);
+        }
+
+        let current = self.session == Some(session);
+        if current && self.is_replay(counter) {
+            return Err(Rejected);
+        }
+        let older = self
+            .session
+            .is_some_and(|live| epoch_of(&session) < epoch_of(&live));
+        if !current && older && self.last_current.elapsed() < QUIET_BEFORE_OLDER {
+            return Err(Rejected);
+        }
+        let candidate = self
+            .candidate
+            .filter(|(candidate, ..)| *candidate == session);
+        if candidate.is_some_and(|(_, highest, _)| counter <= highest) {
+            return Err(Rejected);
+        }
+
+        let ciphertext = Payload {
+            msg: ciphertext,
+            aad: self.direction.aad(),
+        };
+        let payload = self
+            .cipher
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .map_err(|_| Rejected)?;
+
+        if current {
+            // The current sender is still there, whoever else is talking
+            self.candidate = None;
+            self.fresh_link = false;
+            self.last_current = Instant::now();
+            self.mark_seen(counter);
+            return Ok(Some(payload));
+        }
+        let n_packets = candidate.map_or(1, |(.., n_packets)| n_packets + 1);
+        let takes_over_now = self.session.is_none() || (self.fresh_link && !older);
+        if !takes_over_now && n_packets < SWITCH_AFTER {
+            // The latest session is the one to wait for
+            if self
+                .candidate
+                .is_none_or(|(candidate, ..)| epoch_of(&candidate) <= epoch_of(&session))
+            {
+                self.candidate = Some((session, counter, n_packets));
+            }
+            return Ok(None);
+        }
+        match self.session {
+            Some(_) => info!(
+                "The sender restarted, switched to its session {}",
+                epoch_of(&session)
+            ),
+            None => debug!("Accepted session {}", epoch_of(&session)),
+        }
+        self.session = Some(session);
+        self.candidate = None;
+        self.fresh_link = false;
+        self.last_current = Instant::now();
+        self.highest = counter;
+        self.seen = 1;
+        Ok(Some(payload))
+    }
+
+    fn is_replay(&self, counter: u64) -> bool {
+        counter <= self.highest
+            && (self.highest - counter >= REPLAY_WINDOW
+                || self.seen & (1 << (self.highest - counter)) != 0)
+    }
+
+    fn mark_seen(&mut self, counter: u64) {
+        if counter > self.highest {
+            let shift = counter - self.highest;
+            self.seen = if shift >= REPLAY_WINDOW {
+                0
+            } else {
+                self.seen << shift
+            };
+            self.seen |= 1;
+            self.highest = counter;
+        } else {
+            self.seen |= 1 << (self.highest - counter);
+        }
+    }
+
+    pub(crate) fn log_rejected(&self, from: impl std::fmt::Debug) {
+        // Don't flood the log if someone is spraying garbage at us
+        if self.rejected.is_power_of_two() {
+            warn!(
+                "Dropped unauthenticated data from {from:?}, {} times so far",
+                self.rejected
+            );
+        } else {
+            debug!("Dropped unauthenticated data from {from:?}");
+        }
+    }
+}
+
+/// Datagram socket that seals everything it sends and drops anything it receives
+/// that isn't sealed with the same key
+pub struct SealedSocket<S> {
+    inner: S,
+    sealer: Option<Sealer>,
+    opener: Option<Opener>,
+    send_buffer: Vec<u8>,
+    recv_buffer: Vec<u8>,
+}
+
+impl<S> SealedSocket<S> {
+    pub fn new(inner: S, key: &Key) -> Self {
+        let sealer = Sealer::new(key);
+        let opener = Opener::new(key).refusing(sealer.session());
+        Self::with_parts(inner, Some(sealer), Some(opener))
+    }
+
+    /// Only sends, sealing with `sealer`
+    pub fn sealing(inner: S, sealer: Sealer) -> Self {
+        Self::with_parts(inner, Some(sealer), None)
+    }
+
+    /// Only receives, opening with `opener`
+    pub fn opening(inner: S, opener: Opener) -> Self {
+        Self::with_parts(inner, None, Some(opener))
+    }
+
+    fn with_parts(inner: S, sealer: Option<Sealer>, opener: Option<Opener>) -> Self {
+        Self {
+            inner,
+            sealer,
+            opener,
+            send_buffer: Vec::new(),
+            recv_buffer: vec![0; 65536],
+        }
+    }
+
+    pub fn rejected(&self) -> u64 {
+        self.opener.as_ref().map_or(0, |opener| opener.rejected)
+    }
+
+    fn seal(&mut self, buf: &[u8]) -> io::Result<()> {
+        let sealer = self.sealer.as_mut().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::Unsupported, "This socket only receives")
+        })?;
+        self.send_buffer.clear();
+        sealer.seal(buf, &mut self.send_buffer);
+        Ok(())
+    }
+
+    /// Opens the `n_read` bytes in the receive buffer into `buf`, if they're
+    /// authentic and not a replay
+    fn open(
+        &mut self,
+        n_read: usize,
+        addr: SocketAddr,
+        buf: &mut [u8],
+    ) -> io::Result<Option<usize>> {
+        let opener = self
+            .opener
+            .as_mut()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Unsupported, "This socket only sends"))?;
+        match opener.open(&self.recv_buffer[..n_read]) {
+            Ok(Some(payload)) => {
+                let n_read = usize::min(payload.len(), buf.len());
+                buf[..n_read].copy_from_slice(&payload[..n_read]);
+                Ok(Some(n_read))
+            }
+            Ok(None) => Ok(None),
+            Err(Rejected) => {
+                opener.log_rejected(addr);
+                Ok(None)
+            }
+        }
+    }
+}
+
+impl<S: DatagramSocket> DatagramSocket for SealedSocket<S> {
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.seal(buf)?;
+        self.inner.send(&self.send_buffer)?;
+        Ok(buf.len())
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        loop {
+            let (n_read, addr) = self.inner.recv_from(&mut self.recv_buffer)?;
+            if let Some(n_read) = self.open(n_read, addr, buf)? {
+                return Ok((n_read, addr));
+            }
+        }
+    }
+
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        self.inner.read_timeout()
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    fn reopen(&mut self) -> io::Result<()> {
+        self.inner.reopen()
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<S: AsyncDatagramSocket + Send> AsyncDatagramSocket for SealedSocket<S> {
+    async fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.seal(buf)?;
+        self.inner.send(&self.send_buffer).await?;
+        Ok(buf.len())
+    }
+
+    async fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        loop {
+            let (n_read, addr) = self.inner.recv_from(&mut self.recv_buffer).await?;
+            if let Some(n_read) = self.open(n_read, addr, buf)? {
+                return Ok((n_read, addr));
+            }
+        }
+    }
+
+    async fn reopen(&mut self) -> io::Result<()> {
+        self.inner.reopen().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sealed(sealer: &mut Sealer, payload: &[u8]) -> Vec<u8> {
+        let mut packet = Vec::new();
+        sealer.seal(payload, &mut packet);
+        packet
+    }
+
+    #[test]
+    fn rejects_forgeries_and_replays() {
+        let key = parse_key(&"ab".repeat(32)).unwrap();
+        let mut sealer = Sealer::new(&key);
+        let mut opener = Opener::new(&key);
+        let first = sealed(&mut sealer, b"first");
+        let second = sealed(&mut sealer, b"second");
+        assert_eq!(opener.open(&second).unwrap().unwrap(), b"second");
+        assert_eq!(opener.open(&first).unwrap().unwrap(), b"first");
+        assert!(opener.open(&first).is_err());
+
+        let mut forged = sealed(&mut sealer, b"third");
+        *forged.last_mut().unwrap() ^= 1;
+        assert!(opener.open(&forged).is_err());
+        let other_key = parse_key(&"cd".repeat(32)).unwrap();
+        assert!(
+            opener
+                .open(&sealed(&mut Sealer::new(&other_key), b"x"))
+                .is_err()
+        );
+        assert_eq!(opener.rejected, 3);
+    }
+
+    #[test]
+    fn restarted_sender_takes_over_only_after_a_while() {
+        let key = parse_key(&"ab".repeat(32)).unwrap();
+        let mut old = Sealer::new(&key);
+        let old_packets: Vec<_> = (0..8).map(|_| sealed(&mut old, b"old")).collect();
+        let mut live = Sealer::new(&key);
+        let mut opener = Opener::new(&key);
+        opener.open(&sealed(&mut live, b"live")).unwrap().unwrap();
+
+        // Replays of an older session never get in
+        for packet in &old_packets {
+            assert!(opener.open(packet).is_err());
+        }
+        assert_eq!(
+            opener.open(&sealed(&mut live, b"live")).unwrap().unwrap(),
+            b"live"
+        );
+
+        // Neither does a newer one as long as the current one keeps talking
+        let mut newer = Sealer::new(&key);
+        for _ in 0..SWITCH_AFTER * 2 {
+            assert!(opener.open(&sealed(&mut newer, b"new")).unwrap().is_none());
+            assert!(opener.open(&sealed(&mut live, b"live")).unwrap().is_some());
+        }
+
+        // Until the current one goes quiet
+        for _ in 1..SWITCH_AFTER {
+            assert!(opener.open(&sealed(&mut newer, b"new")).unwrap().is_none());
+        }
+        assert_eq!(
+            opener.open(&sealed(&mut newer, b"new")).unwrap().unwrap(),
+            b"new"
+        );
+        assert!(opener.open(&sealed(&mut live, b"live")).is_err());
+    }
+
+    #[test]
+    fn new_link_switches_right_away() {
+        let key = parse_key(&"ab".repeat(32)).unwrap();
+        let mut old = Sealer::new(&key);
+        let mut opener = Opener::new(&key);
+        opener.open(&sealed(&mut old, b"old")).unwrap().unwrap();
+        opener.link_changed();
+        let mut newer = Sealer::new(&key);
+        assert_eq!(
+            opener.open(&sealed(&mut newer, b"new")).unwrap().unwrap(),
+            b"new"
+        );
+        assert!(opener.open(&sealed(&mut old, b"old")).is_err());
+    }
+
+    #[test]
+    fn duplicate_starts_never_share_a_nonce() {
+        let key = parse_key(&"ab".repeat(32)).unwrap();
+        let mut first = Sealer::starting_at(&key, Direction::Forward, 1000);
+        let mut second = Sealer::starting_at(&key, Direction::Forward, 1000);
+        let (first, second) = (sealed(&mut first, b"first"), sealed(&mut second, b"second"));
+        assert_ne!(first[..NONCE_LEN], second[..NONCE_LEN]);
+
+        let mut opener = Opener::new(&key);
+        assert_eq!(opener.open(&first).unwrap().unwrap(), b"first");
+        // The other one is a session of its own, not a replay
+        assert!(opener.open(&second).unwrap().is_none());
+    }
+
+    #[test]
+    fn reflected_packets_are_rejected() {
+        let key = parse_key(&"ab".repeat(32)).unwrap();
+        let mut own = Sealer::new(&key);
+        let mut peer = Sealer::new(&key);
+        let mut opener = Opener::new(&key).refusing(own.session());
+        assert_eq!(
+            opener.open(&sealed(&mut peer, b"peer")).unwrap().unwrap(),
+            b"peer"
+        );
+        opener.link_changed();
+        for _ in 0..SWITCH_AFTER * 2 {
+            assert!(opener.open(&sealed(&mut own, b"own")).is_err());
+        }
+        assert_eq!(
+            opener.open(&sealed(&mut peer, b"peer")).unwrap().unwrap(),
+            b"peer"
+        );
+    }
+
+    #[test]
+    fn packets_only_open_going_their_direction() {
+        let key = parse_key(&"ab".repeat(32)).unwrap();
+        let mut listener = Sealer::with_direction(&key, Direction::FromListener);
+        let packet = sealed(&mut listener, b"to the connector");
+        assert!(
+            Opener::with_direction(&key, Direction::FromConnector)
+                .open(&packet)
+                .is_err()
+        );
+        assert!(Opener::new(&key).open(&packet).is_err());
+        assert_eq!(
+            Opener::with_direction(&key, Direction::FromListener)
+                .open(&packet)
+                .unwrap()
+                .unwrap(),
+            b"to the connector"
+        );
+    }
+
+    #[test]
+    fn sender_with_clock_gone_back_takes_over_once_quiet() {
+        let key = parse_key(&"ab".repeat(32)).unwrap();
+        let mut live = Sealer::starting_at(&key, Direction::Forward, 2000);
+        let mut restarted = Sealer::starting_at(&key, Direction::Forward, 1000);
+        let mut opener = Opener::new(&key);
+        opener.open(&sealed(&mut live, b"live")).unwrap().unwrap();
+        assert!(opener.open(&sealed(&mut restarted, b"old")).is_err());
+
+        opener.last_current = Instant::now() - QUIET_BEFORE_OLDER;
+        for _ in 1..SWITCH_AFTER {
+            assert!(
+                opener
+                    .open(&sealed(&mut restarted, b"old"))
+                    .unwrap()
+                    .is_none()
+            );
+        }
+        assert_eq!(
+            opener
+                .open(&sealed(&mut restarted, b"old"))
+                .unwrap()
+                .unwrap(),
+            b"old"
+        );
+    }
+
+    #[test]
+    fn sealed_datagrams_fit_the_datagram_size() {
+        let receiver = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let socket = crate::network_utils::connected_udp_socket(receiver.local_addr().unwrap());
+        let args = Args {
+            datagram_size: 100,
+            counted_udp: true,
+            ..Default::default()
+        };
+        let key = parse_key(&"ab".repeat(32)).unwrap();
+        let mut sink = crate::sinks::udp_sink(
+            &args,
+            Box::new(socket.unwrap()),
+            "",
+            Some(Sealer::new(&key)),
+        )
+        .unwrap();
+        let mut data = std::collections::VecDeque::from(vec![0; 1000]);
+        while !data.is_empty() {
+            sink.send_from_deque(&mut data).unwrap();
+        }
+        let mut buf = [0; 65536];
+        let mut n_sent = 0;
+        while n_sent < 1000 {
+            let n_read = receiver.recv(&mut buf).unwrap();
+            assert!(n_read <= args.datagram_size);
+            n_sent += n_read - OVERHEAD - 8;
+        }
+    }
+}
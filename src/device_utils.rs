@@ -3,6 +3,22 @@ use wasapi::{AudioClient, WaveFormat};
 
 use anyhow::{Result, anyhow, bail};
 
+use crate::recovery::{DeviceLost, DeviceMissing};
+
+/// AUDCLNT_E_DEVICE_INVALIDATED and AUDCLNT_E_SERVICE_NOT_RUNNING, the HRESULTs
+/// of a device that went away
+const DEVICE_GONE: [u32; 2] = [0x8889_0004, 0x8889_0010];
+
+/// Tells a device that went away from any other failure of WASAPI
+pub fn device_error(err: wasapi::WasapiError, what: &str) -> anyhow::Error {
+    if let wasapi::WasapiError::Windows(cause) = &err
+        && DEVICE_GONE.contains(&(cause.code().0 as u32))
+    {
+        return DeviceLost(format!("{what}: {err}")).into();
+    }
+    anyhow!("{what}: {err}")
+}
+
 pub fn find_device_by_name(direction: wasapi::Direction, query: &str) -> Result<wasapi::Device> {
     let enumerator = wasapi::DeviceEnumerator::new()?;
     let collection = enumerator.get_device_collection(&direction)
@@ -28,11 +44,8 @@ pub fn find_device_by_name(direction: wasapi::Direction, query: &str) -> Result<
         }
     }
 
-    if let Some(device) = result {
-        Ok(device)
-    } else {
-        bail!("No device name contains: {query:?}");
-    }
+    // Whether it's unplugged or misspelled is up to whoever knows if it was there before
+    result.ok_or_else(|| DeviceMissing(query).into())
 }
 
 pub fn open_device_with_format(
@@ -44,7 +57,7 @@ pub fn open_device_with_format(
         .map_err(|err| anyhow!("Couldn't get device state due to error: {err}"))?;
 
     let wasapi::DeviceState::Active = state else {
-        bail!("Device is not active; it's state is {state}");
+        return Err(DeviceLost(format!("Device is not active; it's state is {state}")).into());
     };
 
     let mut client = device
@@ -0,0 +1,749 @@
+use std::{
+    collections::VecDeque,
+    io::{self, Read as _, Write as _},
+    net::{Shutdown, SocketAddr, ToSocketAddrs, UdpSocket},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+        mpsc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, anyhow, bail};
+use log::{debug, info, warn};
+
+use crate::{
+    Args, RecvAudioRestart, Restart, SendAudioRestart,
+    crypto::{self, Direction, Opener, Sealer},
+    endpoint::{BIND, COUNTED, Endpoint, KEEPALIVE, Registry, SIZE, Scheme, TIMEOUT, WINDOW},
+    idc::{self, Backoff, FrameDecoder, FrameEncoder, HEARTBEAT, IdcTimeouts, ReconnectPolicy},
+    network_utils::{DatagramSocket, accept_timeout, tcp_listener, tcp_socket},
+    pcm::PcmFormat,
+    pipeline::{Pipeline, PipelineHandle, SHUTDOWN_TIMEOUT},
+    sinks::{self, SendAudio},
+    sources::{self, RecvAudio},
+};
+
+type OpenSource = Box<dyn FnOnce() -> Result<Box<dyn RecvAudioRestart>> + Send>;
+type OpenSink = Box<dyn FnOnce() -> Result<Box<dyn SendAudioRestart>> + Send>;
+
+/// Both directions of a link to one peer, each half opened on the thread that uses it
+pub struct DuplexLink {
+    /// What the peer sends
+    pub source: OpenSource,
+    /// Goes to the peer
+    pub sink: OpenSink,
+}
+
+/// UDP socket that both halves of a link send and receive through. The peer
+/// not being up yet isn't an error, whatever was sent to it is just lost
+#[derive(Clone)]
+struct SharedUdpSocket(Arc<UdpSocket>);
+
+fn peer_missing(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionReset
+    )
+}
+
+impl DatagramSocket for SharedUdpSocket {
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.0.send(buf) {
+            Err(err) if peer_missing(&err) => Ok(buf.len()),
+            result => result,
+        }
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        loop {
+            match self.0.recv_from(buf) {
+                Err(err) if peer_missing(&err) => continue,
+                result => return result,
+            }
+        }
+    }
+
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        self.0.read_timeout()
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.0.set_read_timeout(timeout)
+    }
+
+    /// Nothing to recreate, and the other half still uses it
+    fn reopen(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn open_udp(endpoint: &Endpoint, args: &Args) -> Result<DuplexLink> {
+    let key = crypto::key_from_args(args)?;
+    let peer = endpoint
+        .address
+        .to_socket_addrs()?
+        .next()
+        .ok_or(anyhow!("Couldn't get socket addr."))?;
+    let bind = match endpoint.param::<SocketAddr>(BIND.name)? {
+        Some(bind) => bind,
+        None if peer.is_ipv4() => SocketAddr::from(([0; 4], peer.port())),
+        None => SocketAddr::from(([0; 16], peer.port())),
+    };
+    let socket = UdpSocket::bind(bind)?;
+    socket.connect(peer)?;
+    let socket = SharedUdpSocket(Arc::new(socket));
+    info!("Exchanging datagrams with {peer} on {bind}");
+    // Both ends seal the same way, so each refuses its own packets reflected back
+    let sealer = key.as_ref().map(Sealer::new);
+    let opener = key
+        .as_ref()
+        .zip(sealer.as_ref())
+        .map(|(key, sealer)| Opener::new(key).refusing(sealer.session()));
+    let (source_args, source_socket) = (args.clone(), socket.clone());
+    let sink_args = args.clone();
+    Ok(DuplexLink {
+        source: Box::new(move || {
+            sources::udp_source(
+                &source_args,
+                Box::new(source_socket),
+                &bind.to_string(),
+                opener,
+            )
+        }),
+        sink: Box::new(move || {
+            sinks::udp_sink(&sink_args, Box::new(socket), &peer.to_string(), sealer)
+        }),
+    })
+}
+
+enum IdcRole {
+    /// Wait for the peer to connect
+    Listen(socket2::Socket),
+    /// Connect to the peer
+    Connect(SocketAddr),
+}
+
+/// The one connection both halves of an idc link use
+struct IdcShared {
+    connection: Mutex<Option<Arc<socket2::Socket>>>,
+    /// Held for a whole frame, so that the halves don't cut into each other's frames
+    encoder: Mutex<FrameEncoder>,
+    /// Milliseconds since `created` when something last went out, kept apart
+    /// from the encoder so that checking it never waits on a blocked write
+    last_sent: AtomicU64,
+    created: Instant,
+    timeouts: IdcTimeouts,
+}
+
+impl IdcShared {
+    fn current(&self) -> Option<Arc<socket2::Socket>> {
+        self.connection.lock().unwrap().clone()
+    }
+
+    /// Forgets `socket` unless it's already been replaced, waking up the half
+    /// that's waiting on it
+    fn drop_connection(&self, socket: &Arc<socket2::Socket>) {
+        let mut connection = self.connection.lock().unwrap();
+        if connection
+            .as_ref()
+            .is_some_and(|current| Arc::ptr_eq(current, socket))
+        {
+            *connection = None;
+            let _ = socket.shutdown(Shutdown::Both);
+        }
+    }
+
+    /// Sends `payload` as a frame
+    fn send(&self, socket: &socket2::Socket, payload: &[u8]) -> io::Result<()> {
+        let mut encoder = self.encoder.lock().unwrap();
+        let mut frame = Vec::new();
+        encoder.encode(payload, &mut frame);
+        (&*socket).write_all(&frame)?;
+        self.sent();
+        Ok(())
+    }
+
+    /// Sends a heartbeat, unless the other half is busy writing anyway
+    fn heartbeat(&self, socket: &socket2::Socket) -> io::Result<()> {
+        let Ok(_encoder) = self.encoder.try_lock() else {
+            return Ok(());
+        };
+        (&*socket).write_all(&HEARTBEAT)?;
+        self.sent();
+        Ok(())
+    }
+
+    fn sent(&self) {
+        let since_created = self.created.elapsed().as_millis() as u64;
+        self.last_sent.store(since_created, Ordering::Relaxed);
+    }
+
+    fn idle(&self) -> bool {
+        let last_sent = Duration::from_millis(self.last_sent.load(Ordering::Relaxed));
+        self.created.elapsed().saturating_sub(last_sent) >= self.timeouts.keepalive
+    }
+}
+
+/// Says goodbye once both halves are gone
+impl Drop for IdcShared {
+    fn drop(&mut self) {
+        if let Some(socket) = self.connection.get_mut().unwrap().take() {
+            idc::say_goodbye(&socket);
+        }
+    }
+}
+
+/// What comes in over an idc link. It's the half that keeps the connection
+/// up, since it's waiting on the peer anyway
+pub struct IdcDuplexSourcePack {
+    shared: Arc<IdcShared>,
+    role: IdcRole,
+    backoff: Backoff,
+    buffer: Vec<u8>,
+    decoder: FrameDecoder,
+    last_heard: Instant,
+}
+
+impl IdcDuplexSourcePack {
+    fn lost(&mut self, socket: &Arc<socket2::Socket>) {
+        self.shared.drop_connection(socket);
+        self.backoff.lost();
+    }
+
+    fn wait_for_connection(&mut self) -> Result<()> {
+        let timeouts = self.shared.timeouts;
+        let socket = match &self.role {
+            IdcRole::Listen(listener) => match accept_timeout(listener, timeouts.keepalive) {
+                Ok(Some((socket, addr))) => {
+                    debug!("Accepted connection from {:?}", addr.as_socket());
+                    socket.set_nonblocking(false)?;
+                    socket
+                }
+                Ok(None) => return Ok(()),
+                Err(err) => {
+                    warn!("Couldn't accept a connection: {err}");
+                    self.backoff.attempt()?;
+                    self.backoff.wait();
+                    return Ok(());
+                }
+            },
+            IdcRole::Connect(address) => {
+                let address = (*address).into();
+                self.backoff.wait();
+                self.backoff.attempt()?;
+                let socket = tcp_socket(&address)?;
+                if let Err(err) = socket.connect_timeout(&address, timeouts.peer_timeout) {
+                    debug!("Couldn't connect to {:?}: {err}", address.as_socket());
+                    return Ok(());
+                }
+                socket
+            }
+        };
+        self.backoff.connected();
+        timeouts.configure(&socket)?;
+        // Wake up regularly to send heartbeats and to notice that the peer is gone
+        socket.set_read_timeout(Some(timeouts.keepalive))?;
+        // A peer that stopped reading is as good as gone
+        socket.set_write_timeout(Some(timeouts.peer_timeout))?;
+        self.last_heard = Instant::now();
+        self.decoder.reset();
+        *self.shared.connection.lock().unwrap() = Some(Arc::new(socket));
+        Ok(())
+    }
+}
+
+impl RecvAudio for IdcDuplexSourcePack {
+    fn recv_to_deque(&mut self, buf: &mut VecDeque<u8>) -> Result<()> {
+        let initial_len = buf.len();
+        loop {
+            let Some(socket) = self.shared.current() else {
+                // Back to the pipeline now and then, to notice it stopping
+                self.wait_for_connection()?;
+                if self.shared.current().is_none() {
+                    return Ok(());
+                }
+                continue;
+            };
+
+            if self.shared.idle()
+                && let Err(err) = self.shared.heartbeat(&socket)
+            {
+                debug!("Couldn't send a heartbeat: {err}");
+                self.lost(&socket);
+                continue;
+            }
+
+            match (&*socket).read(self.buffer.as_mut_slice()) {
+                Ok(0) => {
+                    debug!("Connection closed");
+                    self.lost(&socket);
+                }
+                Ok(n_read) => {
+                    self.last_heard = Instant::now();
+                    let peer = socket.peer_addr().ok().and_then(|addr| addr.as_socket());
+                    if let Err(err) = self.decoder.decode(&self.buffer[..n_read], buf, peer) {
+                        warn!("Dropping connection: {err}");
+                        self.lost(&socket);
+                    } else if self.decoder.said_goodbye {
+                        info!("The peer said goodbye");
+                        self.lost(&socket);
+                    }
+                    if buf.len() > initial_len {
+                        return Ok(());
+                    }
+                }
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    if self.last_heard.elapsed() >= self.shared.timeouts.peer_timeout {
+                        warn!(
+                            "Haven't heard from the peer for {:?}, dropping connection",
+                            self.last_heard.elapsed()
+                        );
+                        self.lost(&socket);
+                    }
+                    // Back to the pipeline now and then, to notice it stopping
+                    return Ok(());
+                }
+                Err(err) => {
+                    debug!("Connection lost: {err}");
+                    self.lost(&socket);
+                }
+            }
+        }
+    }
+}
+
+impl Restart for IdcDuplexSourcePack {
+    fn restart(&mut self) -> Result<()> {
+        if let Some(socket) = self.shared.current() {
+            self.lost(&socket);
+        }
+        Ok(())
+    }
+}
+
+/// What goes out over an idc link. Drops audio while there's no connection,
+/// and waits for the network while there is one
+pub struct IdcDuplexSinkPack {
+    shared: Arc<IdcShared>,
+    buffer: Vec<u8>,
+    block_align: usize,
+}
+
+impl SendAudio for IdcDuplexSinkPack {
+    fn send_from_deque(&mut self, data: &mut VecDeque<u8>) -> Result<()> {
+        let n_blocks = data.len() / self.block_align;
+        let Some(socket) = self.shared.current() else {
+            // Couldn't send, just consume the data
+            data.drain(..n_blocks * self.block_align);
+            return Ok(());
+        };
+        while data.len() >= self.block_align {
+            let n_sent = usize::min(self.buffer.len(), data.len());
+            let n_sent = n_sent - n_sent % self.block_align;
+            for (byte, value) in self.buffer.iter_mut().zip(data.drain(..n_sent)) {
+                *byte = value;
+            }
+            if let Err(err) = self.shared.send(&socket, &self.buffer[..n_sent]) {
+                debug!("Couldn't send: {err}");
+                // The source half reconnects
+                self.shared.drop_connection(&socket);
+                let n_blocks = data.len() / self.block_align;
+                data.drain(..n_blocks * self.block_align);
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Restart for IdcDuplexSinkPack {
+    fn restart(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+fn idc_link(role: IdcRole, name: String, args: &Args) -> Result<DuplexLink> {
+    let key = crypto::key_from_args(args)?;
+    let (sends, receives) = match role {
+        IdcRole::Listen(_) => (Direction::FromListener, Direction::FromConnector),
+        IdcRole::Connect(_) => (Direction::FromConnector, Direction::FromListener),
+    };
+    let sealer = key.as_ref().map(|key| Sealer::with_direction(key, sends));
+    let opener = key
+        .as_ref()
+        .zip(sealer.as_ref())
+        .map(|(key, sealer)| Opener::with_direction(key, receives).refusing(sealer.session()));
+    let block_align = PcmFormat::from_args(args)?.block_align();
+    let buffer_size = args.datagram_size;
+    if buffer_size < block_align {
+        bail!("Chunks of {buffer_size} bytes can't fit a {block_align} bytes long audio frame");
+    }
+    let shared = Arc::new(IdcShared {
+        connection: Mutex::new(None),
+        encoder: Mutex::new(FrameEncoder::new(sealer)),
+        last_sent: AtomicU64::new(0),
+        created: Instant::now(),
+        timeouts: IdcTimeouts::from_args(args),
+    });
+    let source = IdcDuplexSourcePack {
+        shared: shared.clone(),
+        role,
+        backoff: Backoff::new(ReconnectPolicy::from_args(args)?, name),
+        buffer: vec![0; buffer_size],
+        decoder: FrameDecoder::new(opener),
+        last_heard: Instant::now(),
+    };
+    let sink = IdcDuplexSinkPack {
+        shared,
+        buffer: vec![0; buffer_size.min(idc::MAX_PAYLOAD)],
+        block_align,
+    };
+    Ok(DuplexLink {
+        source: Box::new(move || Ok(Box::new(source))),
+        sink: Box::new(move || Ok(Box::new(sink))),
+    })
+}
+
+fn resolve(address: &str) -> Result<SocketAddr> {
+    address
+        .to_socket_addrs()?
+        .next()
+        .ok_or(anyhow!("Couldn't get socket addr."))
+}
+
+fn open_idc_listen(endpoint: &Endpoint, args: &Args) -> Result<DuplexLink> {
+    let address = resolve(&endpoint.address)?;
+    let listener = tcp_listener(&address.into(), 1)?;
+    // Waiting for the peer mustn't keep the pipeline from stopping
+    listener.set_nonblocking(true)?;
+    info!("Listening on {address} for the peer without caring");
+    idc_link(
+        IdcRole::Listen(listener),
+        format!("idc link on {address}"),
+        args,
+    )
+}
+
+fn open_idc_connect(endpoint: &Endpoint, args: &Args) -> Result<DuplexLink> {
+    let address = resolve(&endpoint.address)?;
+    info!("Connecting to the peer at {address} without caring");
+    idc_link(
+        IdcRole::Connect(address),
+        format!("idc link to {address}"),
+        args,
+    )
+}
+
+/// Every kind of link to a peer there is
+pub fn registry() -> Registry<DuplexLink> {
+    let mut registry = Registry::new("duplex link");
+    registry.register(Scheme::new(
+        "udp",
+        "<host:port>",
+        "Exchanges UDP datagrams with the peer through one port",
+        &[SIZE, COUNTED, WINDOW, BIND],
+        open_udp,
+    ));
+    registry.register(Scheme::new(
+        "idc-listen",
+        "<bind address>",
+        "Waits for the peer to connect, then talks idc both ways",
+        &[SIZE, KEEPALIVE, TIMEOUT],
+        open_idc_listen,
+    ));
+    registry.register(Scheme::new(
+        "idc-connect",
+        "<host:port>",
+        "Connects to the listening peer, then talks idc both ways",
+        &[SIZE, KEEPALIVE, TIMEOUT],
+        open_idc_connect,
+    ));
+    registry
+}
+
+/// Gets to see both directions of a duplex link, to keep the peer's audio
+/// coming out of the speakers from going back to it through the mic
+pub trait EchoSuppressor: Send {
+    /// The peer's audio, as the sink takes it
+    fn far_end(&mut self, samples: &[f32]);
+
+    /// Captured audio right before it goes to the peer, to change in place
+    fn near_end(&mut self, samples: &mut [f32]);
+}
+
+/// Turns the mic down while the peer is talking. Cruder than echo
+/// cancellation, but it needs no tuning to the room
+#[derive(Debug, Clone)]
+pub struct EchoGate {
+    /// Peer audio quieter than this doesn't count as talking
+    pub threshold: f32,
+    /// What the mic is multiplied by while the peer talks
+    pub ducked_gain: f32,
+    /// How long after the peer stops the mic stays down, for the room to
+    /// quiet down and to cover the device latency
+    pub hold: Duration,
+    gain: f32,
+    talking_until: Option<Instant>,
+}
+
+impl EchoGate {
+    pub fn new(threshold: f32, ducked_gain: f32, hold: Duration) -> Self {
+        Self {
+            threshold,
+            ducked_gain,
+            hold,
+            gain: 1.0,
+            talking_until: None,
+        }
+    }
+}
+
+/// -40 dB threshold, -20 dB while ducked and 200 ms of hold
+impl Default for EchoGate {
+    fn default() -> Self {
+        Self::new(0.01, 0.1, Duration::from_millis(200))
+    }
+}
+
+impl EchoSuppressor for EchoGate {
+    fn far_end(&mut self, samples: &[f32]) {
+        if samples.iter().any(|sample| sample.abs() >= self.threshold) {
+            self.talking_until = Some(Instant::now() + self.hold);
+        }
+    }
+
+    fn near_end(&mut self, samples: &mut [f32]) {
+        let talking = self
+            .talking_until
+            .is_some_and(|talking_until| Instant::now() < talking_until);
+        let target = if talking { self.ducked_gain } else { 1.0 };
+        // Ramp over the whole chunk, jumps would click
+        let step = (target - self.gain) / samples.len().max(1) as f32;
+        for sample in samples {
+            self.gain += step;
+            *sample *= self.gain;
+        }
+        self.gain = target;
+    }
+}
+
+type SharedSuppressor = Arc<Mutex<Box<dyn EchoSuppressor>>>;
+
+/// Lets the suppressor at captured audio before it goes to the peer
+struct NearEnd {
+    inner: Box<dyn RecvAudioRestart>,
+    suppressor: SharedSuppressor,
+    format: PcmFormat,
+    /// Received bytes that don't make up a whole frame yet
+    pending: VecDeque<u8>,
+    samples: Vec<f32>,
+}
+
+impl RecvAudio for NearEnd {
+    fn recv_to_deque(&mut self, buf: &mut VecDeque<u8>) -> Result<()> {
+        self.inner.recv_to_deque(&mut self.pending)?;
+        let block_align = self.format.block_align();
+        let n_whole = self.pending.len() - self.pending.len() % block_align;
+        let bytes: Vec<u8> = self.pending.drain(..n_whole).collect();
+        self.samples.clear();
+        self.format.decode(&bytes, &mut self.samples);
+        self.suppressor.lock().unwrap().near_end(&mut self.samples);
+        self.format.encode(&self.samples, buf);
+        Ok(())
+    }
+}
+
+impl Restart for NearEnd {
+    fn restart(&mut self) -> Result<()> {
+        self.pending.clear();
+        self.inner.restart()
+    }
+}
+
+/// Shows the suppressor the peer's audio as the sink takes it
+struct FarEnd {
+    inner: Box<dyn SendAudioRestart>,
+    suppressor: SharedSuppressor,
+    format: PcmFormat,
+    bytes: Vec<u8>,
+    samples: Vec<f32>,
+}
+
+impl SendAudio for FarEnd {
+    fn send_from_deque(&mut self, data: &mut VecDeque<u8>) -> Result<()> {
+        self.bytes.clear();
+        self.bytes.extend(data.iter());
+        self.inner.send_from_deque(data)?;
+        let n_taken = self.bytes.len() - data.len();
+        let n_whole = n_taken - n_taken % self.format.block_align();
+        if n_whole > 0 {
+            self.samples.clear();
+            self.format
+                .decode(&self.bytes[..n_whole], &mut self.samples);
+            self.suppressor.lock().unwrap().far_end(&self.samples);
+        }
+        Ok(())
+    }
+}
+
+impl Restart for FarEnd {
+    fn restart(&mut self) -> Result<()> {
+        self.inner.restart()
+    }
+}
+
+/// A two-way link with one peer: what the source captures goes out through
+/// the link, and what comes in through it goes to the sink. Each direction is
+/// its own [`Pipeline`], and the link has one connection and one set of
+/// reconnect logic for both
+pub struct Duplex {
+    outgoing: Pipeline,
+    incoming: Pipeline,
+}
+
+impl Duplex {
+    /// Opens the link to `args.duplex`, with an [`EchoGate`] if `args.echo_suppression` is set
+    pub fn open(args: &Args) -> Result<Self> {
+        let suppressor: Option<Box<dyn EchoSuppressor>> = if args.echo_suppression {
+            info!("Turning the source down while the peer talks");
+            Some(Box::new(EchoGate::default()))
+        } else {
+            None
+        };
+        Self::open_with_suppressor(args, suppressor)
+    }
+
+    pub fn open_with_suppressor(
+        args: &Args,
+        suppressor: Option<Box<dyn EchoSuppressor>>,
+    ) -> Result<Self> {
+        let peer = args
+            .duplex
+            .as_deref()
+            .ok_or(anyhow!("Duplex needs a peer"))?;
+        if !args.tee.is_empty() || !args.mix.is_empty() {
+            bail!("Duplex links can't tee or mix, run those as routes of their own");
+        }
+        let format = PcmFormat::from_args(args)?;
+        let link = registry().open(peer, args)?;
+        let suppressor: Option<SharedSuppressor> =
+            suppressor.map(|suppressor| Arc::new(Mutex::new(suppressor)));
+
+        let capture_args = args.clone();
+        let near_end = suppressor.clone();
+        let outgoing = Pipeline::builder()
+            .name("outgoing")
+            .args(args.clone())
+            .source_with(move || {
+                let capture = sources::from_args(&capture_args)?;
+                Ok(match near_end {
+                    Some(suppressor) => Box::new(NearEnd {
+                        inner: capture,
+                        suppressor,
+                        format,
+                        pending: VecDeque::new(),
+                        samples: Vec::new(),
+                    }),
+                    None => capture,
+                })
+            })
+            .sink_with(link.sink)
+            .build()?;
+
+        let render_args = args.clone();
+        let incoming = Pipeline::builder()
+            .name("incoming")
+            .args(args.clone())
+            .source_with(link.source)
+            .sink_with(move || {
+                let render = sinks::from_args(&render_args)?;
+                Ok(match suppressor {
+                    Some(suppressor) => Box::new(FarEnd {
+                        inner: render,
+                        suppressor,
+                        format,
+                        bytes: Vec::new(),
+                        samples: Vec::new(),
+                    }),
+                    None => render,
+                })
+            })
+            .build()?;
+        Ok(Self { outgoing, incoming })
+    }
+
+    /// Controls the direction from the source to the peer
+    pub fn outgoing(&self) -> PipelineHandle {
+        self.outgoing.handle()
+    }
+
+    /// Controls the direction from the peer to the sink
+    pub fn incoming(&self) -> PipelineHandle {
+        self.incoming.handle()
+    }
+
+    /// Runs both directions until stopped or until either fails, stopping the
+    /// other one too
+    pub fn run(self) -> Result<()> {
+        let handles = [self.outgoing(), self.incoming()];
+        let (done, finished) = mpsc::channel();
+        for pipeline in [self.outgoing, self.incoming] {
+            let done = done.clone();
+            thread::spawn(move || {
+                let _ = done.send(pipeline.run());
+            });
+        }
+        let result = finished
+            .recv()
+            .map_err(|err| anyhow!("Duplex threads vanished: {err}"));
+        for handle in handles {
+            handle.stop();
+        }
+        // Gives the other direction the chance to drain and close too
+        let _ = finished.recv_timeout(SHUTDOWN_TIMEOUT);
+        result?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn near_end(gate: &mut EchoGate) -> Vec<f32> {
+        let mut samples = vec![1.0; 100];
+        gate.near_end(&mut samples);
+        samples
+    }
+
+    #[test]
+    fn gate_ducks_while_the_peer_talks() {
+        let mut gate = EchoGate::new(0.1, 0.1, Duration::from_millis(100));
+        assert!(near_end(&mut gate).iter().all(|&sample| sample == 1.0));
+        // Too quiet to count
+        gate.far_end(&[0.05; 10]);
+        assert!(near_end(&mut gate).iter().all(|&sample| sample == 1.0));
+
+        gate.far_end(&[0.5; 10]);
+        let ramp = near_end(&mut gate);
+        assert!(ramp[0] > 0.9 && (ramp[99] - 0.1).abs() < 1e-3);
+        assert!(ramp.windows(2).all(|pair| pair[1] <= pair[0]));
+        assert!(
+            near_end(&mut gate)
+                .iter()
+                .all(|&sample| (sample - 0.1).abs() < 1e-3)
+        );
+
+        // Comes back up once the hold is over
+        thread::sleep(Duration::from_millis(150));
+        let ramp = near_end(&mut gate);
+        assert!(ramp[0] < 0.2 && (ramp[99] - 1.0).abs() < 1e-3);
+    }
+}
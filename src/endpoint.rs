@@ -0,0 +1,498 @@
+use std::{fmt::Write as _, str::FromStr};
+
+use anyhow::{Context, Result, anyhow, bail};
+
+use crate::Args;
+
+/// A source or sink as given on the command line, like
+/// `udp://0.0.0.0:1234?size=1400&counted=true` or a device name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Endpoint {
+    /// None for device names
+    pub scheme: Option<String>,
+    /// Everything between the scheme and the query
+    pub address: String,
+    pub params: Vec<(String, String)>,
+}
+
+impl Endpoint {
+    /// Parses `scheme://address?key=value&...`, or a device name that may have
+    /// a query too, like `mic?gain=-6`
+    pub fn parse(url: &str) -> Result<Self> {
+        let (scheme, rest) = match url.split_once("://") {
+            Some(("", _)) => bail!("Missing scheme in {url:?}"),
+            Some((scheme, rest)) => (Some(scheme.to_ascii_lowercase()), rest),
+            None => (None, url),
+        };
+        let (address, query) = rest.split_once('?').unwrap_or((rest, ""));
+        let params = query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                pair.split_once('=')
+                    .map(|(key, value)| (key.to_owned(), value.to_owned()))
+                    .ok_or(anyhow!("Expected key=value in {url:?}, got {pair:?}"))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            scheme,
+            address: address.to_owned(),
+            params,
+        })
+    }
+
+    /// The last value given for `name`, parsed
+    pub fn param<T: FromStr>(&self, name: &str) -> Result<Option<T>>
+    where
+        T::Err: std::error::Error + Send + Sync + 'static,
+    {
+        self.params
+            .iter()
+            .rev()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| {
+                value
+                    .parse()
+                    .with_context(|| format!("Invalid value for {name}: {value:?}"))
+            })
+            .transpose()
+    }
+
+    /// Only the given params, joined back into a query
+    pub fn query_of(&self, params: &[Param]) -> String {
+        self.params
+            .iter()
+            .filter(|(key, _)| params.iter().any(|param| param.name == key))
+            .map(|(key, value)| format!("{key}={value}"))
+            .collect::<Vec<_>>()
+            .join("&")
+    }
+}
+
+/// Sets what a param overrides in `Args` from its value
+pub type Apply = fn(&mut Args, &str) -> Result<()>;
+
+/// A query parameter a scheme takes
+#[derive(Debug, Clone, Copy)]
+pub struct Param {
+    pub name: &'static str,
+    pub help: &'static str,
+    /// None for params that whatever opens or runs the endpoint reads itself
+    pub apply: Option<Apply>,
+}
+
+fn parse<T: FromStr>(value: &str) -> Result<T>
+where
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    Ok(value.parse()?)
+}
+
+pub const SIZE: Param = Param {
+    name: "size",
+    help: "max datagram or chunk size in bytes, instead of --datagram-size",
+    apply: Some(|args, value| {
+        args.datagram_size = parse(value)?;
+        Ok(())
+    }),
+};
+pub const COUNTED: Param = Param {
+    name: "counted",
+    help: "true to check packet order and loss, instead of --counted-udp",
+    apply: Some(|args, value| {
+        args.counted_udp = parse(value)?;
+        Ok(())
+    }),
+};
+pub const WINDOW: Param = Param {
+    name: "window",
+    help: "reorder window in packets, instead of --reorder-window",
+    apply: Some(|args, value| {
+        args.reorder_window = parse(value)?;
+        Ok(())
+    }),
+};
+pub const QUEUE: Param = Param {
+    name: "queue",
+    help: "max bytes queued for each client, instead of --buffer-limit",
+    apply: Some(|args, value| {
+        args.buffer_limit = parse(value)?;
+        Ok(())
+    }),
+};
+pub const KEEPALIVE: Param = Param {
+    name: "keepalive",
+    help: "heartbeat interval in ms, instead of --idc-keepalive",
+    apply: Some(|args, value| {
+        args.idc_keepalive = parse(value)?;
+        Ok(())
+    }),
+};
+pub const TIMEOUT: Param = Param {
+    name: "timeout",
+    help: "dead peer timeout in ms, instead of --idc-timeout",
+    apply: Some(|args, value| {
+        args.idc_timeout = parse(value)?;
+        Ok(())
+    }),
+};
+pub const LATENCY: Param = Param {
+    name: "latency",
+    help: "latency budget in ms, instead of --idc-latency-budget",
+    apply: Some(|args, value| {
+        args.idc_latency_budget = parse(value)?;
+        Ok(())
+    }),
+};
+pub const LEGACY: Param = Param {
+    name: "legacy",
+    help: "true to send unframed audio to idc sources older than heartbeats",
+    apply: None,
+};
+pub const BIND: Param = Param {
+    name: "bind",
+    help: "local address to receive on, the peer's port on all interfaces by default",
+    apply: None,
+};
+pub const BUFFER: Param = Param {
+    name: "buffer",
+    help: "bytes buffered for this sink alone, instead of --buffer-limit",
+    apply: None,
+};
+pub const OVERFLOW: Param = Param {
+    name: "overflow",
+    help: "clear, drop-new or block, instead of --overflow",
+    apply: None,
+};
+pub const GAIN: Param = Param {
+    name: "gain",
+    help: "gain in dB for this source in the mix, like -6",
+    apply: None,
+};
+pub const PREBUFFER: Param = Param {
+    name: "prebuffer",
+    help: "ms of audio this source buffers before it joins the mix, 40 by default",
+    apply: None,
+};
+pub const ON_TRANSIENT: Param = Param {
+    name: "on-transient",
+    help: "ignore, restart, restart-pipeline or exit, instead of --on-transient",
+    apply: None,
+};
+pub const ON_DEVICE_LOST: Param = Param {
+    name: "on-device-lost",
+    help: "the same for lost devices, instead of --on-device-lost",
+    apply: None,
+};
+pub const ON_FATAL: Param = Param {
+    name: "on-fatal",
+    help: "the same for any other error, instead of --on-fatal",
+    apply: None,
+};
+pub const NAME: Param = Param {
+    name: "name",
+    help: "stream name, instead of --stream-name",
+    apply: Some(|args, value| {
+        args.stream_name = parse(value)?;
+        Ok(())
+    }),
+};
+
+/// What impair+ schemes take on top of the rest, see [`crate::impair::Impairment`]
+pub const IMPAIRMENTS: [Param; 9] = [
+    Param {
+        name: "loss",
+        help: "chance to lose a packet, like 2%",
+        apply: None,
+    },
+    Param {
+        name: "burst",
+        help: "chances to start and stop losing everything, like 1%,30%",
+        apply: None,
+    },
+    Param {
+        name: "reorder",
+        help: "chance to hold a packet back",
+        apply: None,
+    },
+    Param {
+        name: "reorder_delay",
+        help: "how long held back packets wait, like 20ms",
+        apply: None,
+    },
+    Param {
+        name: "dup",
+        help: "chance to send a packet twice",
+        apply: None,
+    },
+    Param {
+        name: "delay",
+        help: "constant delay, like 50ms",
+        apply: None,
+    },
+    Param {
+        name: "jitter",
+        help: "random extra delay, like 20ms",
+        apply: None,
+    },
+    Param {
+        name: "rate",
+        help: "bandwidth limit, like 5mbit",
+        apply: None,
+    },
+    Param {
+        name: "seed",
+        help: "seed for the random decisions",
+        apply: None,
+    },
+];
+
+type Open<T> = Box<dyn Fn(&Endpoint, &Args) -> Result<T> + Send + Sync>;
+
+/// A kind of source or sink
+pub struct Scheme<T> {
+    pub name: &'static str,
+    /// What goes between `scheme://` and the query
+    pub address: &'static str,
+    pub help: &'static str,
+    pub params: Vec<Param>,
+    /// Gets the endpoint along with `Args` that already have the params applied
+    open: Open<T>,
+}
+
+impl<T> Scheme<T> {
+    pub fn new(
+        name: &'static str,
+        address: &'static str,
+        help: &'static str,
+        params: &[Param],
+        open: impl Fn(&Endpoint, &Args) -> Result<T> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            name,
+            address,
+            help,
+            params: params.to_vec(),
+            open: Box::new(open),
+        }
+    }
+}
+
+/// All the schemes a source or a sink can be opened with
+pub struct Registry<T> {
+    /// "source" or "sink", for messages
+    kind: &'static str,
+    schemes: Vec<Scheme<T>>,
+    /// Params every scheme takes, for whatever runs the endpoint rather than opens it
+    common: Vec<Param>,
+    /// Opens endpoints without a scheme
+    fallback: Option<Open<T>>,
+}
+
+impl<T> Registry<T> {
+    pub fn new(kind: &'static str) -> Self {
+        Self {
+            kind,
+            schemes: Vec::new(),
+            common: Vec::new(),
+            fallback: None,
+        }
+    }
+
+    /// Adds a scheme, replacing one with the same name
+    pub fn register(&mut self, scheme: Scheme<T>) {
+        self.schemes.retain(|known| known.name != scheme.name);
+        self.schemes.push(scheme);
+    }
+
+    /// Makes every scheme take `params` on top of its own
+    pub fn add_common(&mut self, params: &[Param]) {
+        self.common.extend_from_slice(params);
+    }
+
+    /// What opens endpoints without a scheme, like device names
+    pub fn set_fallback(
+        &mut self,
+        open: impl Fn(&Endpoint, &Args) -> Result<T> + Send + Sync + 'static,
+    ) {
+        self.fallback = Some(Box::new(open));
+    }
+
+    pub fn find(&self, name: &str) -> Option<&Scheme<T>> {
+        self.schemes.iter().find(|scheme| scheme.name == name)
+    }
+
+    /// The scheme for `url`, None for the fallback, and `args` with the params applied
+    fn resolve(&self, url: &str, args: &Args) -> Result<(Option<&Scheme<T>>, Endpoint, Args)> {
+        let endpoint = Endpoint::parse(url)?;
+        let Some(name) = &endpoint.scheme else {
+            if self.fallback.is_none() {
+                bail!("{url:?} isn't a {} url", self.kind);
+            }
+            // A device only takes what runs it
+            if let Some((key, _)) = endpoint
+                .params
+                .iter()
+                .find(|(key, _)| !self.common.iter().any(|param| param.name == key))
+            {
+                let known: Vec<_> = self.common.iter().map(|param| param.name).collect();
+                bail!(
+                    "Unknown parameter {key:?} for a device {}, expected one of: {}",
+                    self.kind,
+                    known.join(", ")
+                );
+            }
+            return Ok((None, endpoint, args.clone()));
+        };
+        let scheme = self.find(name).ok_or_else(|| {
+            let known: Vec<_> = self.schemes.iter().map(|scheme| scheme.name).collect();
+            anyhow!(
+                "Unknown {} scheme \"{name}://\", expected one of: {}",
+                self.kind,
+                known.join(", ")
+            )
+        })?;
+
+        let mut args = args.clone();
+        for (key, value) in &endpoint.params {
+            let mut params = scheme.params.iter().chain(&self.common);
+            let Some(param) = params.find(|param| param.name == key) else {
+                let known: Vec<_> = scheme
+                    .params
+                    .iter()
+                    .chain(&self.common)
+                    .map(|param| param.name)
+                    .collect();
+                bail!(
+                    "Unknown parameter {key:?} for a {name}:// {}, expected {}",
+                    self.kind,
+                    if known.is_empty() {
+                        "none".to_owned()
+                    } else {
+                        format!("one of: {}", known.join(", "))
+                    }
+                );
+            };
+            if let Some(apply) = param.apply {
+                apply(&mut args, value)
+                    .with_context(|| format!("Invalid value for {key}: {value:?}"))?;
+            }
+        }
+        Ok((Some(scheme), endpoint, args))
+    }
+
+    pub fn open(&self, url: &str, args: &Args) -> Result<T> {
+        match self.resolve(url, args)? {
+            (Some(scheme), endpoint, args) => (scheme.open)(&endpoint, &args),
+            (None, endpoint, args) => (self.fallback.as_ref().unwrap())(&endpoint, &args),
+        }
+    }
+
+    /// Fails like [`Self::open`] would for a malformed url, an unknown scheme or
+    /// bad params, without opening anything
+    pub fn check(&self, url: &str, args: &Args) -> Result<()> {
+        self.resolve(url, args).map(|_| ())
+    }
+
+    /// Every scheme with its params, one per paragraph
+    pub fn help(&self) -> String {
+        let mut help = String::new();
+        for scheme in &self.schemes {
+            let _ = writeln!(help, "{}://{}", scheme.name, scheme.address);
+            let _ = writeln!(help, "    {}", scheme.help);
+            for param in &scheme.params {
+                let _ = writeln!(help, "    {:<16}{}", param.name, param.help);
+            }
+        }
+        if !self.common.is_empty() {
+            let _ = writeln!(help, "Every {} also takes", self.kind);
+            for param in &self.common {
+                let _ = writeln!(help, "    {:<16}{}", param.name, param.help);
+            }
+        }
+        help
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry() -> Registry<Args> {
+        let mut registry = Registry::new("sink");
+        registry.register(Scheme::new(
+            "udp",
+            "<address>",
+            "Sends UDP datagrams",
+            &[SIZE, BIND],
+            |_: &Endpoint, args: &Args| Ok(args.clone()),
+        ));
+        registry.add_common(&[GAIN]);
+        registry
+    }
+
+    #[test]
+    fn parses_schemes_addresses_and_queries() {
+        let endpoint = Endpoint::parse("UDP://[::1]:1234?size=1400&bind=0.0.0.0:5").unwrap();
+        assert_eq!(endpoint.scheme.as_deref(), Some("udp"));
+        assert_eq!(endpoint.address, "[::1]:1234");
+        assert_eq!(endpoint.param::<usize>("size").unwrap(), Some(1400));
+        assert_eq!(endpoint.param::<usize>("gain").unwrap(), None);
+        assert!(endpoint.param::<usize>("bind").is_err());
+    }
+
+    #[test]
+    fn malformed_urls_are_refused() {
+        let err = Endpoint::parse("://0.0.0.0:1234").unwrap_err();
+        assert!(err.to_string().contains("Missing scheme"), "{err}");
+        let err = Endpoint::parse("udp://0.0.0.0:1234?size=1400&counted").unwrap_err();
+        assert!(err.to_string().contains("\"counted\""), "{err}");
+        // Stray separators are fine
+        let endpoint = Endpoint::parse("udp://0.0.0.0:1234?&size=1400&").unwrap();
+        assert_eq!(endpoint.params, [("size".to_owned(), "1400".to_owned())]);
+    }
+
+    #[test]
+    fn repeated_keys_take_the_last_value() {
+        let url = "udp://127.0.0.1:1234?size=1400&size=800";
+        let endpoint = Endpoint::parse(url).unwrap();
+        assert_eq!(endpoint.param::<usize>("size").unwrap(), Some(800));
+        assert_eq!(
+            registry()
+                .open(url, &Args::default())
+                .unwrap()
+                .datagram_size,
+            800
+        );
+    }
+
+    #[test]
+    fn device_names_can_have_a_query() {
+        let endpoint = Endpoint::parse("Speakers (USB Audio)?gain=-6").unwrap();
+        assert_eq!(endpoint.scheme, None);
+        assert_eq!(endpoint.address, "Speakers (USB Audio)");
+        assert_eq!(endpoint.param::<f64>("gain").unwrap(), Some(-6.0));
+        let endpoint = Endpoint::parse("Speakers (USB Audio)").unwrap();
+        assert!(endpoint.params.is_empty());
+    }
+
+    #[test]
+    fn params_override_only_what_they_set() {
+        let args = registry()
+            .open(
+                "udp://127.0.0.1:1234?size=1400&bind=0.0.0.0:5&gain=-6",
+                &Args::default(),
+            )
+            .unwrap();
+        assert_eq!(args.datagram_size, 1400);
+        assert_eq!(args.buffer_limit, Args::default().buffer_limit);
+
+        let registry = registry();
+        let err = registry.check("udp://127.0.0.1:1234?size=big", &Args::default());
+        assert!(format!("{:#}", err.unwrap_err()).contains("Invalid value for size"));
+        let err = registry.check("udp://127.0.0.1:1234?queue=1", &Args::default());
+        assert!(format!("{:#}", err.unwrap_err()).contains("expected one of: size, bind, gain"));
+        let err = registry.check("tcp://127.0.0.1:1234", &Args::default());
+        assert!(format!("{:#}", err.unwrap_err()).contains("Unknown sink scheme"));
+    }
+}
@@ -0,0 +1,378 @@
+use std::{
+    io::{self, Read as _},
+    net::{Shutdown, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, anyhow, bail};
+use base64::Engine as _;
+use log::{debug, warn};
+
+use crate::network_utils::{ClientQueue, tcp_listener, tcp_socket};
+
+/// Longest request head that's accepted, nothing here needs big requests
+pub const MAX_HEAD_LEN: usize = 8192;
+
+/// Drop connections that haven't sent a whole request head for this long
+const HEAD_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a client may take no part of its response before it's dropped
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub const WS_TEXT: u8 = 0x1;
+pub const WS_BINARY: u8 = 0x2;
+pub const WS_CLOSE: u8 = 0x8;
+pub const WS_PING: u8 = 0x9;
+pub const WS_PONG: u8 = 0xA;
+
+const WS_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    /// Path without the query
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl Request {
+    pub fn parse(head: &str) -> Result<Self> {
+        let mut lines = head.split("\r\n");
+        let request_line = lines.next().unwrap_or_default();
+        let mut parts = request_line.split(' ');
+        let (Some(method), Some(target), Some(version)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            bail!("Malformed request line: {request_line:?}");
+        };
+        if !version.starts_with("HTTP/1.") {
+            bail!("Unsupported HTTP version: {version}");
+        }
+        let headers = lines
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                line.split_once(':')
+                    .map(|(name, value)| (name.trim().to_owned(), value.trim().to_owned()))
+                    .ok_or(anyhow!("Malformed header: {line:?}"))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            method: method.to_owned(),
+            path: target.split('?').next().unwrap_or_default().to_owned(),
+            headers,
+        })
+    }
+
+    /// Value of the first header called `name`, ignoring case
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Whether `name` is a comma-separated header that lists `token`, ignoring case
+    pub fn header_has(&self, name: &str, token: &str) -> bool {
+        self.header(name).is_some_and(|value| {
+            value
+                .split(',')
+                .any(|item| item.trim().eq_ignore_ascii_case(token))
+        })
+    }
+
+    pub fn is_websocket(&self) -> bool {
+        self.header_has("Connection", "upgrade") && self.header_has("Upgrade", "websocket")
+    }
+}
+
+/// A connection whose request head hasn't fully arrived yet
+struct PendingConnection {
+    socket: socket2::Socket,
+    address: Option<SocketAddr>,
+    head: Vec<u8>,
+    since: Instant,
+}
+
+impl PendingConnection {
+    /// Reads what's available, returns the request once the head is complete
+    fn poll(&mut self) -> Result<Option<Request>> {
+        let mut chunk = [0; 1024];
+        loop {
+            match (&self.socket).read(&mut chunk) {
+                Ok(0) => bail!("Connection closed before the request was complete"),
+                Ok(n_read) => {
+                    self.head.extend_from_slice(&chunk[..n_read]);
+                    if let Some(end) = self.head.windows(4).position(|w| w == b"\r\n\r\n") {
+                        let head = std::str::from_utf8(&self.head[..end])
+                            .map_err(|err| anyhow!("Request head isn't UTF-8: {err}"))?;
+                        return Request::parse(head).map(Some);
+                    }
+                    if self.head.len() > MAX_HEAD_LEN {
+                        bail!("Request head is longer than {MAX_HEAD_LEN} bytes");
+                    }
+                }
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => break,
+                Err(error) => return Err(error.into()),
+            }
+        }
+        if self.since.elapsed() >= HEAD_TIMEOUT {
+            bail!("Request took too long to arrive");
+        }
+        Ok(None)
+    }
+}
+
+/// Nonblocking listener that hands out connections along with their parsed requests
+pub struct HttpListener {
+    listener: socket2::Socket,
+    /// Where the listener is bound, kept for when it's recreated
+    address: socket2::SockAddr,
+    pending: Vec<PendingConnection>,
+}
+
+impl HttpListener {
+    fn create_listener(address: &socket2::SockAddr) -> Result<socket2::Socket> {
+        let listener = tcp_listener(address, 128)?;
+        listener.set_nonblocking(true)?;
+        Ok(listener)
+    }
+
+    pub fn bind(address: impl std::net::ToSocketAddrs) -> Result<Self> {
+        let address = address
+            .to_socket_addrs()?
+            .next()
+            .ok_or(anyhow!("Couldn't get socket addr."))?;
+        let listener = Self::create_listener(&address.into())?;
+        // The actual port, if it was picked by the system
+        let address = listener.local_addr()?;
+        Ok(Self {
+            listener,
+            address,
+            pending: Vec::new(),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.address
+            .as_socket()
+            .ok_or(anyhow!("Listener isn't bound to an IP address"))
+    }
+
+    /// Accepts new connections and returns the ones whose requests have fully arrived.
+    /// The returned sockets are nonblocking
+    pub fn poll(&mut self) -> Vec<(socket2::Socket, Option<SocketAddr>, Request)> {
+        loop {
+            match self.listener.accept() {
+                Ok((socket, address)) => {
+                    if let Err(error) = socket.set_nonblocking(true) {
+                        warn!("Couldn't set up a connection: {error}");
+                        continue;
+                    }
+                    self.pending.push(PendingConnection {
+                        socket,
+                        address: address.as_socket(),
+                        head: Vec::new(),
+                        since: Instant::now(),
+                    });
+                }
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => break,
+                Err(error) => {
+                    warn!("Couldn't accept a connection: {error}");
+                    break;
+                }
+            }
+        }
+
+        let mut ready = Vec::new();
+        let mut i = 0;
+        while i < self.pending.len() {
+            match self.pending[i].poll() {
+                Ok(None) => i += 1,
+                Ok(Some(request)) => {
+                    let connection = self.pending.swap_remove(i);
+                    debug!(
+                        "{} {} from {:?}",
+                        request.method, request.path, connection.address
+                    );
+                    ready.push((connection.socket, connection.address, request));
+                }
+                Err(error) => {
+                    let connection = self.pending.swap_remove(i);
+                    debug!("Dropping connection from {:?}: {error}", connection.address);
+                }
+            }
+        }
+        ready
+    }
+
+    /// Drops pending connections and recreates the listener on the same address
+    pub fn reset(&mut self) -> Result<()> {
+        self.pending.clear();
+        // The old listener has to let go of the address before the new one
+        // can bind it. An unbound socket stands in until then
+        drop(std::mem::replace(
+            &mut self.listener,
+            tcp_socket(&self.address)?,
+        ));
+        self.listener = Self::create_listener(&self.address)?;
+        Ok(())
+    }
+}
+
+/// Status line and headers of a response, `headers` being "Name: value" lines
+pub fn response_head(status: &str, headers: &[&str]) -> String {
+    let mut head = format!("HTTP/1.1 {status}\r\n");
+    for header in headers {
+        head.push_str(header);
+        head.push_str("\r\n");
+    }
+    head.push_str("\r\n");
+    head
+}
+
+/// A complete response after which the connection is closed
+pub fn response(status: &str, content_type: &str, body: &[u8]) -> Vec<u8> {
+    let mut response = response_head(
+        status,
+        &[
+            &format!("Content-Type: {content_type}"),
+            &format!("Content-Length: {}", body.len()),
+            "Cache-Control: no-store",
+            "Connection: close",
+        ],
+    )
+    .into_bytes();
+    response.extend_from_slice(body);
+    response
+}
+
+/// Connections getting their last response, written without blocking and
+/// closed once it's out
+#[derive(Default)]
+pub struct Responses {
+    pending: Vec<(socket2::Socket, Option<SocketAddr>, ClientQueue)>,
+}
+
+impl Responses {
+    pub fn push(
+        &mut self,
+        socket: socket2::Socket,
+        address: Option<SocketAddr>,
+        response: Vec<u8>,
+    ) {
+        let mut queue = ClientQueue::new();
+        queue.push_pinned(response.into());
+        self.pending.push((socket, address, queue));
+    }
+
+    /// Sends what the sockets take right now, closing the connections that are
+    /// done or stuck
+    pub fn flush(&mut self) {
+        self.pending
+            .retain_mut(|(socket, address, queue)| match queue.flush(&*socket) {
+                Ok(_) if queue.is_empty() => {
+                    let _ = socket.shutdown(Shutdown::Write);
+                    false
+                }
+                Ok(_) if queue.stalled(RESPONSE_TIMEOUT) => {
+                    debug!("Client {address:?} didn't take its response");
+                    false
+                }
+                Ok(_) => true,
+                Err(error) => {
+                    debug!("Couldn't answer {address:?}: {error}");
+                    false
+                }
+            });
+    }
+
+    pub fn clear(&mut self) {
+        self.pending.clear();
+    }
+}
+
+/// The handshake that completes the WebSocket `request`, or the response that
+/// turns it down
+pub fn accept_websocket(request: &Request) -> Result<Vec<u8>, Vec<u8>> {
+    let Some(key) = request.header("Sec-WebSocket-Key") else {
+        return Err(response(
+            "400 Bad Request",
+            "text/plain",
+            b"WebSocket request without a key",
+        ));
+    };
+    if request.header("Sec-WebSocket-Version") != Some("13") {
+        return Err(response(
+            "426 Upgrade Required",
+            "text/plain",
+            b"Only WebSocket version 13 is supported",
+        ));
+    }
+    let mut hash = sha1_smol::Sha1::new();
+    hash.update(key.as_bytes());
+    hash.update(WS_GUID.as_bytes());
+    let accept = base64::engine::general_purpose::STANDARD.encode(hash.digest().bytes());
+    let head = response_head(
+        "101 Switching Protocols",
+        &[
+            "Upgrade: websocket",
+            "Connection: Upgrade",
+            &format!("Sec-WebSocket-Accept: {accept}"),
+        ],
+    );
+    Ok(head.into_bytes())
+}
+
+/// Appends an unmasked (server to client) WebSocket frame to `out`
+pub fn websocket_frame(opcode: u8, payload: &[u8], out: &mut Vec<u8>) {
+    out.push(0x80 | opcode);
+    match payload.len() {
+        len @ 0..126 => out.push(len as u8),
+        len @ 126..65536 => {
+            out.push(126);
+            out.extend((len as u16).to_be_bytes());
+        }
+        len => {
+            out.push(127);
+            out.extend((len as u64).to_be_bytes());
+        }
+    }
+    out.extend_from_slice(payload);
+}
+
+/// Takes the first complete client frame off `buf`, returning its opcode and unmasked payload.
+/// Fragments are returned as they are, which is fine for control frames
+pub fn read_websocket_frame(buf: &mut Vec<u8>) -> Result<Option<(u8, Vec<u8>)>> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+    let opcode = buf[0] & 0x0F;
+    let masked = buf[1] & 0x80 != 0;
+    let (len, mut pos) = match buf[1] & 0x7F {
+        126 if buf.len() >= 4 => (u16::from_be_bytes([buf[2], buf[3]]) as usize, 4),
+        127 if buf.len() >= 10 => (
+            u64::from_be_bytes(buf[2..10].try_into().unwrap()) as usize,
+            10,
+        ),
+        126 | 127 => return Ok(None),
+        len => (len as usize, 2),
+    };
+    if !masked {
+        bail!("Client sent an unmasked frame");
+    }
+    if len > MAX_HEAD_LEN {
+        bail!("Client sent a {len} bytes long frame");
+    }
+    if buf.len() < pos + 4 + len {
+        return Ok(None);
+    }
+    let mask: [u8; 4] = buf[pos..pos + 4].try_into().unwrap();
+    pos += 4;
+    let payload = buf[pos..pos + len]
+        .iter()
+        .enumerate()
+        .map(|(i, byte)| byte ^ mask[i % 4])
+        .collect();
+    buf.drain(..pos + len);
+    Ok(Some((opcode, payload)))
+}
@@ -0,0 +1,620 @@
+use std::{
+    collections::VecDeque,
+    hash::{BuildHasher, Hasher, RandomState},
+    io::{self, Write},
+    net::Shutdown,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Result, bail};
+use log::{debug, error, info, warn};
+use socket2::{Socket, TcpKeepalive};
+
+use crate::{
+    Args,
+    crypto::{self, Opener, Rejected, Sealer},
+    random,
+};
+
+/// Starts every idc frame, so that the receiver can find the next one in garbage
+const MAGIC: [u8; 4] = *b"SAS\x01";
+/// Magic, big-endian u32 body length, u64 sequence number and u64 timestamp
+pub const HEADER_LEN: usize = 24;
+/// Most audio a frame carries, senders split longer chunks
+pub const MAX_PAYLOAD: usize = 16 * 1024;
+/// Frames longer than this mean the stream is garbage. Waiting for the body
+/// of one that long would hold up the audio behind it, so the cap is as tight
+/// as what senders really make
+const MAX_FRAME_LEN: usize = MAX_PAYLOAD + crypto::OVERHEAD;
+/// Frame without a body, sent by both sides when they have nothing else to send
+pub const HEARTBEAT: [u8; HEADER_LEN] = {
+    let mut heartbeat = [0; HEADER_LEN];
+    heartbeat[0] = MAGIC[0];
+    heartbeat[1] = MAGIC[1];
+    heartbeat[2] = MAGIC[2];
+    heartbeat[3] = MAGIC[3];
+    heartbeat
+};
+/// Frame without a body and with the last sequence number there is, sent before
+/// closing a connection on purpose. Older peers take it for a heartbeat
+pub const GOODBYE: [u8; HEADER_LEN] = {
+    let mut goodbye = HEARTBEAT;
+    let mut i = 8;
+    while i < 16 {
+        goodbye[i] = 0xff;
+        i += 1;
+    }
+    goodbye
+};
+/// How long closing a connection waits for queued audio and the goodbye to go out
+pub const GOODBYE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Sends [`GOODBYE`] and closes the sending side, so that the peer sees the
+/// connection end rather than break. The peer might be gone already, so
+/// whatever fails is ignored
+pub fn say_goodbye(socket: &Socket) {
+    let _ = (&*socket).write_all(&GOODBYE);
+    let _ = socket.shutdown(Shutdown::Write);
+}
+
+/// How often idc peers show they're alive and how long they wait before giving up on each other
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdcTimeouts {
+    pub keepalive: Duration,
+    pub peer_timeout: Duration,
+}
+
+impl Default for IdcTimeouts {
+    fn default() -> Self {
+        Self {
+            keepalive: Duration::from_millis(1000),
+            peer_timeout: Duration::from_millis(5000),
+        }
+    }
+}
+
+impl IdcTimeouts {
+    pub fn from_args(args: &Args) -> Self {
+        Self {
+            keepalive: Duration::from_millis(args.idc_keepalive),
+            peer_timeout: Duration::from_millis(args.idc_timeout),
+        }
+    }
+
+    /// Sets up TCP keepalive and, where there is one, the user timeout, so that the
+    /// kernel also gives up on a vanished peer
+    pub fn configure(&self, socket: &Socket) -> io::Result<()> {
+        // The kernel only takes whole seconds here
+        let keepalive = self.keepalive.max(Duration::from_secs(1));
+        socket.set_tcp_keepalive(
+            &TcpKeepalive::new()
+                .with_time(keepalive)
+                .with_interval(keepalive),
+        )?;
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        socket.set_tcp_user_timeout(Some(self.peer_timeout))?;
+        Ok(())
+    }
+}
+
+/// How idc waits between attempts to connect
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReconnectPolicy {
+    pub min_delay: Duration,
+    pub max_delay: Duration,
+    /// Each failed attempt multiplies the delay by this
+    pub factor: f64,
+    /// Each delay is randomly up to this fraction shorter or longer, so that
+    /// peers that lost each other don't retry in lockstep
+    pub jitter: f64,
+    /// Give up after this many attempts without a lasting connection
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            min_delay: Duration::from_millis(250),
+            max_delay: Duration::from_millis(10000),
+            factor: 2.0,
+            jitter: 0.2,
+            max_attempts: None,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    pub fn from_args(args: &Args) -> Result<Self> {
+        let policy = Self {
+            min_delay: Duration::from_millis(args.idc_reconnect_min),
+            max_delay: Duration::from_millis(args.idc_reconnect_max),
+            factor: args.idc_reconnect_factor,
+            jitter: args.idc_reconnect_jitter,
+            max_attempts: args.idc_reconnect_attempts,
+        };
+        if policy.min_delay > policy.max_delay {
+            bail!("Minimal reconnect delay can't be longer than the maximal one");
+        }
+        if !(1.0..).contains(&policy.factor) {
+            bail!("Reconnect delay factor must be at least 1");
+        }
+        if !(0.0..=1.0).contains(&policy.jitter) {
+            bail!("Reconnect jitter must be between 0 and 1");
+        }
+        Ok(policy)
+    }
+
+    /// Delay after the `attempt`th failed attempt in a row, before jitter
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(64) as i32;
+        self.min_delay
+            .mul_f64(self.factor.powi(exponent))
+            .min(self.max_delay)
+    }
+}
+
+/// What an idc connection is up to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connecting { attempt: u32 },
+    Connected,
+    Lost,
+    GivingUp,
+}
+
+/// Paces connection attempts by a [`ReconnectPolicy`], logging every change of
+/// the connection state and passing it to the callback if there is one
+pub struct Backoff {
+    policy: ReconnectPolicy,
+    /// Goes before every log message
+    name: String,
+    attempt: u32,
+    next_attempt: Instant,
+    connected_at: Option<Instant>,
+    rng_state: u64,
+    callback: Option<Box<dyn FnMut(ConnectionState) + Send>>,
+}
+
+impl Backoff {
+    pub fn new(policy: ReconnectPolicy, name: impl std::fmt::Display) -> Self {
+        Self {
+            policy,
+            name: name.to_string(),
+            attempt: 0,
+            next_attempt: Instant::now(),
+            connected_at: None,
+            rng_state: RandomState::new().build_hasher().finish(),
+            callback: None,
+        }
+    }
+
+    pub fn set_callback(&mut self, callback: impl FnMut(ConnectionState) + Send + 'static) {
+        self.callback = Some(Box::new(callback));
+    }
+
+    fn emit(&mut self, state: ConnectionState) {
+        match state {
+            ConnectionState::Connecting { attempt } => {
+                debug!("{}: connecting, attempt {attempt}", self.name)
+            }
+            ConnectionState::Connected => info!("{}: connected", self.name),
+            ConnectionState::Lost => warn!("{}: connection lost", self.name),
+            ConnectionState::GivingUp => {
+                error!("{}: giving up after {} attempts", self.name, self.attempt)
+            }
+        }
+        if let Some(callback) = &mut self.callback {
+            callback(state);
+        }
+    }
+
+    /// Uniform in -1..1
+    fn random(&mut self) -> f64 {
+        2.0 * random(&mut self.rng_state) - 1.0
+    }
+
+    /// Whether it's time for the next attempt
+    pub fn ready(&self) -> bool {
+        Instant::now() >= self.next_attempt
+    }
+
+    /// When it's time for the next attempt
+    pub fn next_attempt(&self) -> Instant {
+        self.next_attempt
+    }
+
+    /// Sleeps until it's time for the next attempt
+    pub fn wait(&self) {
+        std::thread::sleep(self.next_attempt.saturating_duration_since(Instant::now()));
+    }
+
+    /// Records a new attempt and schedules the next one, fails once out of attempts
+    pub fn attempt(&mut self) -> Result<()> {
+        if self
+            .policy
+            .max_attempts
+            .is_some_and(|max_attempts| self.attempt >= max_attempts)
+        {
+            self.emit(ConnectionState::GivingUp);
+            bail!(
+                "{}: couldn't connect in {} attempts",
+                self.name,
+                self.attempt
+            );
+        }
+        self.attempt += 1;
+        self.emit(ConnectionState::Connecting {
+            attempt: self.attempt,
+        });
+        // After the last one too, so that giving up doesn't come any sooner
+        let delay = self.policy.delay(self.attempt);
+        let jitter = 1.0 + self.policy.jitter * self.random();
+        self.next_attempt = Instant::now() + delay.mul_f64(jitter);
+        Ok(())
+    }
+
+    /// Records that the connection is up, does nothing if it already was
+    pub fn connected(&mut self) {
+        if self.connected_at.is_none() {
+            self.connected_at = Some(Instant::now());
+            self.emit(ConnectionState::Connected);
+        }
+    }
+
+    /// Records that the connection went down, does nothing if it wasn't up.
+    /// Only a connection that lasted is a reason to start over with short delays,
+    /// so that a flapping link still backs off.
+    pub fn lost(&mut self) {
+        if let Some(connected_at) = self.connected_at.take() {
+            if connected_at.elapsed() >= self.policy.max_delay {
+                self.attempt = 0;
+            }
+            self.emit(ConnectionState::Lost);
+        }
+    }
+}
+
+/// Microseconds since the Unix epoch
+fn now_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_micros() as u64)
+}
+
+/// What comes before every idc frame body
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub len: usize,
+    /// Counts frames with audio, so that the receiver can tell how many were lost
+    pub seq: u64,
+    /// When the frame was made, in microseconds since the Unix epoch
+    pub timestamp: u64,
+}
+
+impl FrameHeader {
+    fn write(&self, out: &mut [u8]) {
+        out[..4].copy_from_slice(&MAGIC);
+        out[4..8].copy_from_slice(&(self.len as u32).to_be_bytes());
+        out[8..16].copy_from_slice(&self.seq.to_be_bytes());
+        out[16..24].copy_from_slice(&self.timestamp.to_be_bytes());
+    }
+
+    /// Returns None if `bytes` don't start with a sane header
+    fn read(bytes: &[u8]) -> Option<Self> {
+        if bytes[..4] != MAGIC {
+            return None;
+        }
+        let len = u32::from_be_bytes(bytes[4..8].try_into().unwrap()) as usize;
+        if len > MAX_FRAME_LEN {
+            return None;
+        }
+        Some(Self {
+            len,
+            seq: u64::from_be_bytes(bytes[8..16].try_into().unwrap()),
+            timestamp: u64::from_be_bytes(bytes[16..24].try_into().unwrap()),
+        })
+    }
+}
+
+/// Turns chunks of audio into idc frames
+pub struct FrameEncoder {
+    sealer: Option<Sealer>,
+    seq: u64,
+}
+
+impl FrameEncoder {
+    pub fn new(sealer: Option<Sealer>) -> Self {
+        Self { sealer, seq: 0 }
+    }
+
+    pub fn is_sealed(&self) -> bool {
+        self.sealer.is_some()
+    }
+
+    /// Appends `payload` as a frame to `out`, sealed if there's a sealer.
+    /// `payload` can't be longer than [`MAX_PAYLOAD`]
+    pub fn encode(&mut self, payload: &[u8], out: &mut Vec<u8>) {
+        debug_assert!(payload.len() <= MAX_PAYLOAD);
+        let start = out.len();
+        out.extend_from_slice(&[0; HEADER_LEN]);
+        match &mut self.sealer {
+            Some(sealer) => sealer.seal(payload, out),
+            None => out.extend_from_slice(payload),
+        }
+        let header = FrameHeader {
+            len: out.len() - start - HEADER_LEN,
+            seq: self.seq,
+            timestamp: now_micros(),
+        };
+        header.write(&mut out[start..]);
+        self.seq += 1;
+    }
+}
+
+/// Turns the received idc stream back into audio, skipping heartbeats and
+/// resyncing on the next frame if the stream turns into garbage
+pub struct FrameDecoder {
+    opener: Option<Opener>,
+    /// Received bytes that don't make up a whole frame yet
+    pending: Vec<u8>,
+    next_seq: Option<u64>,
+    /// Frames the sender made but that never arrived
+    pub lost: u64,
+    /// Bytes thrown away while looking for the next frame
+    pub skipped: u64,
+    /// The peer sent a [`GOODBYE`] and is closing the connection
+    pub said_goodbye: bool,
+    /// Whether the peer sends raw audio like versions before framing did,
+    /// known once the first bytes are in
+    unframed: Option<bool>,
+}
+
+impl FrameDecoder {
+    pub fn new(opener: Option<Opener>) -> Self {
+        Self {
+            opener,
+            pending: Vec::new(),
+            next_seq: None,
+            lost: 0,
+            skipped: 0,
+            said_goodbye: false,
+            unframed: None,
+        }
+    }
+
+    /// Forgets whatever was left of the previous connection
+    pub fn reset(&mut self) {
+        self.pending.clear();
+        self.next_seq = None;
+        self.said_goodbye = false;
+        self.unframed = None;
+        if let Some(opener) = &mut self.opener {
+            opener.link_changed();
+        }
+    }
+
+    /// Decodes all complete frames in what's left over plus `bytes` into `buf`,
+    /// keeping the incomplete rest for later. An unauthenticated frame means the
+    /// stream can't be trusted anymore, so that's an error. A connection that
+    /// doesn't start with [`MAGIC`] comes from an older peer and is passed through
+    /// as it is.
+    pub fn decode(
+        &mut self,
+        bytes: &[u8],
+        buf: &mut VecDeque<u8>,
+        from: impl std::fmt::Debug,
+    ) -> Result<()> {
+        self.pending.extend_from_slice(bytes);
+        if self.unframed.is_none() && self.pending.len() >= MAGIC.len() {
+            let unframed = self.pending[..MAGIC.len()] != MAGIC;
+            if unframed {
+                if self.opener.is_some() {
+                    bail!("{from:?} sends unframed audio, which can't be authenticated");
+                }
+                info!("{from:?} sends unframed audio, it must be an older version");
+            }
+            self.unframed = Some(unframed);
+        }
+        if self.unframed == Some(true) {
+            buf.extend(self.pending.drain(..));
+            return Ok(());
+        }
+        let mut start = 0;
+        let mut n_skipped = 0;
+        while self.pending.len() - start >= HEADER_LEN {
+            let rest = &self.pending[start..];
+            let Some(header) = FrameHeader::read(rest) else {
+                // Keep the last few bytes, they might be the start of the next magic
+                let skip = rest[1..]
+                    .windows(MAGIC.len())
+                    .position(|window| window == MAGIC)
+                    .map_or(rest.len() + 1 - MAGIC.len(), |pos| pos + 1);
+                start += skip;
+                n_skipped += skip;
+                continue;
+            };
+            if rest.len() - HEADER_LEN < header.len {
+                break;
+            }
+            let body = &rest[HEADER_LEN..HEADER_LEN + header.len];
+            start += HEADER_LEN + header.len;
+            if header.len == 0 {
+                self.said_goodbye |= header.seq == u64::MAX;
+                continue;
+            }
+            match &mut self.opener {
+                Some(opener) => match opener.open(body) {
+                    Ok(Some(payload)) => buf.extend(payload),
+                    Ok(None) => {}
+                    Err(Rejected) => {
+                        opener.log_rejected(&from);
+                        bail!("Got an unauthenticated frame");
+                    }
+                },
+                None => buf.extend(body),
+            }
+            match self.next_seq {
+                Some(next) if header.seq > next => {
+                    self.lost += header.seq - next;
+                    debug!("Lost {} frames from {from:?}", header.seq - next);
+                }
+                Some(next) if header.seq < next => {
+                    debug!("Frames from {from:?} started over, the sender must have restarted");
+                }
+                _ => {}
+            }
+            self.next_seq = Some(header.seq + 1);
+        }
+        if n_skipped > 0 {
+            self.skipped += n_skipped as u64;
+            warn!("Skipped {n_skipped} bytes of garbage from {from:?} to find the next frame");
+        }
+        self.pending.drain(..start);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    fn policy() -> ReconnectPolicy {
+        ReconnectPolicy {
+            min_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(1000),
+            factor: 2.0,
+            jitter: 0.0,
+            max_attempts: None,
+        }
+    }
+
+    /// How long `backoff` waits after its next attempt
+    fn next_delay(backoff: &mut Backoff) -> Duration {
+        backoff.attempt().unwrap();
+        backoff
+            .next_attempt()
+            .saturating_duration_since(Instant::now())
+    }
+
+    #[test]
+    fn headers_longer_than_any_frame_are_skipped_right_away() {
+        let mut encoder = FrameEncoder::new(None);
+        let mut stream = Vec::new();
+        encoder.encode(&[1; 100], &mut stream);
+        // Garbage that looks like the header of a frame a little too long
+        let mut bogus = HEARTBEAT;
+        bogus[4..8].copy_from_slice(&(MAX_FRAME_LEN as u32 + 1).to_be_bytes());
+        stream.extend(bogus);
+        encoder.encode(&[2; 100], &mut stream);
+
+        let mut decoder = FrameDecoder::new(None);
+        let mut buf = VecDeque::new();
+        decoder.decode(&stream, &mut buf, "test").unwrap();
+        assert_eq!(buf, [[1; 100], [2; 100]].concat());
+        assert_eq!(decoder.skipped, HEADER_LEN as u64);
+        assert_eq!(decoder.lost, 0);
+    }
+
+    #[test]
+    fn longest_sealed_frame_still_decodes() {
+        let key = crypto::parse_key(&"ab".repeat(32)).unwrap();
+        let mut encoder = FrameEncoder::new(Some(Sealer::new(&key)));
+        let payload: Vec<u8> = (0..MAX_PAYLOAD).map(|i| i as u8).collect();
+        let mut stream = Vec::new();
+        encoder.encode(&payload, &mut stream);
+        assert_eq!(stream.len(), HEADER_LEN + MAX_FRAME_LEN);
+
+        let mut decoder = FrameDecoder::new(Some(Opener::new(&key)));
+        let mut buf = VecDeque::new();
+        // Trickling in, like from a slow connection
+        for chunk in stream.chunks(1000) {
+            decoder.decode(chunk, &mut buf, "test").unwrap();
+        }
+        assert_eq!(buf, payload);
+        assert_eq!(decoder.skipped, 0);
+    }
+
+    #[test]
+    fn delay_grows_by_the_factor_up_to_the_cap() {
+        let policy = policy();
+        let delays: Vec<_> = [0, 1, 2, 3, 4, 5, 100, u32::MAX]
+            .map(|attempt| policy.delay(attempt).as_millis())
+            .into();
+        assert_eq!(delays, [100, 100, 200, 400, 800, 1000, 1000, 1000]);
+        let steady = ReconnectPolicy {
+            factor: 1.0,
+            ..policy
+        };
+        assert_eq!(steady.delay(10), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn jitter_stays_within_its_fraction() {
+        let policy = ReconnectPolicy {
+            jitter: 0.2,
+            ..policy()
+        };
+        let mut delays = Vec::new();
+        for _ in 0..200 {
+            let mut backoff = Backoff::new(policy, "test");
+            delays.push(next_delay(&mut backoff).as_secs_f64());
+        }
+        let shortest = delays.iter().copied().fold(f64::MAX, f64::min);
+        let longest = delays.iter().copied().fold(0.0, f64::max);
+        // A little slack for the time between scheduling and measuring
+        assert!(shortest >= 0.079, "{shortest}");
+        assert!(longest <= 0.120, "{longest}");
+        assert!(longest - shortest > 0.02, "{shortest} {longest}");
+    }
+
+    #[test]
+    fn gives_up_after_the_last_attempt_has_had_its_time() {
+        let policy = ReconnectPolicy {
+            max_attempts: Some(3),
+            ..policy()
+        };
+        let states = Arc::new(Mutex::new(Vec::new()));
+        let seen = states.clone();
+        let mut backoff = Backoff::new(policy, "test");
+        backoff.set_callback(move |state| seen.lock().unwrap().push(state));
+        let delays: Vec<_> = (0..3)
+            .map(|_| next_delay(&mut backoff).as_millis().div_ceil(10) * 10)
+            .collect();
+        assert_eq!(delays, [100, 200, 400]);
+        assert!(!backoff.ready());
+        assert!(backoff.attempt().is_err());
+        assert_eq!(
+            *states.lock().unwrap(),
+            [
+                ConnectionState::Connecting { attempt: 1 },
+                ConnectionState::Connecting { attempt: 2 },
+                ConnectionState::Connecting { attempt: 3 },
+                ConnectionState::GivingUp,
+            ]
+        );
+    }
+
+    #[test]
+    fn only_a_lasting_connection_starts_over() {
+        let policy = ReconnectPolicy {
+            min_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(40),
+            ..policy()
+        };
+        let mut backoff = Backoff::new(policy, "test");
+        for _ in 0..3 {
+            backoff.attempt().unwrap();
+        }
+        // Flapping keeps backing off
+        backoff.connected();
+        backoff.lost();
+        assert!(next_delay(&mut backoff) > Duration::from_millis(30));
+
+        backoff.connected();
+        std::thread::sleep(Duration::from_millis(50));
+        backoff.lost();
+        assert!(next_delay(&mut backoff) <= Duration::from_millis(10));
+    }
+}
@@ -0,0 +1,635 @@
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    net::{
+        Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket,
+    },
+    sync::{
+        Arc, Condvar, Mutex,
+        atomic::{AtomicBool, Ordering},
+        mpsc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context as _, Result, anyhow, bail};
+
+use crate::{Restart, network_utils::DatagramSocket, random, sinks::SendAudio, sources::RecvAudio};
+
+/// Packets that would wait longer than this for the bandwidth cap are dropped
+const MAX_QUEUE_DELAY: Duration = Duration::from_millis(500);
+/// How often the threads of an [`ImpairedProxy`] look whether they should stop
+const PROXY_POLL: Duration = Duration::from_millis(10);
+
+/// Network conditions to simulate, parsed from a query like `loss=2%&jitter=20ms`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Impairment {
+    /// Chance of losing any packet
+    pub loss: f64,
+    /// Gilbert-Elliott burst loss: chances of entering and of leaving the state
+    /// where every packet is lost
+    pub burst: Option<(f64, f64)>,
+    /// Chance of holding a packet back by `reorder_delay` so that later ones overtake it
+    pub reorder: f64,
+    pub reorder_delay: Duration,
+    /// Chance of sending a packet twice
+    pub dup: f64,
+    pub delay: Duration,
+    /// Random extra delay of up to this much
+    pub jitter: Duration,
+    /// Bandwidth cap in bits per second
+    pub rate: Option<u64>,
+    pub seed: u64,
+}
+
+impl Default for Impairment {
+    fn default() -> Self {
+        Self {
+            loss: 0.0,
+            burst: None,
+            reorder: 0.0,
+            reorder_delay: Duration::from_millis(10),
+            dup: 0.0,
+            delay: Duration::ZERO,
+            jitter: Duration::ZERO,
+            rate: None,
+            seed: 1,
+        }
+    }
+}
+
+impl Impairment {
+    /// Parses `key=value` pairs separated by `&`, e.g.
+    /// `loss=2%&burst=1%,30%&reorder=1%&jitter=20ms&dup=0.5%&rate=2mbit&seed=42`
+    pub fn parse(query: &str) -> Result<Self> {
+        let mut result = Self::default();
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair
+                .split_once('=')
+                .ok_or(anyhow!("Expected key=value, got {pair:?}"))?;
+            let context = || format!("Invalid value for {key}: {value:?}");
+            match key {
+                "loss" => result.loss = parse_chance(value).with_context(context)?,
+                "burst" => {
+                    let (enter, leave) = value
+                        .split_once(',')
+                        .ok_or(anyhow!("Expected burst=<enter>,<leave>, got {value:?}"))?;
+                    result.burst = Some((
+                        parse_chance(enter).with_context(context)?,
+                        parse_chance(leave).with_context(context)?,
+                    ));
+                }
+                "reorder" => result.reorder = parse_chance(value).with_context(context)?,
+                "reorder_delay" => {
+                    result.reorder_delay = parse_duration(value).with_context(context)?
+                }
+                "dup" => result.dup = parse_chance(value).with_context(context)?,
+                "delay" => result.delay = parse_duration(value).with_context(context)?,
+                "jitter" => result.jitter = parse_duration(value).with_context(context)?,
+                "rate" => result.rate = Some(parse_rate(value).with_context(context)?),
+                "seed" => result.seed = value.parse().with_context(context)?,
+                _ => bail!("Unknown impairment {key:?}"),
+            }
+        }
+        Ok(result)
+    }
+}
+
+/// `2%` or `0.02`
+fn parse_chance(value: &str) -> Result<f64> {
+    let chance = match value.strip_suffix('%') {
+        Some(percent) => percent.parse::<f64>()? / 100.0,
+        None => value.parse()?,
+    };
+    if !(0.0..=1.0).contains(&chance) {
+        bail!("Chance must be between 0% and 100%");
+    }
+    Ok(chance)
+}
+
+/// `20ms`, `1.5s` or `500us`, plain numbers are milliseconds
+fn parse_duration(value: &str) -> Result<Duration> {
+    let (number, unit) = if let Some(number) = value.strip_suffix("ms") {
+        (number, 1e-3)
+    } else if let Some(number) = value.strip_suffix("us") {
+        (number, 1e-6)
+    } else if let Some(number) = value.strip_suffix('s') {
+        (number, 1.0)
+    } else {
+        (value, 1e-3)
+    };
+    Ok(Duration::try_from_secs_f64(number.parse::<f64>()? * unit)?)
+}
+
+/// `2mbit`, `500kbit` or plain bits per second
+fn parse_rate(value: &str) -> Result<u64> {
+    let (number, unit) = if let Some(number) = value.strip_suffix("mbit") {
+        (number, 1e6)
+    } else if let Some(number) = value.strip_suffix("kbit") {
+        (number, 1e3)
+    } else {
+        (value.strip_suffix("bit").unwrap_or(value), 1.0)
+    };
+    let rate = (number.parse::<f64>()? * unit) as u64;
+    if rate == 0 {
+        bail!("Rate must be positive");
+    }
+    Ok(rate)
+}
+
+/// What an [`ImpairedSocket`] or an [`ImpairedProxy`] did to the packets so far
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImpairmentStats {
+    pub passed: u64,
+    pub lost: u64,
+    pub duplicated: u64,
+    pub reordered: u64,
+}
+
+/// Packets waiting for their time, shared with the thread that sends them
+#[derive(Default)]
+struct Held {
+    state: Mutex<HeldState>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct HeldState {
+    /// With when to let them through and where to
+    packets: Vec<(Instant, Vec<u8>, SocketAddr)>,
+    /// The socket is gone or was reopened, the release thread should stop
+    closed: bool,
+    /// What went wrong sending the last held packet, for the next send to return
+    error: Option<io::Error>,
+}
+
+impl HeldState {
+    fn take_due(&mut self, now: Instant) -> Option<(Vec<u8>, SocketAddr)> {
+        let (index, _) = self
+            .packets
+            .iter()
+            .enumerate()
+            .filter(|(_, (release, ..))| *release <= now)
+            .min_by_key(|(_, (release, ..))| *release)?;
+        let (_, packet, addr) = self.packets.remove(index);
+        Some((packet, addr))
+    }
+
+    fn next_release(&self) -> Option<Instant> {
+        self.packets.iter().map(|(release, ..)| *release).min()
+    }
+}
+
+/// Sends held packets once they're due, so the last ones go out even if
+/// nothing is sent after them
+fn release_held(socket: UdpSocket, held: Arc<Held>) {
+    let mut state = held.state.lock().unwrap();
+    while !state.closed {
+        let now = Instant::now();
+        if let Some((packet, _)) = state.take_due(now) {
+            drop(state);
+            let result = socket.send(&packet);
+            state = held.state.lock().unwrap();
+            if let Err(err) = result {
+                state.error = Some(err);
+            }
+            continue;
+        }
+        state = match state.next_release() {
+            Some(release) => held.changed.wait_timeout(state, release - now).unwrap().0,
+            None => held.changed.wait(state).unwrap(),
+        };
+    }
+}
+
+/// UDP socket that loses, duplicates, delays and reorders datagrams, either on
+/// the way out (for sinks) or on the way in (for sources)
+pub struct ImpairedSocket {
+    socket: UdpSocket,
+    impairment: Impairment,
+    rng_state: u64,
+    in_burst: bool,
+    link_free_at: Instant,
+    held: Arc<Held>,
+    /// Whether a thread sends the held packets, which only sinks need
+    releasing: bool,
+    buffer: Vec<u8>,
+    read_timeout: Option<Duration>,
+    pub stats: ImpairmentStats,
+}
+
+impl ImpairedSocket {
+    pub fn new(socket: UdpSocket, impairment: Impairment) -> Self {
+        Self {
+            socket,
+            rng_state: impairment.seed,
+            impairment,
+            in_burst: false,
+            link_free_at: Instant::now(),
+            held: Arc::default(),
+            releasing: false,
+            buffer: vec![0; 65536],
+            read_timeout: None,
+            stats: ImpairmentStats::default(),
+        }
+    }
+
+    pub fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    fn random(&mut self) -> f64 {
+        random(&mut self.rng_state)
+    }
+
+    fn chance(&mut self, chance: f64) -> bool {
+        chance > 0.0 && self.random() < chance
+    }
+
+    fn impair(&mut self, packet: &[u8], addr: SocketAddr) {
+        let now = Instant::now();
+        if let Some((enter, leave)) = self.impairment.burst {
+            self.in_burst = if self.in_burst {
+                !self.chance(leave)
+            } else {
+                self.chance(enter)
+            };
+        }
+        if self.in_burst || self.chance(self.impairment.loss) {
+            self.stats.lost += 1;
+            return;
+        }
+        let copies = if self.chance(self.impairment.dup) {
+            self.stats.duplicated += 1;
+            2
+        } else {
+            1
+        };
+        let held = self.held.clone();
+        let mut state = held.state.lock().unwrap();
+        for _ in 0..copies {
+            let mut release = now;
+            if let Some(rate) = self.impairment.rate {
+                let start = self.link_free_at.max(now);
+                if start - now > MAX_QUEUE_DELAY {
+                    self.stats.lost += 1;
+                    continue;
+                }
+                self.link_free_at =
+                    start + Duration::from_secs_f64(packet.len() as f64 * 8.0 / rate as f64);
+                release = self.link_free_at;
+            }
+            release += self.impairment.delay + self.impairment.jitter.mul_f64(self.random());
+            if self.chance(self.impairment.reorder) {
+                release += self.impairment.reorder_delay;
+                self.stats.reordered += 1;
+            }
+            self.stats.passed += 1;
+            state.packets.push((release, packet.to_vec(), addr));
+        }
+        held.changed.notify_one();
+    }
+
+    fn take_due(&mut self, now: Instant) -> Option<(Vec<u8>, SocketAddr)> {
+        self.held.state.lock().unwrap().take_due(now)
+    }
+
+    /// Stops the release thread and forgets the held packets
+    fn close_held(&mut self) {
+        self.held.state.lock().unwrap().closed = true;
+        self.held.changed.notify_one();
+        self.held = Arc::default();
+        self.releasing = false;
+    }
+}
+
+impl DatagramSocket for ImpairedSocket {
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(err) = self.held.state.lock().unwrap().error.take() {
+            return Err(err);
+        }
+        let peer = self.socket.peer_addr()?;
+        if !self.releasing {
+            let socket = self.socket.try_clone()?;
+            let held = self.held.clone();
+            thread::Builder::new()
+                .name("impair release".to_owned())
+                .spawn(move || release_held(socket, held))?;
+            self.releasing = true;
+        }
+        self.impair(buf, peer);
+        while let Some((packet, _)) = self.take_due(Instant::now()) {
+            self.socket.send(&packet)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let deadline = self.read_timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            let now = Instant::now();
+            if let Some((packet, addr)) = self.take_due(now) {
+                let n_read = usize::min(packet.len(), buf.len());
+                buf[..n_read].copy_from_slice(&packet[..n_read]);
+                return Ok((n_read, addr));
+            }
+            if deadline.is_some_and(|deadline| now >= deadline) {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+
+            // Wait for a real packet, but not past the moment a held one is due
+            // or the read timeout is up
+            let timeout = self
+                .held
+                .state
+                .lock()
+                .unwrap()
+                .next_release()
+                .into_iter()
+                .chain(deadline)
+                .min()
+                .map(|until| {
+                    until
+                        .saturating_duration_since(now)
+                        .max(Duration::from_millis(1))
+                });
+            self.socket.set_read_timeout(timeout)?;
+            match self.socket.recv_from(&mut self.buffer) {
+                Ok((n_read, addr)) => {
+                    let packet = self.buffer[..n_read].to_vec();
+                    self.impair(&packet, addr);
+                }
+                Err(error)
+                    if timeout.is_some()
+                        && matches!(
+                            error.kind(),
+                            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                        ) => {}
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(self.read_timeout)
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.read_timeout = timeout;
+        Ok(())
+    }
+
+    fn reopen(&mut self) -> io::Result<()> {
+        self.close_held();
+        self.socket.reopen()
+    }
+}
+
+impl Drop for ImpairedSocket {
+    fn drop(&mut self) {
+        self.close_held();
+    }
+}
+
+/// The random decisions of an [`ImpairedProxy`], made in the order the chunks
+/// arrive on all of its connections
+struct ProxyShared {
+    impairment: Impairment,
+    decisions: Mutex<(u64, bool)>,
+    stats: Mutex<ImpairmentStats>,
+    stop: AtomicBool,
+}
+
+impl ProxyShared {
+    /// Whether the next chunk gets through, updating the burst state
+    fn passes(&self) -> bool {
+        let mut decisions = self.decisions.lock().unwrap();
+        let (rng_state, in_burst) = &mut *decisions;
+        if let Some((enter, leave)) = self.impairment.burst {
+            let chance = if *in_burst { leave } else { enter };
+            let flip = chance > 0.0 && random(rng_state) < chance;
+            *in_burst ^= flip;
+        }
+        let lost =
+            *in_burst || (self.impairment.loss > 0.0 && random(rng_state) < self.impairment.loss);
+        let mut stats = self.stats.lock().unwrap();
+        if lost {
+            stats.lost += 1;
+        } else {
+            stats.passed += 1;
+        }
+        !lost
+    }
+
+    fn jitter(&self) -> Duration {
+        let mut decisions = self.decisions.lock().unwrap();
+        self.impairment.jitter.mul_f64(random(&mut decisions.0))
+    }
+}
+
+/// Forwards TCP connections to a target through a simulated bad network, for
+/// testing idc. A TCP stream can't lose, reorder or duplicate data without
+/// breaking, so a lost chunk breaks the connection, and everything else only
+/// delays chunks, in order
+pub struct ImpairedProxy {
+    address: SocketAddr,
+    shared: Arc<ProxyShared>,
+}
+
+impl ImpairedProxy {
+    /// Listens on `listen` and connects every client to `target`
+    pub fn new(listen: SocketAddr, target: SocketAddr, impairment: Impairment) -> Result<Self> {
+        if impairment.reorder > 0.0 || impairment.dup > 0.0 {
+            bail!("A stream can't be reordered or duplicated, only delayed, capped or broken");
+        }
+        let listener = TcpListener::bind(listen)?;
+        listener.set_nonblocking(true)?;
+        let address = listener.local_addr()?;
+        let shared = Arc::new(ProxyShared {
+            decisions: Mutex::new((impairment.seed, false)),
+            impairment,
+            stats: Mutex::default(),
+            stop: AtomicBool::new(false),
+        });
+        let proxy_shared = shared.clone();
+        thread::Builder::new()
+            .name(format!("impair proxy to {target}"))
+            .spawn(move || accept_proxied(listener, target, proxy_shared))?;
+        Ok(Self { address, shared })
+    }
+
+    /// Where to connect to reach the target through the proxy
+    pub fn local_addr(&self) -> SocketAddr {
+        self.address
+    }
+
+    pub fn stats(&self) -> ImpairmentStats {
+        self.shared.stats.lock().unwrap().clone()
+    }
+}
+
+impl Drop for ImpairedProxy {
+    fn drop(&mut self) {
+        self.shared.stop.store(true, Ordering::Release);
+    }
+}
+
+fn accept_proxied(listener: TcpListener, target: SocketAddr, shared: Arc<ProxyShared>) {
+    while !shared.stop.load(Ordering::Acquire) {
+        let client = match listener.accept() {
+            Ok((client, _)) => client,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(PROXY_POLL);
+                continue;
+            }
+            Err(_) => continue,
+        };
+        // The client sees a refused connection as one that ends right away
+        let Ok(server) = TcpStream::connect_timeout(&target, Duration::from_secs(1)) else {
+            continue;
+        };
+        let _ = proxy_connection(client, server, &shared);
+    }
+}
+
+fn proxy_connection(
+    client: TcpStream,
+    server: TcpStream,
+    shared: &Arc<ProxyShared>,
+) -> io::Result<()> {
+    client.set_nonblocking(false)?;
+    let broken = Arc::new(AtomicBool::new(false));
+    for (from, to) in [
+        (client.try_clone()?, server.try_clone()?),
+        (server.try_clone()?, client.try_clone()?),
+    ] {
+        let shared = shared.clone();
+        let broken = broken.clone();
+        let ends = [client.try_clone()?, server.try_clone()?];
+        thread::Builder::new()
+            .name("impair proxy".to_owned())
+            .spawn(move || pump(from, to, &shared, &broken, &ends))?;
+    }
+    Ok(())
+}
+
+/// Copies one direction of a proxied connection, delaying and breaking it
+fn pump(
+    mut from: TcpStream,
+    mut to: TcpStream,
+    shared: &Arc<ProxyShared>,
+    broken: &Arc<AtomicBool>,
+    ends: &[TcpStream; 2],
+) {
+    // Breaking shuts down both connections, so both directions notice
+    let break_link = || {
+        broken.store(true, Ordering::Release);
+        for end in ends {
+            let _ = end.shutdown(Shutdown::Both);
+        }
+    };
+    let (chunks, delayed) = mpsc::channel::<(Instant, Vec<u8>)>();
+    let writer_broken = broken.clone();
+    let writer_shared = shared.clone();
+    let writer = thread::spawn(move || {
+        for (release, chunk) in delayed {
+            while Instant::now() < release && !writer_shared.stop.load(Ordering::Acquire) {
+                thread::sleep(PROXY_POLL.min(release.saturating_duration_since(Instant::now())));
+            }
+            if writer_broken.load(Ordering::Acquire) || to.write_all(&chunk).is_err() {
+                break;
+            }
+        }
+        let _ = to.shutdown(Shutdown::Write);
+    });
+
+    let _ = from.set_read_timeout(Some(PROXY_POLL));
+    let mut buffer = vec![0; 65536];
+    let mut last_release = Instant::now();
+    let mut link_free_at = Instant::now();
+    while !shared.stop.load(Ordering::Acquire) && !broken.load(Ordering::Acquire) {
+        let n_read = match from.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n_read) => n_read,
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                continue;
+            }
+            Err(_) => {
+                break_link();
+                break;
+            }
+        };
+        if !shared.passes() {
+            break_link();
+            break;
+        }
+        let now = Instant::now();
+        let mut release = now;
+        if let Some(rate) = shared.impairment.rate {
+            link_free_at =
+                link_free_at.max(now) + Duration::from_secs_f64(n_read as f64 * 8.0 / rate as f64);
+            release = link_free_at;
+        }
+        release += shared.impairment.delay + shared.jitter();
+        // Later chunks can't overtake earlier ones on a stream
+        last_release = last_release.max(release);
+        if chunks
+            .send((last_release, buffer[..n_read].to_vec()))
+            .is_err()
+        {
+            break;
+        }
+    }
+    drop(chunks);
+    let _ = writer.join();
+    if shared.stop.load(Ordering::Acquire) {
+        break_link();
+    }
+}
+
+/// Resolves the `host:port` an impaired scheme connects to
+pub(crate) fn resolve(address: &str) -> Result<SocketAddr> {
+    address
+        .to_socket_addrs()?
+        .next()
+        .ok_or(anyhow!("Couldn't get socket addr."))
+}
+
+/// Where a proxy to `target` listens, on loopback and any free port
+pub(crate) fn proxy_address(target: SocketAddr) -> SocketAddr {
+    if target.is_ipv6() {
+        (Ipv6Addr::LOCALHOST, 0).into()
+    } else {
+        (Ipv4Addr::LOCALHOST, 0).into()
+    }
+}
+
+/// A source or a sink that reaches its peer through an [`ImpairedProxy`]
+pub struct Proxied<T> {
+    pub inner: T,
+    pub proxy: ImpairedProxy,
+}
+
+impl<T: SendAudio> SendAudio for Proxied<T> {
+    fn send_from_deque(&mut self, data: &mut VecDeque<u8>) -> Result<()> {
+        self.inner.send_from_deque(data)
+    }
+}
+
+impl<T: RecvAudio> RecvAudio for Proxied<T> {
+    fn recv_to_deque(&mut self, buf: &mut VecDeque<u8>) -> Result<()> {
+        self.inner.recv_to_deque(buf)
+    }
+}
+
+impl<T: Restart> Restart for Proxied<T> {
+    fn restart(&mut self) -> Result<()> {
+        self.inner.restart()
+    }
+}
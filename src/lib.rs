@@ -1,61 +1,266 @@
+use std::path::PathBuf;
+
 use anyhow::Result;
-use clap::Parser;
 
-use crate::{sinks::SendAudio, sources::RecvAudio};
+use crate::{
+    network_utils::Cidr,
+    pipeline::{OverflowPolicy, UnderflowPolicy},
+    plc::Concealment,
+    recovery::Recovery,
+    sinks::SendAudio,
+    sources::RecvAudio,
+};
 
+#[cfg(feature = "tokio")]
+pub mod async_compat;
+#[cfg(feature = "cli")]
+pub mod config;
+pub mod crypto;
 pub mod device_utils;
+pub mod duplex;
+pub mod endpoint;
+pub mod http;
+pub mod idc;
+pub mod impair;
+pub mod mixer;
+pub mod network_utils;
+pub mod pcm;
+pub mod pipeline;
+pub mod plc;
+#[cfg(feature = "quic")]
+pub mod quic;
+pub mod recovery;
+pub mod ring;
 pub mod sinks;
 pub mod sources;
 
 /// Program to stream raw audio data between WASAPI devices and UDP sockets
-#[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "cli", derive(clap::Parser, serde::Deserialize))]
+#[cfg_attr(feature = "cli", command(version, about, long_about = None))]
+#[cfg_attr(
+    feature = "cli",
+    serde(default, rename_all = "kebab-case", deny_unknown_fields)
+)]
 pub struct Args {
     /// The source eg. "udp://0.0.0.0:1234" or "mic"
     /// WASAPI devices are found by looking at case-insensitive inclusion of provided name
+    #[cfg_attr(
+        feature = "cli",
+        arg(required_unless_present_any = ["config", "list_schemes"], default_value = "")
+    )]
     pub source: String,
 
     /// The sink eg. "udp://192.123.123.1:1234" or "speakers"
     /// WASAPI devices are found by looking at case-insensitive inclusion of provided name
+    #[cfg_attr(
+        feature = "cli",
+        arg(required_unless_present_any = ["config", "list_schemes"], default_value = "")
+    )]
     pub sink: String,
 
+    /// Also send to this sink, through a buffer of its own, eg. "file://recording.wav?overflow=block". Can be given more than once
+    #[cfg_attr(feature = "cli", arg(long, conflicts_with = "duplex"))]
+    pub tee: Vec<String>,
+
+    /// Also mix in this source, with a jitter buffer of its own, eg. "idc://0.0.0.0:5679?gain=-6". Can be given more than once
+    #[cfg_attr(feature = "cli", arg(long, conflicts_with = "duplex"))]
+    pub mix: Vec<String>,
+
+    /// Run the routes in this TOML file instead of a single source and sink
+    #[cfg_attr(feature = "cli", arg(long, conflicts_with_all = ["source", "sink"]))]
+    #[cfg_attr(feature = "cli", serde(skip))]
+    pub config: Option<PathBuf>,
+
+    /// Print every kind of source and sink with the params its url takes, and exit
+    #[cfg_attr(feature = "cli", arg(long))]
+    #[cfg_attr(feature = "cli", serde(skip))]
+    pub list_schemes: bool,
+
+    /// Capture from the source and play to the sink, both through one link with the peer at this url, eg. "idc-connect://192.168.1.2:5678"
+    #[cfg_attr(feature = "cli", arg(long, conflicts_with = "config"))]
+    pub duplex: Option<String>,
+
+    /// Turn the source down while the peer's audio plays, so that it doesn't echo back (duplex only)
+    #[cfg_attr(feature = "cli", arg(long, requires = "duplex"))]
+    pub echo_suppression: bool,
+
     /// Max internal buffer length
-    #[arg(short, long, default_value_t = 10000)]
+    #[cfg_attr(feature = "cli", arg(short, long, default_value_t = 10000))]
     pub buffer_limit: usize,
 
     /// UDP datagram size limit
-    #[arg(short, long, default_value_t = 5000)]
+    #[cfg_attr(feature = "cli", arg(short, long, default_value_t = 5000))]
     pub datagram_size: usize,
 
     /// Bits per sample
-    #[arg(long, default_value_t = 32)]
+    #[cfg_attr(feature = "cli", arg(long, default_value_t = 32))]
     pub bits_per_sample: usize,
 
     /// Sample rate
-    #[arg(short, long, default_value_t = 48000)]
+    #[cfg_attr(feature = "cli", arg(short, long, default_value_t = 48000))]
     pub sample_rate: usize,
 
     /// Channels
-    #[arg(short, long, default_value_t = 2)]
+    #[cfg_attr(feature = "cli", arg(short, long, default_value_t = 2))]
     pub channels: usize,
 
     /// Use floating-point samples
-    #[arg(long)]
+    #[cfg_attr(feature = "cli", arg(long))]
     pub use_float: bool,
 
     /// Check UDP packet order and loss
-    #[arg(long)]
+    #[cfg_attr(feature = "cli", arg(long))]
     pub counted_udp: bool,
 
-    /// Restart the sink and source completely if the buffer fills up
-    #[arg(long)]
+    /// How many out-of-order packets to hold while waiting for a missing one (counted UDP only)
+    #[cfg_attr(feature = "cli", arg(long, default_value_t = 4))]
+    pub reorder_window: usize,
+
+    /// What to play in place of lost packets (counted UDP only)
+    #[cfg_attr(feature = "cli", arg(long, value_enum, default_value_t = Concealment::Silence))]
+    pub concealment: Concealment,
+
+    /// Encrypt and authenticate network streams with this pre-shared key, 64 hex digits
+    #[cfg_attr(feature = "cli", arg(long, conflicts_with = "key_file"))]
+    pub psk: Option<String>,
+
+    /// Like --psk, but read the key from a file
+    #[cfg_attr(feature = "cli", arg(long))]
+    pub key_file: Option<PathBuf>,
+
+    /// Only accept UDP packets from these addresses, eg. "10.0.0.0/24,192.168.1.5"
+    #[cfg_attr(feature = "cli", arg(long, value_delimiter = ','))]
+    pub allow: Vec<Cidr>,
+
+    /// Only accept UDP packets from the first sender until it has been silent for this many milliseconds
+    #[cfg_attr(feature = "cli", arg(long))]
+    pub lock_peer: Option<u64>,
+
+    /// Send an idc or quic heartbeat after this many milliseconds without sending anything
+    #[cfg_attr(feature = "cli", arg(long, default_value_t = 1000))]
+    pub idc_keepalive: u64,
+
+    /// Drop an idc or quic connection after this many milliseconds without hearing from the peer
+    #[cfg_attr(feature = "cli", arg(long, default_value_t = 5000))]
+    pub idc_timeout: u64,
+
+    /// Drop whole idc frames once more than this many milliseconds of audio wait to be sent
+    #[cfg_attr(feature = "cli", arg(long, default_value_t = 200))]
+    pub idc_latency_budget: u64,
+
+    /// Milliseconds to wait before reconnecting idc after the first failed attempt
+    #[cfg_attr(feature = "cli", arg(long, default_value_t = 250))]
+    pub idc_reconnect_min: u64,
+
+    /// Never wait longer than this many milliseconds before reconnecting idc
+    #[cfg_attr(feature = "cli", arg(long, default_value_t = 10000))]
+    pub idc_reconnect_max: u64,
+
+    /// Multiply the idc reconnect delay by this after every failed attempt
+    #[cfg_attr(feature = "cli", arg(long, default_value_t = 2.0))]
+    pub idc_reconnect_factor: f64,
+
+    /// Make idc reconnect delays randomly up to this fraction shorter or longer
+    #[cfg_attr(feature = "cli", arg(long, default_value_t = 0.2))]
+    pub idc_reconnect_jitter: f64,
+
+    /// Exit after this many idc connection attempts in a row without a lasting connection
+    #[cfg_attr(feature = "cli", arg(long))]
+    pub idc_reconnect_attempts: Option<u32>,
+
+    /// PEM certificate chain the quic source presents and the quic sink trusts
+    #[cfg_attr(feature = "cli", arg(long))]
+    pub quic_cert: Option<PathBuf>,
+
+    /// PEM private key of the quic source's certificate
+    #[cfg_attr(feature = "cli", arg(long, requires = "quic_cert"))]
+    pub quic_key: Option<PathBuf>,
+
+    /// Name of the stream that http and icecast sinks tell players
+    #[cfg_attr(feature = "cli", arg(long, default_value = "stupid-audio-stream"))]
+    pub stream_name: String,
+
+    /// What to do when the source delivers faster than the sink takes
+    #[cfg_attr(feature = "cli", arg(long, value_enum, default_value_t = OverflowPolicy::Clear))]
+    pub overflow: OverflowPolicy,
+
+    /// What to give the sink when the source has nothing for it
+    #[cfg_attr(feature = "cli", arg(long, value_enum, default_value_t = UnderflowPolicy::Wait))]
+    pub underflow: UnderflowPolicy,
+
+    /// Restart a sink completely if its buffer fills up, and the source too if every sink's does
+    #[cfg_attr(feature = "cli", arg(long))]
     pub restart_on_buffer_filled: bool,
+
+    /// What a source or a sink does about network hiccups, like a refused datagram
+    #[cfg_attr(feature = "cli", arg(long, value_enum, default_value_t = Recovery::Ignore))]
+    pub on_transient: Recovery,
+
+    /// What a source or a sink does when its audio device goes away
+    #[cfg_attr(feature = "cli", arg(long, value_enum, default_value_t = Recovery::RestartPipeline))]
+    pub on_device_lost: Recovery,
+
+    /// What a source or a sink does about any other error
+    #[cfg_attr(feature = "cli", arg(long, value_enum, default_value_t = Recovery::Exit))]
+    pub on_fatal: Recovery,
+}
+
+/// Same as the command line defaults, with no source or sink
+impl Default for Args {
+    fn default() -> Self {
+        Self {
+            source: String::new(),
+            sink: String::new(),
+            tee: Vec::new(),
+            mix: Vec::new(),
+            config: None,
+            list_schemes: false,
+            duplex: None,
+            echo_suppression: false,
+            buffer_limit: 10000,
+            datagram_size: 5000,
+            bits_per_sample: 32,
+            sample_rate: 48000,
+            channels: 2,
+            use_float: false,
+            counted_udp: false,
+            reorder_window: 4,
+            concealment: Concealment::Silence,
+            psk: None,
+            key_file: None,
+            allow: Vec::new(),
+            lock_peer: None,
+            idc_keepalive: 1000,
+            idc_timeout: 5000,
+            idc_latency_budget: 200,
+            idc_reconnect_min: 250,
+            idc_reconnect_max: 10000,
+            idc_reconnect_factor: 2.0,
+            idc_reconnect_jitter: 0.2,
+            idc_reconnect_attempts: None,
+            quic_cert: None,
+            quic_key: None,
+            stream_name: "stupid-audio-stream".to_owned(),
+            overflow: OverflowPolicy::Clear,
+            underflow: UnderflowPolicy::Wait,
+            restart_on_buffer_filled: false,
+            on_transient: Recovery::Ignore,
+            on_device_lost: Recovery::RestartPipeline,
+            on_fatal: Recovery::Exit,
+        }
+    }
 }
 
 pub trait Restart {
     fn restart(&mut self) -> Result<()>;
 }
 
+#[cfg(feature = "tokio")]
+pub trait AsyncRestart {
+    fn restart(&mut self) -> impl Future<Output = Result<()>> + Send;
+}
+
 pub trait SendAudioRestart: SendAudio + Restart {}
 impl<T: SendAudio + Restart> SendAudioRestart for T {}
 
@@ -63,3 +268,13 @@ pub trait RecvAudioRestart: RecvAudio + Restart {}
 impl<T: RecvAudio + Restart> RecvAudioRestart for T {}
 
 pub const HYPOT_AUDIO_ALIGNMENT: usize = 128; // TODO: use real nBlockAlign
+
+/// Uniform in 0..1, splitmix64 so that runs are reproducible from the seed
+pub(crate) fn random(state: &mut u64) -> f64 {
+    *state = state.wrapping_add(0x9E3779B97F4A7C15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^= z >> 31;
+    (z >> 11) as f64 / (1u64 << 53) as f64
+}
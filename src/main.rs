@@ -1,52 +1,87 @@
-use std::collections::VecDeque;
+use std::{
+    process::ExitCode,
+    sync::atomic::{AtomicBool, Ordering},
+};
 
 use clap::Parser;
-use stupid_audio_stream::{Args, HYPOT_AUDIO_ALIGNMENT, sinks, sources};
-use wasapi::initialize_mta;
+use stupid_audio_stream::{
+    Args,
+    config::{Config, Route, RouteHandle},
+    sinks, sources,
+};
 
 use anyhow::{Result, anyhow};
-use log::warn;
+use log::{error, info, warn};
 use simplelog::{self, SimpleLogger};
 
-fn main() -> Result<()> {
-    SimpleLogger::init(
-        simplelog::LevelFilter::Debug,
-        simplelog::ConfigBuilder::new()
-            .set_time_format_rfc3339()
-            .set_time_offset_to_local()
-            .unwrap()
-            .build(),
-    )?;
+/// What a second Ctrl-C exits with, like a shell does for SIGINT
+const EXIT_INTERRUPTED: u8 = 130;
 
-    initialize_mta().unwrap();
+/// Stops whatever runs on Ctrl-C or SIGTERM, so that sinks get to finish
+/// properly. A second one exits right away
+fn on_shutdown(stop: impl Fn() + Send + 'static) -> Result<()> {
+    let requested = AtomicBool::new(false);
+    ctrlc::set_handler(move || {
+        if requested.swap(true, Ordering::AcqRel) {
+            warn!("Exiting without cleaning up");
+            std::process::exit(EXIT_INTERRUPTED.into());
+        }
+        info!("Shutting down, do that again to exit right away");
+        stop();
+    })
+    .map_err(|err| anyhow!("Couldn't handle Ctrl-C: {err}"))
+}
+
+fn run(args: Args) -> Result<()> {
+    if let Some(path) = &args.config {
+        let routes = Config::load(path)?.spawn()?;
+        let handle = routes.handle();
+        on_shutdown(move || handle.stop())?;
+        return routes.join();
+    }
+    // Restarts like a route of a config would when a source or a sink asks for that
+    let name = match &args.duplex {
+        Some(peer) => format!("duplex link with {peer}"),
+        None => format!("{} to {}", args.source, args.sink),
+    };
+    let route = Route { name, args };
+    let handle = RouteHandle::default();
+    let stopper = handle.clone();
+    on_shutdown(move || stopper.stop())?;
+    route.run(&handle)
+}
 
+/// Exits with 0 once stopped, 1 if anything failed and 2 for a bad command line
+fn main() -> ExitCode {
     let args = Args::parse();
+    if args.list_schemes {
+        print!(
+            "Sources:\n{}\nSinks:\n{}",
+            sources::registry().help(),
+            sinks::registry().help()
+        );
+        return ExitCode::SUCCESS;
+    }
 
-    if args.buffer_limit < HYPOT_AUDIO_ALIGNMENT * 2 {
-        return Err(anyhow!(
-            "Buffer limit must be at least {}",
-            HYPOT_AUDIO_ALIGNMENT * 2
-        ));
+    let mut log_config = simplelog::ConfigBuilder::new();
+    log_config.set_time_format_rfc3339();
+    log_config.set_time_offset_to_local().unwrap();
+    if args.config.is_some() || args.duplex.is_some() {
+        // Threads are named after their routes or directions
+        log_config
+            .set_thread_level(simplelog::LevelFilter::Error)
+            .set_thread_mode(simplelog::ThreadLogMode::Names);
     }
-    let mut source = sources::from_args(&args)?;
-    let mut sink = sinks::from_args(&args)?;
-
-    let mut deq = VecDeque::new();
-
-    loop {
-        source.recv_to_deque(&mut deq)?;
-        sink.send_from_deque(&mut deq)?;
-        if deq.len() > args.buffer_limit {
-            if args.restart_on_buffer_filled {
-                source.restart()?;
-                sink.restart()?;
-                deq.clear();
-                warn!("Buffer too full, restarting source and sink.");
-            } else {
-                let n_blocks = deq.len() / HYPOT_AUDIO_ALIGNMENT;
-                deq.drain(0..(n_blocks * HYPOT_AUDIO_ALIGNMENT));
-                warn!("Buffer too full, clearing.");
-            }
+    SimpleLogger::init(simplelog::LevelFilter::Debug, log_config.build()).unwrap();
+
+    match run(args) {
+        Ok(()) => {
+            info!("Stopped");
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            error!("{err:#}");
+            ExitCode::FAILURE
         }
     }
 }
@@ -0,0 +1,493 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+        mpsc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, anyhow, bail};
+use log::{debug, error, info, warn};
+use wasapi::initialize_mta;
+
+use crate::{
+    Args, RecvAudioRestart, Restart,
+    idc::ReconnectPolicy,
+    pcm::PcmFormat,
+    recovery::{Recovered, Recoverer, Recovery, RecoveryPolicy},
+    sources::RecvAudio,
+};
+
+/// How often the mixer hands out a chunk
+const MIX_INTERVAL: Duration = Duration::from_millis(5);
+/// Stalls longer than this aren't made up for, the mix skips ahead instead
+const MAX_CATCH_UP: Duration = Duration::from_millis(50);
+/// Peaks of the mix are held under this, about -1 dBFS
+const LIMIT: f32 = 0.9;
+/// How long the limiter takes to let go after a peak
+const RELEASE: Duration = Duration::from_millis(100);
+/// Prebuffer of a source unless its url says otherwise
+pub const DEFAULT_PREBUFFER: Duration = Duration::from_millis(40);
+
+pub type OpenSource = Box<dyn FnOnce(&Args) -> Result<Box<dyn RecvAudioRestart>> + Send>;
+
+/// A source of the mix and how loud it is
+pub struct MixInput {
+    /// Shows up in the log, like "mix 1"
+    pub name: String,
+    /// Called on the input's own thread
+    pub open: OpenSource,
+    /// Linear, 1 leaves the source as it is
+    pub gain: f32,
+    /// Audio buffered before the source joins the mix, to ride out jitter
+    pub prebuffer: Duration,
+    /// Restarts on any error by default, the mix goes on without the source meanwhile
+    pub recovery: RecoveryPolicy,
+}
+
+impl MixInput {
+    pub fn new(name: impl Into<String>, open: OpenSource) -> Self {
+        Self {
+            name: name.into(),
+            open,
+            gain: 1.0,
+            prebuffer: DEFAULT_PREBUFFER,
+            recovery: RecoveryPolicy::uniform(Recovery::Restart),
+        }
+    }
+}
+
+/// Gain in dB, like -6, to the factor [`MixInput::gain`] takes
+pub fn db_to_gain(db: f32) -> Result<f32> {
+    if !db.is_finite() {
+        bail!("Gain must be a number of dB, got {db}");
+    }
+    Ok(10f32.powf(db / 20.0))
+}
+
+/// An input's end of the mix
+struct Lane {
+    name: String,
+    gain: f32,
+    /// Frames buffered before joining the mix
+    target: usize,
+    /// Interleaved samples from the input's thread
+    queue: Arc<Mutex<VecDeque<f32>>>,
+    playing: bool,
+}
+
+/// Sums several sources, each read on its own thread and buffered against
+/// jitter. A source that runs dry drops out of the mix until it has buffered
+/// enough again. A limiter keeps the sum from clipping
+pub struct Mixer {
+    format: PcmFormat,
+    lanes: Vec<Lane>,
+    stop: Arc<AtomicBool>,
+    /// What an input's policy says should end the mix
+    failure: Arc<Mutex<Option<anyhow::Error>>>,
+    started: Instant,
+    /// Frames handed out since `started`
+    produced: u64,
+    limiter_gain: f32,
+    /// How much of the way back to 1 the limiter gain goes each frame
+    release: f32,
+    mix: Vec<f32>,
+}
+
+impl Mixer {
+    /// Opens every input, fails if one that can't be opened isn't allowed to go
+    /// without. `thread_prefix` goes in front of the input threads' names
+    pub fn new(inputs: Vec<MixInput>, args: &Args, thread_prefix: Option<&str>) -> Result<Self> {
+        let format = PcmFormat::from_args(args)?;
+        let reconnect = ReconnectPolicy::from_args(args)?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let failure = Arc::new(Mutex::new(None));
+        let max_catch_up = (format.sample_rate as f64 * MAX_CATCH_UP.as_secs_f64()) as usize;
+        let mut lanes = Vec::new();
+        let mut opening = Vec::new();
+        for input in inputs {
+            let queue = Arc::new(Mutex::new(VecDeque::new()));
+            let (opened, open_result) = mpsc::channel();
+            let thread_name = match thread_prefix {
+                Some(prefix) => format!("{prefix} {}", input.name),
+                None => input.name.clone(),
+            };
+            let target = (format.sample_rate as f64 * input.prebuffer.as_secs_f64()) as usize;
+            // The mix would skip anything past this anyway
+            let max_samples = (target * 2 + max_catch_up) * format.channels;
+            let input_args = args.clone();
+            let input_queue = queue.clone();
+            let input_stop = stop.clone();
+            let input_failure = failure.clone();
+            let recoverer = Recoverer::new(input.name.clone(), input.recovery, reconnect);
+            let open = input.open;
+            thread::Builder::new().name(thread_name).spawn(move || {
+                let result = run_input(
+                    &input_args,
+                    recoverer,
+                    open,
+                    &input_queue,
+                    max_samples,
+                    &input_stop,
+                    opened,
+                );
+                if let Err(err) = result {
+                    input_failure.lock().unwrap().get_or_insert(err);
+                }
+            })?;
+            opening.push((input.name.clone(), open_result));
+            lanes.push(Lane {
+                name: input.name,
+                gain: input.gain,
+                target,
+                queue,
+                playing: false,
+            });
+        }
+        for (name, open_result) in opening {
+            let result = open_result
+                .recv()
+                .map_err(|err| anyhow!("The {name} thread vanished: {err}"))
+                .and_then(|result| result);
+            if let Err(err) = result {
+                stop.store(true, Ordering::Release);
+                return Err(err.context(format!("Couldn't open the {name}")));
+            }
+        }
+        info!("Mixing {} sources with a limiter at {LIMIT}", lanes.len());
+        Ok(Self {
+            release: 1.0 - (-1.0 / (RELEASE.as_secs_f32() * format.sample_rate as f32)).exp(),
+            format,
+            lanes,
+            stop,
+            failure,
+            started: Instant::now(),
+            produced: 0,
+            limiter_gain: 1.0,
+            mix: Vec::new(),
+        })
+    }
+
+    /// Adds up to `n_frames` of every playing lane to the mix
+    fn mix_lanes(&mut self, n_frames: usize) {
+        let channels = self.format.channels;
+        self.mix.clear();
+        self.mix.resize(n_frames * channels, 0.0);
+        for lane in &mut self.lanes {
+            let mut queue = lane.queue.lock().unwrap();
+            let available = queue.len() / channels;
+            if !lane.playing {
+                // Only the newest audio joins, so the delay stays at the target
+                let n_kept = usize::max(lane.target, n_frames);
+                if available < n_kept {
+                    continue;
+                }
+                queue.drain(..(available - n_kept) * channels);
+                info!("The {} joined the mix", lane.name);
+                lane.playing = true;
+            } else if available > lane.target * 2 + n_frames {
+                // The source's clock runs ahead of ours
+                let n_skipped = available - lane.target - n_frames;
+                debug!("Skipping {n_skipped} frames of the {}", lane.name);
+                queue.drain(..n_skipped * channels);
+            }
+
+            let n_samples = usize::min(queue.len() / channels, n_frames) * channels;
+            for (mixed, sample) in self.mix.iter_mut().zip(queue.drain(..n_samples)) {
+                *mixed += sample * lane.gain;
+            }
+            if n_samples < n_frames * channels {
+                info!("The {} ran dry, dropping it from the mix", lane.name);
+                lane.playing = false;
+            }
+        }
+    }
+
+    /// Brings peaks down to the limit right away and lets go slowly
+    fn limit(&mut self) {
+        for frame in self.mix.chunks_exact_mut(self.format.channels) {
+            let peak = frame
+                .iter()
+                .fold(0f32, |peak, sample| peak.max(sample.abs()));
+            if peak * self.limiter_gain > LIMIT {
+                self.limiter_gain = LIMIT / peak;
+            }
+            for sample in frame {
+                *sample *= self.limiter_gain;
+            }
+            self.limiter_gain += (1.0 - self.limiter_gain) * self.release;
+        }
+    }
+}
+
+impl RecvAudio for Mixer {
+    fn recv_to_deque(&mut self, buf: &mut VecDeque<u8>) -> Result<()> {
+        if let Some(err) = self.failure.lock().unwrap().take() {
+            return Err(err);
+        }
+        thread::sleep(MIX_INTERVAL);
+        let sample_rate = self.format.sample_rate as f64;
+        let due = (self.started.elapsed().as_secs_f64() * sample_rate) as u64;
+        let max_frames = (MAX_CATCH_UP.as_secs_f64() * sample_rate) as u64;
+        let n_frames = due.saturating_sub(self.produced).min(max_frames) as usize;
+        self.produced = due;
+
+        self.mix_lanes(n_frames);
+        self.limit();
+        self.format.encode(&self.mix, buf);
+        Ok(())
+    }
+}
+
+/// Starts every source over, each joins again once it has buffered enough
+impl Restart for Mixer {
+    fn restart(&mut self) -> Result<()> {
+        for lane in &mut self.lanes {
+            lane.queue.lock().unwrap().clear();
+            lane.playing = false;
+        }
+        self.started = Instant::now();
+        self.produced = 0;
+        self.limiter_gain = 1.0;
+        Ok(())
+    }
+}
+
+/// Input threads stuck in a blocking call are left to finish on their own
+impl Drop for Mixer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+    }
+}
+
+/// Reads one source into its lane's queue until the mixer is gone or the
+/// source's policy gives up on it, the mix goes on without it meanwhile. Only
+/// the newest `max_samples` are kept while the mixer isn't taking any. Fails
+/// with what should end the whole mix
+fn run_input(
+    args: &Args,
+    mut recoverer: Recoverer,
+    open: OpenSource,
+    queue: &Mutex<VecDeque<f32>>,
+    max_samples: usize,
+    stop: &AtomicBool,
+    opened: mpsc::Sender<Result<()>>,
+) -> Result<()> {
+    initialize_mta().unwrap();
+    let name = recoverer.name().to_owned();
+    let mut source = match open(args) {
+        Ok(source) => source,
+        Err(err) if recoverer.can_go_without(&err) => {
+            error!("Couldn't open the {name}, mixing without it: {err:#}");
+            let _ = opened.send(Ok(()));
+            return Ok(());
+        }
+        Err(err) => {
+            let _ = opened.send(Err(recoverer.open_failed(err)));
+            return Ok(());
+        }
+    };
+    let _ = opened.send(Ok(()));
+    // Checked by the mixer already
+    let format = PcmFormat::from_args(args).unwrap();
+    let block_align = format.block_align();
+
+    let mut deq = VecDeque::new();
+    let mut samples = Vec::new();
+    while !stop.load(Ordering::Acquire) {
+        if let Err(err) = source.recv_to_deque(&mut deq) {
+            match recoverer.recover(err, stop) {
+                Recovered::Ignored => {}
+                Recovered::Restart => {
+                    deq.clear();
+                    if let Err(err) = source.restart() {
+                        warn!("Couldn't restart the {name}: {err:#}");
+                    }
+                }
+                Recovered::GaveUp(err) => {
+                    error!("The {name} failed, mixing without it: {err:#}");
+                    return Ok(());
+                }
+                Recovered::Escalate(err) => return Err(err),
+            }
+            continue;
+        }
+        recoverer.succeeded();
+
+        let n_whole = deq.len() - deq.len() % block_align;
+        let bytes: Vec<u8> = deq.drain(..n_whole).collect();
+        samples.clear();
+        format.decode(&bytes, &mut samples);
+        let mut queue = queue.lock().unwrap();
+        queue.extend(&samples);
+        if queue.len() > max_samples {
+            let n_dropped = queue.len() - max_samples;
+            queue.drain(..n_dropped);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: usize = 48000;
+
+    /// A mono mixer over lanes of these gains and targets, without input threads,
+    /// with the queues to feed the lanes through
+    fn mixer(lanes: &[(f32, usize)]) -> (Mixer, Vec<Arc<Mutex<VecDeque<f32>>>>) {
+        let format = PcmFormat::new(32, true, SAMPLE_RATE, 1).unwrap();
+        let queues: Vec<_> = lanes.iter().map(|_| Arc::default()).collect();
+        let lanes = lanes
+            .iter()
+            .zip(&queues)
+            .enumerate()
+            .map(|(i, (&(gain, target), queue))| Lane {
+                name: format!("mix {i}"),
+                gain,
+                target,
+                queue: Arc::clone(queue),
+                playing: false,
+            })
+            .collect();
+        let mixer = Mixer {
+            release: 1.0 - (-1.0 / (RELEASE.as_secs_f32() * SAMPLE_RATE as f32)).exp(),
+            format,
+            lanes,
+            stop: Arc::default(),
+            failure: Arc::default(),
+            started: Instant::now(),
+            produced: 0,
+            limiter_gain: 1.0,
+            mix: Vec::new(),
+        };
+        (mixer, queues)
+    }
+
+    fn feed(queue: &Mutex<VecDeque<f32>>, value: f32, n_frames: usize) {
+        queue
+            .lock()
+            .unwrap()
+            .extend(std::iter::repeat_n(value, n_frames));
+    }
+
+    #[test]
+    fn lanes_add_up_with_their_gains() {
+        let (mut mixer, queues) = mixer(&[(1.0, 0), (0.5, 0)]);
+        feed(&queues[0], 0.25, 10);
+        feed(&queues[1], -0.25, 10);
+        mixer.mix_lanes(10);
+        assert_eq!(mixer.mix, [0.125; 10]);
+        assert!(mixer.lanes.iter().all(|lane| lane.playing));
+    }
+
+    #[test]
+    fn lane_joins_with_its_newest_audio_once_buffered() {
+        let (mut mixer, queues) = mixer(&[(1.0, 10)]);
+        feed(&queues[0], 0.1, 8);
+        mixer.mix_lanes(5);
+        assert!(!mixer.lanes[0].playing);
+        assert_eq!(mixer.mix, [0.0; 5]);
+
+        // Only the target's worth stays to be played, the rest is older
+        feed(&queues[0], 0.2, 12);
+        mixer.mix_lanes(5);
+        assert!(mixer.lanes[0].playing);
+        assert_eq!(mixer.mix, [0.2; 5]);
+        assert_eq!(queues[0].lock().unwrap().len(), 5);
+    }
+
+    #[test]
+    fn dry_lane_drops_out_and_rejoins_while_the_others_play_on() {
+        let (mut mixer, queues) = mixer(&[(1.0, 0), (1.0, 10)]);
+        // The first lane keeps up, just in time
+        let mut mix = |n_frames| {
+            feed(&queues[0], 0.1, n_frames);
+            mixer.mix_lanes(n_frames);
+            (
+                mixer.mix.clone(),
+                mixer
+                    .lanes
+                    .iter()
+                    .map(|lane| lane.playing)
+                    .collect::<Vec<_>>(),
+            )
+        };
+        feed(&queues[1], 0.2, 10);
+        assert_eq!(mix(5), (vec![0.3; 5], vec![true, true]));
+
+        // What's left of the second lane plays out, then it drops out
+        let (mixed, playing) = mix(10);
+        assert_eq!(mixed[..5], [0.3; 5]);
+        assert_eq!(mixed[5..], [0.1; 5]);
+        assert_eq!(playing, [true, false]);
+
+        // Not enough to join again yet
+        feed(&queues[1], 0.2, 5);
+        assert_eq!(mix(5), (vec![0.1; 5], vec![true, false]));
+
+        feed(&queues[1], 0.2, 5);
+        assert_eq!(mix(5), (vec![0.3; 5], vec![true, true]));
+    }
+
+    #[test]
+    fn lane_running_ahead_skips_back_to_its_target() {
+        let (mut mixer, queues) = mixer(&[(1.0, 10)]);
+        feed(&queues[0], 0.1, 10);
+        mixer.mix_lanes(5);
+        // The source's clock is faster, so audio piles up
+        feed(&queues[0], 0.2, 100);
+        mixer.mix_lanes(5);
+        assert_eq!(mixer.mix, [0.2; 5]);
+        assert_eq!(queues[0].lock().unwrap().len(), 10);
+    }
+
+    #[test]
+    fn limiter_leaves_quiet_audio_alone() {
+        let (mut mixer, _) = mixer(&[]);
+        mixer.mix = vec![0.5, -0.8, LIMIT, -LIMIT];
+        mixer.limit();
+        assert_eq!(mixer.mix, [0.5, -0.8, LIMIT, -LIMIT]);
+    }
+
+    #[test]
+    fn limiter_catches_peaks_at_once_and_lets_go_slowly() {
+        let (mut mixer, _) = mixer(&[]);
+        let release_frames = (RELEASE.as_secs_f32() * SAMPLE_RATE as f32) as usize;
+        mixer.mix = vec![0.5; 1 + release_frames * 5];
+        mixer.mix[0] = -1.8;
+        mixer.limit();
+        assert!((mixer.mix[0] + LIMIT).abs() < 1e-6, "{}", mixer.mix[0]);
+        // Right after the peak the rest is still turned down
+        assert!(mixer.mix[1] < 0.26, "{}", mixer.mix[1]);
+        // Halfway back after about 0.7 release times
+        let halfway = mixer.mix.iter().position(|&sample| sample > 0.375).unwrap();
+        let expected = (std::f32::consts::LN_2 * release_frames as f32) as usize;
+        assert!(halfway.abs_diff(expected) < 10, "{halfway} vs {expected}");
+        // And all the way back after a few
+        assert!(mixer.mix.last().unwrap() > &0.496);
+        assert!(mixer.mix.iter().all(|sample| sample.abs() <= LIMIT + 1e-6));
+    }
+
+    #[test]
+    fn limiter_holds_every_channel_of_a_frame_alike() {
+        let (mut mixer, _) = mixer(&[]);
+        mixer.format = PcmFormat::new(32, true, SAMPLE_RATE, 2).unwrap();
+        mixer.mix = vec![1.8, 0.4];
+        mixer.limit();
+        assert!((mixer.mix[0] - LIMIT).abs() < 1e-6);
+        assert!((mixer.mix[1] - 0.2).abs() < 1e-6);
+    }
+
+    #[test]
+    fn gains_in_db() {
+        assert!((db_to_gain(0.0).unwrap() - 1.0).abs() < 1e-6);
+        assert!((db_to_gain(-6.0).unwrap() - 0.501).abs() < 1e-3);
+        assert!((db_to_gain(20.0).unwrap() - 10.0).abs() < 1e-4);
+        assert!(db_to_gain(f32::NAN).is_err());
+    }
+}
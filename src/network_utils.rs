@@ -0,0 +1,516 @@
+use std::{
+    collections::VecDeque,
+    io,
+    net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket},
+    str::FromStr,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail};
+use log::{debug, info, warn};
+
+/// How often a listener waiting for a connection checks for one
+const ACCEPT_POLL: Duration = Duration::from_millis(10);
+
+/// TCP socket of the right family for `address`
+pub fn tcp_socket(address: &socket2::SockAddr) -> io::Result<socket2::Socket> {
+    socket2::Socket::new(
+        if address.is_ipv4() {
+            socket2::Domain::IPV4
+        } else {
+            socket2::Domain::IPV6
+        },
+        socket2::Type::STREAM,
+        Some(socket2::Protocol::TCP),
+    )
+}
+
+/// TCP socket listening on `address`. Can bind again right after an earlier
+/// one let go of it, like when a pipeline is built again
+pub fn tcp_listener(address: &socket2::SockAddr, backlog: i32) -> io::Result<socket2::Socket> {
+    let listener = tcp_socket(address)?;
+    // On Windows that would let two listeners share the port instead, and
+    // there connections left behind don't hold on to it anyway
+    #[cfg(not(windows))]
+    listener.set_reuse_address(true)?;
+    listener.bind(address)?;
+    listener.listen(backlog)?;
+    Ok(listener)
+}
+
+/// Accepts on a non-blocking `listener`, giving up after `timeout`
+pub fn accept_timeout(
+    listener: &socket2::Socket,
+    timeout: Duration,
+) -> io::Result<Option<(socket2::Socket, socket2::SockAddr)>> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        match listener.accept() {
+            Ok(accepted) => return Ok(Some(accepted)),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                if Instant::now() >= deadline {
+                    return Ok(None);
+                }
+                thread::sleep(ACCEPT_POLL);
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// UDP socket on an ephemeral port that sends to `address`
+pub fn connected_udp_socket(address: impl ToSocketAddrs) -> io::Result<UdpSocket> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.connect(address)?;
+    Ok(socket)
+}
+
+/// Whatever the UDP packs send datagrams through, so that things can be put
+/// between them and the network
+pub trait DatagramSocket {
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize>;
+
+    fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+
+    fn read_timeout(&self) -> io::Result<Option<Duration>>;
+
+    /// Makes `recv_from` fail with `WouldBlock` or `TimedOut` once nothing
+    /// arrived for `timeout`, or wait forever with `None`
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()>;
+
+    /// Recreates the socket, connected to the same peer if it was connected and
+    /// bound to the same address otherwise
+    fn reopen(&mut self) -> io::Result<()>;
+}
+
+impl DatagramSocket for UdpSocket {
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        UdpSocket::send(self, buf)
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        UdpSocket::recv_from(self, buf)
+    }
+
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        UdpSocket::read_timeout(self)
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        UdpSocket::set_read_timeout(self, timeout)
+    }
+
+    fn reopen(&mut self) -> io::Result<()> {
+        if let Ok(remote_addr) = self.peer_addr() {
+            *self = connected_udp_socket(remote_addr)?;
+        } else {
+            let addr = self.local_addr()?;
+            *self = UdpSocket::bind(addr)?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: DatagramSocket + ?Sized> DatagramSocket for Box<T> {
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (**self).send(buf)
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        (**self).recv_from(buf)
+    }
+
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        (**self).read_timeout()
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        (**self).set_read_timeout(timeout)
+    }
+
+    fn reopen(&mut self) -> io::Result<()> {
+        (**self).reopen()
+    }
+}
+
+/// [`DatagramSocket`] for tokio, every method has to be cancel-safe
+#[cfg(feature = "tokio")]
+pub trait AsyncDatagramSocket {
+    fn send(&mut self, buf: &[u8]) -> impl Future<Output = io::Result<usize>> + Send;
+
+    fn recv_from(
+        &mut self,
+        buf: &mut [u8],
+    ) -> impl Future<Output = io::Result<(usize, SocketAddr)>> + Send;
+
+    fn reopen(&mut self) -> impl Future<Output = io::Result<()>> + Send;
+}
+
+/// Tokio UDP socket on an ephemeral port that sends to `address`
+#[cfg(feature = "tokio")]
+pub async fn async_connected_udp_socket(
+    address: impl tokio::net::ToSocketAddrs,
+) -> io::Result<tokio::net::UdpSocket> {
+    let socket = tokio::net::UdpSocket::bind("0.0.0.0:0").await?;
+    socket.connect(address).await?;
+    Ok(socket)
+}
+
+#[cfg(feature = "tokio")]
+impl AsyncDatagramSocket for tokio::net::UdpSocket {
+    async fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        tokio::net::UdpSocket::send(self, buf).await
+    }
+
+    async fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        tokio::net::UdpSocket::recv_from(self, buf).await
+    }
+
+    async fn reopen(&mut self) -> io::Result<()> {
+        if let Ok(remote_addr) = self.peer_addr() {
+            *self = async_connected_udp_socket(remote_addr).await?;
+        } else {
+            let addr = self.local_addr()?;
+            // The old socket has to let go of the address first
+            *self = tokio::net::UdpSocket::bind((addr.ip(), 0)).await?;
+            *self = tokio::net::UdpSocket::bind(addr).await?;
+        }
+        Ok(())
+    }
+}
+
+/// A block of addresses like `10.0.0.0/24`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    pub address: IpAddr,
+    pub prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.address, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix_len) = match s.split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (s, None),
+        };
+        let address: IpAddr = address
+            .parse()
+            .map_err(|err| anyhow!("Bad address in {s:?}: {err}"))?;
+        let max_len = if address.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse()
+                .map_err(|err| anyhow!("Bad prefix length in {s:?}: {err}"))?,
+            None => max_len,
+        };
+        if prefix_len > max_len {
+            bail!("Prefix length in {s:?} is longer than {max_len}");
+        }
+        Ok(Self {
+            address,
+            prefix_len,
+        })
+    }
+}
+
+/// Parsed from a string, like on the command line
+#[cfg(feature = "cli")]
+impl<'de> serde::Deserialize<'de> for Cidr {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Decides which senders a source listens to
+#[derive(Debug, Default)]
+pub struct PeerFilter {
+    /// Senders must be in one of these, anyone goes if it's empty
+    pub allowed: Vec<Cidr>,
+    /// Stick to the first sender until it's been silent this long
+    pub lock_timeout: Option<Duration>,
+    locked: Option<(SocketAddr, Instant)>,
+    pub rejected: u64,
+}
+
+impl PeerFilter {
+    pub fn new(allowed: Vec<Cidr>, lock_timeout: Option<Duration>) -> Self {
+        Self {
+            allowed,
+            lock_timeout,
+            ..Default::default()
+        }
+    }
+
+    pub fn accept(&mut self, addr: SocketAddr) -> bool {
+        if !self.allowed.is_empty() && !self.allowed.iter().any(|cidr| cidr.contains(addr.ip())) {
+            self.reject(addr, "it's not allowed");
+            return false;
+        }
+        if let Some(timeout) = self.lock_timeout {
+            match self.locked {
+                Some((peer, last_seen)) if peer != addr && last_seen.elapsed() < timeout => {
+                    self.reject(addr, "locked to another peer");
+                    return false;
+                }
+                Some((peer, _)) if peer != addr => {
+                    info!("{peer} went silent, locking to {addr}");
+                }
+                None => info!("Locking to {addr}"),
+                _ => {}
+            }
+            self.locked = Some((addr, Instant::now()));
+        }
+        true
+    }
+
+    fn reject(&mut self, addr: SocketAddr, reason: &str) {
+        self.rejected += 1;
+        // Don't flood the log if someone keeps sending
+        if self.rejected.is_power_of_two() {
+            warn!(
+                "Ignoring packet from {addr} since {reason}, {} ignored so far",
+                self.rejected
+            );
+        } else {
+            debug!("Ignoring packet from {addr} since {reason}");
+        }
+    }
+}
+
+/// Datagram socket that drops whatever its [`PeerFilter`] rejects
+pub struct FilteredSocket<S> {
+    inner: S,
+    pub filter: PeerFilter,
+}
+
+impl<S: DatagramSocket> FilteredSocket<S> {
+    pub fn new(inner: S, filter: PeerFilter) -> Self {
+        Self { inner, filter }
+    }
+}
+
+impl<S: DatagramSocket> DatagramSocket for FilteredSocket<S> {
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.send(buf)
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        loop {
+            let (n_read, addr) = self.inner.recv_from(buf)?;
+            if self.filter.accept(addr) {
+                return Ok((n_read, addr));
+            }
+        }
+    }
+
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        self.inner.read_timeout()
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    fn reopen(&mut self) -> io::Result<()> {
+        self.inner.reopen()
+    }
+}
+
+/// Stop sending to a stream client that hasn't taken any data for this long
+pub const CLIENT_STALL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Chunks waiting to be written to one client of a nonblocking stream socket
+pub struct ClientQueue {
+    /// With whether the chunk has to go out no matter how far behind the client is
+    queue: VecDeque<(Arc<[u8]>, bool)>,
+    queued_bytes: usize,
+    /// How much of the first chunk in the queue is already sent
+    sent: usize,
+    last_progress: Instant,
+}
+
+impl Default for ClientQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ClientQueue {
+    pub fn new() -> Self {
+        Self {
+            queue: VecDeque::new(),
+            queued_bytes: 0,
+            sent: 0,
+            last_progress: Instant::now(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    pub fn queued_bytes(&self) -> usize {
+        self.queued_bytes
+    }
+
+    /// Queues `chunk`, skipping whole unsent chunks if that's more than `limit` bytes.
+    /// Returns how many bytes were skipped
+    pub fn push(&mut self, chunk: Arc<[u8]>, limit: usize) -> usize {
+        self.queued_bytes += chunk.len();
+        self.queue.push_back((chunk, false));
+        // A partially sent chunk has to go out whole, or the stream breaks
+        let mut index = usize::from(self.sent > 0);
+        let mut n_skipped = 0;
+        while self.queued_bytes > limit && index + 1 < self.queue.len() {
+            if self.queue[index].1 {
+                index += 1;
+                continue;
+            }
+            let (skipped, _) = self.queue.remove(index).unwrap();
+            self.queued_bytes -= skipped.len();
+            n_skipped += skipped.len();
+        }
+        n_skipped
+    }
+
+    /// Queues `chunk` so that it's never skipped, like a header or a control
+    /// message the client can't do without
+    pub fn push_pinned(&mut self, chunk: Arc<[u8]>) {
+        self.queued_bytes += chunk.len();
+        self.queue.push_back((chunk, true));
+    }
+
+    /// Writes as much as the socket takes right now, returns how many bytes that was
+    pub fn flush(&mut self, mut socket: impl io::Write) -> io::Result<usize> {
+        let mut n_flushed = 0;
+        while let Some((chunk, _)) = self.queue.front() {
+            match socket.write(&chunk[self.sent..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n_written) => {
+                    n_flushed += n_written;
+                    self.sent += n_written;
+                    self.last_progress = Instant::now();
+                    if self.sent == chunk.len() {
+                        self.queued_bytes -= chunk.len();
+                        self.queue.pop_front();
+                        self.sent = 0;
+                    }
+                }
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => return Ok(n_flushed),
+                Err(error) => return Err(error),
+            }
+        }
+        self.last_progress = Instant::now();
+        Ok(n_flushed)
+    }
+
+    /// Whether there's data waiting that the client hasn't taken any of for `timeout`
+    pub fn stalled(&self, timeout: Duration) -> bool {
+        !self.queue.is_empty() && self.last_progress.elapsed() >= timeout
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_blocks_of_either_family() {
+        assert_eq!(
+            cidr("10.0.0.0/24"),
+            Cidr {
+                address: ip("10.0.0.0"),
+                prefix_len: 24
+            }
+        );
+        assert_eq!(
+            cidr("fd00::/8"),
+            Cidr {
+                address: ip("fd00::"),
+                prefix_len: 8
+            }
+        );
+    }
+
+    #[test]
+    fn bare_address_is_a_block_of_one() {
+        assert_eq!(cidr("192.168.1.7").prefix_len, 32);
+        assert_eq!(cidr("::1").prefix_len, 128);
+        assert!(cidr("192.168.1.7").contains(ip("192.168.1.7")));
+        assert!(!cidr("192.168.1.7").contains(ip("192.168.1.8")));
+    }
+
+    #[test]
+    fn bad_blocks_are_refused() {
+        for bad in [
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0.0/",
+            "10.0.0.0/-1",
+            "10.0.0/8",
+            "localhost",
+            "",
+        ] {
+            assert!(bad.parse::<Cidr>().is_err(), "{bad:?} was accepted");
+        }
+    }
+
+    #[test]
+    fn contains_up_to_the_prefix_boundary() {
+        let block = cidr("10.0.1.0/23");
+        assert!(block.contains(ip("10.0.0.0")));
+        assert!(block.contains(ip("10.0.1.255")));
+        assert!(!block.contains(ip("10.0.2.0")));
+        assert!(!block.contains(ip("9.255.255.255")));
+
+        let block = cidr("2001:db8::/33");
+        assert!(block.contains(ip("2001:db8:7fff:ffff::1")));
+        assert!(!block.contains(ip("2001:db8:8000::")));
+    }
+
+    #[test]
+    fn zero_prefix_takes_its_whole_family() {
+        assert!(cidr("0.0.0.0/0").contains(ip("203.0.113.9")));
+        assert!(!cidr("0.0.0.0/0").contains(ip("2001:db8::1")));
+        assert!(cidr("::/0").contains(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn mapped_v4_addresses_match_v4_blocks() {
+        assert!(cidr("10.0.0.0/8").contains(ip("::ffff:10.1.2.3")));
+        assert!(!cidr("10.0.0.0/8").contains(ip("::ffff:11.1.2.3")));
+    }
+}
@@ -0,0 +1,110 @@
+use anyhow::{Result, bail};
+
+use crate::Args;
+
+/// Layout of interleaved little-endian PCM, same as what goes to the WASAPI devices
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PcmFormat {
+    pub bits_per_sample: usize,
+    pub use_float: bool,
+    pub sample_rate: usize,
+    pub channels: usize,
+}
+
+impl PcmFormat {
+    pub fn new(
+        bits_per_sample: usize,
+        use_float: bool,
+        sample_rate: usize,
+        channels: usize,
+    ) -> Result<Self> {
+        match (use_float, bits_per_sample) {
+            (false, 8 | 16 | 24 | 32) | (true, 32 | 64) => {}
+            _ => bail!(
+                "Unsupported sample format: {bits_per_sample} bit {}",
+                if use_float { "float" } else { "int" }
+            ),
+        }
+        if channels == 0 || sample_rate == 0 {
+            bail!("Sample rate and channels must be positive");
+        }
+        Ok(Self {
+            bits_per_sample,
+            use_float,
+            sample_rate,
+            channels,
+        })
+    }
+
+    pub fn from_args(args: &Args) -> Result<Self> {
+        Self::new(
+            args.bits_per_sample,
+            args.use_float,
+            args.sample_rate,
+            args.channels,
+        )
+    }
+
+    pub fn bytes_per_sample(&self) -> usize {
+        self.bits_per_sample / 8
+    }
+
+    pub fn block_align(&self) -> usize {
+        self.bytes_per_sample() * self.channels
+    }
+
+    /// Decodes all whole samples of `bytes` to floats in -1..1, appending to `out`
+    pub fn decode(&self, bytes: &[u8], out: &mut Vec<f32>) {
+        let size = self.bytes_per_sample();
+        out.extend(
+            bytes
+                .chunks_exact(size)
+                .map(|s| match (self.use_float, size) {
+                    (true, 4) => f32::from_le_bytes(s.try_into().unwrap()),
+                    (true, _) => f64::from_le_bytes(s.try_into().unwrap()) as f32,
+                    (false, 1) => (s[0] as f32 - 128.0) / 128.0,
+                    (false, 2) => i16::from_le_bytes(s.try_into().unwrap()) as f32 / 32768.0,
+                    (false, 3) => i32::from_le_bytes([0, s[0], s[1], s[2]]) as f32 / 2147483648.0,
+                    (false, _) => i32::from_le_bytes(s.try_into().unwrap()) as f32 / 2147483648.0,
+                }),
+        );
+    }
+
+    /// Encodes floats in -1..1 (clipping the rest) into samples appended to `out`
+    pub fn encode(&self, samples: &[f32], out: &mut impl Extend<u8>) {
+        for &sample in samples {
+            let sample = sample.clamp(-1.0, 1.0);
+            match (self.use_float, self.bytes_per_sample()) {
+                (true, 4) => out.extend(sample.to_le_bytes()),
+                (true, _) => out.extend((sample as f64).to_le_bytes()),
+                (false, 1) => out.extend([(sample * 127.0 + 128.0) as u8]),
+                (false, 2) => out.extend(((sample * 32767.0) as i16).to_le_bytes()),
+                (false, 3) => out.extend(
+                    ((sample as f64 * 2147483647.0) as i32).to_le_bytes()[1..]
+                        .iter()
+                        .copied(),
+                ),
+                (false, _) => out.extend(((sample as f64 * 2147483647.0) as i32).to_le_bytes()),
+            }
+        }
+    }
+
+    /// Header of a WAV file with `data_len` bytes of audio, pass `u32::MAX` if it's unknown
+    pub fn wav_header(&self, data_len: u32) -> Vec<u8> {
+        let block_align = self.block_align();
+        let mut header = Vec::with_capacity(44);
+        header.extend(b"RIFF");
+        header.extend(data_len.saturating_add(36).to_le_bytes());
+        header.extend(b"WAVEfmt ");
+        header.extend(16u32.to_le_bytes());
+        header.extend(if self.use_float { 3u16 } else { 1 }.to_le_bytes());
+        header.extend((self.channels as u16).to_le_bytes());
+        header.extend((self.sample_rate as u32).to_le_bytes());
+        header.extend(((self.sample_rate * block_align) as u32).to_le_bytes());
+        header.extend((block_align as u16).to_le_bytes());
+        header.extend((self.bits_per_sample as u16).to_le_bytes());
+        header.extend(b"data");
+        header.extend(data_len.to_le_bytes());
+        header
+    }
+}
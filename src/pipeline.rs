@@ -0,0 +1,1036 @@
+use std::{
+    collections::VecDeque,
+    fmt,
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, anyhow, bail};
+use log::{debug, error, info, warn};
+
+use wasapi::initialize_mta;
+
+use crate::{
+    Args, HYPOT_AUDIO_ALIGNMENT, RecvAudioRestart, SendAudioRestart,
+    endpoint::{BUFFER, Endpoint, GAIN, OVERFLOW, PREBUFFER, Registry},
+    idc::{IdcTimeouts, ReconnectPolicy},
+    mixer::{self, MixInput, Mixer},
+    network_utils::Cidr,
+    pcm::PcmFormat,
+    plc::Concealment,
+    recovery::{Recovered, Recoverer, Recovery, RecoveryPolicy},
+    ring::{self, Consumer, Producer},
+    sinks, sources,
+};
+
+/// How long the sink waits for audio before it's called with nothing anyway,
+/// so that network sinks still accept clients and send heartbeats
+const UNDERFLOW_WAIT: Duration = Duration::from_millis(5);
+
+/// How long to back off when the sink didn't take anything
+const SINK_IDLE: Duration = Duration::from_millis(1);
+
+/// How long a stopped pipeline waits for its sinks to send what's left and close
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// What to do when the source delivers faster than the sink takes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum, serde::Deserialize))]
+#[cfg_attr(feature = "cli", serde(rename_all = "kebab-case"))]
+pub enum OverflowPolicy {
+    /// Throw away everything buffered and start over
+    #[default]
+    Clear,
+    /// Throw away the new audio that doesn't fit
+    DropNew,
+    /// Wait for the sink to make room, which stalls the source. A tee waits on
+    /// its own instead, keeping up to another buffer's worth of audio until it
+    /// catches up and dropping new audio past that
+    Block,
+}
+
+/// An overflow policy that doesn't exist
+#[derive(Debug)]
+pub struct UnknownOverflowPolicy(String);
+
+impl fmt::Display for UnknownOverflowPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "expected clear, drop-new or block, got {:?}", self.0)
+    }
+}
+
+impl std::error::Error for UnknownOverflowPolicy {}
+
+/// Same names as on the command line
+impl FromStr for OverflowPolicy {
+    type Err = UnknownOverflowPolicy;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "clear" => Ok(Self::Clear),
+            "drop-new" => Ok(Self::DropNew),
+            "block" => Ok(Self::Block),
+            _ => Err(UnknownOverflowPolicy(name.to_owned())),
+        }
+    }
+}
+
+/// What to give the sink when the source has nothing for it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum, serde::Deserialize))]
+#[cfg_attr(feature = "cli", serde(rename_all = "kebab-case"))]
+pub enum UnderflowPolicy {
+    /// Nothing, devices play silence on their own and network sinks send nothing
+    #[default]
+    Wait,
+    /// Silence, keeping the sink busy as if the source was there
+    Silence,
+}
+
+type SourceFactory = Box<dyn FnOnce(&Args) -> Result<Box<dyn RecvAudioRestart>> + Send>;
+/// Called again for a tee that couldn't be opened
+type SinkFactory = Box<dyn FnMut(&Args) -> Result<Box<dyn SendAudioRestart>> + Send>;
+
+/// Flags and counters shared by the pipeline threads and the handles
+#[derive(Default)]
+struct Control {
+    stop: AtomicBool,
+    paused: AtomicBool,
+    received: AtomicU64,
+    sent: AtomicU64,
+    dropped: AtomicU64,
+    overflows: AtomicU64,
+    underflows: AtomicU64,
+}
+
+/// Counters since the pipeline was built, summed over all sinks
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PipelineStats {
+    /// Bytes that came from the source
+    pub received: u64,
+    /// Bytes the sink took, silence included
+    pub sent: u64,
+    /// Bytes thrown away because the buffer was full
+    pub dropped: u64,
+    /// Times the buffer was full
+    pub overflows: u64,
+    /// Times the sink ran out of audio
+    pub underflows: u64,
+}
+
+/// Controls a pipeline from any thread, even while it runs
+#[derive(Clone)]
+pub struct PipelineHandle {
+    control: Arc<Control>,
+}
+
+impl PipelineHandle {
+    /// Makes `run` return soon. A source stuck in a blocking call is left to
+    /// finish on its own
+    pub fn stop(&self) {
+        self.control.stop.store(true, Ordering::Release);
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.control.stop.load(Ordering::Acquire)
+    }
+
+    /// Throws away whatever the source delivers until resumed, the sink gets
+    /// what's already buffered and then underflows
+    pub fn pause(&self) {
+        self.control.paused.store(true, Ordering::Release);
+    }
+
+    pub fn resume(&self) {
+        self.control.paused.store(false, Ordering::Release);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.control.paused.load(Ordering::Acquire)
+    }
+
+    pub fn stats(&self) -> PipelineStats {
+        let control = &self.control;
+        PipelineStats {
+            received: control.received.load(Ordering::Relaxed),
+            sent: control.sent.load(Ordering::Relaxed),
+            dropped: control.dropped.load(Ordering::Relaxed),
+            overflows: control.overflows.load(Ordering::Relaxed),
+            underflows: control.underflows.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Sets up a [`Pipeline`], everything not set is the same as the command line defaults
+pub struct PipelineBuilder {
+    args: Args,
+    source: Option<SourceFactory>,
+    sink: Option<SinkFactory>,
+    tees: Vec<SinkFactory>,
+    mixes: Vec<SourceFactory>,
+    sources: Option<Registry<Box<dyn RecvAudioRestart>>>,
+    sinks: Option<Registry<Box<dyn SendAudioRestart>>>,
+    name: Option<String>,
+}
+
+impl PipelineBuilder {
+    /// Takes all settings from a command line, including the source and the sink
+    pub fn args(mut self, args: Args) -> Self {
+        self.args = args;
+        self
+    }
+
+    /// Where the audio comes from, like "udp://0.0.0.0:1234" or "mic"
+    pub fn source(mut self, url: impl Into<String>) -> Self {
+        self.args.source = url.into();
+        self.source = None;
+        self
+    }
+
+    /// Where the audio goes, like "udp://192.168.1.2:1234" or "speakers"
+    pub fn sink(mut self, url: impl Into<String>) -> Self {
+        self.args.sink = url.into();
+        self.sink = None;
+        self
+    }
+
+    /// Uses a source of your own, `open` is called on the source thread
+    pub fn source_with(
+        mut self,
+        open: impl FnOnce() -> Result<Box<dyn RecvAudioRestart>> + Send + 'static,
+    ) -> Self {
+        self.source = Some(Box::new(|_: &Args| open()));
+        self
+    }
+
+    /// Uses a sink of your own, `open` is called on the sink thread
+    pub fn sink_with(
+        mut self,
+        open: impl FnOnce() -> Result<Box<dyn SendAudioRestart>> + Send + 'static,
+    ) -> Self {
+        let mut open = Some(open);
+        self.sink = Some(Box::new(move |_: &Args| match open.take() {
+            Some(open) => open(),
+            None => bail!("The sink can only be opened once"),
+        }));
+        self
+    }
+
+    /// Also sends to the sink at `url`, which gets a buffer of its own, so that
+    /// one stalling doesn't glitch the others
+    pub fn tee(mut self, url: impl Into<String>) -> Self {
+        self.args.tee.push(url.into());
+        self
+    }
+
+    /// Also sends to a sink of your own, `open` is called on its thread, and
+    /// again after a backoff for as long as it fails
+    pub fn tee_with(
+        mut self,
+        mut open: impl FnMut() -> Result<Box<dyn SendAudioRestart>> + Send + 'static,
+    ) -> Self {
+        self.tees.push(Box::new(move |_: &Args| open()));
+        self
+    }
+
+    /// Also mixes in the source at `url`, which gets a jitter buffer of its own
+    /// and drops out of the mix while it has nothing
+    pub fn mix(mut self, url: impl Into<String>) -> Self {
+        self.args.mix.push(url.into());
+        self
+    }
+
+    /// Also mixes in a source of your own, `open` is called on its thread
+    pub fn mix_with(
+        mut self,
+        open: impl FnOnce() -> Result<Box<dyn RecvAudioRestart>> + Send + 'static,
+    ) -> Self {
+        self.mixes.push(Box::new(|_: &Args| open()));
+        self
+    }
+
+    /// Opens the source url with these schemes instead of [`sources::registry`]
+    pub fn sources(mut self, registry: Registry<Box<dyn RecvAudioRestart>>) -> Self {
+        self.sources = Some(registry);
+        self
+    }
+
+    /// Opens the sink url with these schemes instead of [`sinks::registry`]
+    pub fn sinks(mut self, registry: Registry<Box<dyn SendAudioRestart>>) -> Self {
+        self.sinks = Some(registry);
+        self
+    }
+
+    /// Goes in front of the names of the pipeline's threads, to tell the logs
+    /// of several pipelines apart
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    pub fn format(mut self, format: PcmFormat) -> Self {
+        self.args.bits_per_sample = format.bits_per_sample;
+        self.args.use_float = format.use_float;
+        self.args.sample_rate = format.sample_rate;
+        self.args.channels = format.channels;
+        self
+    }
+
+    /// Max bytes buffered between the source and each sink
+    pub fn buffer(mut self, buffer_limit: usize) -> Self {
+        self.args.buffer_limit = buffer_limit;
+        self
+    }
+
+    /// Max size of network packets and chunks
+    pub fn datagram_size(mut self, datagram_size: usize) -> Self {
+        self.args.datagram_size = datagram_size;
+        self
+    }
+
+    pub fn overflow(mut self, overflow: OverflowPolicy) -> Self {
+        self.args.overflow = overflow;
+        self
+    }
+
+    pub fn underflow(mut self, underflow: UnderflowPolicy) -> Self {
+        self.args.underflow = underflow;
+        self
+    }
+
+    /// Restarts the sink whose buffer filled up instead of applying the
+    /// overflow policy, and the source too once every sink's buffer did
+    pub fn restart_on_buffer_filled(mut self, restart: bool) -> Self {
+        self.args.restart_on_buffer_filled = restart;
+        self
+    }
+
+    /// What the source and the sink do about each class of errors, urls can
+    /// still override it
+    pub fn recovery(mut self, recovery: RecoveryPolicy) -> Self {
+        self.args.on_transient = recovery.transient;
+        self.args.on_device_lost = recovery.device_lost;
+        self.args.on_fatal = recovery.fatal;
+        self
+    }
+
+    /// How long idc, quic and icecast wait between attempts to connect, and
+    /// how long everything else waits before restarting
+    pub fn reconnect(mut self, reconnect: ReconnectPolicy) -> Self {
+        self.args.idc_reconnect_min = reconnect.min_delay.as_millis() as u64;
+        self.args.idc_reconnect_max = reconnect.max_delay.as_millis() as u64;
+        self.args.idc_reconnect_factor = reconnect.factor;
+        self.args.idc_reconnect_jitter = reconnect.jitter;
+        self.args.idc_reconnect_attempts = reconnect.max_attempts;
+        self
+    }
+
+    /// Heartbeats and peer timeouts of connected network endpoints
+    pub fn timeouts(mut self, timeouts: IdcTimeouts) -> Self {
+        self.args.idc_keepalive = timeouts.keepalive.as_millis() as u64;
+        self.args.idc_timeout = timeouts.peer_timeout.as_millis() as u64;
+        self
+    }
+
+    /// How much audio idc sinks queue before dropping whole frames
+    pub fn latency_budget(mut self, budget: Duration) -> Self {
+        self.args.idc_latency_budget = budget.as_millis() as u64;
+        self
+    }
+
+    /// Encrypts and authenticates network streams with this key, 64 hex digits
+    pub fn psk(mut self, psk: impl Into<String>) -> Self {
+        self.args.psk = Some(psk.into());
+        self.args.key_file = None;
+        self
+    }
+
+    /// Like [`PipelineBuilder::psk`], with the key read from a file
+    pub fn key_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.args.key_file = Some(path.into());
+        self.args.psk = None;
+        self
+    }
+
+    /// Numbers UDP packets so that the receiving end notices losses
+    pub fn counted_udp(mut self, counted: bool) -> Self {
+        self.args.counted_udp = counted;
+        self
+    }
+
+    /// How counted UDP and quic sources hold out-of-order packets and make up
+    /// for lost ones
+    pub fn loss_handling(mut self, reorder_window: usize, concealment: Concealment) -> Self {
+        self.args.reorder_window = reorder_window;
+        self.args.concealment = concealment;
+        self
+    }
+
+    /// Only accepts UDP packets from these addresses
+    pub fn allow(mut self, allowed: Vec<Cidr>) -> Self {
+        self.args.allow = allowed;
+        self
+    }
+
+    /// Only accepts UDP packets from the first sender until it has been silent this long
+    pub fn lock_peer(mut self, timeout: Duration) -> Self {
+        self.args.lock_peer = Some(timeout.as_millis() as u64);
+        self
+    }
+
+    /// PEM files of the certificate quic sources present and quic sinks
+    /// trust, the key is only needed by sources
+    pub fn quic_cert(mut self, cert: impl Into<PathBuf>, key: Option<PathBuf>) -> Self {
+        self.args.quic_cert = Some(cert.into());
+        self.args.quic_key = key;
+        self
+    }
+
+    /// What http and icecast sinks tell players the stream is called
+    pub fn stream_name(mut self, name: impl Into<String>) -> Self {
+        self.args.stream_name = name.into();
+        self
+    }
+
+    pub fn build(self) -> Result<Pipeline> {
+        let args = self.args;
+        check_buffer_limit(args.buffer_limit)?;
+        PcmFormat::from_args(&args)?;
+        ReconnectPolicy::from_args(&args)?;
+        let registry = Arc::new(self.sources.unwrap_or_else(sources::registry));
+        let open_url = |name: String, url: &String, recovery: RecoveryPolicy| -> Result<MixInput> {
+            let registry = registry.clone();
+            let url_owned = url.clone();
+            let open: SourceFactory = Box::new(move |args: &Args| registry.open(&url_owned, args));
+            let mut input = MixInput::new(name, open);
+            input.recovery = recovery.with_url(url)?;
+            mix_settings(input, url)
+        };
+        let mut inputs = vec![match self.source {
+            Some(open) => {
+                let mut input = MixInput::new("source", open);
+                input.recovery = RecoveryPolicy::from_args(&args);
+                input
+            }
+            None if args.source.is_empty() => bail!("Pipeline needs a source"),
+            None => open_url(
+                "source".to_owned(),
+                &args.source,
+                RecoveryPolicy::from_args(&args),
+            )?,
+        }];
+        // Mixed in sources come and go on their own, like tees
+        let independent = RecoveryPolicy::uniform(Recovery::Restart);
+        for url in &args.mix {
+            inputs.push(open_url(format!("mix {}", inputs.len()), url, independent)?);
+        }
+        for open in self.mixes {
+            inputs.push(MixInput::new(format!("mix {}", inputs.len()), open));
+        }
+        let (source, source_recovery): (SourceFactory, _) = if inputs.len() > 1 {
+            let name = self.name.clone();
+            let open = Box::new(move |args: &Args| {
+                let mixer = Mixer::new(inputs, args, name.as_deref())?;
+                Ok(Box::new(mixer) as Box<dyn RecvAudioRestart>)
+            });
+            // The inputs already applied their own policies
+            (open, RecoveryPolicy::uniform(Recovery::Exit))
+        } else {
+            let input = inputs.remove(0);
+            if input.gain != 1.0 || input.prebuffer != mixer::DEFAULT_PREBUFFER {
+                bail!("Only mixed sources take gain and prebuffer, add some with --mix");
+            }
+            (input.open, input.recovery)
+        };
+        let registry = Arc::new(self.sinks.unwrap_or_else(sinks::registry));
+        let open_url = |url: &String, recovery: RecoveryPolicy| -> Result<SinkSlot> {
+            let (buffer_limit, overflow) = sink_settings(url, &args)?;
+            let registry = registry.clone();
+            let url_owned = url.clone();
+            Ok(SinkSlot {
+                name: String::new(),
+                open: Box::new(move |args: &Args| registry.open(&url_owned, args)),
+                buffer_limit,
+                overflow,
+                recovery: recovery.with_url(url)?,
+            })
+        };
+        let mut sinks = vec![match self.sink {
+            Some(open) => SinkSlot {
+                name: String::new(),
+                open,
+                buffer_limit: args.buffer_limit,
+                overflow: args.overflow,
+                recovery: RecoveryPolicy::from_args(&args),
+            },
+            None if args.sink.is_empty() => bail!("Pipeline needs a sink"),
+            None => open_url(&args.sink, RecoveryPolicy::from_args(&args))?,
+        }];
+        for url in &args.tee {
+            sinks.push(open_url(url, independent)?);
+        }
+        sinks.extend(self.tees.into_iter().map(|open| SinkSlot {
+            name: String::new(),
+            open,
+            buffer_limit: args.buffer_limit,
+            overflow: args.overflow,
+            recovery: independent,
+        }));
+        for (index, sink) in sinks.iter_mut().enumerate() {
+            sink.name = match index {
+                0 => "sink".to_owned(),
+                _ => format!("tee {index}"),
+            };
+        }
+        Ok(Pipeline {
+            args,
+            source,
+            source_recovery,
+            sinks,
+            control: Arc::default(),
+            name: self.name,
+        })
+    }
+}
+
+fn check_buffer_limit(buffer_limit: usize) -> Result<()> {
+    if buffer_limit < HYPOT_AUDIO_ALIGNMENT * 2 {
+        bail!(
+            "Buffer limit must be at least {}",
+            HYPOT_AUDIO_ALIGNMENT * 2
+        );
+    }
+    Ok(())
+}
+
+/// Takes the gain and the prebuffer of `input` from its `url`
+fn mix_settings(mut input: MixInput, url: &str) -> Result<MixInput> {
+    let endpoint = Endpoint::parse(url)?;
+    if let Some(db) = endpoint.param(GAIN.name)? {
+        input.gain = mixer::db_to_gain(db)?;
+    }
+    if let Some(ms) = endpoint.param(PREBUFFER.name)? {
+        input.prebuffer = Duration::from_millis(ms);
+    }
+    Ok(input)
+}
+
+/// The buffer limit and the overflow policy of the sink at `url`
+fn sink_settings(url: &str, args: &Args) -> Result<(usize, OverflowPolicy)> {
+    let endpoint = Endpoint::parse(url)?;
+    let buffer_limit = endpoint.param(BUFFER.name)?.unwrap_or(args.buffer_limit);
+    check_buffer_limit(buffer_limit)?;
+    let overflow = endpoint.param(OVERFLOW.name)?.unwrap_or(args.overflow);
+    Ok((buffer_limit, overflow))
+}
+
+/// A sink along with how it's buffered
+struct SinkSlot {
+    /// "sink" for the main one, "tee 1" and so on for the rest
+    name: String,
+    open: SinkFactory,
+    buffer_limit: usize,
+    overflow: OverflowPolicy,
+    recovery: RecoveryPolicy,
+}
+
+/// A source and any number of sinks, each on its own thread so that none
+/// blocking stalls the others, joined by a ring buffer for each sink
+pub struct Pipeline {
+    args: Args,
+    source: SourceFactory,
+    source_recovery: RecoveryPolicy,
+    sinks: Vec<SinkSlot>,
+    control: Arc<Control>,
+    name: Option<String>,
+}
+
+impl Pipeline {
+    pub fn builder() -> PipelineBuilder {
+        PipelineBuilder {
+            args: Args::default(),
+            source: None,
+            sink: None,
+            tees: Vec::new(),
+            mixes: Vec::new(),
+            sources: None,
+            sinks: None,
+            name: None,
+        }
+    }
+
+    pub fn handle(&self) -> PipelineHandle {
+        PipelineHandle {
+            control: self.control.clone(),
+        }
+    }
+
+    /// Runs until stopped or until the source or a sink fails in a way its
+    /// [`RecoveryPolicy`] doesn't recover from. An error with a [`RestartPipeline`]
+    /// in it asks whoever runs the pipeline to build it again
+    pub fn run(self) -> Result<()> {
+        let args = self.args;
+        let thread_name = |side: &str| match &self.name {
+            Some(name) => format!("{name} {side}"),
+            None => side.to_owned(),
+        };
+        let block_align = PcmFormat::from_args(&args)?.block_align();
+
+        let (done, finished) = mpsc::channel();
+
+        let mut outputs = Vec::new();
+        let mut sink_threads = Vec::new();
+        for (index, slot) in self.sinks.into_iter().enumerate() {
+            let (producer, consumer) = ring::channel(slot.buffer_limit, block_align);
+            info!(
+                "Buffering up to {} bytes between the source and the {}",
+                producer.capacity(),
+                slot.name
+            );
+            let restart = Arc::new(AtomicBool::new(false));
+            let tee = index > 0;
+            outputs.push(Output {
+                name: slot.name.clone(),
+                producer,
+                overflow: slot.overflow,
+                tee,
+                pending: VecDeque::new(),
+                restart: restart.clone(),
+                n_dropped: 0,
+            });
+
+            let sink_args = args.clone();
+            let sink_control = self.control.clone();
+            let sink_done = done.clone();
+            let sink_thread =
+                thread::Builder::new()
+                    .name(thread_name(&slot.name))
+                    .spawn(move || {
+                        let name = slot.name.clone();
+                        let result =
+                            run_sink(&sink_args, slot, tee, consumer, &sink_control, &restart);
+                        match result {
+                            // Either stopped or the others keep going without it
+                            Ok(()) if tee => {}
+                            result => {
+                                let _ = sink_done.send(result.map_err(|err| {
+                                    err.context(format!("{} failed", capitalize(&name)))
+                                }));
+                            }
+                        }
+                    })?;
+            sink_threads.push(sink_thread);
+        }
+
+        let source_control = self.control.clone();
+        let open_source = self.source;
+        let source_recovery = self.source_recovery;
+        let source_thread =
+            thread::Builder::new()
+                .name(thread_name("source"))
+                .spawn(move || {
+                    let result = run_source(
+                        &args,
+                        open_source,
+                        source_recovery,
+                        outputs,
+                        &source_control,
+                    );
+                    let _ = done.send(result.map_err(|err| err.context("Source failed")));
+                })?;
+
+        let result = finished
+            .recv()
+            .map_err(|err| anyhow!("Pipeline threads vanished: {err}"))?;
+        self.control.stop.store(true, Ordering::Release);
+        // The sinks get to send what's left and close properly, and the source
+        // to let go of its socket before the pipeline is built again. It might
+        // be stuck in a blocking call though, so don't wait forever
+        let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+        for worker in sink_threads.iter().chain([&source_thread]) {
+            while !worker.is_finished() && Instant::now() < deadline {
+                thread::sleep(UNDERFLOW_WAIT);
+            }
+        }
+        result
+    }
+}
+
+fn capitalize(name: &str) -> String {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .map(|first| first.to_uppercase().chain(chars).collect())
+        .unwrap_or_default()
+}
+
+/// The source's end of the ring to one sink
+struct Output {
+    name: String,
+    producer: Producer,
+    overflow: OverflowPolicy,
+    tee: bool,
+    /// Whole frames that didn't fit in the ring yet, a blocking tee keeps up
+    /// to a ring's worth
+    pending: VecDeque<u8>,
+    /// The source restarted because of this sink, it should too
+    restart: Arc<AtomicBool>,
+    n_dropped: u64,
+}
+
+impl Output {
+    /// Throws away everything buffered for this sink
+    fn clear(&mut self, control: &Control) {
+        control.dropped.fetch_add(
+            (self.producer.len() + self.pending.len()) as u64,
+            Ordering::Relaxed,
+        );
+        self.producer.request_clear();
+        self.pending.clear();
+    }
+}
+
+fn run_source(
+    args: &Args,
+    open: SourceFactory,
+    recovery: RecoveryPolicy,
+    mut outputs: Vec<Output>,
+    control: &Control,
+) -> Result<()> {
+    initialize_mta().unwrap();
+    let mut recoverer = Recoverer::new("source", recovery, ReconnectPolicy::from_args(args)?);
+    let mut source = open(args).map_err(|err| recoverer.open_failed(err))?;
+    let block_align = PcmFormat::from_args(args)?.block_align();
+
+    let mut deq = VecDeque::new();
+    while !control.stop.load(Ordering::Acquire) {
+        let n_before = deq.len();
+        if let Err(err) = source.recv_to_deque(&mut deq) {
+            match recoverer.recover(err, &control.stop) {
+                Recovered::Ignored => {}
+                Recovered::Restart => {
+                    deq.clear();
+                    if let Some(Recovered::GaveUp(err) | Recovered::Escalate(err)) =
+                        recoverer.restart(&mut *source, &control.stop)
+                    {
+                        return Err(err);
+                    }
+                }
+                Recovered::GaveUp(err) | Recovered::Escalate(err) => return Err(err),
+            }
+            continue;
+        }
+        recoverer.succeeded();
+        control
+            .received
+            .fetch_add((deq.len() - n_before) as u64, Ordering::Relaxed);
+        if control.paused.load(Ordering::Acquire) {
+            deq.clear();
+            continue;
+        }
+        // A partial frame waits for the rest of it
+        let n_frames = deq.len() - deq.len() % block_align;
+        if n_frames == 0 {
+            continue;
+        }
+
+        let mut n_live = 0;
+        let mut n_restarted = 0;
+        for output in &mut outputs {
+            // A tee that gave up
+            if output.producer.is_closed() {
+                continue;
+            }
+            n_live += 1;
+            let was_behind = !output.pending.is_empty();
+            output.pending.extend(deq.range(..n_frames));
+            output.producer.write_from_deque(&mut output.pending);
+            if output.pending.is_empty() {
+                continue;
+            }
+
+            // Holding up the source would hold up every other sink too
+            if output.tee && output.overflow == OverflowPolicy::Block {
+                if !was_behind {
+                    control.overflows.fetch_add(1, Ordering::Relaxed);
+                }
+                let capacity = output.producer.capacity();
+                if output.pending.len() > capacity {
+                    control
+                        .dropped
+                        .fetch_add((output.pending.len() - capacity) as u64, Ordering::Relaxed);
+                    // The capacity is whole frames, so this keeps them whole
+                    output.pending.truncate(capacity);
+                    output.n_dropped += 1;
+                    if output.n_dropped.is_power_of_two() {
+                        warn!(
+                            "The {} is a whole buffer behind, dropped new audio {} times so far",
+                            output.name, output.n_dropped
+                        );
+                    }
+                }
+                continue;
+            }
+            control.overflows.fetch_add(1, Ordering::Relaxed);
+            if args.restart_on_buffer_filled {
+                output.restart.store(true, Ordering::Release);
+                output.clear(control);
+                n_restarted += 1;
+                warn!("Buffer of the {} too full, restarting it.", output.name);
+                continue;
+            }
+            match output.overflow {
+                OverflowPolicy::Clear => {
+                    output.clear(control);
+                    warn!("Buffer of the {} too full, clearing.", output.name);
+                }
+                OverflowPolicy::DropNew => {
+                    control
+                        .dropped
+                        .fetch_add(output.pending.len() as u64, Ordering::Relaxed);
+                    output.pending.clear();
+                    output.n_dropped += 1;
+                    // Don't flood the log if the sink is just too slow
+                    if output.n_dropped.is_power_of_two() {
+                        warn!(
+                            "Buffer of the {} too full, dropped new audio {} times so far",
+                            output.name, output.n_dropped
+                        );
+                    }
+                }
+                OverflowPolicy::Block => {
+                    while !output.pending.is_empty() && !output.producer.is_closed() {
+                        output.producer.wait(UNDERFLOW_WAIT);
+                        output.producer.write_from_deque(&mut output.pending);
+                    }
+                }
+            }
+        }
+        // Only every sink falling behind at once points at the source. Stopping
+        // sinks leave the others looking like every sink
+        let stopping = control.stop.load(Ordering::Acquire);
+        if n_restarted > 0 && n_restarted == n_live && !stopping {
+            warn!("Every buffer too full, restarting the source too.");
+            deq.clear();
+            if let Some(Recovered::GaveUp(err) | Recovered::Escalate(err)) =
+                recoverer.restart(&mut *source, &control.stop)
+            {
+                return Err(err);
+            }
+        } else {
+            deq.drain(..n_frames);
+        }
+    }
+    Ok(())
+}
+
+/// Feeds one sink until the pipeline stops or its errors end the pipeline. A
+/// tee that gives up returns fine, the others go on without it
+fn run_sink(
+    args: &Args,
+    mut slot: SinkSlot,
+    tee: bool,
+    mut consumer: Consumer,
+    control: &Control,
+    restart: &AtomicBool,
+) -> Result<()> {
+    initialize_mta().unwrap();
+    let name = &slot.name;
+    let mut recoverer = Recoverer::new(name, slot.recovery, ReconnectPolicy::from_args(args)?);
+    let mut sink = loop {
+        match (slot.open)(args) {
+            Ok(sink) => break sink,
+            // The others go on meanwhile
+            Err(err) if tee && recoverer.can_go_without(&err) => {
+                match recoverer.recover(err, &control.stop) {
+                    Recovered::Ignored | Recovered::Restart => {}
+                    Recovered::GaveUp(err) => {
+                        error!("Couldn't open the {name}, going on without it: {err:#}");
+                        return Ok(());
+                    }
+                    Recovered::Escalate(err) => return Err(err),
+                }
+                if control.stop.load(Ordering::Acquire) {
+                    return Ok(());
+                }
+                // What piled up meanwhile is stale
+                let mut stale = VecDeque::new();
+                consumer.read_to_deque(&mut stale, consumer.len());
+                control
+                    .dropped
+                    .fetch_add(stale.len() as u64, Ordering::Relaxed);
+            }
+            Err(err) => return Err(recoverer.open_failed(err)),
+        }
+    };
+    let format = PcmFormat::from_args(args)?;
+
+    let mut silence = Vec::new();
+    let n_silent = format.sample_rate * UNDERFLOW_WAIT.as_millis() as usize / 1000;
+    format.encode(&vec![0.0; n_silent * format.channels], &mut silence);
+
+    // Only as much as one call can take stays outside the ring
+    let max_pending = usize::max(args.datagram_size, format.block_align());
+    let mut deq = VecDeque::new();
+    let mut dry = false;
+    while !control.stop.load(Ordering::Acquire) {
+        if restart.swap(false, Ordering::AcqRel) {
+            deq.clear();
+            match recoverer.restart(&mut *sink, &control.stop) {
+                Some(Recovered::GaveUp(err)) if tee => {
+                    error!("{} failed, going on without it: {err:#}", capitalize(name));
+                    return Ok(());
+                }
+                Some(Recovered::GaveUp(err) | Recovered::Escalate(err)) => return Err(err),
+                _ => {}
+            }
+        }
+        if deq.len() < max_pending && consumer.is_empty() {
+            consumer.wait(UNDERFLOW_WAIT);
+        }
+        let room = max_pending.saturating_sub(deq.len());
+        consumer.read_to_deque(&mut deq, room);
+
+        if deq.len() < format.block_align() {
+            if !dry && !control.paused.load(Ordering::Acquire) {
+                debug!("Ran out of audio to send");
+                control.underflows.fetch_add(1, Ordering::Relaxed);
+                dry = true;
+            }
+            if args.underflow == UnderflowPolicy::Silence {
+                deq.extend(&silence);
+            }
+        } else {
+            dry = false;
+        }
+
+        let pending = deq.len();
+        let result = sink.send_from_deque(&mut deq);
+        control
+            .sent
+            .fetch_add((pending - deq.len()) as u64, Ordering::Relaxed);
+        match result {
+            Ok(()) if deq.len() < pending => recoverer.succeeded(),
+            Ok(()) => {
+                if pending > 0 {
+                    thread::sleep(SINK_IDLE);
+                }
+            }
+            Err(err) => match recoverer.recover(err, &control.stop) {
+                Recovered::Ignored => {}
+                Recovered::Restart => {
+                    // What piled up meanwhile is stale
+                    deq.clear();
+                    consumer.read_to_deque(&mut deq, consumer.len());
+                    control
+                        .dropped
+                        .fetch_add(deq.len() as u64, Ordering::Relaxed);
+                    deq.clear();
+                    match recoverer.restart(&mut *sink, &control.stop) {
+                        Some(Recovered::GaveUp(err)) if tee => {
+                            error!("{} failed, going on without it: {err:#}", capitalize(name));
+                            return Ok(());
+                        }
+                        Some(Recovered::GaveUp(err) | Recovered::Escalate(err)) => return Err(err),
+                        _ => {}
+                    }
+                }
+                Recovered::GaveUp(err) if tee => {
+                    error!("{} failed, going on without it: {err:#}", capitalize(name));
+                    return Ok(());
+                }
+                Recovered::GaveUp(err) | Recovered::Escalate(err) => return Err(err),
+            },
+        }
+    }
+
+    // Whatever is left goes out too, unless that takes too long
+    let deadline = Instant::now() + SHUTDOWN_TIMEOUT / 2;
+    while (deq.len() >= format.block_align() || !consumer.is_empty()) && Instant::now() < deadline {
+        let room = max_pending.saturating_sub(deq.len());
+        consumer.read_to_deque(&mut deq, room);
+        let pending = deq.len();
+        sink.send_from_deque(&mut deq)?;
+        control
+            .sent
+            .fetch_add((pending - deq.len()) as u64, Ordering::Relaxed);
+        if deq.len() == pending {
+            thread::sleep(SINK_IDLE);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn builder() -> PipelineBuilder {
+        Pipeline::builder()
+            .source("udp://127.0.0.1:0")
+            .sink("udp://127.0.0.1:9")
+    }
+
+    #[test]
+    fn settings_end_up_in_the_args() {
+        let pipeline = builder()
+            .format(PcmFormat::new(16, false, 44100, 1).unwrap())
+            .buffer(20000)
+            .overflow(OverflowPolicy::Block)
+            .reconnect(ReconnectPolicy {
+                min_delay: Duration::from_millis(30),
+                max_attempts: Some(4),
+                ..Default::default()
+            })
+            .psk("ab".repeat(32))
+            .build()
+            .unwrap();
+        let args = &pipeline.args;
+        assert_eq!(PcmFormat::from_args(args).unwrap().block_align(), 2);
+        assert_eq!(args.sample_rate, 44100);
+        assert_eq!(args.idc_reconnect_min, 30);
+        assert_eq!(args.idc_reconnect_attempts, Some(4));
+        assert!(args.psk.is_some());
+        assert_eq!(pipeline.sinks[0].buffer_limit, 20000);
+        assert_eq!(pipeline.sinks[0].overflow, OverflowPolicy::Block);
+    }
+
+    #[test]
+    fn urls_override_their_own_buffering() {
+        let pipeline = builder()
+            .tee("udp://127.0.0.1:9?buffer=30000&overflow=drop-new")
+            .tee("udp://127.0.0.1:9")
+            .build()
+            .unwrap();
+        let slots: Vec<_> = pipeline
+            .sinks
+            .iter()
+            .map(|slot| (slot.name.as_str(), slot.buffer_limit, slot.overflow))
+            .collect();
+        assert_eq!(
+            slots,
+            [
+                ("sink", 10000, OverflowPolicy::Clear),
+                ("tee 1", 30000, OverflowPolicy::DropNew),
+                ("tee 2", 10000, OverflowPolicy::Clear),
+            ]
+        );
+    }
+
+    #[test]
+    fn bad_settings_fail_the_build() {
+        let err = |builder: PipelineBuilder| format!("{:#}", builder.build().err().unwrap());
+        assert!(err(Pipeline::builder().sink("udp://127.0.0.1:9")).contains("needs a source"));
+        assert!(err(Pipeline::builder().source("udp://127.0.0.1:0")).contains("needs a sink"));
+        assert!(err(builder().buffer(1)).contains("Buffer limit"));
+        assert!(err(builder().tee("udp://127.0.0.1:9?buffer=1")).contains("Buffer limit"));
+        assert!(err(builder().source("udp://127.0.0.1:0?gain=-6")).contains("--mix"));
+    }
+}
@@ -0,0 +1,254 @@
+use std::collections::VecDeque;
+
+use crate::pcm::PcmFormat;
+
+/// Length of the crossfade from concealed audio back into real audio
+const CROSSFADE_MS: usize = 5;
+/// Concealed audio fades out completely over this long
+const FADE_OUT_MS: usize = 80;
+/// Pitch range searched by the waveform-similarity extrapolation
+const MIN_PITCH_HZ: usize = 50;
+const MAX_PITCH_HZ: usize = 400;
+
+/// What to put in place of lost packets
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum, serde::Deserialize))]
+#[cfg_attr(feature = "cli", serde(rename_all = "kebab-case"))]
+pub enum Concealment {
+    /// Plain silence
+    #[default]
+    Silence,
+    /// Repeat the last packet while fading it out
+    Repeat,
+    /// Repeat the best matching pitch period of recent audio while fading it out
+    Wsola,
+}
+
+/// Fills gaps in a PCM stream and crossfades back into real audio once it resumes
+pub struct Concealer {
+    format: PcmFormat,
+    mode: Concealment,
+    /// Recent output samples, interleaved
+    history: VecDeque<f32>,
+    last_packet: Vec<f32>,
+    /// Audio being repeated during the current gap, interleaved
+    period: Vec<f32>,
+    period_pos: usize,
+    gain: f32,
+    /// Continuation of the concealed audio, real audio is faded in over it
+    tail: Vec<f32>,
+    scratch: Vec<f32>,
+}
+
+impl Concealer {
+    pub fn new(format: PcmFormat, mode: Concealment) -> Self {
+        Self {
+            format,
+            mode,
+            history: VecDeque::new(),
+            last_packet: Vec::new(),
+            period: Vec::new(),
+            period_pos: 0,
+            gain: 1.0,
+            tail: Vec::new(),
+            scratch: Vec::new(),
+        }
+    }
+
+    fn ms_to_frames(&self, ms: usize) -> usize {
+        (self.format.sample_rate * ms / 1000).max(1)
+    }
+
+    fn history_frames(&self) -> usize {
+        self.format.sample_rate / MIN_PITCH_HZ + self.ms_to_frames(CROSSFADE_MS)
+    }
+
+    /// Passes received audio through, crossfading it in if it follows a gap
+    pub fn received(&mut self, bytes: &[u8], out: &mut VecDeque<u8>) {
+        let channels = self.format.channels;
+        let mut samples = std::mem::take(&mut self.scratch);
+        samples.clear();
+        self.format.decode(bytes, &mut samples);
+        samples.truncate(samples.len() / channels * channels);
+
+        if self.tail.is_empty() {
+            out.extend(bytes);
+        } else {
+            let n_samples = usize::min(self.tail.len(), samples.len());
+            let n_frames = n_samples / channels;
+            let frames = samples[..n_samples].chunks_exact_mut(channels);
+            for (frame, (real, concealed)) in
+                frames.zip(self.tail.chunks_exact(channels)).enumerate()
+            {
+                let weight = (frame + 1) as f32 / (n_frames + 1) as f32;
+                for (sample, tail) in real.iter_mut().zip(concealed) {
+                    *sample = tail * (1.0 - weight) + *sample * weight;
+                }
+            }
+            self.format.encode(&samples[..n_samples], out);
+            out.extend(&bytes[n_samples * self.format.bytes_per_sample()..]);
+            self.tail.clear();
+        }
+
+        self.push_history(&samples);
+        self.scratch = std::mem::replace(&mut self.last_packet, samples);
+        self.period.clear();
+        self.gain = 1.0;
+    }
+
+    /// Generates `n_bytes` worth of replacement audio for a lost packet
+    pub fn conceal(&mut self, n_bytes: usize, out: &mut VecDeque<u8>) {
+        let channels = self.format.channels;
+        let n_frames = n_bytes / self.format.block_align();
+        let crossfade_frames = self.ms_to_frames(CROSSFADE_MS);
+
+        if self.period.is_empty() {
+            self.period = match self.mode {
+                Concealment::Silence => Vec::new(),
+                Concealment::Repeat => self.last_packet.clone(),
+                Concealment::Wsola => self.find_period(),
+            };
+            self.period_pos = 0;
+        }
+
+        let step = 1.0 / self.ms_to_frames(FADE_OUT_MS) as f32;
+        let mut samples = Vec::with_capacity((n_frames + crossfade_frames) * channels);
+        for frame in 0..n_frames + crossfade_frames {
+            let gain = (self.gain - frame as f32 * step).max(0.0);
+            for channel in 0..channels {
+                let sample = if self.period.is_empty() {
+                    0.0
+                } else {
+                    let pos = (self.period_pos + frame * channels + channel) % self.period.len();
+                    self.period[pos]
+                };
+                samples.push(sample * gain);
+            }
+        }
+        if !self.period.is_empty() {
+            self.period_pos = (self.period_pos + n_frames * channels) % self.period.len();
+        }
+        self.gain = (self.gain - n_frames as f32 * step).max(0.0);
+
+        self.tail = samples.split_off(n_frames * channels);
+        self.format.encode(&samples, out);
+        self.push_history(&samples);
+    }
+
+    fn push_history(&mut self, samples: &[f32]) {
+        self.history.extend(samples);
+        let limit = self.history_frames() * self.format.channels;
+        if self.history.len() > limit {
+            self.history.drain(..self.history.len() - limit);
+        }
+    }
+
+    /// Finds the pitch period that best continues the recent audio, with its end
+    /// overlap-added onto its start so that it loops without a click
+    fn find_period(&self) -> Vec<f32> {
+        let channels = self.format.channels;
+        let history: Vec<f32> = self.history.iter().copied().collect();
+        let mono: Vec<f32> = history
+            .chunks_exact(channels)
+            .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+            .collect();
+        let n = mono.len();
+        let template_len = self.ms_to_frames(CROSSFADE_MS);
+        let min_lag = self.format.sample_rate / MAX_PITCH_HZ;
+        let max_lag = usize::min(
+            self.format.sample_rate / MIN_PITCH_HZ,
+            n.saturating_sub(template_len),
+        );
+        if min_lag == 0 || min_lag > max_lag {
+            return self.last_packet.clone();
+        }
+
+        let template = &mono[n - template_len..];
+        let template_energy: f32 = template.iter().map(|s| s * s).sum();
+        let mut best_lag = min_lag;
+        let mut best_score = f32::MIN;
+        for lag in min_lag..=max_lag {
+            let candidate = &mono[n - template_len - lag..n - lag];
+            let dot: f32 = template.iter().zip(candidate).map(|(a, b)| a * b).sum();
+            let energy: f32 = candidate.iter().map(|s| s * s).sum();
+            let score = dot / (template_energy * energy).sqrt().max(f32::EPSILON);
+            if score > best_score {
+                best_score = score;
+                best_lag = lag;
+            }
+        }
+
+        let start = history.len() - best_lag * channels;
+        let mut period = history[start..].to_vec();
+        let overlap = usize::min(best_lag / 4, start / channels);
+        for frame in 0..overlap {
+            let weight = (frame + 1) as f32 / (overlap + 1) as f32;
+            for channel in 0..channels {
+                let i = (best_lag - overlap + frame) * channels + channel;
+                let before = start - overlap * channels + frame * channels + channel;
+                period[i] = period[i] * (1.0 - weight) + history[before] * weight;
+            }
+        }
+        period
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: usize = 48000;
+    const PACKET_FRAMES: usize = 240;
+    const N_PACKETS: usize = 200;
+
+    /// Plays a 220 Hz sine with every seventh packet lost and returns how much
+    /// energy the second difference of the output has beyond the clean sine's,
+    /// which is what clicks at the edges of the gaps add
+    fn discontinuity_energy(mode: Concealment) -> f32 {
+        let format = PcmFormat::new(32, false, SAMPLE_RATE, 2).unwrap();
+        let mut concealer = Concealer::new(format, mode);
+        let mut out = VecDeque::new();
+        let mut clean = Vec::new();
+        for packet in 0..N_PACKETS {
+            let mut samples = Vec::new();
+            for frame in 0..PACKET_FRAMES {
+                let t = (packet * PACKET_FRAMES + frame) as f32 / SAMPLE_RATE as f32;
+                let sample = (t * 2.0 * std::f32::consts::PI * 220.0).sin() * 0.5;
+                samples.extend([sample, sample]);
+            }
+            clean.extend(&samples);
+            let mut bytes = Vec::new();
+            format.encode(&samples, &mut bytes);
+            if packet % 7 == 3 {
+                concealer.conceal(bytes.len(), &mut out);
+            } else {
+                concealer.received(&bytes, &mut out);
+            }
+        }
+        let bytes: Vec<u8> = out.into_iter().collect();
+        assert_eq!(
+            bytes.len(),
+            N_PACKETS * PACKET_FRAMES * format.block_align()
+        );
+        let mut samples = Vec::new();
+        format.decode(&bytes, &mut samples);
+
+        let energy = |samples: &[f32]| -> f32 {
+            let left: Vec<f32> = samples.chunks_exact(2).map(|frame| frame[0]).collect();
+            left.windows(3)
+                .map(|w| (w[0] - 2.0 * w[1] + w[2]).powi(2))
+                .sum()
+        };
+        energy(&samples) - energy(&clean)
+    }
+
+    #[test]
+    fn concealment_smooths_over_losses() {
+        let silence = discontinuity_energy(Concealment::Silence);
+        let repeat = discontinuity_energy(Concealment::Repeat);
+        let wsola = discontinuity_energy(Concealment::Wsola);
+        assert!(silence > 0.0);
+        assert!(repeat < silence, "repeat {repeat} silence {silence}");
+        assert!(wsola < repeat, "wsola {wsola} repeat {repeat}");
+    }
+}
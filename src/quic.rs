@@ -0,0 +1,847 @@
+use std::{
+    collections::VecDeque,
+    io,
+    net::SocketAddr,
+    path::Path,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::{Result, anyhow, bail};
+use log::{debug, info, warn};
+use quinn::{Connection, Endpoint};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime, pem::PemObject};
+use tokio::{
+    io::{AsyncBufReadExt as _, BufReader},
+    runtime::{Handle, Runtime},
+    task::JoinHandle,
+};
+
+#[cfg(feature = "tokio")]
+use crate::network_utils::AsyncDatagramSocket;
+use crate::{
+    Args, Restart,
+    idc::{Backoff, ConnectionState, IdcTimeouts, ReconnectPolicy},
+    network_utils::DatagramSocket,
+    pcm::PcmFormat,
+    sinks::SendAudio,
+};
+
+/// How often both sides report their stats over the control stream
+const STATS_INTERVAL: Duration = Duration::from_secs(5);
+const ALPN: &[u8] = b"stupid-audio-stream";
+
+/// Certificate the source presents and the sink trusts, from `--quic-cert` and `--quic-key`
+#[derive(Debug, Default)]
+pub struct QuicCerts {
+    pub cert: Option<Vec<CertificateDer<'static>>>,
+    pub key: Option<PrivateKeyDer<'static>>,
+}
+
+impl QuicCerts {
+    pub fn from_args(args: &Args) -> Result<Self> {
+        let cert = args
+            .quic_cert
+            .as_deref()
+            .map(|path| {
+                CertificateDer::pem_file_iter(path)
+                    .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                    .map_err(|err| anyhow!("Couldn't read certificate {path:?}: {err}"))
+            })
+            .transpose()?;
+        let key = args
+            .quic_key
+            .as_deref()
+            .map(|path: &Path| {
+                PrivateKeyDer::from_pem_file(path)
+                    .map_err(|err| anyhow!("Couldn't read key {path:?}: {err}"))
+            })
+            .transpose()?;
+        Ok(Self { cert, key })
+    }
+}
+
+fn transport_config(timeouts: &IdcTimeouts) -> Result<Arc<quinn::TransportConfig>> {
+    let mut transport = quinn::TransportConfig::default();
+    transport.keep_alive_interval(Some(timeouts.keepalive));
+    transport.max_idle_timeout(Some(timeouts.peer_timeout.try_into()?));
+    Ok(Arc::new(transport))
+}
+
+fn server_config(certs: &QuicCerts, timeouts: &IdcTimeouts) -> Result<quinn::ServerConfig> {
+    let (chain, key) = match (&certs.cert, &certs.key) {
+        (Some(chain), Some(key)) => (chain.clone(), key.clone_key()),
+        (None, None) => {
+            let generated = rcgen::generate_simple_self_signed(["localhost".to_string()])?;
+            info!("Using a freshly generated self-signed certificate");
+            (
+                vec![generated.cert.der().clone()],
+                PrivateKeyDer::Pkcs8(generated.signing_key.serialize_der().into()),
+            )
+        }
+        _ => bail!("The QUIC source needs both --quic-cert and --quic-key, or neither"),
+    };
+    let mut crypto = rustls::ServerConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_protocol_versions(&[&rustls::version::TLS13])?
+    .with_no_client_auth()
+    .with_single_cert(chain, key)?;
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+    let mut config = quinn::ServerConfig::with_crypto(Arc::new(
+        quinn::crypto::rustls::QuicServerConfig::try_from(crypto)?,
+    ));
+    config.transport_config(transport_config(timeouts)?);
+    Ok(config)
+}
+
+fn client_config(certs: &QuicCerts, timeouts: &IdcTimeouts) -> Result<quinn::ClientConfig> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = rustls::ClientConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(&[&rustls::version::TLS13])?;
+    let mut crypto = match &certs.cert {
+        Some(chain) => {
+            let mut roots = rustls::RootCertStore::empty();
+            for cert in chain {
+                roots.add(cert.clone())?;
+            }
+            builder.with_root_certificates(roots).with_no_client_auth()
+        }
+        None => {
+            warn!("No --quic-cert given, so the QUIC sink trusts any source");
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(AnyServer(provider)))
+                .with_no_client_auth()
+        }
+    };
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+    let mut config = quinn::ClientConfig::new(Arc::new(
+        quinn::crypto::rustls::QuicClientConfig::try_from(crypto)?,
+    ));
+    config.transport_config(transport_config(timeouts)?);
+    Ok(config)
+}
+
+/// Accepts any certificate, only still checking that the handshake is signed by it
+#[derive(Debug)]
+struct AnyServer(Arc<rustls::crypto::CryptoProvider>);
+
+impl rustls::client::danger::ServerCertVerifier for AnyServer {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
+        Ok(rustls::client::danger::ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// What went through a [`QuicSocket`], shared with its control stream task
+#[derive(Debug, Default)]
+pub struct QuicStats {
+    pub datagrams_sent: AtomicU64,
+    pub datagrams_received: AtomicU64,
+}
+
+/// `format <bits> <int|float> <sample rate> <channels>`
+fn format_line(format: &PcmFormat) -> String {
+    format!(
+        "format {} {} {} {}\n",
+        format.bits_per_sample,
+        if format.use_float { "float" } else { "int" },
+        format.sample_rate,
+        format.channels
+    )
+}
+
+/// Says hello with the format, checks the peer's, then keeps exchanging stats
+/// until the connection is gone
+async fn control_stream(
+    connection: Connection,
+    (mut send, recv): (quinn::SendStream, quinn::RecvStream),
+    format: PcmFormat,
+    stats: Arc<QuicStats>,
+) {
+    let ours = format_line(&format);
+    let mut lines = BufReader::new(recv).lines();
+    let exchange = async {
+        send.write_all(ours.as_bytes()).await?;
+        let mut interval = tokio::time::interval(STATS_INTERVAL);
+        loop {
+            tokio::select! {
+                line = lines.next_line() => {
+                    let Some(line) = line? else {
+                        return anyhow::Ok(());
+                    };
+                    if line.starts_with("format ") {
+                        if line != ours.trim_end() {
+                            warn!("Peer has a different {line:?}, ours is {:?}", ours.trim_end());
+                        }
+                    } else {
+                        debug!("Peer {line}");
+                    }
+                }
+                _ = interval.tick() => {
+                    let path = connection.stats().path;
+                    let line = format!(
+                        "stats rtt={:?} sent={} received={} lost_packets={} cwnd={}\n",
+                        connection.rtt(),
+                        stats.datagrams_sent.load(Ordering::Relaxed),
+                        stats.datagrams_received.load(Ordering::Relaxed),
+                        path.lost_packets,
+                        path.cwnd,
+                    );
+                    debug!("Our {}", line.trim_end());
+                    send.write_all(line.as_bytes()).await?;
+                }
+            }
+        }
+    };
+    if let Err(err) = exchange.await {
+        debug!("Control stream closed: {err}");
+    }
+}
+
+enum QuicRole {
+    /// Wait for the sink to connect
+    Listen,
+    /// Connect to the source
+    Connect {
+        address: SocketAddr,
+        server_name: String,
+        config: quinn::ClientConfig,
+    },
+}
+
+type Streams = (quinn::SendStream, quinn::RecvStream);
+
+/// The host a sink checks the certificate against, `address` without the port
+fn server_name(address: &str) -> Result<String> {
+    let (host, _) = address
+        .rsplit_once(':')
+        .ok_or(anyhow!("Expected host:port, got {address:?}"))?;
+    Ok(host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_string())
+}
+
+/// Where a sink connecting to `address` binds its endpoint
+fn client_bind(address: &SocketAddr) -> Result<SocketAddr> {
+    Ok(if address.is_ipv4() {
+        "0.0.0.0:0".parse()?
+    } else {
+        "[::]:0".parse()?
+    })
+}
+
+/// The connection a [`QuicSocket`] and an [`AsyncQuicSocket`] keep up. Every
+/// wait in here is cancel-safe, connecting and accepting run as tasks of their own
+struct QuicLink {
+    runtime: Handle,
+    endpoint: Endpoint,
+    role: QuicRole,
+    connection: Option<Connection>,
+    /// Kept here so that a cancelled call doesn't abort the attempt
+    connecting: Option<JoinHandle<Result<(Connection, Streams)>>>,
+    backoff: Backoff,
+    format: PcmFormat,
+    timeouts: IdcTimeouts,
+    stats: Arc<QuicStats>,
+}
+
+impl QuicLink {
+    fn listen(
+        runtime: Handle,
+        address: SocketAddr,
+        format: PcmFormat,
+        certs: &QuicCerts,
+        timeouts: IdcTimeouts,
+        reconnect: ReconnectPolicy,
+    ) -> Result<Self> {
+        let config = server_config(certs, &timeouts)?;
+        let endpoint = {
+            let _runtime = runtime.enter();
+            Endpoint::server(config, address)?
+        };
+        Ok(Self::with_role(
+            runtime,
+            endpoint,
+            QuicRole::Listen,
+            Backoff::new(reconnect, format!("quic source on {address}")),
+            format,
+            timeouts,
+        ))
+    }
+
+    fn connect(
+        runtime: Handle,
+        address: SocketAddr,
+        server_name: String,
+        format: PcmFormat,
+        certs: &QuicCerts,
+        timeouts: IdcTimeouts,
+        reconnect: ReconnectPolicy,
+    ) -> Result<Self> {
+        let endpoint = {
+            let _runtime = runtime.enter();
+            Endpoint::client(client_bind(&address)?)?
+        };
+        let role = QuicRole::Connect {
+            address,
+            server_name,
+            config: client_config(certs, &timeouts)?,
+        };
+        Ok(Self::with_role(
+            runtime,
+            endpoint,
+            role,
+            Backoff::new(reconnect, format!("quic sink to {address}")),
+            format,
+            timeouts,
+        ))
+    }
+
+    fn with_role(
+        runtime: Handle,
+        endpoint: Endpoint,
+        role: QuicRole,
+        backoff: Backoff,
+        format: PcmFormat,
+        timeouts: IdcTimeouts,
+    ) -> Self {
+        Self {
+            runtime,
+            endpoint,
+            role,
+            connection: None,
+            connecting: None,
+            backoff,
+            format,
+            timeouts,
+            stats: Arc::default(),
+        }
+    }
+
+    fn max_datagram_size(&self) -> Option<usize> {
+        self.connection.as_ref()?.max_datagram_size()
+    }
+
+    /// The current connection, unless it's closed by now
+    fn live_connection(&mut self) -> Option<Connection> {
+        let connection = self.connection.as_ref()?;
+        if let Some(reason) = connection.close_reason() {
+            debug!("Connection closed: {reason}");
+            self.connection = None;
+            self.backoff.lost();
+            return None;
+        }
+        Some(connection.clone())
+    }
+
+    /// Connects to the source, or waits for a sink to connect and gives it the
+    /// peer timeout to open its control stream
+    fn start_connecting(&mut self) -> Result<()> {
+        let endpoint = self.endpoint.clone();
+        let connecting = match &self.role {
+            QuicRole::Connect {
+                address,
+                server_name,
+                config,
+            } => {
+                self.backoff.attempt()?;
+                let (config, address, server_name) =
+                    (config.clone(), *address, server_name.clone());
+                self.runtime.spawn(async move {
+                    let connection = endpoint
+                        .connect_with(config, address, &server_name)?
+                        .await?;
+                    let streams = connection.open_bi().await?;
+                    Ok((connection, streams))
+                })
+            }
+            QuicRole::Listen => {
+                let peer_timeout = self.timeouts.peer_timeout;
+                self.runtime.spawn(async move {
+                    let incoming = endpoint
+                        .accept()
+                        .await
+                        .ok_or(anyhow!("The QUIC endpoint is closed"))?;
+                    let address = incoming.remote_address();
+                    let handshake = async {
+                        let connection = incoming.await?;
+                        let streams = connection.accept_bi().await?;
+                        anyhow::Ok((connection, streams))
+                    };
+                    tokio::time::timeout(peer_timeout, handshake)
+                        .await
+                        .map_err(|_| {
+                            anyhow!("{address} didn't open its control stream in {peer_timeout:?}")
+                        })?
+                })
+            }
+        };
+        self.connecting = Some(connecting);
+        Ok(())
+    }
+
+    /// Takes the connection a finished attempt came up with
+    fn finish_connecting(&mut self, attempt: Result<(Connection, Streams)>) -> Result<()> {
+        match (attempt, &self.role) {
+            (Ok((connection, streams)), _) => {
+                debug!("Connected to {}", connection.remote_address());
+                self.runtime.spawn(control_stream(
+                    connection.clone(),
+                    streams,
+                    self.format,
+                    self.stats.clone(),
+                ));
+                self.connection = Some(connection);
+                self.backoff.connected();
+            }
+            (Err(err), QuicRole::Connect { .. }) => debug!("Couldn't connect: {err}"),
+            (Err(err), QuicRole::Listen) => {
+                warn!("Couldn't accept a connection: {err}");
+                self.backoff.attempt()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes sure there's a live connection, without waiting for one to come up
+    async fn poll_connect(&mut self) -> Result<()> {
+        if self.live_connection().is_some() {
+            return Ok(());
+        }
+        if let Some(connecting) = &mut self.connecting {
+            if !connecting.is_finished() {
+                return Ok(());
+            }
+            let attempt = connecting.await;
+            self.connecting = None;
+            self.finish_connecting(attempt?)?;
+        }
+        if self.connection.is_none() && self.backoff.ready() {
+            self.start_connecting()?;
+        }
+        Ok(())
+    }
+
+    /// Waits until there's a live connection
+    async fn wait_for_connection(&mut self) -> Result<Connection> {
+        loop {
+            if let Some(connection) = self.live_connection() {
+                return Ok(connection);
+            }
+            match &mut self.connecting {
+                Some(connecting) => {
+                    let attempt = connecting.await;
+                    self.connecting = None;
+                    self.finish_connecting(attempt?)?;
+                }
+                None if self.backoff.ready() => self.start_connecting()?,
+                None => tokio::time::sleep_until(self.backoff.next_attempt().into()).await,
+            }
+        }
+    }
+
+    async fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.poll_connect().await.map_err(io::Error::other)?;
+        let Some(connection) = &self.connection else {
+            // Not connected, drop it like idc does
+            return Ok(buf.len());
+        };
+        match connection.send_datagram(buf.to_vec().into()) {
+            Ok(()) => {
+                self.stats.datagrams_sent.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(quinn::SendDatagramError::ConnectionLost(err)) => {
+                debug!("Connection lost: {err}");
+                self.connection = None;
+                self.backoff.lost();
+            }
+            // The path got narrower since the size was checked, this one is lost
+            Err(quinn::SendDatagramError::TooLarge) => {
+                debug!("Dropped a datagram of {} bytes, too large", buf.len());
+            }
+            // No point in reconnecting to a peer that will never take them
+            Err(
+                err @ (quinn::SendDatagramError::UnsupportedByPeer
+                | quinn::SendDatagramError::Disabled),
+            ) => return Err(io::Error::new(io::ErrorKind::Unsupported, err)),
+        }
+        Ok(buf.len())
+    }
+
+    async fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        loop {
+            let connection = self.wait_for_connection().await.map_err(io::Error::other)?;
+            match connection.read_datagram().await {
+                Ok(datagram) => {
+                    self.stats
+                        .datagrams_received
+                        .fetch_add(1, Ordering::Relaxed);
+                    let n_read = usize::min(datagram.len(), buf.len());
+                    buf[..n_read].copy_from_slice(&datagram[..n_read]);
+                    return Ok((n_read, connection.remote_address()));
+                }
+                Err(err) => {
+                    debug!("Connection lost: {err}");
+                    self.connection = None;
+                    self.backoff.lost();
+                }
+            }
+        }
+    }
+
+    fn reopen(&mut self) {
+        if let Some(connection) = self.connection.take() {
+            connection.close(0u32.into(), b"bye");
+            self.backoff.lost();
+        }
+        if let Some(connecting) = self.connecting.take() {
+            connecting.abort();
+        }
+    }
+}
+
+/// The control stream task holds on to the connection, so it has to be closed
+/// for the peer to notice
+impl Drop for QuicLink {
+    fn drop(&mut self) {
+        if let Some(connection) = &self.connection {
+            connection.close(0u32.into(), b"bye");
+        }
+        if let Some(connecting) = &self.connecting {
+            connecting.abort();
+        }
+    }
+}
+
+/// QUIC connection that carries audio in unreliable datagrams, with format and
+/// stats going over a reliable stream next to them
+pub struct QuicSocket {
+    link: QuicLink,
+    read_timeout: Option<Duration>,
+    pub stats: Arc<QuicStats>,
+    /// Dropped last, the link's tasks run on it
+    runtime: Runtime,
+}
+
+impl QuicSocket {
+    fn with_link(runtime: Runtime, link: QuicLink) -> Self {
+        Self {
+            stats: link.stats.clone(),
+            link,
+            read_timeout: None,
+            runtime,
+        }
+    }
+
+    fn runtime() -> io::Result<Runtime> {
+        // The endpoint has to keep working between calls, so it gets its own thread
+        tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+    }
+
+    /// Listens on `address` for a sink to connect
+    pub fn listen(
+        address: SocketAddr,
+        format: PcmFormat,
+        certs: &QuicCerts,
+        timeouts: IdcTimeouts,
+        reconnect: ReconnectPolicy,
+    ) -> Result<Self> {
+        let runtime = Self::runtime()?;
+        let link = QuicLink::listen(
+            runtime.handle().clone(),
+            address,
+            format,
+            certs,
+            timeouts,
+            reconnect,
+        )?;
+        Ok(Self::with_link(runtime, link))
+    }
+
+    /// Connects to a source listening on `address`, reconnecting whenever that fails
+    pub fn connect(
+        address: &str,
+        format: PcmFormat,
+        certs: &QuicCerts,
+        timeouts: IdcTimeouts,
+        reconnect: ReconnectPolicy,
+    ) -> Result<Self> {
+        let server_name = server_name(address)?;
+        let address = std::net::ToSocketAddrs::to_socket_addrs(address)?
+            .next()
+            .ok_or(anyhow!("Couldn't get socket addr."))?;
+        let runtime = Self::runtime()?;
+        let link = QuicLink::connect(
+            runtime.handle().clone(),
+            address,
+            server_name,
+            format,
+            certs,
+            timeouts,
+            reconnect,
+        )?;
+        Ok(Self::with_link(runtime, link))
+    }
+
+    pub fn on_state_change(&mut self, callback: impl FnMut(ConnectionState) + Send + 'static) {
+        self.link.backoff.set_callback(callback);
+    }
+
+    /// Where the endpoint is bound
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.link.endpoint.local_addr()
+    }
+
+    /// Biggest datagram the current connection takes, if there is one
+    pub fn max_datagram_size(&self) -> Option<usize> {
+        self.link.max_datagram_size()
+    }
+
+    pub fn format(&self) -> PcmFormat {
+        self.link.format
+    }
+}
+
+impl DatagramSocket for QuicSocket {
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.runtime.block_on(self.link.send(buf))
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let recv = self.link.recv_from(buf);
+        match self.read_timeout {
+            Some(timeout) => self
+                .runtime
+                .block_on(async { tokio::time::timeout(timeout, recv).await })
+                .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?,
+            None => self.runtime.block_on(recv),
+        }
+    }
+
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(self.read_timeout)
+    }
+
+    /// Covers waiting for a peer to connect too, nothing is lost when it runs out
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.read_timeout = timeout;
+        Ok(())
+    }
+
+    fn reopen(&mut self) -> io::Result<()> {
+        self.link.reopen();
+        Ok(())
+    }
+}
+
+/// [`QuicSocket`] on the tokio runtime it's made on, cancel-safe
+#[cfg(feature = "tokio")]
+pub struct AsyncQuicSocket {
+    link: QuicLink,
+    pub stats: Arc<QuicStats>,
+}
+
+#[cfg(feature = "tokio")]
+impl AsyncQuicSocket {
+    /// Listens on `address` for a sink to connect
+    pub fn listen(
+        address: SocketAddr,
+        format: PcmFormat,
+        certs: &QuicCerts,
+        timeouts: IdcTimeouts,
+        reconnect: ReconnectPolicy,
+    ) -> Result<Self> {
+        let link = QuicLink::listen(
+            Handle::try_current()?,
+            address,
+            format,
+            certs,
+            timeouts,
+            reconnect,
+        )?;
+        Ok(Self {
+            stats: link.stats.clone(),
+            link,
+        })
+    }
+
+    /// Connects to a source listening on `address`, reconnecting whenever that fails
+    pub async fn connect(
+        address: &str,
+        format: PcmFormat,
+        certs: &QuicCerts,
+        timeouts: IdcTimeouts,
+        reconnect: ReconnectPolicy,
+    ) -> Result<Self> {
+        let server_name = server_name(address)?;
+        let address = tokio::net::lookup_host(address)
+            .await?
+            .next()
+            .ok_or(anyhow!("Couldn't get socket addr."))?;
+        let link = QuicLink::connect(
+            Handle::try_current()?,
+            address,
+            server_name,
+            format,
+            certs,
+            timeouts,
+            reconnect,
+        )?;
+        Ok(Self {
+            stats: link.stats.clone(),
+            link,
+        })
+    }
+
+    pub fn on_state_change(&mut self, callback: impl FnMut(ConnectionState) + Send + 'static) {
+        self.link.backoff.set_callback(callback);
+    }
+
+    /// Where the endpoint is bound
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.link.endpoint.local_addr()
+    }
+
+    /// Biggest datagram the current connection takes, if there is one
+    pub fn max_datagram_size(&self) -> Option<usize> {
+        self.link.max_datagram_size()
+    }
+
+    pub fn format(&self) -> PcmFormat {
+        self.link.format
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl AsyncDatagramSocket for AsyncQuicSocket {
+    async fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.link.send(buf).await
+    }
+
+    async fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.link.recv_from(buf).await
+    }
+
+    async fn reopen(&mut self) -> io::Result<()> {
+        self.link.reopen();
+        Ok(())
+    }
+}
+
+/// Sends counted datagrams like [`crate::sinks::network::CountedUdpSinkPack`], but as
+/// many as it takes to empty the deque, each as big as the connection allows
+pub struct QuicSinkPack<S = QuicSocket> {
+    pub socket: S,
+    pub(crate) current_id: u64,
+    pub(crate) buffer: Vec<u8>,
+}
+
+impl<S> QuicSinkPack<S> {
+    pub fn new(socket: S, buffer_size: usize) -> Self {
+        Self {
+            socket,
+            current_id: 0,
+            buffer: vec![0; buffer_size],
+        }
+    }
+
+    /// Puts the next datagram with whole audio frames from the front of `data`
+    /// together in the buffer, without taking them out. Returns its length and
+    /// how much audio it holds
+    pub(crate) fn next_datagram(
+        &mut self,
+        data: &VecDeque<u8>,
+        max_datagram_size: Option<usize>,
+        block_align: usize,
+    ) -> Option<(usize, usize)> {
+        let limit = match max_datagram_size {
+            Some(limit) => usize::min(limit, self.buffer.len()),
+            None => self.buffer.len(),
+        };
+        let tag = self.current_id.to_be_bytes();
+        let n_audio = usize::min(limit.saturating_sub(tag.len()), data.len());
+        let n_audio = n_audio - n_audio % block_align;
+        if n_audio == 0 {
+            return None;
+        }
+        self.buffer[..tag.len()].copy_from_slice(&tag);
+        for (byte, value) in self.buffer[tag.len()..tag.len() + n_audio]
+            .iter_mut()
+            .zip(data)
+        {
+            *byte = *value;
+        }
+        Some((tag.len() + n_audio, n_audio))
+    }
+}
+
+impl SendAudio for QuicSinkPack {
+    fn send_from_deque(&mut self, data: &mut VecDeque<u8>) -> Result<()> {
+        let block_align = self.socket.format().block_align();
+        loop {
+            let max_datagram_size = self.socket.max_datagram_size();
+            let Some((n_sent, n_audio)) = self.next_datagram(data, max_datagram_size, block_align)
+            else {
+                return Ok(());
+            };
+            self.socket.send(&self.buffer[..n_sent])?;
+            data.drain(..n_audio);
+            self.current_id += 1;
+        }
+    }
+}
+
+impl Restart for QuicSinkPack {
+    fn restart(&mut self) -> Result<()> {
+        self.current_id = 0;
+        self.socket.reopen()?;
+        Ok(())
+    }
+}
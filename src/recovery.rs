@@ -0,0 +1,466 @@
+use std::{
+    fmt, io,
+    str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use log::warn;
+
+use crate::{
+    Args, Restart,
+    endpoint::{Endpoint, ON_DEVICE_LOST, ON_FATAL, ON_TRANSIENT},
+    idc::ReconnectPolicy,
+};
+
+/// How long an endpoint that ignores its errors waits before trying again
+const IGNORE_IDLE: Duration = Duration::from_millis(5);
+
+/// How bad an error of a source or a sink is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// The network hiccupped, like a refused datagram or a reset connection
+    Transient,
+    /// The audio device went away, like when it's unplugged or disabled
+    DeviceLost,
+    /// Anything else
+    Fatal,
+}
+
+impl fmt::Display for ErrorClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Transient => "transient",
+            Self::DeviceLost => "device lost",
+            Self::Fatal => "fatal",
+        })
+    }
+}
+
+/// The audio device of a source or a sink is gone or not there yet
+#[derive(Debug)]
+pub struct DeviceLost(pub String);
+
+impl fmt::Display for DeviceLost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for DeviceLost {}
+
+/// No audio device has the name a source or a sink was given
+#[derive(Debug)]
+pub struct DeviceMissing(pub String);
+
+impl fmt::Display for DeviceMissing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "No device name contains: {:?}", self.0)
+    }
+}
+
+impl std::error::Error for DeviceMissing {}
+
+/// A source or a sink failed with `cause` and its policy says to build the
+/// pipeline again
+#[derive(Debug)]
+pub struct RestartPipeline {
+    pub class: ErrorClass,
+    pub cause: anyhow::Error,
+}
+
+impl RestartPipeline {
+    pub fn wrap(cause: anyhow::Error, class: ErrorClass) -> anyhow::Error {
+        Self { class, cause }.into()
+    }
+}
+
+impl fmt::Display for RestartPipeline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} error, building the pipeline again", self.class)
+    }
+}
+
+impl std::error::Error for RestartPipeline {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.cause.as_ref())
+    }
+}
+
+/// Whether anything in `err` asks for the pipeline to be built again
+pub fn restarts_pipeline(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| cause.is::<RestartPipeline>())
+}
+
+/// Whether `err` is one that building the pipeline again only has to wait out,
+/// like a port the last build didn't let go of yet or a device that was
+/// unplugged. When building it the first time, they're mistakes
+pub fn passes_on_rebuild(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        cause.is::<DeviceMissing>()
+            || cause
+                .downcast_ref::<io::Error>()
+                .is_some_and(|err| err.kind() == io::ErrorKind::AddrInUse)
+    })
+}
+
+/// Looks through everything that caused `err` for what kind of error it is
+pub fn classify(err: &anyhow::Error) -> ErrorClass {
+    if err.chain().any(|cause| cause.is::<DeviceLost>()) {
+        return ErrorClass::DeviceLost;
+    }
+    let transient = err.chain().any(|cause| {
+        cause.downcast_ref::<io::Error>().is_some_and(|err| {
+            matches!(
+                err.kind(),
+                io::ErrorKind::ConnectionRefused
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::NotConnected
+                    | io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::TimedOut
+                    | io::ErrorKind::WouldBlock
+                    | io::ErrorKind::Interrupted
+                    | io::ErrorKind::HostUnreachable
+                    | io::ErrorKind::NetworkUnreachable
+                    | io::ErrorKind::NetworkDown
+            )
+        })
+    });
+    if transient {
+        ErrorClass::Transient
+    } else {
+        ErrorClass::Fatal
+    }
+}
+
+/// What a source or a sink does about one class of errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum, serde::Deserialize))]
+#[cfg_attr(feature = "cli", serde(rename_all = "kebab-case"))]
+pub enum Recovery {
+    /// Carry on as if nothing happened
+    Ignore,
+    /// Restart the source or the sink that failed, with the idc reconnect backoff
+    Restart,
+    /// Build the whole pipeline again, with the idc reconnect backoff
+    RestartPipeline,
+    /// Stop with the error
+    Exit,
+}
+
+/// A recovery that doesn't exist
+#[derive(Debug)]
+pub struct UnknownRecovery(String);
+
+impl fmt::Display for UnknownRecovery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "expected ignore, restart, restart-pipeline or exit, got {:?}",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for UnknownRecovery {}
+
+/// Same names as on the command line
+impl FromStr for Recovery {
+    type Err = UnknownRecovery;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "ignore" => Ok(Self::Ignore),
+            "restart" => Ok(Self::Restart),
+            "restart-pipeline" => Ok(Self::RestartPipeline),
+            "exit" => Ok(Self::Exit),
+            _ => Err(UnknownRecovery(name.to_owned())),
+        }
+    }
+}
+
+/// What one source or sink does about each class of errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecoveryPolicy {
+    pub transient: Recovery,
+    pub device_lost: Recovery,
+    pub fatal: Recovery,
+}
+
+/// Same as the command line defaults
+impl Default for RecoveryPolicy {
+    fn default() -> Self {
+        Self {
+            transient: Recovery::Ignore,
+            device_lost: Recovery::RestartPipeline,
+            fatal: Recovery::Exit,
+        }
+    }
+}
+
+impl RecoveryPolicy {
+    pub fn from_args(args: &Args) -> Self {
+        Self {
+            transient: args.on_transient,
+            device_lost: args.on_device_lost,
+            fatal: args.on_fatal,
+        }
+    }
+
+    /// The same for every class
+    pub const fn uniform(recovery: Recovery) -> Self {
+        Self {
+            transient: recovery,
+            device_lost: recovery,
+            fatal: recovery,
+        }
+    }
+
+    /// Takes whatever the endpoint at `url` overrides
+    pub fn with_url(mut self, url: &str) -> Result<Self> {
+        let endpoint = Endpoint::parse(url)?;
+        if let Some(recovery) = endpoint.param(ON_TRANSIENT.name)? {
+            self.transient = recovery;
+        }
+        if let Some(recovery) = endpoint.param(ON_DEVICE_LOST.name)? {
+            self.device_lost = recovery;
+        }
+        if let Some(recovery) = endpoint.param(ON_FATAL.name)? {
+            self.fatal = recovery;
+        }
+        Ok(self)
+    }
+
+    pub fn recovery(&self, class: ErrorClass) -> Recovery {
+        match class {
+            ErrorClass::Transient => self.transient,
+            ErrorClass::DeviceLost => self.device_lost,
+            ErrorClass::Fatal => self.fatal,
+        }
+    }
+}
+
+/// What became of an error handed to [`Recoverer`]
+pub(crate) enum Recovered {
+    /// Try again right away
+    Ignored,
+    /// The backoff is over, restart the endpoint
+    Restart,
+    /// Out of attempts, the endpoint is done for
+    GaveUp(anyhow::Error),
+    /// The pipeline has to stop, with a [`RestartPipeline`] in the error if it
+    /// should be built again
+    Escalate(anyhow::Error),
+}
+
+/// Applies the policy of one source or sink to its errors
+pub(crate) struct Recoverer {
+    /// Like "sink" or "mix 1", for the log
+    name: String,
+    policy: RecoveryPolicy,
+    reconnect: ReconnectPolicy,
+    failures: u32,
+    n_ignored: u64,
+}
+
+impl Recoverer {
+    pub fn new(
+        name: impl Into<String>,
+        policy: RecoveryPolicy,
+        reconnect: ReconnectPolicy,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            policy,
+            reconnect,
+            failures: 0,
+            n_ignored: 0,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The endpoint did its job again
+    pub fn succeeded(&mut self) {
+        self.failures = 0;
+    }
+
+    /// Decides what to do about `err`, waiting out the backoff before a restart
+    /// unless `stop` is set meanwhile
+    pub fn recover(&mut self, err: anyhow::Error, stop: &AtomicBool) -> Recovered {
+        // Another endpoint already decided that
+        if restarts_pipeline(&err) {
+            return Recovered::Escalate(err);
+        }
+        let class = classify(&err);
+        match self.policy.recovery(class) {
+            Recovery::Ignore => {
+                self.n_ignored += 1;
+                // Don't flood the log with an error that keeps coming
+                if self.n_ignored.is_power_of_two() {
+                    warn!(
+                        "Ignored {} errors of the {} so far, the last one {class}: {err:#}",
+                        self.n_ignored, self.name
+                    );
+                }
+                thread::sleep(IGNORE_IDLE);
+                Recovered::Ignored
+            }
+            Recovery::Restart => {
+                self.failures += 1;
+                if self
+                    .reconnect
+                    .max_attempts
+                    .is_some_and(|max_attempts| self.failures > max_attempts)
+                {
+                    let failures = self.failures;
+                    return Recovered::GaveUp(
+                        err.context(format!("Gave up after {failures} failures in a row")),
+                    );
+                }
+                let delay = self.reconnect.delay(self.failures);
+                warn!(
+                    "The {} failed ({class}), restarting it in {delay:?}: {err:#}",
+                    self.name
+                );
+                let deadline = Instant::now() + delay;
+                while Instant::now() < deadline && !stop.load(Ordering::Acquire) {
+                    thread::sleep(IGNORE_IDLE.min(deadline - Instant::now()));
+                }
+                Recovered::Restart
+            }
+            Recovery::RestartPipeline => Recovered::Escalate(RestartPipeline::wrap(err, class)),
+            Recovery::Exit => Recovered::Escalate(err),
+        }
+    }
+
+    /// Restarts `endpoint`, handing what that fails with to [`Self::recover`]
+    /// and trying again for as long as that says so. Returns what should end
+    /// the endpoint, nothing if it's restarted or its errors are ignored
+    pub fn restart<R: Restart + ?Sized>(
+        &mut self,
+        endpoint: &mut R,
+        stop: &AtomicBool,
+    ) -> Option<Recovered> {
+        loop {
+            let Err(err) = endpoint.restart() else {
+                return None;
+            };
+            match self.recover(err, stop) {
+                Recovered::Restart if !stop.load(Ordering::Acquire) => {}
+                Recovered::Ignored | Recovered::Restart => return None,
+                ended => return Some(ended),
+            }
+        }
+    }
+
+    /// Whether an endpoint that comes and goes on its own, like a tee, would
+    /// rather go on without being opened than stop the pipeline over `err`
+    pub fn can_go_without(&self, err: &anyhow::Error) -> bool {
+        !restarts_pipeline(err)
+            && matches!(
+                self.policy.recovery(classify(err)),
+                Recovery::Ignore | Recovery::Restart
+            )
+    }
+
+    /// What stops the pipeline when the endpoint couldn't be opened. There's
+    /// nothing to ignore or restart then, so those build the pipeline again
+    pub fn open_failed(&self, err: anyhow::Error) -> anyhow::Error {
+        if restarts_pipeline(&err) {
+            return err;
+        }
+        let class = classify(&err);
+        match self.policy.recovery(class) {
+            Recovery::Exit => err,
+            _ => RestartPipeline::wrap(err, class),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::{Context, anyhow};
+
+    use super::*;
+
+    fn io_error(kind: io::ErrorKind) -> anyhow::Error {
+        anyhow::Error::new(io::Error::from(kind)).context("Couldn't send")
+    }
+
+    #[test]
+    fn classifies_by_every_cause() {
+        for kind in [
+            io::ErrorKind::ConnectionRefused,
+            io::ErrorKind::ConnectionReset,
+            io::ErrorKind::TimedOut,
+            io::ErrorKind::WouldBlock,
+        ] {
+            assert_eq!(classify(&io_error(kind)), ErrorClass::Transient);
+        }
+        assert_eq!(
+            classify(&io_error(io::ErrorKind::PermissionDenied)),
+            ErrorClass::Fatal
+        );
+        assert_eq!(classify(&anyhow!("Bad format")), ErrorClass::Fatal);
+
+        let lost = Err::<(), _>(DeviceLost("Unplugged".into()))
+            .context("Couldn't write")
+            .unwrap_err();
+        assert_eq!(classify(&lost), ErrorClass::DeviceLost);
+        // Not a device that went away, just one that's not there
+        let missing = anyhow::Error::new(DeviceMissing("mic".into()));
+        assert_eq!(classify(&missing), ErrorClass::Fatal);
+    }
+
+    #[test]
+    fn only_rebuilds_wait_out_missing_devices_and_taken_ports() {
+        assert!(passes_on_rebuild(&anyhow::Error::new(DeviceMissing(
+            "mic".into()
+        ))));
+        assert!(passes_on_rebuild(&io_error(io::ErrorKind::AddrInUse)));
+        assert!(!passes_on_rebuild(&io_error(
+            io::ErrorKind::AddrNotAvailable
+        )));
+        assert!(!passes_on_rebuild(&anyhow::Error::new(DeviceLost(
+            "Unplugged".into()
+        ))));
+    }
+
+    #[test]
+    fn restart_pipeline_is_found_under_context() {
+        let err =
+            RestartPipeline::wrap(anyhow!("Gone"), ErrorClass::DeviceLost).context("Sink failed");
+        assert!(restarts_pipeline(&err));
+        assert!(!restarts_pipeline(&anyhow!("Gone")));
+    }
+
+    #[test]
+    fn url_overrides_only_what_it_sets() {
+        let policy = RecoveryPolicy::default()
+            .with_url("udp://0.0.0.0:1234?on-fatal=restart&on-transient=exit")
+            .unwrap();
+        assert_eq!(
+            policy,
+            RecoveryPolicy {
+                transient: Recovery::Exit,
+                device_lost: Recovery::RestartPipeline,
+                fatal: Recovery::Restart,
+            }
+        );
+        assert_eq!(
+            RecoveryPolicy::default().with_url("mic").unwrap(),
+            RecoveryPolicy::default()
+        );
+        assert!(
+            RecoveryPolicy::default()
+                .with_url("mic?on-fatal=retry")
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,342 @@
+use std::{
+    cell::UnsafeCell,
+    collections::VecDeque,
+    sync::{
+        Arc, OnceLock,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    thread::{self, Thread},
+    time::Duration,
+};
+
+/// State shared by both ends of the ring
+struct Shared {
+    buf: Box<[UnsafeCell<u8>]>,
+    block_align: usize,
+    /// Total bytes ever written and read, wrapping, the difference is what's buffered
+    written: AtomicUsize,
+    read: AtomicUsize,
+    /// The consumer should throw away everything that's buffered
+    clear_requested: AtomicBool,
+    /// One of the ends is gone
+    closed: AtomicBool,
+    /// Threads to wake up when there's something to read or room to write
+    reader: OnceLock<Thread>,
+    writer: OnceLock<Thread>,
+}
+
+// The producer only touches the free part of `buf` and the consumer only the
+// buffered part, with `written` and `read` handing bytes over between them
+unsafe impl Sync for Shared {}
+
+impl Shared {
+    fn capacity(&self) -> usize {
+        self.buf.len()
+    }
+
+    fn len(&self) -> usize {
+        self.written
+            .load(Ordering::Acquire)
+            .wrapping_sub(self.read.load(Ordering::Acquire))
+    }
+
+    fn ptr(&self) -> *mut u8 {
+        UnsafeCell::raw_get(self.buf.as_ptr())
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        for thread in [self.reader.get(), self.writer.get()].into_iter().flatten() {
+            thread.unpark();
+        }
+    }
+}
+
+/// Fixed-size single producer single consumer byte queue that only ever moves
+/// whole audio frames, so the two ends can't get out of step
+pub fn channel(capacity: usize, block_align: usize) -> (Producer, Consumer) {
+    let capacity = capacity - capacity % block_align;
+    let shared = Arc::new(Shared {
+        buf: (0..capacity).map(|_| UnsafeCell::new(0)).collect(),
+        block_align,
+        written: AtomicUsize::new(0),
+        read: AtomicUsize::new(0),
+        clear_requested: AtomicBool::new(false),
+        closed: AtomicBool::new(false),
+        reader: OnceLock::new(),
+        writer: OnceLock::new(),
+    });
+    (
+        Producer {
+            shared: shared.clone(),
+        },
+        Consumer { shared },
+    )
+}
+
+pub struct Producer {
+    shared: Arc<Shared>,
+}
+
+impl Producer {
+    pub fn capacity(&self) -> usize {
+        self.shared.capacity()
+    }
+
+    /// Bytes buffered right now
+    pub fn len(&self) -> usize {
+        self.shared.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether the consumer is gone
+    pub fn is_closed(&self) -> bool {
+        self.shared.closed.load(Ordering::Acquire)
+    }
+
+    /// Copies as many whole frames from the start of `parts` as fit, returns how many bytes that was
+    fn write_parts(&mut self, parts: [&[u8]; 2]) -> usize {
+        let shared = &*self.shared;
+        let written = shared.written.load(Ordering::Relaxed);
+        let read = shared.read.load(Ordering::Acquire);
+        let free = shared.capacity() - written.wrapping_sub(read);
+        let n_written = usize::min(free, parts[0].len() + parts[1].len());
+        let n_written = n_written - n_written % shared.block_align;
+
+        let mut pos = written % shared.capacity().max(1);
+        let mut left = n_written;
+        for part in parts {
+            let mut part = &part[..usize::min(part.len(), left)];
+            left -= part.len();
+            while !part.is_empty() {
+                let n = usize::min(part.len(), shared.capacity() - pos);
+                // SAFETY: `pos..pos + n` is in the free part, which the consumer doesn't touch
+                unsafe { std::ptr::copy_nonoverlapping(part.as_ptr(), shared.ptr().add(pos), n) };
+                part = &part[n..];
+                pos = (pos + n) % shared.capacity();
+            }
+        }
+
+        if n_written > 0 {
+            shared
+                .written
+                .store(written.wrapping_add(n_written), Ordering::Release);
+            if let Some(reader) = shared.reader.get() {
+                reader.unpark();
+            }
+        }
+        n_written
+    }
+
+    /// Writes as many whole frames of `data` as fit, returns how many bytes that was
+    pub fn write(&mut self, data: &[u8]) -> usize {
+        self.write_parts([data, &[]])
+    }
+
+    /// Moves as many whole frames from the front of `data` as fit, returns how many bytes that was
+    pub fn write_from_deque(&mut self, data: &mut VecDeque<u8>) -> usize {
+        let (front, back) = data.as_slices();
+        let n_written = self.write_parts([front, back]);
+        data.drain(..n_written);
+        n_written
+    }
+
+    /// Asks the consumer to throw away everything buffered before its next read
+    pub fn request_clear(&self) {
+        self.shared.clear_requested.store(true, Ordering::Release);
+    }
+
+    /// Sleeps until the consumer reads something or `timeout` passes.
+    /// Only wakes up early for the thread that calls this first
+    pub fn wait(&self, timeout: Duration) {
+        let shared = &*self.shared;
+        shared.writer.get_or_init(thread::current);
+        if shared.len() + shared.block_align > shared.capacity() && !self.is_closed() {
+            thread::park_timeout(timeout);
+        }
+    }
+}
+
+impl Drop for Producer {
+    fn drop(&mut self) {
+        self.shared.close();
+    }
+}
+
+pub struct Consumer {
+    shared: Arc<Shared>,
+}
+
+impl Consumer {
+    pub fn capacity(&self) -> usize {
+        self.shared.capacity()
+    }
+
+    /// Bytes buffered right now
+    pub fn len(&self) -> usize {
+        self.shared.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether the producer is gone
+    pub fn is_closed(&self) -> bool {
+        self.shared.closed.load(Ordering::Acquire)
+    }
+
+    /// Appends up to `max` bytes of whole frames to `out`, returns how many bytes that was
+    pub fn read_to_deque(&mut self, out: &mut VecDeque<u8>, max: usize) -> usize {
+        let shared = &*self.shared;
+        let written = shared.written.load(Ordering::Acquire);
+        let mut read = shared.read.load(Ordering::Relaxed);
+        if shared.clear_requested.swap(false, Ordering::AcqRel) {
+            read = written;
+        }
+        let n_read = usize::min(written.wrapping_sub(read), max);
+        let n_read = n_read - n_read % shared.block_align;
+
+        let mut pos = read % shared.capacity().max(1);
+        let mut left = n_read;
+        while left > 0 {
+            let n = usize::min(left, shared.capacity() - pos);
+            // SAFETY: `pos..pos + n` is in the buffered part, which the producer doesn't touch
+            let part = unsafe { std::slice::from_raw_parts(shared.ptr().add(pos), n) };
+            out.extend(part);
+            left -= n;
+            pos = (pos + n) % shared.capacity();
+        }
+
+        shared
+            .read
+            .store(read.wrapping_add(n_read), Ordering::Release);
+        if let Some(writer) = shared.writer.get() {
+            writer.unpark();
+        }
+        n_read
+    }
+
+    /// Sleeps until there's something to read or `timeout` passes.
+    /// Only wakes up early for the thread that calls this first
+    pub fn wait(&self, timeout: Duration) {
+        let shared = &*self.shared;
+        shared.reader.get_or_init(thread::current);
+        if shared.len() < shared.block_align && !self.is_closed() {
+            thread::park_timeout(timeout);
+        }
+    }
+}
+
+impl Drop for Consumer {
+    fn drop(&mut self) {
+        self.shared.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+
+    const BLOCK_ALIGN: usize = 4;
+
+    #[test]
+    fn wraps_around() {
+        let (mut producer, mut consumer) = channel(12, BLOCK_ALIGN);
+        let mut out = VecDeque::new();
+        for round in 0..10u8 {
+            let frames: Vec<u8> = (0..8).map(|byte| round.wrapping_mul(8) + byte).collect();
+            assert_eq!(producer.write(&frames), 8);
+            out.clear();
+            assert_eq!(consumer.read_to_deque(&mut out, usize::MAX), 8);
+            assert_eq!(out, frames);
+        }
+    }
+
+    #[test]
+    fn only_moves_whole_frames() {
+        // Rounded down to whole frames
+        let (mut producer, mut consumer) = channel(10, BLOCK_ALIGN);
+        assert_eq!(producer.capacity(), 8);
+        assert_eq!(producer.write(&[1; 7]), 4);
+        assert_eq!(producer.write(&[2; 12]), 4);
+        assert_eq!(producer.write(&[3; 4]), 0);
+
+        let mut out = VecDeque::new();
+        assert_eq!(consumer.read_to_deque(&mut out, 6), 4);
+        assert_eq!(out, [1; 4]);
+        let mut data = VecDeque::from(vec![4; 6]);
+        assert_eq!(producer.write_from_deque(&mut data), 4);
+        assert_eq!(data.len(), 2);
+    }
+
+    #[test]
+    fn clearing_drops_what_was_buffered() {
+        let (mut producer, mut consumer) = channel(16, BLOCK_ALIGN);
+        producer.write(&[1; 8]);
+        producer.request_clear();
+        let mut out = VecDeque::new();
+        assert_eq!(consumer.read_to_deque(&mut out, usize::MAX), 0);
+        producer.write(&[2; 4]);
+        assert_eq!(consumer.read_to_deque(&mut out, usize::MAX), 4);
+        assert_eq!(out, [2; 4]);
+    }
+
+    #[test]
+    fn hands_over_everything_between_threads() {
+        const N_BYTES: usize = 1 << 20;
+        // Small, so that both ends keep finding it full or empty
+        let (mut producer, mut consumer) = channel(64, BLOCK_ALIGN);
+        let writer = thread::spawn(move || {
+            let mut data: VecDeque<u8> = (0..N_BYTES).map(|index| index as u8).collect();
+            while !data.is_empty() {
+                if producer.write_from_deque(&mut data) == 0 {
+                    producer.wait(Duration::from_millis(100));
+                }
+            }
+        });
+        let mut out = VecDeque::new();
+        while out.len() < N_BYTES {
+            if consumer.read_to_deque(&mut out, 12) == 0 {
+                consumer.wait(Duration::from_millis(100));
+            }
+        }
+        writer.join().unwrap();
+        assert!(
+            out.iter()
+                .enumerate()
+                .all(|(index, &byte)| byte == index as u8)
+        );
+    }
+
+    #[test]
+    fn waiting_ends_when_the_other_end_is_dropped() {
+        let (producer, consumer) = channel(16, BLOCK_ALIGN);
+        let reader = thread::spawn(move || {
+            let start = Instant::now();
+            consumer.wait(Duration::from_secs(10));
+            (start.elapsed(), consumer.is_closed())
+        });
+        thread::sleep(Duration::from_millis(50));
+        drop(producer);
+        let (waited, closed) = reader.join().unwrap();
+        assert!(waited < Duration::from_secs(5) && closed);
+
+        let (mut producer, consumer) = channel(16, BLOCK_ALIGN);
+        producer.write(&[0; 16]);
+        let writer = thread::spawn(move || {
+            let start = Instant::now();
+            producer.wait(Duration::from_secs(10));
+            (start.elapsed(), producer.is_closed())
+        });
+        thread::sleep(Duration::from_millis(50));
+        drop(consumer);
+        let (waited, closed) = writer.join().unwrap();
+        assert!(waited < Duration::from_secs(5) && closed);
+    }
+}
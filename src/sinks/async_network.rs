@@ -0,0 +1,373 @@
+use std::{
+    collections::VecDeque,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, anyhow, bail};
+use log::{debug, warn};
+use tokio::net::{TcpListener, TcpStream};
+
+#[cfg(feature = "quic")]
+use crate::quic::{AsyncQuicSocket, QuicSinkPack};
+use crate::{
+    AsyncRestart,
+    crypto::Sealer,
+    idc::{self, Backoff, ConnectionState, FrameEncoder, HEARTBEAT, IdcTimeouts, ReconnectPolicy},
+    network_utils::{AsyncDatagramSocket, ClientQueue},
+    pcm::PcmFormat,
+    sinks::{
+        AsyncSendAudio,
+        network::{CountedUdpSinkPack, IdcClients, UdpSinkPack, connection_lost},
+    },
+};
+
+/// Copies the first `out.len()` bytes of `data` without taking them out
+fn peek(data: &VecDeque<u8>, out: &mut [u8]) {
+    for (byte, value) in out.iter_mut().zip(data) {
+        *byte = *value;
+    }
+}
+
+impl<S: AsyncDatagramSocket + Send> AsyncSendAudio for UdpSinkPack<S> {
+    async fn send_from_deque(&mut self, data: &mut VecDeque<u8>) -> Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let n_sent = usize::min(self.buffer.len(), data.len());
+        if n_sent == self.buffer.len() {
+            debug!("Splitting datagram!");
+        }
+        peek(data, &mut self.buffer[..n_sent]);
+        self.socket.send(&self.buffer[..n_sent]).await?;
+        data.drain(..n_sent);
+
+        Ok(())
+    }
+}
+
+impl<S: AsyncDatagramSocket + Send> AsyncRestart for UdpSinkPack<S> {
+    async fn restart(&mut self) -> Result<()> {
+        self.socket.reopen().await?;
+        Ok(())
+    }
+}
+
+impl<S: AsyncDatagramSocket + Send> AsyncSendAudio for CountedUdpSinkPack<S> {
+    async fn send_from_deque(&mut self, data: &mut VecDeque<u8>) -> Result<()> {
+        let Some((n_sent, n_audio)) = self.next_datagram(data) else {
+            return Ok(());
+        };
+        self.socket.send(&self.buffer[..n_sent]).await?;
+        data.drain(..n_audio);
+
+        self.current_id += 1;
+
+        Ok(())
+    }
+}
+
+impl<S: AsyncDatagramSocket + Send> AsyncRestart for CountedUdpSinkPack<S> {
+    async fn restart(&mut self) -> Result<()> {
+        self.current_id = 0;
+        self.socket.reopen().await?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "quic")]
+impl AsyncSendAudio for QuicSinkPack<AsyncQuicSocket> {
+    async fn send_from_deque(&mut self, data: &mut VecDeque<u8>) -> Result<()> {
+        let block_align = self.socket.format().block_align();
+        loop {
+            let max_datagram_size = self.socket.max_datagram_size();
+            let Some((n_sent, n_audio)) = self.next_datagram(data, max_datagram_size, block_align)
+            else {
+                return Ok(());
+            };
+            self.socket.send(&self.buffer[..n_sent]).await?;
+            data.drain(..n_audio);
+            self.current_id += 1;
+        }
+    }
+}
+
+#[cfg(feature = "quic")]
+impl AsyncRestart for QuicSinkPack<AsyncQuicSocket> {
+    async fn restart(&mut self) -> Result<()> {
+        self.current_id = 0;
+        self.socket.reopen().await?;
+        Ok(())
+    }
+}
+
+/// Lets [`ClientQueue::flush`] write to a tokio stream without waiting
+struct TryWrite<'a>(&'a TcpStream);
+
+impl io::Write for TryWrite<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.try_write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+enum Connection {
+    Down,
+    /// Kept here so that a cancelled call doesn't abort the attempt
+    Connecting(Pin<Box<dyn Future<Output = io::Result<TcpStream>> + Send>>),
+    Up(TcpStream),
+}
+
+/// [`IdcSinkPack`](super::network::IdcSinkPack) on tokio. Like that one it
+/// never waits for the network: audio is dropped while there's no connection,
+/// and whole frames are skipped once more than the latency budget is queued
+pub struct AsyncIdcSinkPack {
+    address: SocketAddr,
+    connection: Connection,
+    backoff: Backoff,
+    buffer: Vec<u8>,
+    block_align: usize,
+    encoder: FrameEncoder,
+    queue: ClientQueue,
+    /// The latency budget in bytes
+    queue_limit: usize,
+    /// Bytes skipped for being over the latency budget
+    pub skipped: u64,
+    timeouts: IdcTimeouts,
+    last_heard: Instant,
+    last_sent: Instant,
+}
+
+impl AsyncIdcSinkPack {
+    pub async fn new(
+        address: impl tokio::net::ToSocketAddrs,
+        buffer_size: usize,
+        format: PcmFormat,
+        latency_budget: Duration,
+        sealer: Option<Sealer>,
+        timeouts: IdcTimeouts,
+        reconnect: ReconnectPolicy,
+    ) -> Result<Self> {
+        let block_align = format.block_align();
+        if buffer_size < block_align {
+            bail!("Chunks of {buffer_size} bytes can't fit a {block_align} bytes long audio frame");
+        }
+        let address = tokio::net::lookup_host(address)
+            .await?
+            .next()
+            .ok_or(anyhow!("Couldn't get socket addr."))?;
+        let bytes_per_second = (format.sample_rate * block_align) as f64;
+        Ok(Self {
+            address,
+            connection: Connection::Down,
+            backoff: Backoff::new(reconnect, format!("idc sink to {address}")),
+            buffer: vec![0; buffer_size.min(idc::MAX_PAYLOAD)],
+            block_align,
+            encoder: FrameEncoder::new(sealer),
+            queue: ClientQueue::new(),
+            queue_limit: (bytes_per_second * latency_budget.as_secs_f64()) as usize,
+            skipped: 0,
+            timeouts,
+            last_heard: Instant::now(),
+            last_sent: Instant::now(),
+        })
+    }
+
+    pub fn on_state_change(&mut self, callback: impl FnMut(ConnectionState) + Send + 'static) {
+        self.backoff.set_callback(callback);
+    }
+
+    fn drop_connection(&mut self) {
+        self.connection = Connection::Down;
+        self.queue = ClientQueue::new();
+        self.backoff.lost();
+    }
+
+    /// Starts connecting when it's time to, and checks on the connection without waiting
+    async fn poll_connection(&mut self) -> Result<()> {
+        if matches!(self.connection, Connection::Down) && self.backoff.ready() {
+            self.backoff.attempt()?;
+            let connect =
+                tokio::time::timeout(self.timeouts.peer_timeout, TcpStream::connect(self.address));
+            self.connection = Connection::Connecting(Box::pin(async move {
+                connect
+                    .await
+                    .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()))
+            }));
+        }
+
+        if let Connection::Connecting(connecting) = &mut self.connection {
+            let result = tokio::select! {
+                biased;
+                result = connecting => Some(result),
+                () = std::future::ready(()) => None,
+            };
+            match result {
+                None => {}
+                Some(Ok(stream)) => {
+                    self.timeouts.configure(&socket2::SockRef::from(&stream))?;
+                    self.connection = Connection::Up(stream);
+                    self.backoff.connected();
+                    // Give the new connection the full timeout to show it's alive
+                    self.last_heard = Instant::now();
+                    self.last_sent = Instant::now();
+                }
+                Some(Err(err)) => {
+                    debug!("Couldn't connect to {}: {err}", self.address);
+                    self.connection = Connection::Down;
+                }
+            }
+        }
+
+        if let Connection::Up(stream) = &self.connection {
+            let mut incoming = [0; 64];
+            let alive = loop {
+                match stream.try_read(&mut incoming) {
+                    Ok(0) => break false,
+                    Ok(_) => self.last_heard = Instant::now(),
+                    Err(err) if connection_lost(&err) => break false,
+                    Err(_) => break true,
+                }
+            };
+            if !alive || self.last_heard.elapsed() >= self.timeouts.peer_timeout {
+                debug!(
+                    "Haven't heard from the source for {:?}",
+                    self.last_heard.elapsed()
+                );
+                self.drop_connection();
+            }
+        }
+        Ok(())
+    }
+}
+
+impl AsyncSendAudio for AsyncIdcSinkPack {
+    async fn send_from_deque(&mut self, data: &mut VecDeque<u8>) -> Result<()> {
+        self.poll_connection().await?;
+        let Connection::Up(stream) = &self.connection else {
+            // Couldn't send, just consume the data
+            let n_blocks = data.len() / self.block_align;
+            data.drain(..n_blocks * self.block_align);
+            return Ok(());
+        };
+
+        if data.len() > self.buffer.len() {
+            debug!("Splitting datagram!");
+        }
+        while data.len() >= self.block_align {
+            let n_sent = usize::min(self.buffer.len(), data.len());
+            let n_sent = n_sent - n_sent % self.block_align;
+            for (byte, value) in self.buffer.iter_mut().zip(data.drain(..n_sent)) {
+                *byte = value;
+            }
+            let mut frame = Vec::new();
+            self.encoder.encode(&self.buffer[..n_sent], &mut frame);
+            let n_skipped = self.queue.push(frame.into(), self.queue_limit);
+            if n_skipped > 0 {
+                self.skipped += n_skipped as u64;
+                debug!("Over the latency budget, skipped {n_skipped} bytes");
+            }
+        }
+        if self.queue.is_empty() && self.last_sent.elapsed() >= self.timeouts.keepalive {
+            self.queue.push(Arc::new(HEARTBEAT), usize::MAX);
+        }
+
+        match self.queue.flush(TryWrite(stream)) {
+            Ok(0) => {}
+            Ok(_) => self.last_sent = Instant::now(),
+            Err(err) => {
+                debug!("Couldn't send: {err}");
+                self.drop_connection();
+            }
+        }
+        Ok(())
+    }
+}
+
+impl AsyncRestart for AsyncIdcSinkPack {
+    async fn restart(&mut self) -> Result<()> {
+        self.drop_connection();
+        Ok(())
+    }
+}
+
+/// [`IdcServerSinkPack`](super::network::IdcServerSinkPack) on tokio, listens
+/// for any number of idc sources and sends the same stream to each of them
+pub struct AsyncIdcServerSinkPack {
+    listener: TcpListener,
+    clients: IdcClients,
+}
+
+impl AsyncIdcServerSinkPack {
+    pub async fn new(
+        address: impl tokio::net::ToSocketAddrs,
+        buffer_size: usize,
+        block_align: usize,
+        queue_limit: usize,
+        sealer: Option<Sealer>,
+        timeouts: IdcTimeouts,
+    ) -> Result<Self> {
+        let clients = IdcClients::new(buffer_size, block_align, queue_limit, sealer, timeouts)?;
+        Ok(Self {
+            listener: TcpListener::bind(address).await?,
+            clients,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn n_clients(&self) -> usize {
+        self.clients.len()
+    }
+
+    /// Takes every client that's waiting, without waiting for more
+    async fn accept_clients(&mut self) -> Result<()> {
+        loop {
+            let accepted = tokio::select! {
+                biased;
+                accepted = self.listener.accept() => accepted,
+                () = std::future::ready(()) => return Ok(()),
+            };
+            match accepted {
+                Ok((stream, address)) => {
+                    let socket = socket2::Socket::from(stream.into_std()?);
+                    self.clients.add(socket, Some(address))?;
+                }
+                Err(err) => {
+                    warn!("Couldn't accept a client: {err}");
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+impl AsyncSendAudio for AsyncIdcServerSinkPack {
+    async fn send_from_deque(&mut self, data: &mut VecDeque<u8>) -> Result<()> {
+        self.accept_clients().await?;
+        self.clients.send_from_deque(data)
+    }
+}
+
+/// Says goodbye to the clients whose sockets take it right away, waiting for
+/// the others would hold up the runtime
+impl Drop for AsyncIdcServerSinkPack {
+    fn drop(&mut self) {
+        self.clients.goodbye(Duration::ZERO);
+    }
+}
+
+impl AsyncRestart for AsyncIdcServerSinkPack {
+    async fn restart(&mut self) -> Result<()> {
+        self.clients.clear();
+        Ok(())
+    }
+}
@@ -1,4 +1,4 @@
-use std::collections::VecDeque;
+use std::{collections::VecDeque, thread, time::Duration};
 
 use anyhow::{Result, anyhow};
 
@@ -43,10 +43,11 @@ impl DeviceSinkPack {
 
 impl SendAudio for DeviceSinkPack {
     fn send_from_deque(&mut self, data: &mut VecDeque<u8>) -> Result<()> {
-        let mut frames_to_write =
-            self.audio_client
-                .get_available_space_in_frames()
-                .map_err(|err| anyhow!("Can't get available space: {err}"))? as usize;
+        let mut frames_to_write = self
+            .audio_client
+            .get_available_space_in_frames()
+            .map_err(|err| device_utils::device_error(err, "Can't get available space"))?
+            as usize;
         let blockalign = self.format.get_blockalign() as usize;
         if frames_to_write > data.len() / blockalign {
             frames_to_write = data.len() / blockalign;
@@ -56,11 +57,24 @@ impl SendAudio for DeviceSinkPack {
         }
         self.audio_render_client
             .write_to_device_from_deque(frames_to_write, data, None)
-            .map_err(|err| anyhow!("Couldn't write to device: {err}"))?;
+            .map_err(|err| device_utils::device_error(err, "Couldn't write to device"))?;
         Ok(())
     }
 }
 
+/// Lets what's buffered in the device play out before stopping the stream
+impl Drop for DeviceSinkPack {
+    fn drop(&mut self) {
+        if let Ok(n_padding) = self.audio_client.get_current_padding() {
+            let sample_rate = self.format.get_samplespersec().max(1);
+            thread::sleep(Duration::from_secs_f64(
+                n_padding as f64 / sample_rate as f64,
+            ));
+        }
+        let _ = self.audio_client.stop_stream();
+    }
+}
+
 impl Restart for DeviceSinkPack {
     fn restart(&mut self) -> Result<()> {
         self.audio_client
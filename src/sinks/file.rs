@@ -0,0 +1,105 @@
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, anyhow};
+
+use crate::{Restart, pcm::PcmFormat, sinks::SendAudio};
+
+/// How often the WAV header catches up with the length, so that a recording
+/// that was cut short still plays
+const HEADER_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Records to a WAV file, or to raw PCM if the name doesn't end in .wav
+pub struct FileSinkPack {
+    path: PathBuf,
+    file: BufWriter<File>,
+    format: PcmFormat,
+    wav: bool,
+    /// Bytes of audio written so far
+    written: u64,
+    header_updated: Instant,
+}
+
+impl FileSinkPack {
+    pub fn new(path: impl AsRef<Path>, format: PcmFormat) -> Result<Self> {
+        let path = path.as_ref().to_owned();
+        let wav = path
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("wav"));
+        let file = File::create(&path)
+            .map_err(|err| anyhow!("Couldn't create {}: {err}", path.display()))?;
+        let mut pack = Self {
+            path,
+            file: BufWriter::new(file),
+            format,
+            wav,
+            written: 0,
+            header_updated: Instant::now(),
+        };
+        if wav {
+            pack.file.write_all(&format.wav_header(0))?;
+        }
+        Ok(pack)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Bytes of audio written so far, without the header
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    /// Writes out everything buffered and, for a WAV, the length so far
+    pub fn flush(&mut self) -> Result<()> {
+        if self.wav {
+            // Past 4 GB players have to find the end on their own
+            let len = u32::try_from(self.written).unwrap_or(u32::MAX);
+            self.file.seek(SeekFrom::Start(0))?;
+            self.file.write_all(&self.format.wav_header(len))?;
+            self.file.seek(SeekFrom::End(0))?;
+            self.header_updated = Instant::now();
+        }
+        self.file
+            .flush()
+            .map_err(|err| anyhow!("Couldn't write to {}: {err}", self.path.display()))
+    }
+}
+
+impl SendAudio for FileSinkPack {
+    fn send_from_deque(&mut self, data: &mut VecDeque<u8>) -> Result<()> {
+        let n_written = data.len() - data.len() % self.format.block_align();
+        let (front, back) = data.as_slices();
+        let n_front = usize::min(front.len(), n_written);
+        self.file
+            .write_all(&front[..n_front])
+            .and_then(|()| self.file.write_all(&back[..n_written - n_front]))
+            .map_err(|err| anyhow!("Couldn't write to {}: {err}", self.path.display()))?;
+        data.drain(..n_written);
+        self.written += n_written as u64;
+
+        if self.wav && self.header_updated.elapsed() >= HEADER_INTERVAL {
+            self.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Keeps appending to the same file
+impl Restart for FileSinkPack {
+    fn restart(&mut self) -> Result<()> {
+        self.flush()
+    }
+}
+
+impl Drop for FileSinkPack {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
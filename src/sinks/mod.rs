@@ -1,54 +1,369 @@
-use std::collections::VecDeque;
+use std::{collections::VecDeque, time::Duration};
 
 use log::info;
 use wasapi::{Direction, WaveFormat};
 
-use anyhow::{Result, anyhow};
+use anyhow::{Result, anyhow, bail};
 
-use crate::{Args, SendAudioRestart, device_utils};
+use crate::{
+    Args, SendAudioRestart,
+    crypto::{self, SealedSocket, Sealer},
+    device_utils,
+    endpoint::{
+        BUFFER, COUNTED, Endpoint, IMPAIRMENTS, KEEPALIVE, LATENCY, LEGACY, NAME, ON_DEVICE_LOST,
+        ON_FATAL, ON_TRANSIENT, OVERFLOW, QUEUE, Registry, SIZE, Scheme, TIMEOUT,
+    },
+    idc::{IdcTimeouts, ReconnectPolicy},
+    impair::{ImpairedProxy, ImpairedSocket, Impairment, Proxied, proxy_address, resolve},
+    network_utils::{DatagramSocket, connected_udp_socket},
+    pcm::PcmFormat,
+};
 
+#[cfg(feature = "tokio")]
+pub mod async_network;
 pub mod device;
+pub mod file;
 pub mod network;
+pub mod web;
 
 pub trait SendAudio {
     fn send_from_deque(&mut self, data: &mut VecDeque<u8>) -> Result<()>;
 }
 
-pub fn from_args(args: &Args) -> Result<Box<dyn SendAudioRestart>> {
-    Ok(if let Some(address) = args.sink.strip_prefix("udp://") {
-        let buffer_size = args.datagram_size;
-        if args.counted_udp {
-            let pack = network::CountedUdpSinkPack::new(address, buffer_size)?;
-            info!("Sending to {address} datagrams of up to {buffer_size} bytes with loss checks");
-            Box::new(pack)
-        } else {
-            let pack = network::UdpSinkPack::new(address, buffer_size)?;
-            info!("Sending to {address} datagrams of up to {buffer_size} bytes");
-            Box::new(pack)
+/// [`SendAudio`] for tokio. Cancel-safe: whatever wasn't sent when the future
+/// is dropped is still in `data`, or in the pack to go out with the next call
+#[cfg(feature = "tokio")]
+pub trait AsyncSendAudio {
+    fn send_from_deque(
+        &mut self,
+        data: &mut VecDeque<u8>,
+    ) -> impl Future<Output = Result<()>> + Send;
+}
+
+pub(crate) fn udp_sink(
+    args: &Args,
+    socket: Box<dyn DatagramSocket>,
+    address: &str,
+    sealer: Option<Sealer>,
+) -> Result<Box<dyn SendAudioRestart>> {
+    let (socket, buffer_size): (Box<dyn DatagramSocket>, _) = match sealer {
+        Some(sealer) => {
+            info!("Sealing packets with the pre-shared key");
+            // Sealed datagrams still have to fit in --datagram-size
+            let buffer_size = args.datagram_size.saturating_sub(crypto::OVERHEAD);
+            if buffer_size == 0 {
+                bail!(
+                    "A datagram size of {} leaves no room for audio next to the {} bytes sealing adds",
+                    args.datagram_size,
+                    crypto::OVERHEAD
+                );
+            };
+            (Box::new(SealedSocket::sealing(socket, sealer)), buffer_size)
         }
-    } else if let Some(address) = args.sink.strip_prefix("idc://") {
-        let buffer_size = args.datagram_size;
-        let pack = network::IdcSinkPack::new(address, buffer_size)?;
-        info!("Sending to {address} datagrams of up to {buffer_size} bytes without caring");
-        Box::new(pack)
+        None => (socket, args.datagram_size),
+    };
+    if args.counted_udp {
+        let block_align = PcmFormat::from_args(args)?.block_align();
+        // The tag goes first, then whole frames
+        if buffer_size < 8 + block_align {
+            bail!(
+                "Counted datagrams of {buffer_size} bytes can't fit a {block_align} bytes long audio frame"
+            );
+        }
+        let pack = network::CountedUdpSinkPack::with_socket(socket, buffer_size, block_align);
+        info!("Sending to {address} datagrams of up to {buffer_size} bytes with loss checks");
+        Ok(Box::new(pack))
     } else {
-        let format = WaveFormat::new(
-            args.bits_per_sample,
-            args.bits_per_sample,
-            &wasapi::SampleType::Int,
-            args.sample_rate,
-            args.channels,
-            None,
-        );
-        let device = device_utils::find_device_by_name(Direction::Render, &args.sink)?;
-        let sink_pack = device::DeviceSinkPack::new(device, format)?;
-
-        let name = sink_pack
-            .device()
-            .get_friendlyname()
-            .map_err(|err| anyhow!("Couldn't get device name due to error: {err}"))?;
-        info!("Sending to {name}");
-
-        Box::new(sink_pack)
-    })
+        let pack = network::UdpSinkPack::with_socket(socket, buffer_size);
+        info!("Sending to {address} datagrams of up to {buffer_size} bytes");
+        Ok(Box::new(pack))
+    }
+}
+
+#[cfg(feature = "quic")]
+fn quic_sink(args: &Args, address: &str) -> Result<Box<dyn SendAudioRestart>> {
+    use crate::quic::{QuicCerts, QuicSinkPack, QuicSocket};
+
+    let buffer_size = args.datagram_size;
+    let socket = QuicSocket::connect(
+        address,
+        PcmFormat::from_args(args)?,
+        &QuicCerts::from_args(args)?,
+        IdcTimeouts::from_args(args),
+        ReconnectPolicy::from_args(args)?,
+    )?;
+    let pack = QuicSinkPack::new(socket, buffer_size);
+    info!("Sending to {address} QUIC datagrams of up to {buffer_size} bytes with loss checks");
+    Ok(Box::new(pack))
+}
+
+#[cfg(not(feature = "quic"))]
+fn quic_sink(_args: &Args, _address: &str) -> Result<Box<dyn SendAudioRestart>> {
+    Err(anyhow!(
+        "Built without QUIC support, enable the quic feature"
+    ))
+}
+
+fn open_udp(endpoint: &Endpoint, args: &Args) -> Result<Box<dyn SendAudioRestart>> {
+    let key = crypto::key_from_args(args)?;
+    let address = &endpoint.address;
+    let socket = Box::new(connected_udp_socket(address)?);
+    udp_sink(args, socket, address, key.as_ref().map(Sealer::new))
+}
+
+fn open_impaired_udp(endpoint: &Endpoint, args: &Args) -> Result<Box<dyn SendAudioRestart>> {
+    let key = crypto::key_from_args(args)?;
+    let address = &endpoint.address;
+    let impairment = Impairment::parse(&endpoint.query_of(&IMPAIRMENTS))?;
+    info!("Impairing sent packets with {impairment:?}");
+    let socket = Box::new(ImpairedSocket::new(
+        connected_udp_socket(address)?,
+        impairment,
+    ));
+    udp_sink(args, socket, address, key.as_ref().map(Sealer::new))
+}
+
+fn open_idc_connect(endpoint: &Endpoint, args: &Args) -> Result<Box<dyn SendAudioRestart>> {
+    Ok(Box::new(idc_sink(endpoint, args, &endpoint.address)?))
+}
+
+fn open_impaired_idc(endpoint: &Endpoint, args: &Args) -> Result<Box<dyn SendAudioRestart>> {
+    let target = resolve(&endpoint.address)?;
+    let impairment = Impairment::parse(&endpoint.query_of(&IMPAIRMENTS))?;
+    info!("Impairing the connection to {target} with {impairment:?}");
+    let proxy = ImpairedProxy::new(proxy_address(target), target, impairment)?;
+    let inner = idc_sink(endpoint, args, &proxy.local_addr().to_string())?;
+    Ok(Box::new(Proxied { inner, proxy }))
+}
+
+fn idc_sink(endpoint: &Endpoint, args: &Args, address: &str) -> Result<network::IdcSinkPack> {
+    let key = crypto::key_from_args(args)?;
+    let buffer_size = args.datagram_size;
+    let sealer = key.as_ref().map(Sealer::new);
+    let format = PcmFormat::from_args(args)?;
+    let latency_budget = Duration::from_millis(args.idc_latency_budget);
+    let timeouts = IdcTimeouts::from_args(args);
+    let reconnect = ReconnectPolicy::from_args(args)?;
+    let mut pack = network::IdcSinkPack::new(
+        address,
+        buffer_size,
+        format,
+        latency_budget,
+        sealer,
+        timeouts,
+        reconnect,
+    )?;
+    if endpoint.param(LEGACY.name)?.unwrap_or(false) {
+        pack.set_legacy(true)?;
+        info!("Sending unframed audio to {address} for an older idc source");
+    }
+    info!(
+        "Sending to {address} datagrams of up to {buffer_size} bytes without caring, queueing up to {latency_budget:?} of audio"
+    );
+    Ok(pack)
+}
+
+fn open_idc_listen(endpoint: &Endpoint, args: &Args) -> Result<Box<dyn SendAudioRestart>> {
+    let key = crypto::key_from_args(args)?;
+    let address = &endpoint.address;
+    let buffer_size = args.datagram_size;
+    let queue_limit = args.buffer_limit;
+    let sealer = key.as_ref().map(Sealer::new);
+    let block_align = PcmFormat::from_args(args)?.block_align();
+    let timeouts = IdcTimeouts::from_args(args);
+    let pack = network::IdcServerSinkPack::new(
+        address,
+        buffer_size,
+        block_align,
+        queue_limit,
+        sealer,
+        timeouts,
+    )?;
+    info!(
+        "Listening on {address} for any number of receivers, queueing up to {queue_limit} bytes for each"
+    );
+    Ok(Box::new(pack))
+}
+
+fn open_ws(endpoint: &Endpoint, args: &Args) -> Result<Box<dyn SendAudioRestart>> {
+    let address = &endpoint.address;
+    let buffer_size = args.datagram_size;
+    let queue_limit = args.buffer_limit;
+    let format = PcmFormat::from_args(args)?;
+    let pack = web::WsSinkPack::new(address, buffer_size, format, queue_limit)?;
+    info!(
+        "Serving a player page on http://{address}/ and streaming to any number of browsers, queueing up to {queue_limit} bytes for each"
+    );
+    Ok(Box::new(pack))
+}
+
+fn open_http(endpoint: &Endpoint, args: &Args) -> Result<Box<dyn SendAudioRestart>> {
+    let url = &endpoint.address;
+    let (address, path) = match url.find('/') {
+        Some(i) => url.split_at(i),
+        None => (url.as_str(), "/"),
+    };
+    let buffer_size = args.datagram_size;
+    let queue_limit = args.buffer_limit;
+    let format = PcmFormat::from_args(args)?;
+    let pack = web::HttpSinkPack::new(
+        address,
+        path,
+        buffer_size,
+        format,
+        queue_limit,
+        &args.stream_name,
+    )?;
+    info!(
+        "Serving an endless WAV on http://{address}{path} to any number of clients, dropping ones that fall {queue_limit} bytes behind"
+    );
+    Ok(Box::new(pack))
+}
+
+fn open_icecast(endpoint: &Endpoint, args: &Args) -> Result<Box<dyn SendAudioRestart>> {
+    let target = web::IcecastTarget::parse(&endpoint.address)?;
+    let buffer_size = args.datagram_size;
+    let queue_limit = args.buffer_limit;
+    let format = PcmFormat::from_args(args)?;
+    let timeouts = IdcTimeouts::from_args(args);
+    let reconnect = ReconnectPolicy::from_args(args)?;
+    info!(
+        "Sending a WAV stream to the Icecast server at {}{} as {}",
+        target.host, target.mount, target.user
+    );
+    let pack = web::IcecastSinkPack::new(
+        target,
+        buffer_size,
+        format,
+        queue_limit,
+        &args.stream_name,
+        timeouts,
+        reconnect,
+    )?;
+    Ok(Box::new(pack))
+}
+
+fn open_file(endpoint: &Endpoint, args: &Args) -> Result<Box<dyn SendAudioRestart>> {
+    let path = &endpoint.address;
+    let pack = file::FileSinkPack::new(path, PcmFormat::from_args(args)?)?;
+    info!("Recording to {path}");
+    Ok(Box::new(pack))
+}
+
+fn open_device(endpoint: &Endpoint, args: &Args) -> Result<Box<dyn SendAudioRestart>> {
+    let format = WaveFormat::new(
+        args.bits_per_sample,
+        args.bits_per_sample,
+        &wasapi::SampleType::Int,
+        args.sample_rate,
+        args.channels,
+        None,
+    );
+    let device = device_utils::find_device_by_name(Direction::Render, &endpoint.address)?;
+    let sink_pack = device::DeviceSinkPack::new(device, format)?;
+
+    let name = sink_pack
+        .device()
+        .get_friendlyname()
+        .map_err(|err| anyhow!("Couldn't get device name due to error: {err}"))?;
+    info!("Sending to {name}");
+
+    Ok(Box::new(sink_pack))
+}
+
+/// Every kind of sink there is, with device names as the fallback
+pub fn registry() -> Registry<Box<dyn SendAudioRestart>> {
+    let mut registry = Registry::new("sink");
+    registry.register(Scheme::new(
+        "udp",
+        "<host:port>",
+        "Sends UDP datagrams",
+        &[SIZE, COUNTED],
+        open_udp,
+    ));
+    registry.register(Scheme::new(
+        "impair+udp",
+        "<host:port>",
+        "Sends UDP datagrams through a simulated bad network",
+        &[&[SIZE, COUNTED][..], &IMPAIRMENTS].concat(),
+        open_impaired_udp,
+    ));
+    for name in ["idc", "idc-connect"] {
+        registry.register(Scheme::new(
+            name,
+            "<host:port>",
+            "Connects to a listening idc source",
+            &[SIZE, LATENCY, KEEPALIVE, TIMEOUT, LEGACY],
+            open_idc_connect,
+        ));
+    }
+    for name in ["impair+idc", "impair+idc-connect"] {
+        registry.register(Scheme::new(
+            name,
+            "<host:port>",
+            "Connects to a listening idc source through a simulated bad network",
+            &[
+                &[SIZE, LATENCY, KEEPALIVE, TIMEOUT, LEGACY][..],
+                &IMPAIRMENTS,
+            ]
+            .concat(),
+            open_impaired_idc,
+        ));
+    }
+    registry.register(Scheme::new(
+        "idc-listen",
+        "<bind address>",
+        "Waits for any number of idc sources to connect",
+        &[SIZE, QUEUE, KEEPALIVE, TIMEOUT],
+        open_idc_listen,
+    ));
+    registry.register(Scheme::new(
+        "quic",
+        "<host:port>",
+        "Connects to a listening quic source (needs the quic feature)",
+        &[SIZE, KEEPALIVE, TIMEOUT],
+        |endpoint: &Endpoint, args: &Args| quic_sink(args, &endpoint.address),
+    ));
+    registry.register(Scheme::new(
+        "ws",
+        "<bind address>",
+        "Serves a player page and streams to browsers over WebSocket",
+        &[SIZE, QUEUE],
+        open_ws,
+    ));
+    registry.register(Scheme::new(
+        "http",
+        "<bind address>/<path>",
+        "Serves an endless WAV to media players",
+        &[SIZE, QUEUE, NAME],
+        open_http,
+    ));
+    registry.register(Scheme::new(
+        "icecast",
+        "<user>:<password>@<host:port>/<mount>",
+        "Streams a WAV to an Icecast server",
+        &[SIZE, QUEUE, NAME, KEEPALIVE, TIMEOUT],
+        open_icecast,
+    ));
+    registry.register(Scheme::new(
+        "file",
+        "<path>",
+        "Records to a WAV file, or to raw PCM if the name doesn't end in .wav",
+        &[],
+        open_file,
+    ));
+    // Taken by the pipeline for the sink's own buffer
+    registry.add_common(&[BUFFER, OVERFLOW, ON_TRANSIENT, ON_DEVICE_LOST, ON_FATAL]);
+    registry.set_fallback(open_device);
+    registry
+}
+
+/// Opens the sink at `url`, taking everything else from `args`
+pub fn from_url(url: &str, args: &Args) -> Result<Box<dyn SendAudioRestart>> {
+    registry().open(url, args)
+}
+
+/// Opens the sink `args` names, like a pipeline built from them would
+pub fn from_args(args: &Args) -> Result<Box<dyn SendAudioRestart>> {
+    from_url(&args.sink, args)
 }
@@ -1,49 +1,78 @@
 use std::{
     collections::VecDeque,
-    io::Read as _,
-    net::UdpSocket,
+    io::{self, Read as _},
+    net::{Shutdown, SocketAddr, UdpSocket},
+    sync::Arc,
+    thread,
     time::{Duration, Instant},
 };
 
-use crate::{HYPOT_AUDIO_ALIGNMENT, Restart};
+use crate::{
+    Restart,
+    crypto::Sealer,
+    idc::{
+        self, Backoff, ConnectionState, FrameEncoder, GOODBYE, GOODBYE_TIMEOUT, HEARTBEAT,
+        IdcTimeouts, ReconnectPolicy,
+    },
+    network_utils::{
+        CLIENT_STALL_TIMEOUT, ClientQueue, DatagramSocket, connected_udp_socket, tcp_listener,
+        tcp_socket,
+    },
+    pcm::PcmFormat,
+};
 
 use super::SendAudio;
-use anyhow::{Result, anyhow};
-use log::{debug, warn};
+use anyhow::{Result, anyhow, bail};
+use log::{debug, info, warn};
+
+/// Whether reading from the peer failed because the connection is gone
+pub(crate) fn connection_lost(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionRefused
+    )
+}
 
-pub struct UdpSinkPack {
-    pub socket: UdpSocket,
+pub struct UdpSinkPack<S = UdpSocket> {
+    pub socket: S,
     pub buffer: Vec<u8>,
 }
 
 impl UdpSinkPack {
     pub fn new(address: impl std::net::ToSocketAddrs, buffer_size: usize) -> Result<Self> {
-        let socket = UdpSocket::bind("0.0.0.0:0")?;
-        socket.connect(address)?;
-        Ok(Self {
+        Ok(Self::with_socket(
+            connected_udp_socket(address)?,
+            buffer_size,
+        ))
+    }
+}
+
+impl<S> UdpSinkPack<S> {
+    pub fn with_socket(socket: S, buffer_size: usize) -> Self {
+        Self {
             socket,
             buffer: vec![0; buffer_size],
-        })
+        }
     }
 }
 
-impl Restart for UdpSinkPack {
+impl<S: DatagramSocket> Restart for UdpSinkPack<S> {
     fn restart(&mut self) -> Result<()> {
-        let remote_addr = self.socket.peer_addr()?;
-        self.socket = UdpSocket::bind("0.0.0.0:0")?;
-        self.socket.connect(remote_addr)?;
+        self.socket.reopen()?;
         Ok(())
     }
 }
 
-impl SendAudio for UdpSinkPack {
+impl<S: DatagramSocket> SendAudio for UdpSinkPack<S> {
     fn send_from_deque(&mut self, data: &mut VecDeque<u8>) -> Result<()> {
         if data.is_empty() {
             return Ok(());
         }
         let n_sent = usize::min(self.buffer.len(), data.len());
         if n_sent == self.buffer.len() {
-            warn!("Splitting datagram!");
+            debug!("Splitting datagram!");
         }
         data.read_exact(&mut self.buffer[..n_sent])?;
         self.socket.send(&self.buffer[..n_sent])?;
@@ -52,37 +81,70 @@ impl SendAudio for UdpSinkPack {
     }
 }
 
-pub struct CountedUdpSinkPack {
+/// Sends datagrams tagged with their number, each with whole audio frames so
+/// that the receiving end can conceal a lost one exactly
+pub struct CountedUdpSinkPack<S = UdpSocket> {
     pub current_id: u64,
-    pub socket: UdpSocket,
+    pub socket: S,
     pub buffer: Vec<u8>,
+    pub block_align: usize,
 }
 
 impl CountedUdpSinkPack {
-    pub fn new(address: impl std::net::ToSocketAddrs, buffer_size: usize) -> Result<Self> {
-        let socket = UdpSocket::bind("0.0.0.0:0")?;
-        socket.connect(address)?;
-        Ok(Self {
+    pub fn new(
+        address: impl std::net::ToSocketAddrs,
+        buffer_size: usize,
+        block_align: usize,
+    ) -> Result<Self> {
+        Ok(Self::with_socket(
+            connected_udp_socket(address)?,
+            buffer_size,
+            block_align,
+        ))
+    }
+}
+
+impl<S> CountedUdpSinkPack<S> {
+    pub fn with_socket(socket: S, buffer_size: usize, block_align: usize) -> Self {
+        Self {
             current_id: 0,
             socket,
             buffer: vec![0; buffer_size],
-        })
+            block_align,
+        }
     }
-}
 
-impl SendAudio for CountedUdpSinkPack {
-    fn send_from_deque(&mut self, data: &mut VecDeque<u8>) -> Result<()> {
-        if data.is_empty() {
-            return Ok(());
-        }
+    /// Puts the next datagram with whole audio frames from the front of `data`
+    /// together in the buffer, without taking them out. Returns its length and
+    /// how much audio it holds
+    pub(crate) fn next_datagram(&mut self, data: &VecDeque<u8>) -> Option<(usize, usize)> {
         let tag = self.current_id.to_be_bytes();
-        let n_sent = usize::min(self.buffer.len(), data.len() + tag.len());
-        if n_sent == self.buffer.len() {
-            warn!("Splitting datagram!");
+        let n_audio = usize::min(self.buffer.len().saturating_sub(tag.len()), data.len());
+        let n_audio = n_audio - n_audio % self.block_align;
+        if n_audio == 0 {
+            return None;
+        }
+        if n_audio < data.len() {
+            debug!("Splitting datagram!");
         }
         self.buffer[..tag.len()].copy_from_slice(&tag);
-        data.read_exact(&mut self.buffer[tag.len()..n_sent])?;
+        for (byte, value) in self.buffer[tag.len()..tag.len() + n_audio]
+            .iter_mut()
+            .zip(data)
+        {
+            *byte = *value;
+        }
+        Some((tag.len() + n_audio, n_audio))
+    }
+}
+
+impl<S: DatagramSocket> SendAudio for CountedUdpSinkPack<S> {
+    fn send_from_deque(&mut self, data: &mut VecDeque<u8>) -> Result<()> {
+        let Some((n_sent, n_audio)) = self.next_datagram(data) else {
+            return Ok(());
+        };
         self.socket.send(&self.buffer[..n_sent])?;
+        data.drain(..n_audio);
 
         self.current_id += 1;
 
@@ -90,88 +152,552 @@ impl SendAudio for CountedUdpSinkPack {
     }
 }
 
-impl Restart for CountedUdpSinkPack {
+impl<S: DatagramSocket> Restart for CountedUdpSinkPack<S> {
     fn restart(&mut self) -> Result<()> {
         self.current_id = 0;
-        let remote_addr = self.socket.peer_addr()?;
-        self.socket = UdpSocket::bind("0.0.0.0:0")?;
-        self.socket.connect(remote_addr)?;
+        self.socket.reopen()?;
         Ok(())
     }
 }
 
+/// How often a connecting idc sink reports how much audio is waiting to be sent
+const LATENCY_REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
 pub struct IdcSinkPack {
     address: socket2::SockAddr,
     socket: socket2::Socket,
-    last_connection_attempt: Instant,
+    backoff: Backoff,
     buffer: Vec<u8>,
+    format: PcmFormat,
+    encoder: FrameEncoder,
+    /// Frames waiting to be sent with how much audio each one carries
+    queue: VecDeque<(Vec<u8>, Duration)>,
+    queued: Duration,
+    /// How much of the first frame in the queue is already sent
+    frame_sent: usize,
+    /// Whole frames are dropped once more audio than this is queued
+    latency_budget: Duration,
+    pub dropped: u64,
+    timeouts: IdcTimeouts,
+    last_heard: Instant,
+    last_sent: Instant,
+    /// Sends raw audio without heartbeats, for sources from before framing
+    legacy: bool,
+    last_report: Instant,
 }
 
 impl IdcSinkPack {
-    fn create_socket(address: &socket2::SockAddr) -> Result<socket2::Socket> {
-        let socket = socket2::Socket::new(
-            if address.is_ipv4() {
-                socket2::Domain::IPV4
-            } else {
-                socket2::Domain::IPV6
-            },
-            socket2::Type::STREAM,
-            Some(socket2::Protocol::TCP),
-        )?;
+    fn create_socket(
+        address: &socket2::SockAddr,
+        timeouts: &IdcTimeouts,
+    ) -> Result<socket2::Socket> {
+        let socket = tcp_socket(address)?;
         socket.set_nonblocking(true)?;
+        timeouts.configure(&socket)?;
         let _ = socket.connect(address);
         Ok(socket)
     }
 
-    pub fn new(address: impl std::net::ToSocketAddrs, buffer_size: usize) -> Result<Self> {
-        let address = address
+    pub fn new(
+        address: impl std::net::ToSocketAddrs,
+        buffer_size: usize,
+        format: PcmFormat,
+        latency_budget: Duration,
+        sealer: Option<Sealer>,
+        timeouts: IdcTimeouts,
+        reconnect: ReconnectPolicy,
+    ) -> Result<Self> {
+        let block_align = format.block_align();
+        if buffer_size < block_align {
+            bail!("Chunks of {buffer_size} bytes can't fit a {block_align} bytes long audio frame");
+        }
+        let address: SocketAddr = address
             .to_socket_addrs()?
             .next()
-            .ok_or(anyhow!("Couldn't get socket addr."))?
-            .into();
-        let socket = Self::create_socket(&address)?;
+            .ok_or(anyhow!("Couldn't get socket addr."))?;
+        let mut backoff = Backoff::new(reconnect, format!("idc sink to {address}"));
+        backoff.attempt()?;
+        let address = address.into();
+        let socket = Self::create_socket(&address, &timeouts)?;
         Ok(Self {
             address,
             socket,
-            last_connection_attempt: Instant::now(),
-            buffer: vec![0; buffer_size],
+            backoff,
+            buffer: vec![0; buffer_size.min(idc::MAX_PAYLOAD)],
+            format,
+            encoder: FrameEncoder::new(sealer),
+            queue: VecDeque::new(),
+            queued: Duration::ZERO,
+            frame_sent: 0,
+            latency_budget,
+            dropped: 0,
+            timeouts,
+            last_heard: Instant::now(),
+            last_sent: Instant::now(),
+            legacy: false,
+            last_report: Instant::now(),
         })
     }
+
+    /// Talks to sources older than idc framing: they take nothing but raw audio
+    /// and never send anything back, so only a closed connection shows they're gone
+    pub fn set_legacy(&mut self, legacy: bool) -> Result<()> {
+        if legacy && self.encoder.is_sealed() {
+            bail!("Older idc sources can't take encrypted audio");
+        }
+        self.legacy = legacy;
+        Ok(())
+    }
+
+    /// How much audio is waiting to be sent
+    pub fn queued_latency(&self) -> Duration {
+        self.queued
+    }
+
+    pub fn on_state_change(&mut self, callback: impl FnMut(ConnectionState) + Send + 'static) {
+        self.backoff.set_callback(callback);
+    }
+
+    /// Reads whatever the source sent, returns false if the connection is dead
+    fn peer_alive(&mut self) -> bool {
+        let mut incoming = [0; 64];
+        loop {
+            match (&self.socket).read(&mut incoming) {
+                Ok(0) => return false,
+                Ok(_) => {
+                    self.last_heard = Instant::now();
+                    self.backoff.connected();
+                }
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+                Err(error) if connection_lost(&error) => return false,
+                // Nothing to read, or still connecting, the peer timeout
+                // decides about those
+                Err(_) => break,
+            }
+        }
+        self.legacy || self.last_heard.elapsed() < self.timeouts.peer_timeout
+    }
+
+    fn reconnect(&mut self) -> Result<()> {
+        self.backoff.lost();
+        if self.backoff.ready() {
+            self.backoff.attempt()?;
+            self.socket = Self::create_socket(&self.address, &self.timeouts)?;
+            // Give the new connection the full timeout to show it's alive
+            self.last_heard = Instant::now();
+            self.clear_queue();
+        }
+        Ok(())
+    }
+
+    /// Logs the queued latency, louder once it takes up half the budget
+    fn report_latency(&mut self) {
+        self.last_report = Instant::now();
+        let queued = self.queued_latency();
+        if queued > self.latency_budget / 2 {
+            info!(
+                "{queued:?} of audio waiting to be sent, {} frames dropped so far",
+                self.dropped
+            );
+        } else {
+            debug!(
+                "{queued:?} of audio waiting to be sent, {} frames dropped so far",
+                self.dropped
+            );
+        }
+    }
+
+    fn clear_queue(&mut self) {
+        self.queue.clear();
+        self.queued = Duration::ZERO;
+        self.frame_sent = 0;
+    }
+
+    /// Queues `frame`, then drops whole unsent frames while over the latency budget
+    fn push(&mut self, frame: Vec<u8>, duration: Duration) {
+        self.queue.push_back((frame, duration));
+        self.queued += duration;
+        // A partially sent frame has to go out whole, or the stream breaks
+        let started = usize::from(self.frame_sent > 0);
+        let mut n_dropped = 0;
+        while self.queued > self.latency_budget && self.queue.len() > started + 1 {
+            let (_, duration) = self.queue.remove(started).unwrap();
+            self.queued -= duration;
+            n_dropped += 1;
+        }
+        if n_dropped > 0 {
+            self.dropped += n_dropped;
+            // Don't flood the log if the network is just too slow
+            if self.dropped.is_power_of_two() {
+                warn!(
+                    "Over the {:?} latency budget with {:?} queued, dropped {} frames so far",
+                    self.latency_budget, self.queued, self.dropped
+                );
+            } else {
+                debug!("Over the latency budget, dropped {n_dropped} frames");
+            }
+        }
+    }
+
+    /// Sends as much of the queue as the socket takes right now
+    fn flush(&mut self) -> Result<()> {
+        while let Some((frame, duration)) = self.queue.front() {
+            match self.socket.send(&frame[self.frame_sent..]) {
+                Ok(n_written) => {
+                    self.frame_sent += n_written;
+                    self.last_sent = Instant::now();
+                    self.backoff.connected();
+                    if self.frame_sent == frame.len() {
+                        self.queued -= *duration;
+                        self.queue.pop_front();
+                        self.frame_sent = 0;
+                    }
+                }
+                Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => {
+                    debug!("Encountered WouldBlock with {:?} queued", self.queued);
+                    break;
+                }
+                Err(error) => {
+                    debug!("Couldn't send: {error}");
+                    self.reconnect()?;
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 impl SendAudio for IdcSinkPack {
     fn send_from_deque(&mut self, data: &mut VecDeque<u8>) -> Result<()> {
-        let n_sent = usize::min(self.buffer.len(), data.len());
-        if n_sent == self.buffer.len() {
-            warn!("Splitting datagram!");
+        if !self.peer_alive() {
+            if self.last_heard.elapsed() >= self.timeouts.peer_timeout {
+                debug!(
+                    "Haven't heard from the source for {:?}",
+                    self.last_heard.elapsed()
+                );
+            }
+            self.reconnect()?;
+            // Couldn't send, just consume the data, whole audio frames only
+            let block_align = self.format.block_align();
+            data.drain(..data.len() - data.len() % block_align);
+            return Ok(());
         }
-        data.read_exact(&mut self.buffer[..n_sent])?;
-        match self.socket.send(&self.buffer[..n_sent]) {
-            Ok(_) => {}
-            Err(error) => {
-                if error.kind() == std::io::ErrorKind::WouldBlock {
-                    debug!("Encountered WouldBlock: {error:?}");
-                } else if self.last_connection_attempt.elapsed() > Duration::from_millis(2000) {
-                    debug!("Can't send so trying to reconnect");
-                    match self.socket.connect(&self.address) {
-                        Ok(_) => {}
-                        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
-                        _ => self.socket = Self::create_socket(&self.address)?,
-                    };
-                    self.last_connection_attempt = Instant::now();
+
+        let block_align = self.format.block_align();
+        if data.len() > self.buffer.len() {
+            debug!("Splitting datagram!");
+        }
+        while data.len() >= block_align {
+            let n_sent = usize::min(self.buffer.len(), data.len());
+            let n_sent = n_sent - n_sent % block_align;
+            data.read_exact(&mut self.buffer[..n_sent])?;
+            let frame = if self.legacy {
+                self.buffer[..n_sent].to_vec()
+            } else {
+                let mut frame = Vec::new();
+                self.encoder.encode(&self.buffer[..n_sent], &mut frame);
+                frame
+            };
+            let n_frames = (n_sent / block_align) as f64;
+            let duration = Duration::from_secs_f64(n_frames / self.format.sample_rate as f64);
+            self.push(frame, duration);
+        }
+        if !self.legacy
+            && self.queue.is_empty()
+            && self.last_sent.elapsed() >= self.timeouts.keepalive
+        {
+            self.push(HEARTBEAT.to_vec(), Duration::ZERO);
+        }
+        self.flush()?;
+        if self.last_report.elapsed() >= LATENCY_REPORT_INTERVAL {
+            self.report_latency();
+        }
+        Ok(())
+    }
+}
+
+/// Sends what's still queued and says goodbye, unless that takes longer than
+/// [`GOODBYE_TIMEOUT`]
+impl Drop for IdcSinkPack {
+    fn drop(&mut self) {
+        if !self.legacy {
+            self.push(GOODBYE.to_vec(), Duration::ZERO);
+        }
+        let deadline = Instant::now() + GOODBYE_TIMEOUT;
+        while let Some((frame, _)) = self.queue.front()
+            && Instant::now() < deadline
+        {
+            match self.socket.send(&frame[self.frame_sent..]) {
+                Ok(n_written) => {
+                    self.frame_sent += n_written;
+                    if self.frame_sent == frame.len() {
+                        self.queue.pop_front();
+                        self.frame_sent = 0;
+                    }
+                }
+                Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(1));
                 }
-                // Couldn't send, just consume the data
-                let n_blocks = data.len() / HYPOT_AUDIO_ALIGNMENT;
-                data.drain(0..(n_blocks * HYPOT_AUDIO_ALIGNMENT));
+                Err(_) => return,
             }
-        };
-        Ok(())
+        }
+        let _ = self.socket.shutdown(Shutdown::Write);
     }
 }
 
 impl Restart for IdcSinkPack {
     fn restart(&mut self) -> Result<()> {
-        self.socket = Self::create_socket(&self.address)?;
+        self.backoff.lost();
+        self.socket = Self::create_socket(&self.address, &self.timeouts)?;
+        self.last_heard = Instant::now();
+        self.clear_queue();
+        Ok(())
+    }
+}
+
+struct IdcClient {
+    socket: socket2::Socket,
+    address: Option<SocketAddr>,
+    queue: ClientQueue,
+    last_sent: Instant,
+    last_heard: Instant,
+}
+
+impl IdcClient {
+    fn push(&mut self, chunk: Arc<[u8]>, limit: usize) {
+        let n_skipped = self.queue.push(chunk, limit);
+        if n_skipped > 0 {
+            debug!(
+                "Client {:?} is too slow, skipped {n_skipped} bytes",
+                self.address
+            );
+        }
+    }
+
+    /// Sends as much as the socket takes right now, returns false if the client is gone
+    fn flush(&mut self, timeouts: &IdcTimeouts) -> bool {
+        let mut incoming = [0; 64];
+        loop {
+            match (&self.socket).read(&mut incoming) {
+                Ok(0) => return false,
+                Ok(_) => self.last_heard = Instant::now(),
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+                Err(error) if connection_lost(&error) => return false,
+                // Nothing to read, or still connecting, the peer timeout
+                // decides about those
+                Err(_) => break,
+            }
+        }
+        if self.last_heard.elapsed() >= timeouts.peer_timeout {
+            debug!("Haven't heard from client {:?} for too long", self.address);
+            return false;
+        }
+        if self.queue.is_empty() && self.last_sent.elapsed() >= timeouts.keepalive {
+            self.push(Arc::new(HEARTBEAT), usize::MAX);
+        }
+
+        match self.queue.flush(&self.socket) {
+            Ok(0) => {}
+            Ok(_) => self.last_sent = Instant::now(),
+            Err(error) => {
+                debug!("Can't send to client {:?}: {error}", self.address);
+                return false;
+            }
+        }
+        !self.queue.stalled(CLIENT_STALL_TIMEOUT)
+    }
+}
+
+/// The idc sources connected to a listening sink, each with its own queue
+pub(crate) struct IdcClients {
+    clients: Vec<IdcClient>,
+    buffer: Vec<u8>,
+    block_align: usize,
+    /// Max bytes queued for a single client
+    queue_limit: usize,
+    encoder: FrameEncoder,
+    timeouts: IdcTimeouts,
+}
+
+impl IdcClients {
+    pub(crate) fn new(
+        buffer_size: usize,
+        block_align: usize,
+        queue_limit: usize,
+        sealer: Option<Sealer>,
+        timeouts: IdcTimeouts,
+    ) -> Result<Self> {
+        if buffer_size < block_align {
+            bail!("Chunks of {buffer_size} bytes can't fit a {block_align} bytes long audio frame");
+        }
+        Ok(Self {
+            clients: Vec::new(),
+            buffer: vec![0; buffer_size.min(idc::MAX_PAYLOAD)],
+            block_align,
+            queue_limit,
+            encoder: FrameEncoder::new(sealer),
+            timeouts,
+        })
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.clients.len()
+    }
+
+    /// Takes on a nonblocking socket of a client that just connected
+    pub(crate) fn add(
+        &mut self,
+        socket: socket2::Socket,
+        address: Option<SocketAddr>,
+    ) -> Result<()> {
+        self.timeouts.configure(&socket)?;
+        self.clients.push(IdcClient {
+            socket,
+            address,
+            queue: ClientQueue::new(),
+            last_sent: Instant::now(),
+            last_heard: Instant::now(),
+        });
+        info!(
+            "Client {address:?} connected, {} in total",
+            self.clients.len()
+        );
+        Ok(())
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.clients.clear();
+    }
+
+    /// Queues whole audio frames from `data` for every client and sends what
+    /// their sockets take right now
+    pub(crate) fn send_from_deque(&mut self, data: &mut VecDeque<u8>) -> Result<()> {
+        let n_sent = usize::min(self.buffer.len(), data.len());
+        let n_sent = n_sent - n_sent % self.block_align;
+        data.read_exact(&mut self.buffer[..n_sent])?;
+        if n_sent > 0 && !self.clients.is_empty() {
+            let mut frame = Vec::new();
+            self.encoder.encode(&self.buffer[..n_sent], &mut frame);
+            let chunk: Arc<[u8]> = frame.into();
+            for client in &mut self.clients {
+                client.push(chunk.clone(), self.queue_limit);
+            }
+        }
+
+        let n_clients = self.clients.len();
+        self.clients.retain_mut(|client| {
+            let alive = client.flush(&self.timeouts);
+            if !alive {
+                info!("Dropping client {:?}", client.address);
+            }
+            alive
+        });
+        if self.clients.len() != n_clients {
+            info!("{} clients left", self.clients.len());
+        }
+        Ok(())
+    }
+
+    /// Sends every client what's still queued for it and says goodbye, unless
+    /// that takes longer than `timeout`
+    pub(crate) fn goodbye(&mut self, timeout: Duration) {
+        let goodbye: Arc<[u8]> = Arc::new(GOODBYE);
+        for client in &mut self.clients {
+            client.queue.push(goodbye.clone(), usize::MAX);
+        }
+        let deadline = Instant::now() + timeout;
+        loop {
+            self.clients
+                .retain_mut(|client| match client.queue.flush(&client.socket) {
+                    Ok(_) if client.queue.is_empty() => {
+                        let _ = client.socket.shutdown(Shutdown::Write);
+                        false
+                    }
+                    Ok(_) => true,
+                    Err(_) => false,
+                });
+            if self.clients.is_empty() || Instant::now() >= deadline {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
+
+/// Listens for any number of idc sources and sends the same stream to each of them
+pub struct IdcServerSinkPack {
+    listener: socket2::Socket,
+    clients: IdcClients,
+}
+
+impl IdcServerSinkPack {
+    fn create_listener(address: &socket2::SockAddr) -> Result<socket2::Socket> {
+        let listener = tcp_listener(address, 128)?;
+        listener.set_nonblocking(true)?;
+        Ok(listener)
+    }
+
+    pub fn new(
+        address: impl std::net::ToSocketAddrs,
+        buffer_size: usize,
+        block_align: usize,
+        queue_limit: usize,
+        sealer: Option<Sealer>,
+        timeouts: IdcTimeouts,
+    ) -> Result<Self> {
+        let clients = IdcClients::new(buffer_size, block_align, queue_limit, sealer, timeouts)?;
+        let address = address
+            .to_socket_addrs()?
+            .next()
+            .ok_or(anyhow!("Couldn't get socket addr."))?;
+        Ok(Self {
+            listener: Self::create_listener(&address.into())?,
+            clients,
+        })
+    }
+
+    pub fn n_clients(&self) -> usize {
+        self.clients.len()
+    }
+
+    fn accept_clients(&mut self) -> Result<()> {
+        loop {
+            match self.listener.accept() {
+                Ok((socket, address)) => {
+                    socket.set_nonblocking(true)?;
+                    self.clients.add(socket, address.as_socket())?;
+                }
+                Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => return Ok(()),
+                Err(error) => {
+                    warn!("Couldn't accept a client: {error}");
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+impl SendAudio for IdcServerSinkPack {
+    fn send_from_deque(&mut self, data: &mut VecDeque<u8>) -> Result<()> {
+        self.accept_clients()?;
+        self.clients.send_from_deque(data)
+    }
+}
+
+/// Sends every client what's still queued for it and says goodbye, unless that
+/// takes longer than [`GOODBYE_TIMEOUT`]
+impl Drop for IdcServerSinkPack {
+    fn drop(&mut self) {
+        self.clients.goodbye(GOODBYE_TIMEOUT);
+    }
+}
+
+/// Keeps listening on the same socket, binding the address again would fail
+/// while the old socket still holds it
+impl Restart for IdcServerSinkPack {
+    fn restart(&mut self) -> Result<()> {
+        self.clients.clear();
         Ok(())
     }
 }
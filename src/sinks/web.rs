@@ -0,0 +1,759 @@
+use std::{
+    collections::VecDeque,
+    io::Read as _,
+    net::{SocketAddr, ToSocketAddrs as _},
+    sync::Arc,
+    time::Instant,
+};
+
+use anyhow::{Result, anyhow, bail};
+use base64::Engine as _;
+use log::{debug, info, warn};
+
+use crate::{
+    HYPOT_AUDIO_ALIGNMENT, Restart,
+    http::{
+        self, HttpListener, Request, Responses, WS_BINARY, WS_CLOSE, WS_PING, WS_PONG, WS_TEXT,
+        websocket_frame,
+    },
+    idc::{Backoff, ConnectionState, IdcTimeouts, ReconnectPolicy},
+    network_utils::{CLIENT_STALL_TIMEOUT, ClientQueue, tcp_socket},
+    pcm::PcmFormat,
+};
+
+use super::SendAudio;
+
+const PLAYER_PAGE: &str = include_str!("player.html");
+
+/// Format of the stream as the player page expects it
+fn format_json(format: &PcmFormat) -> String {
+    format!(
+        r#"{{"sampleRate":{},"channels":{},"bitsPerSample":{},"float":{}}}"#,
+        format.sample_rate, format.channels, format.bits_per_sample, format.use_float
+    )
+}
+
+struct WsClient {
+    socket: socket2::Socket,
+    address: Option<SocketAddr>,
+    queue: ClientQueue,
+    /// Received bytes that don't make a whole frame yet
+    incoming: Vec<u8>,
+    closing: bool,
+}
+
+impl WsClient {
+    /// Queues a frame that can't be skipped, unlike audio
+    fn push_frame(&mut self, opcode: u8, payload: &[u8]) {
+        let mut frame = Vec::new();
+        websocket_frame(opcode, payload, &mut frame);
+        self.queue.push_pinned(frame.into());
+    }
+
+    /// Handles whatever the browser sent, returns false once it's gone
+    fn read(&mut self) -> bool {
+        let mut chunk = [0; 256];
+        loop {
+            match (&self.socket).read(&mut chunk) {
+                Ok(0) => return false,
+                Ok(n_read) => self.incoming.extend_from_slice(&chunk[..n_read]),
+                Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(_) => return false,
+            }
+        }
+        loop {
+            match http::read_websocket_frame(&mut self.incoming) {
+                Ok(Some((WS_CLOSE, _))) => {
+                    debug!("Client {:?} closed the WebSocket", self.address);
+                    if !self.closing {
+                        self.push_frame(WS_CLOSE, &[]);
+                        self.closing = true;
+                    }
+                }
+                Ok(Some((WS_PING, payload))) => self.push_frame(WS_PONG, &payload),
+                Ok(Some(_)) => {}
+                Ok(None) => return true,
+                Err(error) => {
+                    debug!("Client {:?} sent garbage: {error}", self.address);
+                    return false;
+                }
+            }
+        }
+    }
+
+    /// Sends as much as the socket takes right now, returns false if the client is gone
+    fn flush(&mut self) -> bool {
+        if !self.read() {
+            return false;
+        }
+        if let Err(error) = self.queue.flush(&self.socket) {
+            debug!("Can't send to client {:?}: {error}", self.address);
+            return false;
+        }
+        if self.closing && self.queue.is_empty() {
+            return false;
+        }
+        !self.queue.stalled(CLIENT_STALL_TIMEOUT)
+    }
+}
+
+/// Serves a player page and sends the stream over WebSocket to every browser that opens it
+pub struct WsSinkPack {
+    listener: HttpListener,
+    responses: Responses,
+    clients: Vec<WsClient>,
+    buffer: Vec<u8>,
+    format: PcmFormat,
+    /// Max bytes queued for a single client
+    queue_limit: usize,
+}
+
+impl WsSinkPack {
+    pub fn new(
+        address: impl std::net::ToSocketAddrs,
+        buffer_size: usize,
+        format: PcmFormat,
+        queue_limit: usize,
+    ) -> Result<Self> {
+        let block_align = format.block_align();
+        if buffer_size < block_align {
+            bail!("Chunks of {buffer_size} bytes can't fit a {block_align} bytes long audio frame");
+        }
+        Ok(Self {
+            listener: HttpListener::bind(address)?,
+            responses: Responses::default(),
+            clients: Vec::new(),
+            buffer: vec![0; buffer_size],
+            format,
+            queue_limit,
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn n_clients(&self) -> usize {
+        self.clients.len()
+    }
+
+    fn handle(&mut self, socket: socket2::Socket, address: Option<SocketAddr>, request: Request) {
+        let response = match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/stream") if request.is_websocket() => {
+                match http::accept_websocket(&request) {
+                    Ok(handshake) => {
+                        let mut client = WsClient {
+                            socket,
+                            address,
+                            queue: ClientQueue::new(),
+                            incoming: Vec::new(),
+                            closing: false,
+                        };
+                        client.queue.push_pinned(handshake.into());
+                        client.push_frame(WS_TEXT, format_json(&self.format).as_bytes());
+                        self.clients.push(client);
+                        info!(
+                            "Client {address:?} connected, {} in total",
+                            self.clients.len()
+                        );
+                        return;
+                    }
+                    Err(refusal) => {
+                        warn!("Turned down the WebSocket of {address:?}");
+                        refusal
+                    }
+                }
+            }
+            ("GET", "/" | "/index.html") => {
+                let page = PLAYER_PAGE.replace("{{FORMAT}}", &format_json(&self.format));
+                http::response("200 OK", "text/html; charset=utf-8", page.as_bytes())
+            }
+            ("GET", _) => http::response("404 Not Found", "text/plain", b"Not found"),
+            _ => http::response(
+                "405 Method Not Allowed",
+                "text/plain",
+                b"Method not allowed",
+            ),
+        };
+        self.responses.push(socket, address, response);
+    }
+}
+
+impl SendAudio for WsSinkPack {
+    fn send_from_deque(&mut self, data: &mut VecDeque<u8>) -> Result<()> {
+        for (socket, address, request) in self.listener.poll() {
+            self.handle(socket, address, request);
+        }
+        self.responses.flush();
+
+        let block_align = self.format.block_align();
+        let n_sent = usize::min(self.buffer.len(), data.len());
+        let n_sent = n_sent - n_sent % block_align;
+        data.read_exact(&mut self.buffer[..n_sent])?;
+        if n_sent > 0 && !self.clients.is_empty() {
+            let mut frame = Vec::new();
+            websocket_frame(WS_BINARY, &self.buffer[..n_sent], &mut frame);
+            let chunk: Arc<[u8]> = frame.into();
+            for client in &mut self.clients {
+                if !client.closing {
+                    let n_skipped = client.queue.push(chunk.clone(), self.queue_limit);
+                    if n_skipped > 0 {
+                        debug!(
+                            "Client {:?} is too slow, skipped {n_skipped} bytes",
+                            client.address
+                        );
+                    }
+                }
+            }
+        }
+
+        let n_clients = self.clients.len();
+        self.clients.retain_mut(|client| {
+            let alive = client.flush();
+            if !alive {
+                info!("Dropping client {:?}", client.address);
+            }
+            alive
+        });
+        if self.clients.len() != n_clients {
+            info!("{} clients left", self.clients.len());
+        }
+        Ok(())
+    }
+}
+
+impl Restart for WsSinkPack {
+    fn restart(&mut self) -> Result<()> {
+        self.responses.clear();
+        self.clients.clear();
+        self.listener.reset()
+    }
+}
+
+/// Audio bytes between ICY metadata blocks
+const ICY_METAINT: usize = 16000;
+
+struct HttpClient {
+    socket: socket2::Socket,
+    address: Option<SocketAddr>,
+    queue: ClientQueue,
+    /// Audio bytes until the next ICY metadata block, if the client asked for them
+    until_metadata: Option<usize>,
+    sent_title: bool,
+}
+
+impl HttpClient {
+    /// ICY metadata block, with the title in the first one and empty afterwards
+    fn metadata(&mut self, title: &str) -> Arc<[u8]> {
+        if self.sent_title {
+            return Arc::new([0]);
+        }
+        self.sent_title = true;
+        let title: String = title.chars().filter(|&c| c != '\'').take(200).collect();
+        let mut block = format!("_StreamTitle='{title}';").into_bytes();
+        block.resize((block.len() - 1).div_ceil(16) * 16 + 1, 0);
+        block[0] = ((block.len() - 1) / 16) as u8;
+        block.into()
+    }
+
+    /// Queues `chunk` with ICY metadata cut in where needed, returns false if
+    /// that would be more than `limit` bytes
+    fn push(&mut self, chunk: &Arc<[u8]>, limit: usize, title: &str) -> bool {
+        if self.queue.queued_bytes() + chunk.len() > limit {
+            return false;
+        }
+        let Some(mut until_metadata) = self.until_metadata else {
+            self.queue.push(chunk.clone(), usize::MAX);
+            return true;
+        };
+        let mut rest = &chunk[..];
+        while rest.len() >= until_metadata {
+            let (before, after) = rest.split_at(until_metadata);
+            if !before.is_empty() {
+                self.queue.push(before.into(), usize::MAX);
+            }
+            let metadata = self.metadata(title);
+            self.queue.push(metadata, usize::MAX);
+            rest = after;
+            until_metadata = ICY_METAINT;
+        }
+        if !rest.is_empty() {
+            self.queue.push(rest.into(), usize::MAX);
+        }
+        self.until_metadata = Some(until_metadata - rest.len());
+        true
+    }
+
+    /// Sends as much as the socket takes right now, returns false if the client is gone
+    fn flush(&mut self) -> bool {
+        let mut incoming = [0; 256];
+        loop {
+            match (&self.socket).read(&mut incoming) {
+                Ok(0) => return false,
+                Ok(_) => {}
+                Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(_) => return false,
+            }
+        }
+        if let Err(error) = self.queue.flush(&self.socket) {
+            debug!("Can't send to client {:?}: {error}", self.address);
+            return false;
+        }
+        !self.queue.stalled(CLIENT_STALL_TIMEOUT)
+    }
+}
+
+/// Serves the stream as an endless WAV file to any number of HTTP clients
+pub struct HttpSinkPack {
+    listener: HttpListener,
+    responses: Responses,
+    /// Path the stream is served on
+    path: String,
+    clients: Vec<HttpClient>,
+    buffer: Vec<u8>,
+    format: PcmFormat,
+    /// Max bytes queued for a single client before it's dropped
+    queue_limit: usize,
+    name: String,
+}
+
+impl HttpSinkPack {
+    pub fn new(
+        address: impl std::net::ToSocketAddrs,
+        path: &str,
+        buffer_size: usize,
+        format: PcmFormat,
+        queue_limit: usize,
+        name: &str,
+    ) -> Result<Self> {
+        let block_align = format.block_align();
+        if buffer_size < block_align {
+            bail!("Chunks of {buffer_size} bytes can't fit a {block_align} bytes long audio frame");
+        }
+        Ok(Self {
+            listener: HttpListener::bind(address)?,
+            responses: Responses::default(),
+            path: path.to_owned(),
+            clients: Vec::new(),
+            buffer: vec![0; buffer_size],
+            format,
+            queue_limit,
+            name: name.to_owned(),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn n_clients(&self) -> usize {
+        self.clients.len()
+    }
+
+    fn handle(&mut self, socket: socket2::Socket, address: Option<SocketAddr>, request: Request) {
+        let response = match request.method.as_str() {
+            "GET" | "HEAD" if request.path == self.path => {
+                let icy = request.header("Icy-MetaData") == Some("1");
+                let name = format!("icy-name: {}", self.name);
+                let metaint = format!("icy-metaint: {ICY_METAINT}");
+                let mut headers = vec![
+                    "Content-Type: audio/wav",
+                    "Cache-Control: no-store",
+                    "Connection: close",
+                    &name,
+                ];
+                if icy {
+                    headers.push(&metaint);
+                }
+                let head = http::response_head("200 OK", &headers).into_bytes();
+                if request.method == "HEAD" {
+                    self.responses.push(socket, address, head);
+                    return;
+                }
+                let mut client = HttpClient {
+                    socket,
+                    address,
+                    queue: ClientQueue::new(),
+                    until_metadata: icy.then_some(ICY_METAINT),
+                    sent_title: false,
+                };
+                client.queue.push_pinned(head.into());
+                client.push(
+                    &self.format.wav_header(u32::MAX).into(),
+                    usize::MAX,
+                    &self.name,
+                );
+                self.clients.push(client);
+                info!(
+                    "Client {address:?} connected, {} in total",
+                    self.clients.len()
+                );
+                return;
+            }
+            "GET" | "HEAD" => http::response("404 Not Found", "text/plain", b"Not found"),
+            _ => http::response(
+                "405 Method Not Allowed",
+                "text/plain",
+                b"Method not allowed",
+            ),
+        };
+        self.responses.push(socket, address, response);
+    }
+}
+
+impl SendAudio for HttpSinkPack {
+    fn send_from_deque(&mut self, data: &mut VecDeque<u8>) -> Result<()> {
+        for (socket, address, request) in self.listener.poll() {
+            self.handle(socket, address, request);
+        }
+        self.responses.flush();
+
+        let block_align = self.format.block_align();
+        let n_sent = usize::min(self.buffer.len(), data.len());
+        let n_sent = n_sent - n_sent % block_align;
+        data.read_exact(&mut self.buffer[..n_sent])?;
+        let chunk: Option<Arc<[u8]>> = (n_sent > 0).then(|| self.buffer[..n_sent].into());
+
+        let n_clients = self.clients.len();
+        self.clients.retain_mut(|client| {
+            let pushed = chunk
+                .as_ref()
+                .is_none_or(|chunk| client.push(chunk, self.queue_limit, &self.name));
+            if !pushed {
+                info!("Client {:?} is too slow, dropping it", client.address);
+                return false;
+            }
+            let alive = client.flush();
+            if !alive {
+                info!("Dropping client {:?}", client.address);
+            }
+            alive
+        });
+        if self.clients.len() != n_clients {
+            info!("{} clients left", self.clients.len());
+        }
+        Ok(())
+    }
+}
+
+impl Restart for HttpSinkPack {
+    fn restart(&mut self) -> Result<()> {
+        self.responses.clear();
+        self.clients.clear();
+        self.listener.reset()
+    }
+}
+
+/// Where and how to log into an Icecast server, from `user:password@host:port/mount`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IcecastTarget {
+    pub user: String,
+    pub password: String,
+    /// `host:port` as given
+    pub host: String,
+    pub mount: String,
+}
+
+impl IcecastTarget {
+    pub fn parse(url: &str) -> Result<Self> {
+        let (credentials, rest) = url.rsplit_once('@').ok_or(anyhow!(
+            "Icecast url needs a password, like source:hackme@host:8000/mount"
+        ))?;
+        let (user, password) = credentials
+            .split_once(':')
+            .unwrap_or(("source", credentials));
+        let (host, mount) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => bail!("Icecast url needs a mount point, like host:8000/stream"),
+        };
+        if mount.len() < 2 {
+            bail!("Icecast mount point can't be empty");
+        }
+        Ok(Self {
+            user: user.to_owned(),
+            password: password.to_owned(),
+            host: host.to_owned(),
+            mount: mount.to_owned(),
+        })
+    }
+}
+
+enum IcecastState {
+    /// Waiting for the next attempt
+    Down,
+    /// Sending the request and waiting for the server to accept it
+    Handshake {
+        sent: usize,
+        response: Vec<u8>,
+        since: Instant,
+    },
+    Streaming,
+}
+
+/// Sends the stream as a WAV to an Icecast server, like any other source client would
+pub struct IcecastSinkPack {
+    target: IcecastTarget,
+    address: socket2::SockAddr,
+    socket: socket2::Socket,
+    state: IcecastState,
+    /// `SOURCE` for servers that don't know `PUT` yet
+    method: &'static str,
+    backoff: Backoff,
+    buffer: Vec<u8>,
+    format: PcmFormat,
+    queue: ClientQueue,
+    /// Max bytes queued before whole chunks are skipped
+    queue_limit: usize,
+    name: String,
+    timeouts: IdcTimeouts,
+}
+
+impl IcecastSinkPack {
+    fn create_socket(
+        address: &socket2::SockAddr,
+        timeouts: &IdcTimeouts,
+    ) -> Result<socket2::Socket> {
+        let socket = tcp_socket(address)?;
+        socket.set_nonblocking(true)?;
+        timeouts.configure(&socket)?;
+        let _ = socket.connect(address);
+        Ok(socket)
+    }
+
+    pub fn new(
+        target: IcecastTarget,
+        buffer_size: usize,
+        format: PcmFormat,
+        queue_limit: usize,
+        name: &str,
+        timeouts: IdcTimeouts,
+        reconnect: ReconnectPolicy,
+    ) -> Result<Self> {
+        let block_align = format.block_align();
+        if buffer_size < block_align {
+            bail!("Chunks of {buffer_size} bytes can't fit a {block_align} bytes long audio frame");
+        }
+        let address: SocketAddr = target
+            .host
+            .to_socket_addrs()?
+            .next()
+            .ok_or(anyhow!("Couldn't get socket addr."))?;
+        let backoff = Backoff::new(
+            reconnect,
+            format!("icecast sink to {}{}", target.host, target.mount),
+        );
+        let address = address.into();
+        Ok(Self {
+            target,
+            socket: tcp_socket(&address)?,
+            address,
+            state: IcecastState::Down,
+            method: "PUT",
+            backoff,
+            buffer: vec![0; buffer_size],
+            format,
+            queue: ClientQueue::new(),
+            queue_limit,
+            name: name.to_owned(),
+            timeouts,
+        })
+    }
+
+    pub fn on_state_change(&mut self, callback: impl FnMut(ConnectionState) + Send + 'static) {
+        self.backoff.set_callback(callback);
+    }
+
+    pub fn is_streaming(&self) -> bool {
+        matches!(self.state, IcecastState::Streaming)
+    }
+
+    fn request(&self) -> String {
+        let credentials = format!("{}:{}", self.target.user, self.target.password);
+        let credentials = base64::engine::general_purpose::STANDARD.encode(credentials);
+        let format = &self.format;
+        let request_line = match self.method {
+            "PUT" => format!("PUT {} HTTP/1.1", self.target.mount),
+            method => format!("{method} {} HTTP/1.0", self.target.mount),
+        };
+        let mut headers = vec![
+            format!("Host: {}", self.target.host),
+            format!("Authorization: Basic {credentials}"),
+            "Content-Type: audio/wav".to_owned(),
+            format!(
+                "User-Agent: stupid-audio-stream/{}",
+                env!("CARGO_PKG_VERSION")
+            ),
+            format!("Ice-Name: {}", self.name),
+            "Ice-Public: 0".to_owned(),
+            format!(
+                "Ice-Audio-Info: samplerate={};channels={}",
+                format.sample_rate, format.channels
+            ),
+        ];
+        if self.method == "PUT" {
+            headers.push("Expect: 100-continue".to_owned());
+        }
+        let mut request = request_line;
+        for header in headers {
+            request.push_str("\r\n");
+            request.push_str(&header);
+        }
+        request.push_str("\r\n\r\n");
+        request
+    }
+
+    fn reconnect(&mut self) -> Result<()> {
+        self.backoff.lost();
+        self.state = IcecastState::Down;
+        if self.backoff.ready() {
+            self.backoff.attempt()?;
+            self.socket = Self::create_socket(&self.address, &self.timeouts)?;
+            self.queue = ClientQueue::new();
+            self.state = IcecastState::Handshake {
+                sent: 0,
+                response: Vec::new(),
+                since: Instant::now(),
+            };
+        }
+        Ok(())
+    }
+
+    /// Pushes the handshake along, returns false if it failed
+    fn handshake(&mut self) -> bool {
+        let request = self.request();
+        let IcecastState::Handshake {
+            sent,
+            response,
+            since,
+        } = &mut self.state
+        else {
+            return true;
+        };
+        if since.elapsed() >= self.timeouts.peer_timeout {
+            warn!(
+                "Icecast server didn't answer in {:?}",
+                self.timeouts.peer_timeout
+            );
+            return false;
+        }
+        while *sent < request.len() {
+            match self.socket.send(&request.as_bytes()[*sent..]) {
+                Ok(n_written) => *sent += n_written,
+                Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => return true,
+                Err(error) => {
+                    debug!("Couldn't send the request: {error}");
+                    return false;
+                }
+            }
+        }
+        let mut chunk = [0; 1024];
+        let head = loop {
+            if let Some(end) = response.windows(4).position(|w| w == b"\r\n\r\n") {
+                break String::from_utf8_lossy(&response[..end]).into_owned();
+            }
+            if response.len() > http::MAX_HEAD_LEN {
+                warn!("Icecast server sent a huge response");
+                return false;
+            }
+            match (&self.socket).read(&mut chunk) {
+                Ok(0) => {
+                    debug!("Icecast server closed the connection during the handshake");
+                    return false;
+                }
+                Ok(n_read) => response.extend_from_slice(&chunk[..n_read]),
+                Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => return true,
+                Err(error) => {
+                    debug!("Couldn't read the response: {error}");
+                    return false;
+                }
+            }
+        };
+        let status_line = head.lines().next().unwrap_or_default();
+        match status_line.split(' ').nth(1) {
+            Some("100" | "200") => {
+                self.state = IcecastState::Streaming;
+                self.backoff.connected();
+                self.queue
+                    .push_pinned(self.format.wav_header(u32::MAX).into());
+                true
+            }
+            Some("405" | "501") if self.method == "PUT" => {
+                info!("Icecast server doesn't take PUT, trying SOURCE");
+                self.method = "SOURCE";
+                false
+            }
+            Some("401" | "403") => {
+                warn!("Icecast server didn't take the credentials: {status_line}");
+                false
+            }
+            _ => {
+                warn!("Icecast server refused the stream: {status_line}");
+                false
+            }
+        }
+    }
+
+    /// Reads whatever the server sent, returns false if the connection is dead
+    fn server_alive(&mut self) -> bool {
+        let mut incoming = [0; 256];
+        loop {
+            match (&self.socket).read(&mut incoming) {
+                Ok(0) => return false,
+                Ok(_) => {}
+                Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => return true,
+                Err(_) => return false,
+            }
+        }
+    }
+}
+
+impl SendAudio for IcecastSinkPack {
+    fn send_from_deque(&mut self, data: &mut VecDeque<u8>) -> Result<()> {
+        let alive = match self.state {
+            IcecastState::Down => false,
+            IcecastState::Handshake { .. } => self.handshake(),
+            IcecastState::Streaming => self.server_alive(),
+        };
+        if !alive {
+            self.reconnect()?;
+        }
+        if !self.is_streaming() {
+            // Couldn't send, just consume the data
+            let n_blocks = data.len() / HYPOT_AUDIO_ALIGNMENT;
+            data.drain(0..(n_blocks * HYPOT_AUDIO_ALIGNMENT));
+            return Ok(());
+        }
+
+        let block_align = self.format.block_align();
+        while data.len() >= block_align {
+            let n_sent = usize::min(self.buffer.len(), data.len());
+            let n_sent = n_sent - n_sent % block_align;
+            data.read_exact(&mut self.buffer[..n_sent])?;
+            let n_skipped = self
+                .queue
+                .push(self.buffer[..n_sent].into(), self.queue_limit);
+            if n_skipped > 0 {
+                debug!("Icecast server is too slow, skipped {n_skipped} bytes");
+            }
+        }
+        let flushed = match self.queue.flush(&self.socket) {
+            Ok(_) => !self.queue.stalled(self.timeouts.peer_timeout),
+            Err(error) => {
+                debug!("Couldn't send: {error}");
+                false
+            }
+        };
+        if !flushed {
+            self.reconnect()?;
+        }
+        Ok(())
+    }
+}
+
+impl Restart for IcecastSinkPack {
+    fn restart(&mut self) -> Result<()> {
+        self.backoff.lost();
+        self.state = IcecastState::Down;
+        Ok(())
+    }
+}
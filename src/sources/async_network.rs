@@ -0,0 +1,299 @@
+use std::{collections::VecDeque, io, net::SocketAddr, time::Instant};
+
+use anyhow::{Result, anyhow};
+use log::{debug, warn};
+use tokio::{
+    io::Interest,
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{
+    AsyncRestart,
+    crypto::Opener,
+    idc::{Backoff, ConnectionState, FrameDecoder, HEARTBEAT, IdcTimeouts, ReconnectPolicy},
+    network_utils::AsyncDatagramSocket,
+    sources::{
+        AsyncRecvAudio,
+        network::{CheckedUdpSourcePack, HOLD_TIMEOUT, UdpSourcePack},
+    },
+};
+
+impl<S: AsyncDatagramSocket + Send> AsyncRecvAudio for UdpSourcePack<S> {
+    async fn recv_to_deque(&mut self, buf: &mut VecDeque<u8>) -> Result<()> {
+        let (n_read, _) = self.socket.recv_from(self.buffer.as_mut_slice()).await?;
+        buf.extend(&self.buffer[..n_read]);
+        Ok(())
+    }
+}
+
+impl<S: AsyncDatagramSocket + Send> AsyncRestart for UdpSourcePack<S> {
+    async fn restart(&mut self) -> Result<()> {
+        self.socket.reopen().await?;
+        Ok(())
+    }
+}
+
+impl<S: AsyncDatagramSocket + Send> AsyncRecvAudio for CheckedUdpSourcePack<S> {
+    async fn recv_to_deque(&mut self, buf: &mut VecDeque<u8>) -> Result<()> {
+        let recv = self.socket.recv_from(self.buffer.as_mut_slice());
+        // Only held back packets are worth waking up for
+        let received = if self.pending.is_empty() {
+            recv.await
+        } else {
+            match tokio::time::timeout(HOLD_TIMEOUT, recv).await {
+                Ok(received) => received,
+                Err(_) => {
+                    self.flush_held(buf);
+                    return Ok(());
+                }
+            }
+        };
+        let (n_read, _) = received?;
+        self.handle_packet(n_read, buf);
+        Ok(())
+    }
+}
+
+impl<S: AsyncDatagramSocket + Send> AsyncRestart for CheckedUdpSourcePack<S> {
+    async fn restart(&mut self) -> Result<()> {
+        self.current_id = 0;
+        self.pending.clear();
+        self.socket.reopen().await?;
+        Ok(())
+    }
+}
+
+enum IdcRole {
+    /// Wait for the sink to connect
+    Listen(TcpListener),
+    /// Connect to the sink
+    Connect(SocketAddr),
+}
+
+/// [`IdcSourcePack`](super::network::IdcSourcePack) on tokio
+pub struct AsyncIdcSourcePack {
+    role: IdcRole,
+    stream: Option<TcpStream>,
+    backoff: Backoff,
+    buffer: Vec<u8>,
+    decoder: FrameDecoder,
+    timeouts: IdcTimeouts,
+    last_heard: Instant,
+    last_sent: Instant,
+    /// The part of a heartbeat that still has to go out
+    outgoing: Vec<u8>,
+}
+
+impl AsyncIdcSourcePack {
+    fn with_role(
+        role: IdcRole,
+        buffer_size: usize,
+        opener: Option<Opener>,
+        timeouts: IdcTimeouts,
+        backoff: Backoff,
+    ) -> Self {
+        Self {
+            role,
+            stream: None,
+            backoff,
+            buffer: vec![0; buffer_size],
+            decoder: FrameDecoder::new(opener),
+            timeouts,
+            last_heard: Instant::now(),
+            last_sent: Instant::now(),
+            outgoing: Vec::new(),
+        }
+    }
+
+    /// Listens on `address` for the sink to connect
+    pub async fn new(
+        address: impl tokio::net::ToSocketAddrs,
+        buffer_size: usize,
+        opener: Option<Opener>,
+        timeouts: IdcTimeouts,
+        reconnect: ReconnectPolicy,
+    ) -> Result<Self> {
+        let listener = TcpListener::bind(address).await?;
+        let address = listener.local_addr()?;
+        Ok(Self::with_role(
+            IdcRole::Listen(listener),
+            buffer_size,
+            opener,
+            timeouts,
+            Backoff::new(reconnect, format!("idc source on {address}")),
+        ))
+    }
+
+    /// Connects to a sink listening on `address`, reconnecting whenever that fails
+    pub async fn connect(
+        address: impl tokio::net::ToSocketAddrs,
+        buffer_size: usize,
+        opener: Option<Opener>,
+        timeouts: IdcTimeouts,
+        reconnect: ReconnectPolicy,
+    ) -> Result<Self> {
+        let address = tokio::net::lookup_host(address)
+            .await?
+            .next()
+            .ok_or(anyhow!("Couldn't get socket addr."))?;
+        Ok(Self::with_role(
+            IdcRole::Connect(address),
+            buffer_size,
+            opener,
+            timeouts,
+            Backoff::new(reconnect, format!("idc source from {address}")),
+        ))
+    }
+
+    /// Where a listening source listens
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match &self.role {
+            IdcRole::Listen(listener) => listener.local_addr().ok(),
+            IdcRole::Connect(_) => None,
+        }
+    }
+
+    pub fn on_state_change(&mut self, callback: impl FnMut(ConnectionState) + Send + 'static) {
+        self.backoff.set_callback(callback);
+    }
+
+    fn drop_connection(&mut self) {
+        self.stream = None;
+        self.backoff.lost();
+    }
+
+    async fn wait_for_connection(&mut self) -> Result<()> {
+        let stream = match &self.role {
+            IdcRole::Listen(listener) => match listener.accept().await {
+                Ok((stream, addr)) => {
+                    debug!("Accepted connection from {addr}");
+                    stream
+                }
+                Err(err) => {
+                    warn!("Couldn't accept a connection: {err}");
+                    self.backoff.attempt()?;
+                    tokio::time::sleep_until(self.backoff.next_attempt().into()).await;
+                    return Ok(());
+                }
+            },
+            IdcRole::Connect(address) => {
+                let address = *address;
+                tokio::time::sleep_until(self.backoff.next_attempt().into()).await;
+                self.backoff.attempt()?;
+                let connect = TcpStream::connect(address);
+                match tokio::time::timeout(self.timeouts.peer_timeout, connect).await {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(err)) => {
+                        debug!("Couldn't connect to {address}: {err}");
+                        return Ok(());
+                    }
+                    Err(_) => {
+                        debug!("Couldn't connect to {address}: timed out");
+                        return Ok(());
+                    }
+                }
+            }
+        };
+        self.backoff.connected();
+        self.timeouts.configure(&socket2::SockRef::from(&stream))?;
+        self.stream = Some(stream);
+        self.last_heard = Instant::now();
+        self.last_sent = Instant::now();
+        self.outgoing.clear();
+        self.decoder.reset();
+        Ok(())
+    }
+}
+
+impl AsyncRecvAudio for AsyncIdcSourcePack {
+    async fn recv_to_deque(&mut self, buf: &mut VecDeque<u8>) -> Result<()> {
+        let initial_len = buf.len();
+        loop {
+            let Some(stream) = &self.stream else {
+                self.wait_for_connection().await?;
+                continue;
+            };
+
+            if self.outgoing.is_empty() && self.last_sent.elapsed() >= self.timeouts.keepalive {
+                self.outgoing.extend_from_slice(&HEARTBEAT);
+            }
+            // Wake up to send heartbeats and to notice that the sink is gone
+            let mut deadline = self.last_heard + self.timeouts.peer_timeout;
+            let mut interest = Interest::READABLE;
+            if self.outgoing.is_empty() {
+                deadline = deadline.min(self.last_sent + self.timeouts.keepalive);
+            } else {
+                interest |= Interest::WRITABLE;
+            }
+            let ready = tokio::select! {
+                ready = stream.ready(interest) => ready,
+                () = tokio::time::sleep_until(deadline.into()) => {
+                    if self.last_heard.elapsed() >= self.timeouts.peer_timeout {
+                        warn!(
+                            "Haven't heard from the sink for {:?}, dropping connection",
+                            self.last_heard.elapsed()
+                        );
+                        self.drop_connection();
+                    }
+                    continue;
+                }
+            };
+            let ready = match ready {
+                Ok(ready) => ready,
+                Err(err) => {
+                    debug!("Connection lost: {err}");
+                    self.drop_connection();
+                    continue;
+                }
+            };
+
+            if ready.is_writable() && !self.outgoing.is_empty() {
+                match stream.try_write(&self.outgoing) {
+                    Ok(n_written) => {
+                        self.outgoing.drain(..n_written);
+                        self.last_sent = Instant::now();
+                    }
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(err) => {
+                        debug!("Couldn't send a heartbeat: {err}");
+                        self.drop_connection();
+                        continue;
+                    }
+                }
+            }
+
+            if ready.is_readable() {
+                match stream.try_read(self.buffer.as_mut_slice()) {
+                    Ok(0) => {
+                        debug!("Connection closed by the sink");
+                        self.drop_connection();
+                    }
+                    Ok(n_read) => {
+                        self.last_heard = Instant::now();
+                        let peer = stream.peer_addr().ok();
+                        match self.decoder.decode(&self.buffer[..n_read], buf, peer) {
+                            Ok(()) if buf.len() > initial_len => return Ok(()),
+                            Ok(()) => {}
+                            Err(err) => {
+                                warn!("Dropping connection: {err}");
+                                self.drop_connection();
+                            }
+                        }
+                    }
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(err) => {
+                        debug!("Connection lost: {err}");
+                        self.drop_connection();
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl AsyncRestart for AsyncIdcSourcePack {
+    async fn restart(&mut self) -> Result<()> {
+        self.drop_connection();
+        Ok(())
+    }
+}
@@ -42,7 +42,7 @@ impl DeviceSourcePack {
         let prev = buf.len();
         self.audio_capture_client
             .read_from_device_to_deque(buf)
-            .map_err(|err| anyhow!("Couldn't read from device: {err}"))?;
+            .map_err(|err| device_utils::device_error(err, "Couldn't read from device"))?;
         Ok(buf.len() - prev)
     }
 }
@@ -50,14 +50,25 @@ impl DeviceSourcePack {
 impl RecvAudio for DeviceSourcePack {
     fn recv_to_deque(&mut self, buf: &mut VecDeque<u8>) -> Result<()> {
         while let 0 = self.maybe_recv_to_deque(buf)? {
-            self.event_handle
-                .wait_for_event(1000)
-                .map_err(|err| anyhow!("Timeout error: {err}"))?;
+            match self.event_handle.wait_for_event(1000) {
+                Ok(()) => {}
+                // Nothing captured yet, like while the device is paused
+                Err(wasapi::WasapiError::EventTimeout) => return Ok(()),
+                Err(err) => {
+                    return Err(device_utils::device_error(err, "Device stopped delivering"));
+                }
+            }
         }
         Ok(())
     }
 }
 
+impl Drop for DeviceSourcePack {
+    fn drop(&mut self) {
+        let _ = self.audio_client.stop_stream();
+    }
+}
+
 impl Restart for DeviceSourcePack {
     fn restart(&mut self) -> Result<()> {
         self.audio_client
@@ -1,11 +1,26 @@
-use std::collections::VecDeque;
+use std::{collections::VecDeque, net::UdpSocket, time::Duration};
 
 use log::info;
 
 use anyhow::{Result, anyhow};
 
-use crate::{Args, RecvAudioRestart, device_utils};
+use crate::{
+    Args, RecvAudioRestart,
+    crypto::{self, Opener, SealedSocket},
+    device_utils,
+    endpoint::{
+        COUNTED, Endpoint, GAIN, IMPAIRMENTS, KEEPALIVE, ON_DEVICE_LOST, ON_FATAL, ON_TRANSIENT,
+        PREBUFFER, Registry, SIZE, Scheme, TIMEOUT, WINDOW,
+    },
+    idc::{IdcTimeouts, ReconnectPolicy},
+    impair::{ImpairedProxy, ImpairedSocket, Impairment, Proxied, proxy_address, resolve},
+    network_utils::{DatagramSocket, FilteredSocket, PeerFilter},
+    pcm::PcmFormat,
+    plc::Concealer,
+};
 
+#[cfg(feature = "tokio")]
+pub mod async_network;
 pub mod device;
 pub mod network;
 
@@ -13,46 +28,366 @@ pub trait RecvAudio {
     fn recv_to_deque(&mut self, buf: &mut VecDeque<u8>) -> Result<()>;
 }
 
-pub fn from_args(args: &Args) -> Result<Box<dyn RecvAudioRestart>> {
-    Ok(if let Some(address) = args.source.strip_prefix("udp://") {
-        let buffer_size = args.datagram_size;
-        if args.counted_udp {
-            let pack = network::CheckedUdpSourcePack::new(address, buffer_size)?;
-            info!(
-                "Listening on {address} to packets of a most {buffer_size} bytes with loss checks"
-            );
-            Box::new(pack)
-        } else {
-            let pack = network::UdpSourcePack::new(address, buffer_size)?;
-            info!("Listening on {address} to packets of a most {buffer_size} bytes");
-            Box::new(pack)
+/// [`RecvAudio`] for tokio. Cancel-safe: dropping the future loses nothing
+/// that was already received, the next call picks up where it left off
+#[cfg(feature = "tokio")]
+pub trait AsyncRecvAudio {
+    fn recv_to_deque(&mut self, buf: &mut VecDeque<u8>) -> impl Future<Output = Result<()>> + Send;
+}
+
+/// Puts the peer filter of `args` under the key, so senders that aren't let
+/// through never get to the opener and can't take over its session
+fn guard_socket(
+    args: &Args,
+    socket: Box<dyn DatagramSocket>,
+    opener: Option<Opener>,
+) -> Box<dyn DatagramSocket> {
+    if !args.allow.is_empty() {
+        info!("Only accepting packets from {:?}", args.allow);
+    }
+    if let Some(lock_peer) = args.lock_peer {
+        info!("Only accepting packets from one sender until it's silent for {lock_peer} ms");
+    }
+    let lock_timeout = args.lock_peer.map(Duration::from_millis);
+    let socket: Box<dyn DatagramSocket> = if args.allow.is_empty() && lock_timeout.is_none() {
+        socket
+    } else {
+        let filter = PeerFilter::new(args.allow.clone(), lock_timeout);
+        Box::new(FilteredSocket::new(socket, filter))
+    };
+    match opener {
+        Some(opener) => {
+            info!("Only accepting packets sealed with the pre-shared key");
+            Box::new(SealedSocket::opening(socket, opener))
         }
-    } else if let Some(address) = args.source.strip_prefix("idc://") {
-        let buffer_size = args.datagram_size;
-        let pack = network::IdcSourcePack::new(address, buffer_size)?;
-        info!("Listening on {address} to packets of a most {buffer_size} bytes without caring");
+        None => socket,
+    }
+}
+
+pub(crate) fn udp_source(
+    args: &Args,
+    socket: Box<dyn DatagramSocket>,
+    address: &str,
+    opener: Option<Opener>,
+) -> Result<Box<dyn RecvAudioRestart>> {
+    let buffer_size = args.datagram_size;
+    let mut socket = guard_socket(args, socket, opener);
+    if socket.read_timeout()?.is_none() {
+        socket.set_read_timeout(Some(network::RECV_TIMEOUT))?;
+    }
+    Ok(if args.counted_udp {
+        let reorder_window = args.reorder_window;
+        let concealer = Concealer::new(PcmFormat::from_args(args)?, args.concealment);
+        let pack = network::CheckedUdpSourcePack::with_socket(
+            socket,
+            buffer_size,
+            reorder_window,
+            concealer,
+        );
+        info!(
+            "Listening on {address} to packets of a most {buffer_size} bytes with loss checks and a reorder window of {reorder_window}, concealing losses with {:?}",
+            args.concealment
+        );
         Box::new(pack)
     } else {
-        let format = wasapi::WaveFormat::new(
-            args.bits_per_sample,
-            args.bits_per_sample,
-            if args.use_float {
-                &wasapi::SampleType::Float
-            } else {
-                &wasapi::SampleType::Int
-            },
-            args.sample_rate,
-            args.channels,
-            None,
-        );
-        let device = device_utils::find_device_by_name(wasapi::Direction::Capture, &args.source)?;
-        let source_pack = device::DeviceSourcePack::new(device, format)?;
-
-        let name = source_pack
-            .device()
-            .get_friendlyname()
-            .map_err(|err| anyhow!("Couldn't get device name due to error: {err}"))?;
-        info!("Capturing from {name}");
-        Box::new(source_pack)
+        let pack = network::UdpSourcePack::with_socket(socket, buffer_size);
+        info!("Listening on {address} to packets of a most {buffer_size} bytes");
+        Box::new(pack)
     })
 }
+
+#[cfg(feature = "quic")]
+fn quic_source(args: &Args, address: &str) -> Result<Box<dyn RecvAudioRestart>> {
+    use crate::quic::{QuicCerts, QuicSocket};
+
+    let buffer_size = args.datagram_size;
+    let reorder_window = args.reorder_window;
+    let format = PcmFormat::from_args(args)?;
+    let socket = QuicSocket::listen(
+        address.parse()?,
+        format,
+        &QuicCerts::from_args(args)?,
+        IdcTimeouts::from_args(args),
+        ReconnectPolicy::from_args(args)?,
+    )?;
+    let concealer = Concealer::new(format, args.concealment);
+    let pack =
+        network::CheckedUdpSourcePack::with_socket(socket, buffer_size, reorder_window, concealer);
+    info!(
+        "Listening on {address} for QUIC datagrams with loss checks and a reorder window of {reorder_window}, concealing losses with {:?}",
+        args.concealment
+    );
+    Ok(Box::new(pack))
+}
+
+#[cfg(not(feature = "quic"))]
+fn quic_source(_args: &Args, _address: &str) -> Result<Box<dyn RecvAudioRestart>> {
+    Err(anyhow!(
+        "Built without QUIC support, enable the quic feature"
+    ))
+}
+
+fn open_udp(endpoint: &Endpoint, args: &Args) -> Result<Box<dyn RecvAudioRestart>> {
+    let key = crypto::key_from_args(args)?;
+    let address = &endpoint.address;
+    let socket = Box::new(UdpSocket::bind(address)?);
+    udp_source(args, socket, address, key.as_ref().map(Opener::new))
+}
+
+fn open_impaired_udp(endpoint: &Endpoint, args: &Args) -> Result<Box<dyn RecvAudioRestart>> {
+    let key = crypto::key_from_args(args)?;
+    let address = &endpoint.address;
+    let impairment = Impairment::parse(&endpoint.query_of(&IMPAIRMENTS))?;
+    info!("Impairing received packets with {impairment:?}");
+    let socket = Box::new(ImpairedSocket::new(UdpSocket::bind(address)?, impairment));
+    udp_source(args, socket, address, key.as_ref().map(Opener::new))
+}
+
+fn open_idc_listen(endpoint: &Endpoint, args: &Args) -> Result<Box<dyn RecvAudioRestart>> {
+    let key = crypto::key_from_args(args)?;
+    let address = &endpoint.address;
+    let buffer_size = args.datagram_size;
+    let opener = key.as_ref().map(Opener::new);
+    let timeouts = IdcTimeouts::from_args(args);
+    let reconnect = ReconnectPolicy::from_args(args)?;
+    let pack = network::IdcSourcePack::new(address, buffer_size, opener, timeouts, reconnect)?;
+    info!("Listening on {address} to packets of a most {buffer_size} bytes without caring");
+    Ok(Box::new(pack))
+}
+
+fn open_idc_connect(endpoint: &Endpoint, args: &Args) -> Result<Box<dyn RecvAudioRestart>> {
+    Ok(Box::new(idc_connect_source(args, &endpoint.address)?))
+}
+
+fn open_impaired_idc_connect(
+    endpoint: &Endpoint,
+    args: &Args,
+) -> Result<Box<dyn RecvAudioRestart>> {
+    let target = resolve(&endpoint.address)?;
+    let impairment = Impairment::parse(&endpoint.query_of(&IMPAIRMENTS))?;
+    info!("Impairing the connection to {target} with {impairment:?}");
+    let proxy = ImpairedProxy::new(proxy_address(target), target, impairment)?;
+    let inner = idc_connect_source(args, &proxy.local_addr().to_string())?;
+    Ok(Box::new(Proxied { inner, proxy }))
+}
+
+fn idc_connect_source(args: &Args, address: &str) -> Result<network::IdcSourcePack> {
+    let key = crypto::key_from_args(args)?;
+    let buffer_size = args.datagram_size;
+    let opener = key.as_ref().map(Opener::new);
+    let timeouts = IdcTimeouts::from_args(args);
+    let reconnect = ReconnectPolicy::from_args(args)?;
+    let pack = network::IdcSourcePack::connect(address, buffer_size, opener, timeouts, reconnect)?;
+    info!("Receiving from {address} in chunks of at most {buffer_size} bytes without caring");
+    Ok(pack)
+}
+
+fn open_device(endpoint: &Endpoint, args: &Args) -> Result<Box<dyn RecvAudioRestart>> {
+    let format = wasapi::WaveFormat::new(
+        args.bits_per_sample,
+        args.bits_per_sample,
+        if args.use_float {
+            &wasapi::SampleType::Float
+        } else {
+            &wasapi::SampleType::Int
+        },
+        args.sample_rate,
+        args.channels,
+        None,
+    );
+    let device = device_utils::find_device_by_name(wasapi::Direction::Capture, &endpoint.address)?;
+    let source_pack = device::DeviceSourcePack::new(device, format)?;
+
+    let name = source_pack
+        .device()
+        .get_friendlyname()
+        .map_err(|err| anyhow!("Couldn't get device name due to error: {err}"))?;
+    info!("Capturing from {name}");
+    Ok(Box::new(source_pack))
+}
+
+/// Every kind of source there is, with device names as the fallback
+pub fn registry() -> Registry<Box<dyn RecvAudioRestart>> {
+    let mut registry = Registry::new("source");
+    registry.register(Scheme::new(
+        "udp",
+        "<bind address>",
+        "Receives UDP datagrams",
+        &[SIZE, COUNTED, WINDOW],
+        open_udp,
+    ));
+    registry.register(Scheme::new(
+        "impair+udp",
+        "<bind address>",
+        "Receives UDP datagrams through a simulated bad network",
+        &[&[SIZE, COUNTED, WINDOW][..], &IMPAIRMENTS].concat(),
+        open_impaired_udp,
+    ));
+    for name in ["idc", "idc-listen"] {
+        registry.register(Scheme::new(
+            name,
+            "<bind address>",
+            "Waits for an idc sink to connect",
+            &[SIZE, KEEPALIVE, TIMEOUT],
+            open_idc_listen,
+        ));
+    }
+    registry.register(Scheme::new(
+        "idc-connect",
+        "<host:port>",
+        "Connects to a listening idc sink",
+        &[SIZE, KEEPALIVE, TIMEOUT],
+        open_idc_connect,
+    ));
+    registry.register(Scheme::new(
+        "impair+idc-connect",
+        "<host:port>",
+        "Connects to a listening idc sink through a simulated bad network",
+        &[&[SIZE, KEEPALIVE, TIMEOUT][..], &IMPAIRMENTS].concat(),
+        open_impaired_idc_connect,
+    ));
+    registry.register(Scheme::new(
+        "quic",
+        "<bind address>",
+        "Waits for a quic sink to connect (needs the quic feature)",
+        &[SIZE, WINDOW, KEEPALIVE, TIMEOUT],
+        |endpoint: &Endpoint, args: &Args| quic_source(args, &endpoint.address),
+    ));
+    // Taken by the pipeline when mixing
+    registry.add_common(&[GAIN, PREBUFFER, ON_TRANSIENT, ON_DEVICE_LOST, ON_FATAL]);
+    registry.set_fallback(open_device);
+    registry
+}
+
+/// Opens the source at `url`, taking everything else from `args`
+pub fn from_url(url: &str, args: &Args) -> Result<Box<dyn RecvAudioRestart>> {
+    registry().open(url, args)
+}
+
+/// Opens the source `args` names, like a pipeline built from them would
+pub fn from_args(args: &Args) -> Result<Box<dyn RecvAudioRestart>> {
+    from_url(&args.source, args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{Sealer, parse_key};
+
+    #[test]
+    fn from_args_opens_the_source_they_name() {
+        let args = Args {
+            source: "udp://127.0.0.1:0?size=800".to_owned(),
+            ..Default::default()
+        };
+        assert!(from_args(&args).is_ok());
+        let args = Args {
+            source: "udp://127.0.0.1:0?bogus=1".to_owned(),
+            ..args
+        };
+        let err = from_args(&args).err().unwrap();
+        assert!(
+            err.to_string().contains("Unknown parameter \"bogus\""),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn locked_peer_keeps_the_session() {
+        let key = parse_key(&"ab".repeat(32)).unwrap();
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        let address = receiver.local_addr().unwrap();
+        let args = Args {
+            lock_peer: Some(10_000),
+            ..Default::default()
+        };
+        let mut socket = guard_socket(&args, Box::new(receiver), Some(Opener::new(&key)));
+
+        let locked = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let other = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut locked_sealer = Sealer::new(&key);
+        let mut other_sealer = Sealer::new(&key);
+        let send = |socket: &UdpSocket, sealer: &mut Sealer, payload: &[u8]| {
+            let mut packet = Vec::new();
+            sealer.seal(payload, &mut packet);
+            socket.send_to(&packet, address).unwrap();
+        };
+        let mut buf = [0; 64];
+
+        send(&locked, &mut locked_sealer, b"locked");
+        let (n_read, _) = socket.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..n_read], b"locked");
+        // A newer session from another keyed sender never reaches the opener
+        for _ in 0..8 {
+            send(&other, &mut other_sealer, b"other");
+        }
+        send(&locked, &mut locked_sealer, b"still locked");
+        let (n_read, from) = socket.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..n_read], b"still locked");
+        assert_eq!(from, locked.local_addr().unwrap());
+    }
+
+    /// A checked source with a reorder window of 2 and a sender that tags
+    /// 8 bytes of `id` with any id
+    fn checked_pair() -> (network::CheckedUdpSourcePack, impl Fn(u64)) {
+        let format = PcmFormat::new(16, false, 48000, 1).unwrap();
+        let concealer = Concealer::new(format, crate::plc::Concealment::Silence);
+        let source = network::CheckedUdpSourcePack::new("127.0.0.1:0", 64, 2, concealer).unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sender.connect(source.socket.local_addr().unwrap()).unwrap();
+        let send = move |id: u64| {
+            let packet = [&id.to_be_bytes()[..], &[id as u8; 8]].concat();
+            sender.send(&packet).unwrap();
+        };
+        (source, send)
+    }
+
+    #[test]
+    fn restarted_sender_is_taken_back_right_away() {
+        let (mut source, send) = checked_pair();
+        let mut received = VecDeque::new();
+        for id in (0..10).chain(0..3) {
+            send(id);
+            source.recv_to_deque(&mut received).unwrap();
+        }
+        // Packet 0 of the new run may be lost
+        send(1);
+        source.recv_to_deque(&mut received).unwrap();
+        assert_eq!(received.len(), 13 * 8);
+        assert!(
+            received
+                .range(10 * 8..)
+                .eq(&[[0; 8], [1; 8], [2; 8]].concat())
+        );
+    }
+
+    #[test]
+    fn losses_before_the_first_packet_are_concealed_in_full() {
+        let (mut source, send) = checked_pair();
+        let mut received = VecDeque::new();
+        for id in 2..5 {
+            send(id);
+            source.recv_to_deque(&mut received).unwrap();
+        }
+        // 0 and 1 as silence, then 2 to 4
+        assert_eq!(received.len(), 5 * 8);
+        assert!(received.range(..16).all(|&sample| sample == 0));
+    }
+
+    #[test]
+    fn held_packets_come_out_once_the_stream_stops() {
+        let (mut source, send) = checked_pair();
+        let mut received = VecDeque::new();
+        for id in [0, 2] {
+            send(id);
+            source.recv_to_deque(&mut received).unwrap();
+        }
+        assert_eq!(received.len(), 8);
+        // Nothing else arrives, so 1 is concealed and 2 comes out
+        source.recv_to_deque(&mut received).unwrap();
+        assert_eq!(received.len(), 3 * 8);
+    }
+}
@@ -1,164 +1,459 @@
 use std::{
-    cmp::Ordering,
-    collections::VecDeque,
+    collections::{BTreeMap, VecDeque},
     io::{Read as _, Write as _},
-    net::UdpSocket,
+    net::{SocketAddr, UdpSocket},
+    time::{Duration, Instant},
 };
 
 use anyhow::{Result, anyhow};
-use log::{debug, warn};
+use log::{debug, info, warn};
 
-use crate::{Restart, sources::RecvAudio};
+use crate::{
+    Restart,
+    crypto::Opener,
+    idc::{self, Backoff, ConnectionState, FrameDecoder, HEARTBEAT, IdcTimeouts, ReconnectPolicy},
+    network_utils::{DatagramSocket, accept_timeout, tcp_listener, tcp_socket},
+    plc::Concealer,
+    sources::RecvAudio,
+};
 
-pub struct UdpSourcePack {
-    pub socket: UdpSocket,
+pub struct UdpSourcePack<S = UdpSocket> {
+    pub socket: S,
     pub buffer: Vec<u8>,
 }
 
 impl UdpSourcePack {
     pub fn new(address: impl std::net::ToSocketAddrs, buffer_size: usize) -> Result<Self> {
-        Ok(Self {
-            socket: UdpSocket::bind(address)?,
+        Ok(Self::with_socket(UdpSocket::bind(address)?, buffer_size))
+    }
+}
+
+impl<S> UdpSourcePack<S> {
+    pub fn with_socket(socket: S, buffer_size: usize) -> Self {
+        Self {
+            socket,
             buffer: vec![0; buffer_size],
-        })
+        }
     }
 }
 
-impl RecvAudio for UdpSourcePack {
+impl<S: DatagramSocket> RecvAudio for UdpSourcePack<S> {
     fn recv_to_deque(&mut self, buf: &mut VecDeque<u8>) -> Result<()> {
-        let (n_read, _) = self.socket.recv_from(self.buffer.as_mut_slice())?;
-        buf.write_all(&self.buffer[..n_read])?;
+        match self.socket.recv_from(self.buffer.as_mut_slice()) {
+            Ok((n_read, _)) => buf.write_all(&self.buffer[..n_read])?,
+            // Nothing arrived before the socket's read timeout
+            Err(err) if is_timeout(&err) => {}
+            Err(err) => return Err(err.into()),
+        }
         Ok(())
     }
 }
 
-impl Restart for UdpSourcePack {
+impl<S: DatagramSocket> Restart for UdpSourcePack<S> {
     fn restart(&mut self) -> Result<()> {
-        let addr = self.socket.local_addr()?;
-        self.socket = UdpSocket::bind(addr)?;
+        self.socket.reopen()?;
         Ok(())
     }
 }
 
-pub struct CheckedUdpSourcePack {
+/// How long a UDP source waits for a packet before going back to the
+/// pipeline, to notice it stopping
+pub(crate) const RECV_TIMEOUT: Duration = Duration::from_millis(100);
+
+fn is_timeout(err: &std::io::Error) -> bool {
+    matches!(
+        err.kind(),
+        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+    )
+}
+
+/// Packets this far away from the expected id are not reordering anymore,
+/// it's either a restarted sender or a long outage
+const RESYNC_DISTANCE: u64 = 256;
+/// How long packets held back for a missing one wait once nothing else arrives
+pub(crate) const HOLD_TIMEOUT: Duration = Duration::from_millis(100);
+
+pub struct CheckedUdpSourcePack<S = UdpSocket> {
     pub current_id: u64,
-    pub socket: UdpSocket,
+    pub socket: S,
     pub buffer: Vec<u8>,
+    /// How many packets past a missing one to hold back before giving up on it
+    pub reorder_window: usize,
+    pub pending: BTreeMap<u64, Vec<u8>>,
+    pub last_payload_len: usize,
+    pub concealer: Concealer,
+    /// The socket's own read timeout while [`HOLD_TIMEOUT`] takes its place
+    timeout_before_hold: Option<Option<Duration>>,
 }
 
 impl CheckedUdpSourcePack {
-    pub fn new(address: impl std::net::ToSocketAddrs, buffer_size: usize) -> Result<Self> {
-        Ok(Self {
+    pub fn new(
+        address: impl std::net::ToSocketAddrs,
+        buffer_size: usize,
+        reorder_window: usize,
+        concealer: Concealer,
+    ) -> Result<Self> {
+        Ok(Self::with_socket(
+            UdpSocket::bind(address)?,
+            buffer_size,
+            reorder_window,
+            concealer,
+        ))
+    }
+}
+
+impl<S> CheckedUdpSourcePack<S> {
+    pub fn with_socket(
+        socket: S,
+        buffer_size: usize,
+        reorder_window: usize,
+        concealer: Concealer,
+    ) -> Self {
+        Self {
             current_id: 0,
-            socket: UdpSocket::bind(address)?,
+            socket,
             buffer: vec![0; buffer_size],
-        })
+            reorder_window,
+            pending: BTreeMap::new(),
+            last_payload_len: 0,
+            concealer,
+            timeout_before_hold: None,
+        }
     }
-}
 
-impl RecvAudio for CheckedUdpSourcePack {
-    fn recv_to_deque(&mut self, buf: &mut VecDeque<u8>) -> Result<()> {
-        let (n_read, _) = self.socket.recv_from(self.buffer.as_mut_slice())?;
+    /// Passes on the packets that are next in line, and gives up on missing
+    /// ones once too many are held back or `give_up` says so
+    fn flush_pending(&mut self, buf: &mut VecDeque<u8>, give_up: bool) {
+        loop {
+            if let Some(payload) = self.pending.remove(&self.current_id) {
+                self.concealer.received(&payload, buf);
+                self.last_payload_len = payload.len();
+                self.current_id += 1;
+            } else if self.pending.len() > self.reorder_window
+                || give_up && !self.pending.is_empty()
+            {
+                let (&next_id, next) = self.pending.first_key_value().unwrap();
+                let n_lost = next_id - self.current_id;
+                warn!("Lost {n_lost} packets, concealing");
+                // Nothing came before, so the next one is the best guess
+                let payload_len = match self.last_payload_len {
+                    0 => next.len(),
+                    len => len,
+                };
+                for _ in 0..n_lost {
+                    self.concealer.conceal(payload_len, buf);
+                }
+                self.current_id = next_id;
+            } else {
+                return;
+            }
+        }
+    }
+
+    /// Gives up on the missing packets that held back the rest, because
+    /// nothing arrived for [`HOLD_TIMEOUT`]
+    pub(crate) fn flush_held(&mut self, buf: &mut VecDeque<u8>) {
+        debug!(
+            "Nothing arrived for {HOLD_TIMEOUT:?}, passing on {} held packets",
+            self.pending.len()
+        );
+        self.flush_pending(buf, true);
+    }
+
+    /// Takes the packet of `n_read` bytes at the start of the buffer
+    pub(crate) fn handle_packet(&mut self, n_read: usize, buf: &mut VecDeque<u8>) {
         let tag_size = self.current_id.to_be_bytes().len();
+        if n_read < tag_size {
+            warn!("Got a packet too short to contain an id, ignoring");
+            return;
+        }
         let supposed_id = u64::from_be_bytes(self.buffer[..tag_size].try_into().unwrap());
-        match supposed_id.cmp(&self.current_id) {
-            Ordering::Less => {
-                warn!(
-                    "Got a packet from the past, {} packets late",
-                    self.current_id - supposed_id
-                );
-                self.current_id = supposed_id;
+        if supposed_id < self.current_id {
+            let lateness = self.current_id - supposed_id;
+            // A restarted sender starts over at 0, and that one might be lost. Only
+            // a packet that's later than the reorder window can't be one of the first
+            let restarted = supposed_id <= 1 && lateness > self.reorder_window as u64;
+            if lateness < RESYNC_DISTANCE && !restarted {
+                warn!("Got a packet from the past, {lateness} packets late, discarding");
+                return;
             }
-            Ordering::Greater => {
-                warn!(
-                    "Got a packet from the future, {} packets early",
-                    supposed_id - self.current_id
-                );
-                self.current_id = supposed_id;
-            }
-            Ordering::Equal => {}
+            warn!("Got a packet {lateness} packets in the past, assuming the sender restarted");
+            self.pending.clear();
+            self.current_id = supposed_id;
+        } else if supposed_id - self.current_id >= RESYNC_DISTANCE {
+            warn!(
+                "Got a packet from the future, {} packets early, resyncing",
+                supposed_id - self.current_id
+            );
+            self.pending.clear();
+            self.current_id = supposed_id;
+        } else if supposed_id > self.current_id && !self.pending.contains_key(&supposed_id) {
+            debug!(
+                "Got a packet from the future, {} packets early, holding it",
+                supposed_id - self.current_id
+            );
         }
-        buf.write_all(&self.buffer[tag_size..n_read])?;
+        self.pending
+            .entry(supposed_id)
+            .or_insert_with(|| self.buffer[tag_size..n_read].to_vec());
 
-        self.current_id += 1;
+        self.flush_pending(buf, false);
+    }
+}
 
+impl<S: DatagramSocket> RecvAudio for CheckedUdpSourcePack<S> {
+    fn recv_to_deque(&mut self, buf: &mut VecDeque<u8>) -> Result<()> {
+        // Only held back packets are worth waking up for
+        if !self.pending.is_empty() && self.timeout_before_hold.is_none() {
+            let timeout = self.socket.read_timeout()?;
+            let hold_timeout = timeout.map_or(HOLD_TIMEOUT, |timeout| timeout.min(HOLD_TIMEOUT));
+            self.socket.set_read_timeout(Some(hold_timeout))?;
+            self.timeout_before_hold = Some(timeout);
+        } else if self.pending.is_empty()
+            && let Some(timeout) = self.timeout_before_hold.take()
+        {
+            self.socket.set_read_timeout(timeout)?;
+        }
+        match self.socket.recv_from(self.buffer.as_mut_slice()) {
+            Ok((n_read, _)) => self.handle_packet(n_read, buf),
+            Err(err) if self.timeout_before_hold.is_some() && is_timeout(&err) => {
+                self.flush_held(buf);
+            }
+            Err(err) if is_timeout(&err) => {}
+            Err(err) => return Err(err.into()),
+        }
         Ok(())
     }
 }
 
-impl Restart for CheckedUdpSourcePack {
+impl<S: DatagramSocket> Restart for CheckedUdpSourcePack<S> {
     fn restart(&mut self) -> Result<()> {
         self.current_id = 0;
-        let addr = self.socket.local_addr()?;
-        self.socket = UdpSocket::bind(addr)?;
+        self.pending.clear();
+        self.socket.reopen()?;
+        self.timeout_before_hold = None;
         Ok(())
     }
 }
 
+enum IdcRole {
+    /// Wait for the sink to connect
+    Listen(socket2::Socket),
+    /// Connect to the sink
+    Connect(SocketAddr),
+}
+
 pub struct IdcSourcePack {
-    listener: socket2::Socket,
+    role: IdcRole,
     socket: Option<socket2::Socket>,
+    backoff: Backoff,
     buffer: Vec<u8>,
+    decoder: FrameDecoder,
+    timeouts: IdcTimeouts,
+    last_heard: Instant,
+    last_sent: Instant,
 }
 
 impl IdcSourcePack {
     fn create_listener(address: &socket2::SockAddr) -> Result<socket2::Socket> {
-        let listener = socket2::Socket::new(
-            if address.is_ipv4() {
-                socket2::Domain::IPV4
-            } else {
-                socket2::Domain::IPV6
-            },
-            socket2::Type::STREAM,
-            Some(socket2::Protocol::TCP),
-        )?;
-        listener.bind(address)?;
-        listener.listen(1)?;
+        let listener = tcp_listener(address, 1)?;
+        // Waiting for a sink mustn't keep the pipeline from stopping
+        listener.set_nonblocking(true)?;
         Ok(listener)
     }
 
-    pub fn new(address: impl std::net::ToSocketAddrs, buffer_size: usize) -> Result<Self> {
+    fn with_role(
+        role: IdcRole,
+        buffer_size: usize,
+        opener: Option<Opener>,
+        timeouts: IdcTimeouts,
+        backoff: Backoff,
+    ) -> Self {
+        Self {
+            role,
+            socket: None,
+            backoff,
+            buffer: vec![0; buffer_size],
+            decoder: FrameDecoder::new(opener),
+            timeouts,
+            last_heard: Instant::now(),
+            last_sent: Instant::now(),
+        }
+    }
+
+    /// Listens on `address` for the sink to connect
+    pub fn new(
+        address: impl std::net::ToSocketAddrs,
+        buffer_size: usize,
+        opener: Option<Opener>,
+        timeouts: IdcTimeouts,
+        reconnect: ReconnectPolicy,
+    ) -> Result<Self> {
         let address = address
             .to_socket_addrs()?
             .next()
             .ok_or(anyhow!("Couldn't get socket addr."))?;
         let listener = Self::create_listener(&address.into())?;
-        Ok(Self {
-            listener,
-            socket: None,
-            buffer: vec![0; buffer_size],
-        })
+        Ok(Self::with_role(
+            IdcRole::Listen(listener),
+            buffer_size,
+            opener,
+            timeouts,
+            Backoff::new(reconnect, format!("idc source on {address}")),
+        ))
+    }
+
+    /// Connects to a sink listening on `address`, reconnecting whenever that fails
+    pub fn connect(
+        address: impl std::net::ToSocketAddrs,
+        buffer_size: usize,
+        opener: Option<Opener>,
+        timeouts: IdcTimeouts,
+        reconnect: ReconnectPolicy,
+    ) -> Result<Self> {
+        let address = address
+            .to_socket_addrs()?
+            .next()
+            .ok_or(anyhow!("Couldn't get socket addr."))?;
+        Ok(Self::with_role(
+            IdcRole::Connect(address),
+            buffer_size,
+            opener,
+            timeouts,
+            Backoff::new(reconnect, format!("idc source from {address}")),
+        ))
+    }
+
+    pub fn on_state_change(&mut self, callback: impl FnMut(ConnectionState) + Send + 'static) {
+        self.backoff.set_callback(callback);
+    }
+
+    fn drop_connection(&mut self) {
+        self.socket = None;
+        self.backoff.lost();
+    }
+
+    /// Waits up to one keepalive interval for a sink, returns without a
+    /// connection if none turned up
+    fn wait_for_connection(&mut self) -> Result<()> {
+        let socket = match &self.role {
+            IdcRole::Listen(listener) => match accept_timeout(listener, self.timeouts.keepalive) {
+                Ok(Some((s, addr))) => {
+                    debug!("Accepted connection from {:?}", addr.as_socket());
+                    s.set_nonblocking(false)?;
+                    s
+                }
+                Ok(None) => return Ok(()),
+                Err(err) => {
+                    warn!("Couldn't accept a connection: {err}");
+                    self.backoff.attempt()?;
+                    self.backoff.wait();
+                    return Ok(());
+                }
+            },
+            IdcRole::Connect(address) => {
+                let address = (*address).into();
+                self.backoff.wait();
+                self.backoff.attempt()?;
+                let socket = tcp_socket(&address)?;
+                if let Err(err) = socket.connect_timeout(&address, self.timeouts.peer_timeout) {
+                    debug!("Couldn't connect to {:?}: {err}", address.as_socket());
+                    return Ok(());
+                }
+                socket
+            }
+        };
+        self.backoff.connected();
+        self.timeouts.configure(&socket)?;
+        // Wake up regularly to send heartbeats and to notice that the sink is gone
+        socket.set_read_timeout(Some(self.timeouts.keepalive))?;
+        socket.set_write_timeout(Some(self.timeouts.keepalive))?;
+        self.socket = Some(socket);
+        self.last_heard = Instant::now();
+        self.last_sent = Instant::now();
+        self.decoder.reset();
+        Ok(())
     }
 }
 
 impl RecvAudio for IdcSourcePack {
     fn recv_to_deque(&mut self, buf: &mut VecDeque<u8>) -> Result<()> {
+        let initial_len = buf.len();
         loop {
-            if self.socket.is_none() {
-                // wait for connection
-                let (s, addr) = self.listener.accept()?;
-                debug!("Accepted connection from {:?}", addr);
-                self.socket = Some(s);
+            let Some(socket) = &self.socket else {
+                self.wait_for_connection()?;
+                if self.socket.is_none() {
+                    return Ok(());
+                }
+                continue;
+            };
+
+            if self.last_sent.elapsed() >= self.timeouts.keepalive {
+                if let Err(err) = (&*socket).write_all(&HEARTBEAT) {
+                    debug!("Couldn't send a heartbeat: {err}");
+                    self.drop_connection();
+                    continue;
+                }
+                self.last_sent = Instant::now();
             }
-            let socket = self.socket.as_mut().unwrap();
 
-            match socket.read(self.buffer.as_mut_slice()) {
-                Ok(0) => unreachable!(),
+            match (&*socket).read(self.buffer.as_mut_slice()) {
+                Ok(0) => {
+                    debug!("Connection closed by the sink");
+                    self.drop_connection();
+                }
                 Ok(n_read) => {
-                    buf.write_all(&self.buffer[..n_read])?;
-                    return Ok(())
-                },
-                Err(_) => self.socket = None,
+                    self.last_heard = Instant::now();
+                    let peer = socket.peer_addr().ok().and_then(|addr| addr.as_socket());
+                    if let Err(err) = self.decoder.decode(&self.buffer[..n_read], buf, peer) {
+                        warn!("Dropping connection: {err}");
+                        self.drop_connection();
+                    } else if self.decoder.said_goodbye {
+                        info!("The sink said goodbye");
+                        self.drop_connection();
+                    }
+                    if buf.len() > initial_len {
+                        return Ok(());
+                    }
+                }
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    if self.last_heard.elapsed() >= self.timeouts.peer_timeout {
+                        warn!(
+                            "Haven't heard from the sink for {:?}, dropping connection",
+                            self.last_heard.elapsed()
+                        );
+                        self.drop_connection();
+                    }
+                    // Back to the pipeline now and then, to notice it stopping
+                    return Ok(());
+                }
+                Err(err) => {
+                    debug!("Connection lost: {err}");
+                    self.drop_connection();
+                }
             }
         }
     }
 }
 
+impl Drop for IdcSourcePack {
+    fn drop(&mut self) {
+        if let Some(socket) = &self.socket {
+            idc::say_goodbye(socket);
+        }
+    }
+}
+
+/// Keeps listening on the same socket, binding the address again would fail
+/// while the old socket still holds it
 impl Restart for IdcSourcePack {
     fn restart(&mut self) -> Result<()> {
-        self.socket = None;
-        let address = self.listener.local_addr()?;
-        self.listener = Self::create_listener(&address)?;
+        self.drop_connection();
         Ok(())
     }
 }
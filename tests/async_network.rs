@@ -0,0 +1,209 @@
+//! Runs the tokio packs over localhost while timeouts keep cancelling the
+//! receiving side, which mustn't lose anything to that
+
+#![cfg(feature = "tokio")]
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use stupid_audio_stream::{
+    idc::{IdcTimeouts, ReconnectPolicy},
+    network_utils::async_connected_udp_socket,
+    pcm::PcmFormat,
+    plc::{Concealer, Concealment},
+    sinks::{
+        AsyncSendAudio,
+        async_network::{AsyncIdcServerSinkPack, AsyncIdcSinkPack},
+        network::CountedUdpSinkPack,
+    },
+    sources::{AsyncRecvAudio, async_network::AsyncIdcSourcePack, network::CheckedUdpSourcePack},
+};
+use tokio::task::JoinHandle;
+
+const LOCALHOST: &str = "127.0.0.1:0";
+/// Short enough to cancel plenty of calls mid-way
+const CANCEL_AFTER: Duration = Duration::from_micros(300);
+
+fn format() -> PcmFormat {
+    PcmFormat::new(16, false, 48000, 2).unwrap()
+}
+
+fn timeouts() -> IdcTimeouts {
+    IdcTimeouts {
+        keepalive: Duration::from_millis(100),
+        peer_timeout: Duration::from_secs(1),
+    }
+}
+
+fn reconnect() -> ReconnectPolicy {
+    ReconnectPolicy {
+        min_delay: Duration::from_millis(20),
+        ..Default::default()
+    }
+}
+
+/// Keeps sending audio frames that each hold their number, counting from 1
+fn spawn_sender(mut sink: impl AsyncSendAudio + Send + 'static) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut number = 1u32;
+        let mut data = VecDeque::new();
+        loop {
+            for _ in 0..240 {
+                data.extend(number.to_be_bytes());
+                number += 1;
+            }
+            while !data.is_empty() {
+                sink.send_from_deque(&mut data).await.unwrap();
+            }
+            tokio::time::sleep(Duration::from_millis(2)).await;
+        }
+    })
+}
+
+/// Receives at least `n_bytes` through timeouts, returns them with how many
+/// calls got cancelled
+async fn receive(source: &mut impl AsyncRecvAudio, n_bytes: usize) -> (VecDeque<u8>, u32) {
+    let mut received = VecDeque::new();
+    let mut n_cancelled = 0;
+    let start = Instant::now();
+    while received.len() < n_bytes {
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "Only {} bytes arrived",
+            received.len()
+        );
+        match tokio::time::timeout(CANCEL_AFTER, source.recv_to_deque(&mut received)).await {
+            Ok(result) => result.unwrap(),
+            Err(_) => n_cancelled += 1,
+        }
+    }
+    (received, n_cancelled)
+}
+
+/// Checks that the frames count up without a gap, past what was concealed
+/// before the first one arrived
+fn assert_continuous(received: &VecDeque<u8>, n_cancelled: u32) {
+    assert!(n_cancelled > 0);
+    let received: Vec<u8> = received.iter().copied().collect();
+    let numbers: Vec<u32> = received
+        .chunks_exact(4)
+        .map(|frame| u32::from_be_bytes(frame.try_into().unwrap()))
+        .collect();
+    let start = (0..numbers.len())
+        .find(|&start| {
+            numbers[start..]
+                .windows(2)
+                .take(64)
+                .all(|pair| pair[1] == pair[0] + 1)
+        })
+        .unwrap();
+    assert!(numbers.len() - start > 1000);
+    for pair in numbers[start..].windows(2) {
+        assert_eq!(pair[1], pair[0] + 1, "Frames went missing");
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn checked_udp_source_loses_nothing_to_timeouts() {
+    let socket = tokio::net::UdpSocket::bind(LOCALHOST).await.unwrap();
+    let address = socket.local_addr().unwrap();
+    let concealer = Concealer::new(format(), Concealment::Silence);
+    let mut source = CheckedUdpSourcePack::with_socket(socket, 2000, 4, concealer);
+    let sink = CountedUdpSinkPack::with_socket(
+        async_connected_udp_socket(address).await.unwrap(),
+        1000,
+        format().block_align(),
+    );
+    let sender = spawn_sender(sink);
+
+    let (received, n_cancelled) = receive(&mut source, 40000).await;
+    sender.abort();
+    assert_continuous(&received, n_cancelled);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn idc_source_loses_nothing_to_timeouts() {
+    let mut source = AsyncIdcSourcePack::new(LOCALHOST, 4096, None, timeouts(), reconnect())
+        .await
+        .unwrap();
+    let address = source.local_addr().unwrap();
+    let sink = AsyncIdcSinkPack::new(
+        address,
+        1000,
+        format(),
+        Duration::from_secs(5),
+        None,
+        timeouts(),
+        reconnect(),
+    )
+    .await
+    .unwrap();
+    let sender = spawn_sender(sink);
+
+    let (received, n_cancelled) = receive(&mut source, 40000).await;
+    sender.abort();
+    assert_continuous(&received, n_cancelled);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn listening_idc_sink_feeds_every_source() {
+    let sink = AsyncIdcServerSinkPack::new(
+        LOCALHOST,
+        1000,
+        format().block_align(),
+        1 << 20,
+        None,
+        timeouts(),
+    )
+    .await
+    .unwrap();
+    let address = sink.local_addr().unwrap();
+    let mut sources = Vec::new();
+    for _ in 0..2 {
+        let source = AsyncIdcSourcePack::connect(address, 4096, None, timeouts(), reconnect())
+            .await
+            .unwrap();
+        sources.push(source);
+    }
+    let sender = spawn_sender(sink);
+
+    for source in &mut sources {
+        let (received, n_cancelled) = receive(source, 40000).await;
+        assert_continuous(&received, n_cancelled);
+    }
+    sender.abort();
+}
+
+#[cfg(feature = "quic")]
+#[tokio::test(flavor = "multi_thread")]
+async fn quic_source_loses_nothing_to_timeouts() {
+    use stupid_audio_stream::quic::{AsyncQuicSocket, QuicCerts, QuicSinkPack};
+
+    let socket = AsyncQuicSocket::listen(
+        LOCALHOST.parse().unwrap(),
+        format(),
+        &QuicCerts::default(),
+        timeouts(),
+        reconnect(),
+    )
+    .unwrap();
+    let address = format!("localhost:{}", socket.local_addr().unwrap().port());
+    let concealer = Concealer::new(format(), Concealment::Silence);
+    let mut source = CheckedUdpSourcePack::with_socket(socket, 5000, 4, concealer);
+    let socket = AsyncQuicSocket::connect(
+        &address,
+        format(),
+        &QuicCerts::default(),
+        timeouts(),
+        reconnect(),
+    )
+    .await
+    .unwrap();
+    let sender = spawn_sender(QuicSinkPack::new(socket, 5000));
+
+    let (received, n_cancelled) = receive(&mut source, 40000).await;
+    sender.abort();
+    assert_continuous(&received, n_cancelled);
+}
@@ -0,0 +1,136 @@
+//! Runs two ends of a duplex link over localhost, with UDP sockets standing in
+//! for the devices on each end
+
+use std::{
+    net::{TcpListener, UdpSocket},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use stupid_audio_stream::{Args, duplex::Duplex, pipeline::PipelineHandle};
+
+fn udp_port() -> u16 {
+    UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+fn tcp_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// One end of the link, running on its own thread
+struct End {
+    /// Where to send what the end captures
+    capture: u16,
+    /// What the end plays
+    render: UdpSocket,
+    handles: [PipelineHandle; 2],
+    running: JoinHandle<Result<()>>,
+}
+
+impl End {
+    fn open(peer: &str) -> Self {
+        let render = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let capture = udp_port();
+        let args = Args {
+            source: format!("udp://127.0.0.1:{capture}"),
+            sink: format!("udp://{}", render.local_addr().unwrap()),
+            duplex: Some(peer.to_owned()),
+            datagram_size: 800,
+            idc_keepalive: 100,
+            ..Default::default()
+        };
+        let duplex = Duplex::open(&args).unwrap();
+        let handles = [duplex.outgoing(), duplex.incoming()];
+        let running = thread::spawn(move || duplex.run());
+        Self {
+            capture,
+            render,
+            handles,
+            running,
+        }
+    }
+
+    fn stop(self) {
+        for handle in &self.handles {
+            handle.stop();
+        }
+        self.running.join().unwrap().unwrap();
+    }
+}
+
+/// Captures `value` on `from` for a while, returns how much of it `to` played.
+/// Anything else played, like `to`'s own audio, fails the test
+fn send(from: &End, to: &End, value: u8) -> usize {
+    let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+    to.render
+        .set_read_timeout(Some(Duration::from_millis(300)))
+        .unwrap();
+    let mut n_played = 0;
+    let mut buf = [0; 4096];
+    for _ in 0..100 {
+        sender
+            .send_to(&[value; 400], ("127.0.0.1", from.capture))
+            .unwrap();
+        thread::sleep(Duration::from_millis(2));
+        to.render.set_nonblocking(true).unwrap();
+        while let Ok(n_read) = to.render.recv(&mut buf) {
+            assert!(buf[..n_read].iter().all(|&byte| byte == value));
+            n_played += n_read;
+        }
+        to.render.set_nonblocking(false).unwrap();
+    }
+    while let Ok(n_read) = to.render.recv(&mut buf) {
+        n_played += n_read;
+    }
+    n_played
+}
+
+fn talk_both_ways(first: End, second: End) {
+    // Time to connect
+    thread::sleep(Duration::from_millis(500));
+    assert!(send(&first, &second, 1) > 20000);
+    assert!(send(&second, &first, 2) > 20000);
+    first.stop();
+    second.stop();
+}
+
+#[test]
+fn udp_link_carries_both_ways() {
+    let (first, second) = (udp_port(), udp_port());
+    talk_both_ways(
+        End::open(&format!("udp://127.0.0.1:{second}?bind=127.0.0.1:{first}")),
+        End::open(&format!("udp://127.0.0.1:{first}?bind=127.0.0.1:{second}")),
+    );
+}
+
+#[test]
+fn idc_link_carries_both_ways_whoever_comes_first() {
+    let port = tcp_port();
+    let listening = End::open(&format!("idc-listen://127.0.0.1:{port}"));
+    let connecting = End::open(&format!("idc-connect://127.0.0.1:{port}"));
+    talk_both_ways(listening, connecting);
+
+    let port = tcp_port();
+    let connecting = End::open(&format!("idc-connect://127.0.0.1:{port}"));
+    thread::sleep(Duration::from_millis(300));
+    let listening = End::open(&format!("idc-listen://127.0.0.1:{port}"));
+    talk_both_ways(connecting, listening);
+}
+
+#[test]
+fn listening_end_stops_without_a_peer() {
+    let end = End::open(&format!("idc-listen://127.0.0.1:{}", tcp_port()));
+    thread::sleep(Duration::from_millis(200));
+    let start = Instant::now();
+    end.stop();
+    assert!(start.elapsed() < Duration::from_secs(1));
+}
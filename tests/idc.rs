@@ -0,0 +1,123 @@
+//! Opens idc sinks and sources from urls in either role and checks that audio
+//! gets through, and keeps getting through after the listening end comes back
+
+use std::{
+    collections::VecDeque,
+    net::{SocketAddr, TcpListener},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use stupid_audio_stream::{Args, RecvAudioRestart, sinks, sources};
+
+fn free_address() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+fn args() -> Args {
+    Args {
+        idc_keepalive: 50,
+        idc_timeout: 500,
+        idc_reconnect_min: 10,
+        idc_reconnect_max: 50,
+        ..Default::default()
+    }
+}
+
+/// Opens the sink at `url` on its own thread and keeps sending `value` until
+/// `stop` is set
+fn spawn_sender(url: String, value: u8, stop: Arc<AtomicBool>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut sink = sinks::from_url(&url, &args()).unwrap();
+        while !stop.load(Ordering::Relaxed) {
+            sink.send_from_deque(&mut VecDeque::from([value; 960]))
+                .unwrap();
+            thread::sleep(Duration::from_millis(5));
+        }
+    })
+}
+
+/// Receives until `value` arrives, failing the test if it takes too long
+fn wait_for(source: &mut Box<dyn RecvAudioRestart>, value: u8) {
+    let start = Instant::now();
+    let mut received = VecDeque::new();
+    while !received.contains(&value) {
+        assert!(
+            start.elapsed() < Duration::from_secs(5),
+            "{value} never arrived"
+        );
+        received.clear();
+        source.recv_to_deque(&mut received).unwrap();
+    }
+}
+
+fn carries_audio(sink: &str, source: &str) {
+    let mut source = sources::from_url(source, &args()).unwrap();
+    let stop = Arc::new(AtomicBool::new(false));
+    let sending = spawn_sender(sink.to_owned(), 1, stop.clone());
+    wait_for(&mut source, 1);
+    stop.store(true, Ordering::Relaxed);
+    sending.join().unwrap();
+}
+
+#[test]
+fn source_can_listen() {
+    let address = free_address();
+    carries_audio(&format!("idc://{address}"), &format!("idc://{address}"));
+    let address = free_address();
+    carries_audio(
+        &format!("idc-connect://{address}"),
+        &format!("idc-listen://{address}"),
+    );
+}
+
+#[test]
+fn sink_can_listen() {
+    let address = free_address();
+    carries_audio(
+        &format!("idc-listen://{address}"),
+        &format!("idc-connect://{address}"),
+    );
+}
+
+#[test]
+fn connecting_source_comes_back_to_a_listening_sink_that_did() {
+    let address = free_address();
+    let sink = format!("idc-listen://{address}");
+    let mut source = sources::from_url(&format!("idc-connect://{address}"), &args()).unwrap();
+    let stop = Arc::new(AtomicBool::new(false));
+    let sending = spawn_sender(sink.clone(), 1, stop.clone());
+    wait_for(&mut source, 1);
+    stop.store(true, Ordering::Relaxed);
+    // Drops the sink and the connection with it
+    sending.join().unwrap();
+
+    stop.store(false, Ordering::Relaxed);
+    let sending = spawn_sender(sink.clone(), 2, stop.clone());
+    wait_for(&mut source, 2);
+    stop.store(true, Ordering::Relaxed);
+    sending.join().unwrap();
+}
+
+#[test]
+fn connecting_sink_comes_back_to_a_listening_source_that_did() {
+    let address = free_address();
+    let source = format!("idc-listen://{address}");
+    let stop = Arc::new(AtomicBool::new(false));
+    let sending = spawn_sender(format!("idc-connect://{address}"), 1, stop.clone());
+    let mut listening = sources::from_url(&source, &args()).unwrap();
+    wait_for(&mut listening, 1);
+    drop(listening);
+
+    let mut listening = sources::from_url(&source, &args()).unwrap();
+    wait_for(&mut listening, 1);
+    stop.store(true, Ordering::Relaxed);
+    sending.join().unwrap();
+}
@@ -0,0 +1,544 @@
+//! Runs the transports through seeded simulated bad networks
+
+use std::{
+    collections::VecDeque,
+    io::Read,
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        mpsc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use stupid_audio_stream::{
+    Restart,
+    idc::{ConnectionState, FrameDecoder, IdcTimeouts, ReconnectPolicy},
+    impair::{ImpairedProxy, ImpairedSocket, Impairment, ImpairmentStats},
+    network_utils::{DatagramSocket, connected_udp_socket},
+    pcm::PcmFormat,
+    plc::{Concealer, Concealment},
+    sinks::{
+        SendAudio, network::CountedUdpSinkPack, network::IdcServerSinkPack, network::IdcSinkPack,
+    },
+    sources::{RecvAudio, network::CheckedUdpSourcePack, network::IdcSourcePack},
+};
+
+const PAYLOAD_LEN: usize = 96;
+
+fn receiver() -> UdpSocket {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_millis(300)))
+        .unwrap();
+    socket
+}
+
+/// Sends packets numbered 0 to `n_packets` through `impairment` and returns the
+/// numbers in the order they arrived
+fn arrivals(impairment: &str, n_packets: u32) -> (Vec<u32>, ImpairmentStats) {
+    let receiver = receiver();
+    let impairment = Impairment::parse(impairment).unwrap();
+    let mut socket = ImpairedSocket::new(
+        connected_udp_socket(receiver.local_addr().unwrap()).unwrap(),
+        impairment,
+    );
+    for number in 0..n_packets {
+        socket.send(&number.to_be_bytes()).unwrap();
+    }
+    let mut numbers = Vec::new();
+    let mut buf = [0; 4];
+    while let Ok(n_read) = receiver.recv(&mut buf) {
+        assert_eq!(n_read, 4);
+        numbers.push(u32::from_be_bytes(buf));
+    }
+    (numbers, socket.stats.clone())
+}
+
+#[test]
+fn same_seed_same_losses() {
+    let impairment = "loss=10%&burst=2%,40%&dup=5%&seed=42";
+    let (first, first_stats) = arrivals(impairment, 500);
+    let (second, second_stats) = arrivals(impairment, 500);
+    assert_eq!(first, second);
+    assert_eq!(first_stats, second_stats);
+    assert!(first_stats.lost > 50, "{first_stats:?}");
+    assert!(first_stats.duplicated > 0, "{first_stats:?}");
+
+    let (other, _) = arrivals("loss=10%&burst=2%,40%&dup=5%&seed=43", 500);
+    assert_ne!(first, other);
+}
+
+#[test]
+fn delayed_packets_go_out_without_more_sends() {
+    let start = Instant::now();
+    let (numbers, _) = arrivals("delay=30ms&jitter=20ms", 5);
+    let mut sorted = numbers.clone();
+    sorted.sort();
+    assert_eq!(sorted, [0, 1, 2, 3, 4]);
+    assert!(start.elapsed() >= Duration::from_millis(30));
+}
+
+#[test]
+fn reorder_window_restores_the_order() {
+    let n_packets = 400;
+    let window = 16;
+    let format = PcmFormat::new(16, false, 48000, 1).unwrap();
+    let receiver = receiver();
+    let address = receiver.local_addr().unwrap();
+    let mut source = CheckedUdpSourcePack::with_socket(
+        receiver,
+        8 + PAYLOAD_LEN,
+        window,
+        Concealer::new(format, Concealment::Silence),
+    );
+    let receiving = thread::spawn(move || {
+        let mut out = VecDeque::new();
+        // Read timeouts just come back empty, the stream is over once they keep doing so
+        let mut last_arrival = Instant::now();
+        while last_arrival.elapsed() < Duration::from_millis(300) {
+            let n_before = out.len();
+            source.recv_to_deque(&mut out).unwrap();
+            if out.len() > n_before {
+                last_arrival = Instant::now();
+            }
+        }
+        out
+    });
+
+    let impairment = Impairment::parse("reorder=20%&reorder_delay=3ms&seed=7").unwrap();
+    let socket = ImpairedSocket::new(connected_udp_socket(address).unwrap(), impairment);
+    let mut sink = CountedUdpSinkPack::with_socket(socket, 8 + PAYLOAD_LEN, format.block_align());
+    let mut sent = Vec::new();
+    for packet in 0..n_packets {
+        let payload = [(packet % 251) as u8; PAYLOAD_LEN];
+        sent.extend(payload);
+        sink.send_from_deque(&mut VecDeque::from(payload)).unwrap();
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert!(sink.socket.stats.reordered > 20, "{:?}", sink.socket.stats);
+
+    let out: Vec<u8> = receiving.join().unwrap().into();
+    // Only what's still held back at the end may be missing
+    assert!(out.len() >= (n_packets - window) * PAYLOAD_LEN);
+    assert_eq!(out, sent[..out.len()]);
+}
+
+#[test]
+fn losses_are_concealed_in_whole_frames_whatever_the_datagram_size() {
+    // Room for 250 frames and a byte
+    let format = PcmFormat::new(16, false, 48000, 2).unwrap();
+    let datagram_size = 8 + 1001;
+    let receiver = receiver();
+    let address = receiver.local_addr().unwrap();
+    let mut source = CheckedUdpSourcePack::with_socket(
+        receiver,
+        datagram_size,
+        4,
+        Concealer::new(format, Concealment::Silence),
+    );
+    let receiving = thread::spawn(move || {
+        let mut out = VecDeque::new();
+        let mut last_arrival = Instant::now();
+        while last_arrival.elapsed() < Duration::from_millis(300) {
+            let n_before = out.len();
+            source.recv_to_deque(&mut out).unwrap();
+            if out.len() > n_before {
+                last_arrival = Instant::now();
+            }
+        }
+        out
+    });
+
+    let impairment = Impairment::parse("loss=10%&seed=5").unwrap();
+    let socket = ImpairedSocket::new(connected_udp_socket(address).unwrap(), impairment);
+    let mut sink = CountedUdpSinkPack::with_socket(socket, datagram_size, format.block_align());
+    // Every frame holds its number, counting from 1
+    let mut data: VecDeque<u8> = (1..=50_000u32).flat_map(u32::to_be_bytes).collect();
+    while !data.is_empty() {
+        sink.send_from_deque(&mut data).unwrap();
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert!(sink.socket.stats.lost > 10, "{:?}", sink.socket.stats);
+
+    let out: Vec<u8> = receiving.join().unwrap().into();
+    assert_eq!(out.len() % 4, 0);
+    // Silence stands in for lost frames, and the 5 ms after it fade back in
+    let crossfade = 240;
+    let mut n_concealed = 0;
+    let mut last_concealed = None;
+    for (index, frame) in out.chunks_exact(4).enumerate() {
+        match u32::from_be_bytes(frame.try_into().unwrap()) {
+            0 => {
+                n_concealed += 1;
+                last_concealed = Some(index);
+            }
+            number if number as usize == index + 1 => {}
+            _ => assert!(
+                last_concealed.is_some_and(|last| index - last <= crossfade),
+                "Frames got out of step at {index}"
+            ),
+        }
+    }
+    assert!(n_concealed >= 250 * 10, "{n_concealed}");
+}
+
+#[test]
+fn idc_reconnects_through_a_breaking_link() {
+    // Grab a free port for the sink to listen on
+    let address = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let timeouts = IdcTimeouts {
+        keepalive: Duration::from_millis(100),
+        peer_timeout: Duration::from_millis(500),
+    };
+    let reconnect = ReconnectPolicy {
+        min_delay: Duration::from_millis(10),
+        max_delay: Duration::from_millis(50),
+        ..Default::default()
+    };
+    let mut sink = IdcServerSinkPack::new(address, 960, 2, 1 << 20, None, timeouts).unwrap();
+    let proxy = ImpairedProxy::new(
+        "127.0.0.1:0".parse().unwrap(),
+        address,
+        Impairment::parse("loss=2%&jitter=2ms&seed=3").unwrap(),
+    )
+    .unwrap();
+    let mut source =
+        IdcSourcePack::connect(proxy.local_addr(), 4096, None, timeouts, reconnect).unwrap();
+    let connects = Arc::new(AtomicU32::new(0));
+    let counted = connects.clone();
+    source.on_state_change(move |state| {
+        if state == ConnectionState::Connected {
+            counted.fetch_add(1, Ordering::Relaxed);
+        }
+    });
+
+    let sending = thread::spawn(move || {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(3) {
+            sink.send_from_deque(&mut VecDeque::from([1; 960])).unwrap();
+            thread::sleep(Duration::from_millis(5));
+        }
+    });
+    let mut received = VecDeque::new();
+    let start = Instant::now();
+    let mut received_late = 0;
+    while start.elapsed() < Duration::from_millis(2500) {
+        source.recv_to_deque(&mut received).unwrap();
+        if start.elapsed() > Duration::from_secs(2) {
+            received_late += received.len();
+        }
+        received.clear();
+    }
+    sending.join().unwrap();
+
+    let stats = proxy.stats();
+    assert!(stats.lost >= 2, "{stats:?}");
+    assert!(connects.load(Ordering::Relaxed) >= 3);
+    // Still going after all that
+    assert!(received_late > 0);
+}
+
+#[test]
+fn idc_recovers_from_a_stalled_source_in_time() {
+    let address = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let timeouts = IdcTimeouts {
+        keepalive: Duration::from_millis(50),
+        peer_timeout: Duration::from_millis(300),
+    };
+    let reconnect = ReconnectPolicy {
+        min_delay: Duration::from_millis(20),
+        max_delay: Duration::from_millis(20),
+        jitter: 0.0,
+        ..Default::default()
+    };
+    let mut source = IdcSourcePack::new(address, 4096, None, timeouts, reconnect).unwrap();
+    let format = PcmFormat::new(16, false, 48000, 2).unwrap();
+    let mut sink = IdcSinkPack::new(
+        address,
+        960,
+        format,
+        Duration::from_millis(100),
+        None,
+        timeouts,
+        reconnect,
+    )
+    .unwrap();
+    let (states, seen) = mpsc::channel();
+    sink.on_state_change(move |state| {
+        let _ = states.send((state, Instant::now()));
+    });
+    let stop = Arc::new(AtomicBool::new(false));
+    let sending = {
+        let stop = stop.clone();
+        thread::spawn(move || {
+            while !stop.load(Ordering::Relaxed) {
+                sink.send_from_deque(&mut VecDeque::from([1; 960])).unwrap();
+                thread::sleep(Duration::from_millis(5));
+            }
+        })
+    };
+
+    let mut received = VecDeque::new();
+    while received.is_empty() {
+        source.recv_to_deque(&mut received).unwrap();
+    }
+    // The source hangs and stops sending heartbeats
+    let stalled = Instant::now();
+    thread::sleep(timeouts.peer_timeout * 2);
+    let resumed = Instant::now();
+    received.clear();
+    while received.is_empty() {
+        source.recv_to_deque(&mut received).unwrap();
+        assert!(resumed.elapsed() < Duration::from_secs(2));
+    }
+    let recovered = resumed.elapsed();
+    stop.store(true, Ordering::Relaxed);
+    sending.join().unwrap();
+
+    let states: Vec<_> = seen.try_iter().collect();
+    let (_, lost) = states
+        .iter()
+        .find(|(state, _)| *state == ConnectionState::Lost)
+        .expect("The sink never noticed");
+    let (_, connected) = states
+        .iter()
+        .rfind(|(state, _)| *state == ConnectionState::Connected)
+        .unwrap();
+    // The last heartbeat can be up to one keepalive older than the stall
+    let noticed = lost.duration_since(stalled);
+    assert!(
+        noticed >= timeouts.peer_timeout - timeouts.keepalive,
+        "{noticed:?}"
+    );
+    assert!(
+        noticed < timeouts.peer_timeout + Duration::from_millis(100),
+        "{noticed:?}"
+    );
+    let reconnected = connected.duration_since(*lost);
+    assert!(
+        reconnected < reconnect.max_delay + Duration::from_millis(50),
+        "{reconnected:?}"
+    );
+    // The source finds the new connection waiting once it's back
+    assert!(recovered < Duration::from_millis(200), "{recovered:?}");
+}
+
+#[test]
+fn idc_source_takes_unframed_audio_from_older_sinks() {
+    let address = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let timeouts = IdcTimeouts::default();
+    let mut source = IdcSourcePack::new(address, 4096, None, timeouts, Default::default()).unwrap();
+    let format = PcmFormat::new(16, false, 48000, 2).unwrap();
+    let mut sink = IdcSinkPack::new(
+        address,
+        960,
+        format,
+        Duration::from_millis(100),
+        None,
+        timeouts,
+        Default::default(),
+    )
+    .unwrap();
+    sink.set_legacy(true).unwrap();
+    let sent: Vec<u8> = (0..960).map(|i| (i % 251) as u8).collect();
+    let mut received = VecDeque::new();
+    while received.len() < sent.len() {
+        sink.send_from_deque(&mut VecDeque::from(sent.clone()))
+            .unwrap();
+        source.recv_to_deque(&mut received).unwrap();
+    }
+    let received: Vec<u8> = received.into();
+    assert_eq!(received[..sent.len()], sent);
+}
+
+#[test]
+fn idc_source_waits_for_a_sink_without_hanging() {
+    let address = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let timeouts = IdcTimeouts {
+        keepalive: Duration::from_millis(50),
+        peer_timeout: Duration::from_millis(300),
+    };
+    let mut source = IdcSourcePack::new(address, 4096, None, timeouts, Default::default()).unwrap();
+    let start = Instant::now();
+    let mut received = VecDeque::new();
+    source.recv_to_deque(&mut received).unwrap();
+    assert!(received.is_empty());
+    assert!(start.elapsed() < Duration::from_millis(200));
+}
+
+#[test]
+fn idc_sink_drops_whole_frames_over_the_latency_budget() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let format = PcmFormat::new(16, false, 48000, 2).unwrap();
+    let latency_budget = Duration::from_millis(100);
+    let mut sink = IdcSinkPack::new(
+        listener.local_addr().unwrap(),
+        960,
+        format,
+        latency_budget,
+        None,
+        IdcTimeouts {
+            keepalive: Duration::from_millis(100),
+            peer_timeout: Duration::from_secs(30),
+        },
+        Default::default(),
+    )
+    .unwrap();
+    let (mut peer, _) = listener.accept().unwrap();
+
+    // Nobody reads, so the socket buffers fill up and the queue grows.
+    // Every frame carries 5 ms of one byte value
+    let frame_length = Duration::from_millis(5);
+    for i in 0..5000 {
+        sink.send_from_deque(&mut VecDeque::from([i as u8; 960]))
+            .unwrap();
+        assert!(sink.queued_latency() <= latency_budget + frame_length);
+    }
+    assert!(sink.dropped > 0);
+
+    let reading = thread::spawn(move || {
+        let mut decoder = FrameDecoder::new(None);
+        let mut received = VecDeque::new();
+        let mut buf = vec![0; 65536];
+        peer.set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        while let Ok(n_read @ 1..) = peer.read(&mut buf) {
+            decoder
+                .decode(&buf[..n_read], &mut received, "sink")
+                .unwrap();
+        }
+        (decoder, received)
+    });
+    for _ in 0..100 {
+        sink.send_from_deque(&mut VecDeque::new()).unwrap();
+        thread::sleep(Duration::from_millis(5));
+    }
+    let (decoder, received) = reading.join().unwrap();
+
+    // Gaps only ever fall between frames
+    assert_eq!(decoder.skipped, 0);
+    assert!(decoder.lost > 0);
+    let received: Vec<u8> = received.into();
+    assert_eq!(received.len() % 960, 0);
+    for frame in received.chunks(960) {
+        assert!(frame.iter().all(|&byte| byte == frame[0]));
+    }
+}
+
+#[test]
+fn listening_idc_ends_restart_on_the_same_address() {
+    let sink_address = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let source_address = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let timeouts = IdcTimeouts::default();
+    let mut sink = IdcServerSinkPack::new(sink_address, 960, 2, 1 << 20, None, timeouts).unwrap();
+    let mut source =
+        IdcSourcePack::new(source_address, 4096, None, timeouts, Default::default()).unwrap();
+    sink.restart().unwrap();
+    source.restart().unwrap();
+
+    // Both still take connections
+    let mut receiver =
+        IdcSourcePack::connect(sink_address, 4096, None, timeouts, Default::default()).unwrap();
+    let sending = thread::spawn(move || {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_millis(500) {
+            sink.send_from_deque(&mut VecDeque::from([1; 960])).unwrap();
+            thread::sleep(Duration::from_millis(5));
+        }
+    });
+    let mut received = VecDeque::new();
+    while received.is_empty() {
+        receiver.recv_to_deque(&mut received).unwrap();
+    }
+    sending.join().unwrap();
+    TcpStream::connect(source_address).unwrap();
+}
+
+#[test]
+fn idc_sink_without_a_source_drops_whole_audio_frames() {
+    let address = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    // 24 bit stereo, so 6 bytes per audio frame
+    let format = PcmFormat::new(24, false, 48000, 2).unwrap();
+    let mut sink = IdcSinkPack::new(
+        address,
+        960,
+        format,
+        Duration::from_millis(100),
+        None,
+        IdcTimeouts::default(),
+        Default::default(),
+    )
+    .unwrap();
+    // Let the refused connection come back
+    thread::sleep(Duration::from_millis(50));
+    let mut data = VecDeque::from([0; 1000]);
+    sink.send_from_deque(&mut data).unwrap();
+    assert_eq!(data.len(), 1000 % 6);
+}
+
+#[test]
+fn idc_sink_reconnects_on_a_reset_but_not_on_a_quiet_peer() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let format = PcmFormat::new(16, false, 48000, 2).unwrap();
+    let mut sink = IdcSinkPack::new(
+        listener.local_addr().unwrap(),
+        960,
+        format,
+        Duration::from_millis(100),
+        None,
+        IdcTimeouts {
+            keepalive: Duration::from_millis(50),
+            peer_timeout: Duration::from_secs(30),
+        },
+        ReconnectPolicy {
+            min_delay: Duration::from_millis(10),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let mut send_for = |duration| {
+        let start = Instant::now();
+        while start.elapsed() < duration {
+            sink.send_from_deque(&mut VecDeque::from([0; 960])).unwrap();
+            thread::sleep(Duration::from_millis(5));
+        }
+    };
+
+    // Nobody accepts or says anything for a while, that's up to the timeout
+    send_for(Duration::from_millis(300));
+    let (peer, _) = listener.accept().unwrap();
+    listener.set_nonblocking(true).unwrap();
+    assert!(listener.accept().is_err(), "The sink gave up too soon");
+
+    // Resetting the connection gets it dropped right away
+    socket2::SockRef::from(&peer)
+        .set_linger(Some(Duration::ZERO))
+        .unwrap();
+    drop(peer);
+    send_for(Duration::from_millis(300));
+    assert!(listener.accept().is_ok(), "The sink didn't reconnect");
+}
@@ -0,0 +1,315 @@
+//! Runs whole pipelines with stand-in sources and sinks
+
+use std::{
+    collections::VecDeque,
+    io,
+    net::TcpListener,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU32, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, bail};
+use stupid_audio_stream::{
+    Restart,
+    idc::{IdcTimeouts, ReconnectPolicy},
+    pcm::PcmFormat,
+    pipeline::{OverflowPolicy, Pipeline},
+    recovery::{Recovery, RecoveryPolicy},
+    sinks::SendAudio,
+    sources::RecvAudio,
+};
+
+/// Counts up one byte at a time, as fast as it's asked to
+struct CountingSource(u8);
+
+impl RecvAudio for CountingSource {
+    fn recv_to_deque(&mut self, buf: &mut VecDeque<u8>) -> Result<()> {
+        thread::sleep(Duration::from_millis(1));
+        for _ in 0..400 {
+            buf.push_back(self.0);
+            self.0 = self.0.wrapping_add(1);
+        }
+        Ok(())
+    }
+}
+
+impl Restart for CountingSource {
+    fn restart(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Counts how often `0` restarts
+struct Watched<T>(T, Arc<AtomicU32>);
+
+impl<T: RecvAudio> RecvAudio for Watched<T> {
+    fn recv_to_deque(&mut self, buf: &mut VecDeque<u8>) -> Result<()> {
+        self.0.recv_to_deque(buf)
+    }
+}
+
+impl<T> Restart for Watched<T> {
+    fn restart(&mut self) -> Result<()> {
+        self.1.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+/// Takes everything it's given and keeps it
+struct RecordingSink(Arc<Mutex<Vec<u8>>>);
+
+impl SendAudio for RecordingSink {
+    fn send_from_deque(&mut self, data: &mut VecDeque<u8>) -> Result<()> {
+        self.0.lock().unwrap().extend(data.drain(..));
+        thread::sleep(Duration::from_millis(1));
+        Ok(())
+    }
+}
+
+impl Restart for RecordingSink {
+    fn restart(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Never takes anything, and can't restart the first few times
+struct StuckSink {
+    n_restarts: Arc<AtomicU32>,
+}
+
+impl SendAudio for StuckSink {
+    fn send_from_deque(&mut self, _data: &mut VecDeque<u8>) -> Result<()> {
+        thread::sleep(Duration::from_millis(1));
+        Ok(())
+    }
+}
+
+impl Restart for StuckSink {
+    fn restart(&mut self) -> Result<()> {
+        if self.n_restarts.fetch_add(1, Ordering::Relaxed) < 3 {
+            return Err(io::Error::from(io::ErrorKind::ConnectionRefused).into());
+        }
+        Ok(())
+    }
+}
+
+/// Takes nothing until let go, so it can't be told to restart either
+struct BlockedSink(Arc<AtomicBool>);
+
+impl SendAudio for BlockedSink {
+    fn send_from_deque(&mut self, _data: &mut VecDeque<u8>) -> Result<()> {
+        while self.0.load(Ordering::Acquire) {
+            thread::sleep(Duration::from_millis(5));
+        }
+        Ok(())
+    }
+}
+
+impl Restart for BlockedSink {
+    fn restart(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Fails for good once it had the time to get going
+struct FailingSink {
+    fails_at: Instant,
+}
+
+impl FailingSink {
+    fn new() -> Self {
+        Self {
+            fails_at: Instant::now() + Duration::from_millis(200),
+        }
+    }
+}
+
+impl SendAudio for FailingSink {
+    fn send_from_deque(&mut self, data: &mut VecDeque<u8>) -> Result<()> {
+        if Instant::now() >= self.fails_at {
+            bail!("The sink is gone");
+        }
+        data.clear();
+        thread::sleep(Duration::from_millis(5));
+        Ok(())
+    }
+}
+
+impl Restart for FailingSink {
+    fn restart(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+fn assert_counting(bytes: &[u8]) {
+    assert!(bytes.len() > 10_000, "Only {} bytes arrived", bytes.len());
+    for pair in bytes.windows(2) {
+        assert_eq!(pair[1], pair[0].wrapping_add(1), "Audio went missing");
+    }
+}
+
+/// Short, so that a source waiting for a connection notices the stop soon
+fn timeouts() -> IdcTimeouts {
+    IdcTimeouts {
+        keepalive: Duration::from_millis(50),
+        ..Default::default()
+    }
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+#[test]
+fn rebuilt_pipeline_listens_on_the_same_port() {
+    let source = format!("idc-listen://127.0.0.1:{}", free_port());
+    let failing = Pipeline::builder()
+        .source(&source)
+        .timeouts(timeouts())
+        .sink_with(|| Ok(Box::new(FailingSink::new())))
+        .build()
+        .unwrap();
+    assert!(failing.run().is_err());
+
+    // Right away, like a route restarting
+    let rebuilt = Pipeline::builder()
+        .source(&source)
+        .timeouts(timeouts())
+        .sink_with(|| Ok(Box::new(FailingSink::new())))
+        .build()
+        .unwrap();
+    let err = rebuilt.run().unwrap_err();
+    assert!(
+        format!("{err:#}").contains("The sink is gone"),
+        "The source couldn't listen again: {err:#}"
+    );
+}
+
+#[test]
+fn failed_restarts_go_through_the_recovery_policy() {
+    let n_restarts = Arc::new(AtomicU32::new(0));
+    let sink_restarts = n_restarts.clone();
+    let pipeline = Pipeline::builder()
+        .source_with(|| Ok(Box::new(CountingSource(0))))
+        .sink_with(move || {
+            Ok(Box::new(StuckSink {
+                n_restarts: sink_restarts,
+            }))
+        })
+        .format(PcmFormat::new(8, false, 48000, 1).unwrap())
+        .buffer(4000)
+        .restart_on_buffer_filled(true)
+        .recovery(RecoveryPolicy::uniform(Recovery::Restart))
+        .reconnect(ReconnectPolicy {
+            min_delay: Duration::from_millis(5),
+            ..Default::default()
+        })
+        .build()
+        .unwrap();
+    let handle = pipeline.handle();
+    let running = thread::spawn(move || pipeline.run());
+    let start = Instant::now();
+    while n_restarts.load(Ordering::Relaxed) < 5 {
+        assert!(!running.is_finished(), "{:?}", running.join().unwrap());
+        assert!(start.elapsed() < Duration::from_secs(10));
+        thread::sleep(Duration::from_millis(10));
+    }
+    handle.stop();
+    running.join().unwrap().unwrap();
+}
+
+#[test]
+fn every_sink_gets_the_whole_stream() {
+    let recorded: [Arc<Mutex<Vec<u8>>>; 3] = Default::default();
+    let mut builder = Pipeline::builder()
+        .source_with(|| Ok(Box::new(CountingSource(0))))
+        .format(PcmFormat::new(8, false, 48000, 1).unwrap())
+        .buffer(100_000)
+        .overflow(OverflowPolicy::Block);
+    let main = recorded[0].clone();
+    builder = builder.sink_with(move || Ok(Box::new(RecordingSink(main))));
+    for tee in &recorded[1..] {
+        let tee = tee.clone();
+        builder = builder.tee_with(move || Ok(Box::new(RecordingSink(tee.clone()))));
+    }
+    let pipeline = builder.build().unwrap();
+    let handle = pipeline.handle();
+    let running = thread::spawn(move || pipeline.run());
+    thread::sleep(Duration::from_millis(300));
+    handle.stop();
+    running.join().unwrap().unwrap();
+
+    for sink in &recorded {
+        assert_counting(&sink.lock().unwrap());
+    }
+    assert_eq!(handle.stats().dropped, 0);
+}
+
+#[test]
+fn stuck_blocking_tee_keeps_at_most_a_buffer_and_holds_up_nothing() {
+    let recorded = Arc::new(Mutex::new(Vec::new()));
+    let main = recorded.clone();
+    let pipeline = Pipeline::builder()
+        .source_with(|| Ok(Box::new(CountingSource(0))))
+        .sink_with(move || Ok(Box::new(RecordingSink(main))))
+        .tee_with(|| {
+            Ok(Box::new(StuckSink {
+                n_restarts: Arc::default(),
+            }))
+        })
+        .format(PcmFormat::new(8, false, 48000, 1).unwrap())
+        .buffer(4000)
+        .datagram_size(400)
+        .overflow(OverflowPolicy::Block)
+        .build()
+        .unwrap();
+    let handle = pipeline.handle();
+    let running = thread::spawn(move || pipeline.run());
+    thread::sleep(Duration::from_millis(300));
+    handle.stop();
+    running.join().unwrap().unwrap();
+
+    assert_counting(&recorded.lock().unwrap());
+    // The tee's ring, as much again waiting for it and a datagram in the
+    // tee itself, the rest is dropped
+    let stats = handle.stats();
+    assert!(
+        stats.received - stats.dropped <= 2 * 4000 + 400,
+        "The tee kept {} bytes",
+        stats.received - stats.dropped
+    );
+}
+
+#[test]
+fn full_tee_restarts_only_itself() {
+    for _ in 0..5 {
+        let n_restarts = Arc::new(AtomicU32::new(0));
+        let source_restarts = n_restarts.clone();
+        let blocked = Arc::new(AtomicBool::new(true));
+        let tee_blocked = blocked.clone();
+        let pipeline = Pipeline::builder()
+            .source_with(|| Ok(Box::new(Watched(CountingSource(0), source_restarts))))
+            .sink_with(|| Ok(Box::new(RecordingSink(Arc::default()))))
+            .tee_with(move || Ok(Box::new(BlockedSink(tee_blocked.clone()))))
+            .format(PcmFormat::new(8, false, 48000, 1).unwrap())
+            .buffer(4000)
+            .restart_on_buffer_filled(true)
+            .build()
+            .unwrap();
+        let handle = pipeline.handle();
+        let running = thread::spawn(move || pipeline.run());
+        thread::sleep(Duration::from_millis(100));
+        handle.stop();
+        blocked.store(false, Ordering::Release);
+        running.join().unwrap().unwrap();
+        assert_eq!(n_restarts.load(Ordering::Relaxed), 0);
+    }
+}
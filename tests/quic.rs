@@ -0,0 +1,251 @@
+//! Runs QUIC sinks and sources against each other over localhost
+
+#![cfg(feature = "quic")]
+
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    sync::{Arc, mpsc},
+    thread,
+    time::{Duration, Instant},
+};
+
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use stupid_audio_stream::{
+    Restart,
+    idc::{ConnectionState, IdcTimeouts, ReconnectPolicy},
+    network_utils::DatagramSocket,
+    pcm::PcmFormat,
+    plc::{Concealer, Concealment},
+    quic::{QuicCerts, QuicSinkPack, QuicSocket},
+    sinks::SendAudio,
+    sources::{RecvAudio, network::CheckedUdpSourcePack},
+};
+
+const LOCALHOST: &str = "127.0.0.1:0";
+
+fn format() -> PcmFormat {
+    PcmFormat::new(16, false, 48000, 2).unwrap()
+}
+
+fn timeouts() -> IdcTimeouts {
+    IdcTimeouts {
+        keepalive: Duration::from_millis(100),
+        peer_timeout: Duration::from_millis(500),
+    }
+}
+
+/// Retries quickly, so that a listener that turned away a bad peer takes
+/// the next one soon
+fn reconnect() -> ReconnectPolicy {
+    ReconnectPolicy {
+        min_delay: Duration::from_millis(20),
+        max_delay: Duration::from_millis(100),
+        ..Default::default()
+    }
+}
+
+/// A fresh self-signed certificate for `localhost` with its key
+fn certificate() -> (CertificateDer<'static>, QuicCerts) {
+    let generated = rcgen::generate_simple_self_signed(["localhost".to_string()]).unwrap();
+    let cert = generated.cert.der().clone();
+    let certs = QuicCerts {
+        cert: Some(vec![cert.clone()]),
+        key: Some(PrivateKeyDer::Pkcs8(
+            generated.signing_key.serialize_der().into(),
+        )),
+    };
+    (cert, certs)
+}
+
+fn trusting(cert: CertificateDer<'static>) -> QuicCerts {
+    QuicCerts {
+        cert: Some(vec![cert]),
+        key: None,
+    }
+}
+
+fn listen(certs: &QuicCerts) -> (QuicSocket, String) {
+    let socket = QuicSocket::listen(
+        LOCALHOST.parse().unwrap(),
+        format(),
+        certs,
+        timeouts(),
+        reconnect(),
+    )
+    .unwrap();
+    let address = format!("localhost:{}", socket.local_addr().unwrap().port());
+    (socket, address)
+}
+
+fn connect(address: &str, certs: &QuicCerts) -> QuicSocket {
+    QuicSocket::connect(address, format(), certs, timeouts(), reconnect()).unwrap()
+}
+
+/// Receives datagrams in the background and passes on their ids
+fn ids_of(mut socket: QuicSocket) -> mpsc::Receiver<u64> {
+    let (ids, received) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buf = [0; 2000];
+        while let Ok((n_read, _)) = socket.recv_from(&mut buf) {
+            assert!(n_read >= 8);
+            if ids
+                .send(u64::from_be_bytes(buf[..8].try_into().unwrap()))
+                .is_err()
+            {
+                return;
+            }
+        }
+    });
+    received
+}
+
+#[test]
+fn audio_arrives_whole_over_localhost() {
+    let (socket, address) = listen(&QuicCerts::default());
+    let receiving = thread::spawn(move || {
+        let concealer = Concealer::new(format(), Concealment::Silence);
+        let mut source = CheckedUdpSourcePack::with_socket(socket, 5000, 4, concealer);
+        let mut received = VecDeque::new();
+        while received.len() < 40000 {
+            source.recv_to_deque(&mut received).unwrap();
+        }
+        // The source too, dropping it would close the connection
+        (received, source)
+    });
+    let mut socket = connect(&address, &QuicCerts::default());
+    let (states, seen) = mpsc::channel();
+    socket.on_state_change(move |state| {
+        let _ = states.send(state);
+    });
+    let mut sink = QuicSinkPack::new(socket, 5000);
+
+    let start = Instant::now();
+    let mut sent = Vec::new();
+    let mut counter = 0u32;
+    while !receiving.is_finished() && start.elapsed() < Duration::from_secs(10) {
+        let chunk: Vec<u8> = (0..960)
+            .map(|_| {
+                counter += 1;
+                (counter % 251) as u8
+            })
+            .collect();
+        let mut data = VecDeque::from(chunk.clone());
+        sink.send_from_deque(&mut data).unwrap();
+        assert!(data.is_empty());
+        // Audio sent before the connection is up is dropped
+        if sink.socket.max_datagram_size().is_some() {
+            sent.extend(chunk);
+        }
+        thread::sleep(Duration::from_millis(5));
+    }
+    let (received, _source) = receiving.join().unwrap();
+    let received: Vec<u8> = received.into();
+
+    // Past the start, what arrived is a stretch of what was sent
+    let tail = &received[20000..];
+    let start = sent
+        .windows(64)
+        .position(|window| window == &tail[..64])
+        .unwrap();
+    assert_eq!(&sent[start..start + tail.len()], tail);
+    assert!(
+        seen.try_iter()
+            .any(|state| state == ConnectionState::Connected)
+    );
+    // A datagram too large for the path is lost like any other
+    let max_datagram_size = sink.socket.max_datagram_size().unwrap();
+    let mut socket = sink.socket;
+    assert!(socket.send(&vec![0; max_datagram_size + 1]).is_ok());
+}
+
+#[test]
+fn sink_only_trusts_the_pinned_certificate() {
+    let (cert, certs) = certificate();
+    let (other, _) = certificate();
+    let (socket, address) = listen(&certs);
+    let _ids = ids_of(socket);
+
+    for (trusted, connects) in [(other, false), (cert, true)] {
+        let mut socket = connect(&address, &trusting(trusted));
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(2) && socket.max_datagram_size().is_none() {
+            socket.send(&[0; 8]).unwrap();
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(socket.max_datagram_size().is_some(), connects);
+    }
+}
+
+#[test]
+fn source_doesnt_wait_forever_for_a_silent_peer() {
+    let (cert, certs) = certificate();
+    let (socket, address) = listen(&certs);
+    let target: SocketAddr = socket.local_addr().unwrap();
+    let ids = ids_of(socket);
+
+    // Connects but never opens the control stream
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let _silent = runtime.block_on(async {
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(cert.clone()).unwrap();
+        let mut crypto = rustls::ClientConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+        crypto.alpn_protocols = vec![b"stupid-audio-stream".to_vec()];
+        let config = quinn::ClientConfig::new(Arc::new(
+            quinn::crypto::rustls::QuicClientConfig::try_from(crypto).unwrap(),
+        ));
+        let endpoint = quinn::Endpoint::client(LOCALHOST.parse().unwrap()).unwrap();
+        let connection = endpoint
+            .connect_with(config, target, "localhost")
+            .unwrap()
+            .await
+            .unwrap();
+        (endpoint, connection)
+    });
+
+    let mut sink = QuicSinkPack::new(connect(&address, &trusting(cert)), 2000);
+    let start = Instant::now();
+    while ids.try_recv().is_err() {
+        assert!(
+            start.elapsed() < Duration::from_secs(3),
+            "The source still waits for the silent peer"
+        );
+        sink.send_from_deque(&mut VecDeque::from([0; 960])).unwrap();
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn restarted_sink_counts_from_zero() {
+    let (socket, address) = listen(&QuicCerts::default());
+    let ids = ids_of(socket);
+    let mut sink = QuicSinkPack::new(connect(&address, &QuicCerts::default()), 2000);
+    let send = |sink: &mut QuicSinkPack| {
+        sink.send_from_deque(&mut VecDeque::from([0; 960])).unwrap();
+        thread::sleep(Duration::from_millis(2));
+    };
+
+    let mut last = 0;
+    let start = Instant::now();
+    while last < 300 {
+        assert!(start.elapsed() < Duration::from_secs(5));
+        send(&mut sink);
+        last = ids.try_iter().last().unwrap_or(last);
+    }
+    sink.restart().unwrap();
+    let start = Instant::now();
+    // Some early ones are lost while the new connection comes up
+    while !ids.try_iter().any(|id| id < 300) {
+        assert!(
+            start.elapsed() < Duration::from_secs(5),
+            "Still counting on after the restart"
+        );
+        send(&mut sink);
+    }
+}
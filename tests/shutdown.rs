@@ -0,0 +1,173 @@
+//! Runs the binary and stops it like a user or a service manager would
+
+#![cfg(feature = "cli")]
+
+use std::{
+    collections::VecDeque,
+    io::Read,
+    net::{TcpListener, UdpSocket},
+    path::PathBuf,
+    process::{Child, Command, ExitStatus, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use stupid_audio_stream::idc::FrameDecoder;
+
+fn command() -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_stupid-audio-stream"));
+    command.stdout(Stdio::null()).stderr(Stdio::null());
+    command
+}
+
+/// Fails the test if `child` takes longer than a few seconds to exit
+fn wait(child: &mut Child) -> ExitStatus {
+    let start = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            return status;
+        }
+        if start.elapsed() > Duration::from_secs(5) {
+            child.kill().unwrap();
+            panic!("Still running after {:?}", start.elapsed());
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[cfg(unix)]
+fn signal(child: &Child, name: &str) {
+    let status = Command::new("kill")
+        .args([format!("-{name}"), child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+}
+
+/// Sends 800 bytes of audio to `address` every 5 ms for `duration`
+fn send_udp(address: &str, duration: Duration) {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        socket.send_to(&[1; 800], address).unwrap();
+        thread::sleep(Duration::from_millis(5));
+    }
+}
+
+fn udp_address() -> String {
+    UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string()
+}
+
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("sas-{}-{name}", std::process::id()))
+}
+
+#[test]
+fn bad_command_line_exits_with_2() {
+    let status = command().arg("--bogus").status().unwrap();
+    assert_eq!(status.code(), Some(2));
+    let status = command().arg("udp://0.0.0.0:0").status().unwrap();
+    assert_eq!(status.code(), Some(2));
+}
+
+#[test]
+fn failing_route_exits_with_1() {
+    let status = command()
+        .args(["udp://127.0.0.1:0", "udp://127.0.0.1:9?bogus=1"])
+        .status()
+        .unwrap();
+    assert_eq!(status.code(), Some(1));
+
+    let config = temp_path("broken.toml");
+    std::fs::write(&config, "[routes.a]\nsource = \"udp://127.0.0.1:0\"\n").unwrap();
+    let status = command().arg("--config").arg(&config).status().unwrap();
+    std::fs::remove_file(&config).unwrap();
+    assert_eq!(status.code(), Some(1));
+}
+
+#[test]
+fn listing_schemes_exits_with_0() {
+    let output = Command::new(env!("CARGO_BIN_EXE_stupid-audio-stream"))
+        .arg("--list-schemes")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let listed = String::from_utf8(output.stdout).unwrap();
+    assert!(listed.contains("Sources:") && listed.contains("Sinks:"));
+    assert!(listed.contains("idc-connect"), "{listed}");
+}
+
+#[cfg(unix)]
+#[test]
+fn sigterm_finishes_the_recordings_of_every_route() {
+    let sources = [udp_address(), udp_address()];
+    let recordings = [temp_path("first.wav"), temp_path("second.wav")];
+    let config = temp_path("routes.toml");
+    let mut text = String::new();
+    for (i, (source, recording)) in sources.iter().zip(&recordings).enumerate() {
+        text += &format!(
+            "[routes.route-{i}]\nsource = \"udp://{source}\"\nsink = {:?}\n",
+            format!("file://{}", recording.display())
+        );
+    }
+    std::fs::write(&config, text).unwrap();
+    let mut child = command().arg("--config").arg(&config).spawn().unwrap();
+    thread::scope(|scope| {
+        for source in &sources {
+            scope.spawn(|| send_udp(source, Duration::from_millis(500)));
+        }
+    });
+    signal(&child, "TERM");
+    let status = wait(&mut child);
+    std::fs::remove_file(&config).unwrap();
+    assert!(status.success(), "{status}");
+
+    for recording in &recordings {
+        let wav = std::fs::read(recording).unwrap();
+        std::fs::remove_file(recording).unwrap();
+        let data_len = u32::from_le_bytes(wav[40..44].try_into().unwrap());
+        assert!(data_len > 0);
+        // The header says how long the audio is, not just how long it was
+        // the last time it caught up
+        assert_eq!(data_len as usize, wav.len() - 44);
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn sigint_says_goodbye_to_idc_peers() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let source = udp_address();
+    let mut child = command()
+        .args([
+            format!("udp://{source}"),
+            format!("idc-connect://{}", listener.local_addr().unwrap()),
+        ])
+        .spawn()
+        .unwrap();
+    let (mut peer, _) = listener.accept().unwrap();
+    send_udp(&source, Duration::from_millis(200));
+    signal(&child, "INT");
+
+    peer.set_read_timeout(Some(Duration::from_secs(3))).unwrap();
+    let mut decoder = FrameDecoder::new(None);
+    let mut received = VecDeque::new();
+    let mut buf = [0; 4096];
+    loop {
+        let n_read = peer.read(&mut buf).unwrap();
+        if n_read == 0 {
+            break;
+        }
+        decoder
+            .decode(&buf[..n_read], &mut received, "test")
+            .unwrap();
+    }
+    assert!(!received.is_empty());
+    assert!(decoder.said_goodbye);
+    let status = wait(&mut child);
+    assert!(status.success(), "{status}");
+}